@@ -0,0 +1,850 @@
+//! Build script that generates Rust types for Kafka protocol messages.
+//!
+//! Apache Kafka describes every request, response and internal record in JSON message specs
+//! (`clients/src/main/resources/common/message/*.json` upstream). Copies of the specs the
+//! broker implements are vendored under `resources/message/`. For each spec this script emits
+//! a module containing one struct per (nested) struct in the spec, with `read`/`write` methods
+//! that honour `versions`, `nullableVersions`, `taggedVersions`, `flexibleVersions` and field
+//! defaults.
+//!
+//! The output is written to `$OUT_DIR/kafka_messages.rs` and included by
+//! `src/kafka_protocol/kafka_messages.rs`.
+
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::{env, fs};
+
+const SPEC_DIR: &str = "resources/message";
+
+fn main() {
+    println!("cargo:rerun-if-changed={SPEC_DIR}");
+
+    let mut paths: Vec<_> = fs::read_dir(SPEC_DIR)
+        .unwrap_or_else(|e| panic!("failed to read {SPEC_DIR}: {e}"))
+        .map(|entry| entry.expect("failed to read spec directory entry").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    paths.sort();
+
+    let mut out = String::new();
+    out.push_str("// @generated by build.rs from resources/message/*.json. Do not edit.\n\n");
+
+    for path in &paths {
+        println!("cargo:rerun-if-changed={}", path.display());
+        let spec = MessageSpec::load(path);
+        spec.generate(&mut out);
+    }
+
+    let out_path =
+        Path::new(&env::var("OUT_DIR").expect("OUT_DIR not set")).join("kafka_messages.rs");
+    fs::write(&out_path, out)
+        .unwrap_or_else(|e| panic!("failed to write {}: {e}", out_path.display()));
+}
+
+/* ---------------------------------------------------------------------------------------------
+Spec model
+--------------------------------------------------------------------------------------------- */
+
+/// An inclusive range of versions, as written in specs (`"0+"`, `"1-3"`, `"2"`, `"none"`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Versions {
+    min: i16,
+    max: i16,
+}
+
+impl Versions {
+    const NONE: Versions = Versions { min: 1, max: 0 };
+
+    fn parse(text: &str) -> Versions {
+        let text = text.trim();
+        if text == "none" {
+            Versions::NONE
+        } else if let Some(min) = text.strip_suffix('+') {
+            Versions {
+                min: parse_i16(min),
+                max: i16::MAX,
+            }
+        } else if let Some((min, max)) = text.split_once('-') {
+            Versions {
+                min: parse_i16(min),
+                max: parse_i16(max),
+            }
+        } else {
+            let v = parse_i16(text);
+            Versions { min: v, max: v }
+        }
+    }
+
+    fn is_empty(self) -> bool {
+        self.min > self.max
+    }
+
+    fn intersect(self, other: Versions) -> Versions {
+        Versions {
+            min: self.min.max(other.min),
+            max: self.max.min(other.max),
+        }
+    }
+
+    fn contains_all(self, other: Versions) -> bool {
+        other.is_empty() || (self.min <= other.min && self.max >= other.max)
+    }
+
+    /// Returns a boolean Rust expression testing whether `version` is in this range, assuming
+    /// it is already known to be within `context`.
+    fn condition(self, context: Versions) -> String {
+        if self.is_empty() || self.intersect(context).is_empty() {
+            return "false".to_string();
+        }
+        let mut parts = Vec::new();
+        if self.min > context.min {
+            parts.push(format!("version >= {}", self.min));
+        }
+        if self.max < context.max {
+            parts.push(format!("version <= {}", self.max));
+        }
+        if parts.is_empty() {
+            "true".to_string()
+        } else {
+            parts.join(" && ")
+        }
+    }
+}
+
+fn parse_i16(text: &str) -> i16 {
+    text.trim()
+        .parse()
+        .unwrap_or_else(|_| panic!("invalid version number {text:?}"))
+}
+
+/// The type of a field.
+#[derive(Debug, Clone, PartialEq)]
+enum FieldType {
+    Bool,
+    Int8,
+    Int16,
+    Uint16,
+    Int32,
+    Uint32,
+    Int64,
+    Float64,
+    String,
+    Bytes,
+    Records,
+    Uuid,
+    Array(Box<FieldType>),
+    Struct(String),
+}
+
+impl FieldType {
+    fn parse(text: &str) -> FieldType {
+        if let Some(element) = text.strip_prefix("[]") {
+            return FieldType::Array(Box::new(FieldType::parse(element)));
+        }
+        match text {
+            "bool" => FieldType::Bool,
+            "int8" => FieldType::Int8,
+            "int16" => FieldType::Int16,
+            "uint16" => FieldType::Uint16,
+            "int32" => FieldType::Int32,
+            "uint32" => FieldType::Uint32,
+            "int64" => FieldType::Int64,
+            "float64" => FieldType::Float64,
+            "string" => FieldType::String,
+            "bytes" => FieldType::Bytes,
+            "records" => FieldType::Records,
+            "uuid" => FieldType::Uuid,
+            other => FieldType::Struct(other.to_string()),
+        }
+    }
+
+    /// The Rust type used for a non-null value.
+    fn rust_type(&self) -> String {
+        match self {
+            FieldType::Bool => "bool".into(),
+            FieldType::Int8 => "i8".into(),
+            FieldType::Int16 => "i16".into(),
+            FieldType::Uint16 => "u16".into(),
+            FieldType::Int32 => "i32".into(),
+            FieldType::Uint32 => "u32".into(),
+            FieldType::Int64 => "i64".into(),
+            FieldType::Float64 => "f64".into(),
+            FieldType::String => "String".into(),
+            FieldType::Bytes => "Vec<u8>".into(),
+            FieldType::Records => "Records".into(),
+            FieldType::Uuid => "Uuid".into(),
+            FieldType::Array(element) => format!("Vec<{}>", element.rust_type()),
+            FieldType::Struct(name) => name.clone(),
+        }
+    }
+
+    /// Whether values of this type are `Copy` in Rust.
+    fn is_copy(&self) -> bool {
+        !self.can_be_nullable()
+    }
+
+    /// Whether values of this type can be null on the wire (given `nullableVersions`).
+    fn can_be_nullable(&self) -> bool {
+        matches!(
+            self,
+            FieldType::String
+                | FieldType::Bytes
+                | FieldType::Records
+                | FieldType::Array(_)
+                | FieldType::Struct(_)
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Field {
+    name: String,
+    ty: FieldType,
+    versions: Versions,
+    nullable_versions: Versions,
+    tagged_versions: Versions,
+    tag: Option<u32>,
+    default: Option<String>,
+    about: Option<String>,
+    /// Inline struct definition for `Struct`/`[]Struct` fields.
+    fields: Option<Vec<Field>>,
+}
+
+impl Field {
+    fn parse(value: &Value) -> Field {
+        let name = str_attr(value, "name")
+            .expect("field without a name")
+            .to_string();
+        let ty = FieldType::parse(str_attr(value, "type").expect("field without a type"));
+        let versions = str_attr(value, "versions").map_or(Versions::NONE, Versions::parse);
+        let nullable_versions =
+            str_attr(value, "nullableVersions").map_or(Versions::NONE, Versions::parse);
+        let tagged_versions =
+            str_attr(value, "taggedVersions").map_or(Versions::NONE, Versions::parse);
+        let tag = value.get("tag").and_then(Value::as_u64).map(|t| t as u32);
+        let default = value.get("default").map(|d| match d {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        });
+        let about = str_attr(value, "about").map(str::to_string);
+        let fields = value
+            .get("fields")
+            .and_then(Value::as_array)
+            .map(|fields| fields.iter().map(Field::parse).collect());
+
+        Field {
+            name,
+            ty,
+            versions,
+            nullable_versions,
+            tagged_versions,
+            tag,
+            default,
+            about,
+            fields,
+        }
+    }
+
+    fn rust_name(&self) -> String {
+        let name = snake_case(&self.name);
+        match name.as_str() {
+            "type" | "match" | "ref" | "mod" | "use" | "struct" | "fn" | "loop" | "move"
+            | "self" => {
+                format!("r#{name}")
+            }
+            _ => name,
+        }
+    }
+
+    /// Fields that are nullable in any version are stored as `Option`.
+    fn is_option(&self) -> bool {
+        self.ty.can_be_nullable() && !self.nullable_versions.is_empty()
+    }
+
+    fn rust_type(&self) -> String {
+        if self.is_option() {
+            format!("Option<{}>", self.ty.rust_type())
+        } else {
+            self.ty.rust_type()
+        }
+    }
+
+    /// The Rust expression for the field's default value.
+    fn default_expr(&self) -> String {
+        let default = self.default.as_deref().map(str::trim);
+        if self.is_option() && default == Some("null") {
+            return "None".into();
+        }
+        let value = match &self.ty {
+            FieldType::Bool => match default {
+                Some("true") => "true".into(),
+                _ => "false".into(),
+            },
+            FieldType::Int8
+            | FieldType::Int16
+            | FieldType::Uint16
+            | FieldType::Int32
+            | FieldType::Uint32
+            | FieldType::Int64 => match default {
+                Some(d) if !d.is_empty() => {
+                    let value = match d.strip_prefix("0x").or_else(|| d.strip_prefix("0X")) {
+                        Some(hex) => i128::from_str_radix(hex, 16).expect("invalid hex default"),
+                        None => d.parse::<i128>().expect("invalid integer default"),
+                    };
+                    value.to_string()
+                }
+                _ => "0".into(),
+            },
+            FieldType::Float64 => match default {
+                Some(d) if !d.is_empty() => {
+                    format!("{}_f64", d.parse::<f64>().expect("invalid float default"))
+                }
+                _ => "0.0".into(),
+            },
+            FieldType::String => match default {
+                Some(d) => format!("{d:?}.to_string()"),
+                None => "String::new()".into(),
+            },
+            FieldType::Uuid => "Uuid::nil()".into(),
+            // Records default to null, like the Java generator.
+            FieldType::Records if self.is_option() => return "None".into(),
+            FieldType::Bytes | FieldType::Array(_) => "Vec::new()".into(),
+            FieldType::Records => "Records::default()".into(),
+            // Nullable structs default to null; others to their own defaults.
+            FieldType::Struct(_) if self.is_option() => return "None".into(),
+            FieldType::Struct(name) => format!("{name}::default()"),
+        };
+        if self.is_option() {
+            format!("Some({value})")
+        } else {
+            value
+        }
+    }
+}
+
+fn str_attr<'a>(value: &'a Value, key: &str) -> Option<&'a str> {
+    value.get(key).and_then(Value::as_str)
+}
+
+/// A struct to emit: the message itself, an inline nested struct, or a common struct.
+struct StructSpec {
+    name: String,
+    versions: Versions,
+    fields: Vec<Field>,
+    about: Option<String>,
+}
+
+struct MessageSpec {
+    name: String,
+    kind: String,
+    api_key: Option<i16>,
+    valid_versions: Versions,
+    flexible_versions: Versions,
+    fields: Vec<Field>,
+    common_structs: Vec<StructSpec>,
+}
+
+impl MessageSpec {
+    fn load(path: &Path) -> MessageSpec {
+        let text = fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("failed to read {}: {e}", path.display()));
+        // Specs contain `//` line comments, which JSON doesn't allow.
+        let json: String = text
+            .lines()
+            .filter(|line| !line.trim_start().starts_with("//"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let value: Value = serde_json::from_str(&json)
+            .unwrap_or_else(|e| panic!("failed to parse {}: {e}", path.display()));
+
+        let fields = value["fields"]
+            .as_array()
+            .map(|fields| fields.iter().map(Field::parse).collect())
+            .unwrap_or_default();
+        let valid_versions =
+            Versions::parse(str_attr(&value, "validVersions").expect("missing validVersions"));
+        let common_structs = value
+            .get("commonStructs")
+            .and_then(Value::as_array)
+            .map(|structs| {
+                structs
+                    .iter()
+                    .map(|s| StructSpec {
+                        name: str_attr(s, "name")
+                            .expect("common struct without a name")
+                            .to_string(),
+                        versions: str_attr(s, "versions").map_or(valid_versions, Versions::parse),
+                        fields: s["fields"]
+                            .as_array()
+                            .expect("common struct without fields")
+                            .iter()
+                            .map(Field::parse)
+                            .collect(),
+                        about: None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        MessageSpec {
+            name: str_attr(&value, "name")
+                .expect("spec without a name")
+                .to_string(),
+            kind: str_attr(&value, "type").unwrap_or("data").to_string(),
+            api_key: value
+                .get("apiKey")
+                .and_then(Value::as_i64)
+                .map(|k| k as i16),
+            valid_versions,
+            flexible_versions: str_attr(&value, "flexibleVersions")
+                .map_or(Versions::NONE, Versions::parse),
+            fields,
+            common_structs,
+        }
+    }
+
+    /// Collects every struct in the message: the top-level one, inline nested ones, and common
+    /// structs, de-duplicated by name.
+    fn structs(&self) -> Vec<StructSpec> {
+        let mut structs = vec![StructSpec {
+            name: self.name.clone(),
+            versions: self.valid_versions,
+            fields: self.fields.clone(),
+            about: Some(format!(
+                "{} ({} {}).",
+                self.name,
+                self.kind,
+                self.api_key.map_or("-".to_string(), |k| k.to_string())
+            )),
+        }];
+        collect_nested(&self.fields, self.valid_versions, &mut structs);
+        for common in &self.common_structs {
+            structs.push(StructSpec {
+                name: common.name.clone(),
+                versions: common.versions.intersect(self.valid_versions),
+                fields: common.fields.clone(),
+                about: None,
+            });
+            collect_nested(&common.fields, common.versions, &mut structs);
+        }
+
+        let mut seen = HashSet::new();
+        structs.retain(|s| seen.insert(s.name.clone()));
+        structs
+    }
+
+    fn generate(&self, out: &mut String) {
+        let module = snake_case(&self.name);
+        writeln!(out, "pub mod {module} {{").unwrap();
+        out.push_str("    use crate::kafka_protocol::kafka_codec::*;\n");
+        out.push_str("    use crate::kafka_protocol::kafka_error::KafkaResult;\n");
+        out.push_str(
+            "    use crate::kafka_protocol::kafka_records::{write_records, EncodeBuf, Records};\n",
+        );
+        out.push_str("    use uuid::Uuid;\n\n");
+        if let Some(api_key) = self.api_key {
+            writeln!(out, "    /// The API key of this message.").unwrap();
+            writeln!(out, "    pub const API_KEY: i16 = {api_key};").unwrap();
+        }
+        writeln!(out, "    /// The lowest version of this message.").unwrap();
+        writeln!(
+            out,
+            "    pub const LOWEST_VERSION: i16 = {};",
+            self.valid_versions.min
+        )
+        .unwrap();
+        writeln!(out, "    /// The highest version of this message.").unwrap();
+        writeln!(
+            out,
+            "    pub const HIGHEST_VERSION: i16 = {};\n",
+            self.valid_versions.max
+        )
+        .unwrap();
+
+        for spec in self.structs() {
+            self.generate_struct(out, &spec);
+        }
+        out.push_str("}\n");
+        writeln!(out, "pub use {module}::{};\n", self.name).unwrap();
+    }
+
+    fn generate_struct(&self, out: &mut String, spec: &StructSpec) {
+        let context = spec.versions;
+        let fields: Vec<&Field> = spec
+            .fields
+            .iter()
+            .filter(|f| {
+                !f.versions.intersect(context).is_empty()
+                    || !f.tagged_versions.intersect(context).is_empty()
+            })
+            .collect();
+
+        // Definition.
+        if let Some(about) = &spec.about {
+            writeln!(out, "    /// {about}").unwrap();
+        }
+        out.push_str("    #[derive(Debug, Clone, PartialEq)]\n");
+        writeln!(out, "    pub struct {} {{", spec.name).unwrap();
+        for field in &fields {
+            if let Some(about) = &field.about {
+                writeln!(out, "        /// {}", about.replace('\n', " ")).unwrap();
+            }
+            writeln!(
+                out,
+                "        pub {}: {},",
+                field.rust_name(),
+                field.rust_type()
+            )
+            .unwrap();
+        }
+        out.push_str("        /// Tagged fields not described by the spec, preserved as-is.\n");
+        out.push_str("        pub unknown_tagged_fields: Vec<RawTaggedField>,\n");
+        out.push_str("    }\n\n");
+
+        // Default.
+        writeln!(out, "    impl Default for {} {{", spec.name).unwrap();
+        out.push_str("        fn default() -> Self {\n            Self {\n");
+        for field in &fields {
+            writeln!(
+                out,
+                "                {}: {},",
+                field.rust_name(),
+                field.default_expr()
+            )
+            .unwrap();
+        }
+        out.push_str("                unknown_tagged_fields: Vec::new(),\n");
+        out.push_str("            }\n        }\n    }\n\n");
+
+        let flexible = self.flexible_versions.condition(context);
+
+        // Reader.
+        writeln!(out, "    impl {} {{", spec.name).unwrap();
+        out.push_str("        /// Decodes this struct as encoded for `version`.\n");
+        out.push_str(
+            "        pub fn read(cursor: &mut &[u8], version: i16) -> KafkaResult<Self> {\n",
+        );
+        writeln!(out, "            let flexible = {flexible};").unwrap();
+        out.push_str("            let mut this = Self::default();\n");
+        for field in &fields {
+            let Some(condition) = inline_condition(field, context) else {
+                continue;
+            };
+            writeln!(out, "            if {condition} {{").unwrap();
+            writeln!(
+                out,
+                "                this.{} = {};",
+                field.rust_name(),
+                read_field_expr(field, context, "cursor")
+            )
+            .unwrap();
+            out.push_str("            }\n");
+        }
+        out.push_str("            if flexible {\n");
+        out.push_str("                for field in read_tagged_fields(cursor)? {\n");
+        out.push_str("                    match field.tag {\n");
+        for field in fields.iter().filter(|f| f.tag.is_some()) {
+            let tagged = field.tagged_versions.condition(context);
+            writeln!(
+                out,
+                "                        {} if {tagged} => {{",
+                field.tag.unwrap()
+            )
+            .unwrap();
+            out.push_str("                            let mut data: &[u8] = &field.data;\n");
+            writeln!(
+                out,
+                "                            this.{} = {};",
+                field.rust_name(),
+                read_field_expr(field, context, "&mut data")
+            )
+            .unwrap();
+            out.push_str("                        }\n");
+        }
+        out.push_str("                        _ => this.unknown_tagged_fields.push(field),\n");
+        out.push_str("                    }\n                }\n            }\n");
+        out.push_str("            Ok(this)\n        }\n\n");
+
+        // Writer.
+        out.push_str("        /// Encodes this struct for `version`.\n");
+        out.push_str("        pub fn write(&self, buf: &mut EncodeBuf, version: i16) {\n");
+        writeln!(out, "            let flexible = {flexible};").unwrap();
+        for field in &fields {
+            let Some(condition) = inline_condition(field, context) else {
+                continue;
+            };
+            writeln!(out, "            if {condition} {{").unwrap();
+            write_field_stmt(
+                out,
+                field,
+                context,
+                &format!("self.{}", field.rust_name()),
+                "buf",
+                4,
+            );
+            out.push_str("            }\n");
+        }
+        out.push_str("            if flexible {\n");
+        out.push_str(
+            "                let mut tagged_fields = self.unknown_tagged_fields.clone();\n",
+        );
+        for field in fields.iter().filter(|f| f.tag.is_some()) {
+            let tagged = field.tagged_versions.condition(context);
+            let name = field.rust_name();
+            writeln!(
+                out,
+                "                if ({tagged}) && self.{name} != {} {{",
+                field.default_expr()
+            )
+            .unwrap();
+            out.push_str("                    let mut data = EncodeBuf::new();\n");
+            write_field_stmt(out, field, context, &format!("self.{name}"), "&mut data", 5);
+            writeln!(
+                out,
+                "                    tagged_fields.push(RawTaggedField {{ tag: {}, data: data.into_bytes() }});",
+                field.tag.unwrap()
+            )
+            .unwrap();
+            out.push_str("                }\n");
+        }
+        out.push_str("                tagged_fields.sort_by_key(|field| field.tag);\n");
+        out.push_str("                write_tagged_fields(buf, &tagged_fields);\n");
+        out.push_str("            }\n        }\n    }\n\n");
+    }
+}
+
+/// Returns the condition under which `field` is encoded inline (rather than as a tagged field),
+/// or `None` if it never is within `context`.
+fn inline_condition(field: &Field, context: Versions) -> Option<String> {
+    let versions = field.versions.intersect(context);
+    if versions.is_empty() {
+        return None;
+    }
+    if field.tag.is_none() {
+        return Some(field.versions.condition(context));
+    }
+    if field.tagged_versions.contains_all(versions) {
+        return None;
+    }
+    Some(format!(
+        "({}) && !({})",
+        field.versions.condition(context),
+        field.tagged_versions.condition(context)
+    ))
+}
+
+fn collect_nested(fields: &[Field], context: Versions, structs: &mut Vec<StructSpec>) {
+    for field in fields {
+        let Some(nested) = &field.fields else {
+            continue;
+        };
+        let name = match &field.ty {
+            FieldType::Struct(name) => name.clone(),
+            FieldType::Array(element) => match element.as_ref() {
+                FieldType::Struct(name) => name.clone(),
+                other => panic!(
+                    "field {} has nested fields but element type {other:?}",
+                    field.name
+                ),
+            },
+            other => panic!("field {} has nested fields but type {other:?}", field.name),
+        };
+        let versions = field.versions.intersect(context);
+        structs.push(StructSpec {
+            name,
+            versions: if versions.is_empty() {
+                field.tagged_versions.intersect(context)
+            } else {
+                versions
+            },
+            fields: nested.clone(),
+            about: field.about.clone(),
+        });
+        collect_nested(nested, versions, structs);
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------
+Expression generation
+--------------------------------------------------------------------------------------------- */
+
+/// Returns an expression reading a non-null value of `ty` from `cursor`.
+fn read_value_expr(ty: &FieldType, cursor: &str) -> String {
+    match ty {
+        FieldType::Bool => format!("read_bool({cursor})?"),
+        FieldType::Int8 => format!("read_i8({cursor})?"),
+        FieldType::Int16 => format!("read_i16({cursor})?"),
+        FieldType::Uint16 => format!("read_u16({cursor})?"),
+        FieldType::Int32 => format!("read_i32({cursor})?"),
+        FieldType::Uint32 => format!("read_u32({cursor})?"),
+        FieldType::Int64 => format!("read_i64({cursor})?"),
+        FieldType::Float64 => format!("read_f64({cursor})?"),
+        FieldType::Uuid => format!("read_uuid({cursor})?"),
+        FieldType::String => {
+            format!("if flexible {{ read_compact_string({cursor})? }} else {{ read_string({cursor})? }}")
+        }
+        FieldType::Bytes => {
+            format!(
+                "if flexible {{ read_compact_bytes({cursor})? }} else {{ read_bytes({cursor})? }}"
+            )
+        }
+        FieldType::Records => format!(
+            "Records::Bytes(if flexible {{ read_compact_bytes({cursor})? }} else {{ read_bytes({cursor})? }})"
+        ),
+        FieldType::Struct(name) => format!("{name}::read({cursor}, version)?"),
+        FieldType::Array(element) => {
+            let element_expr = read_value_expr(element, "c");
+            format!("read_array({cursor}, flexible, |c| Ok({element_expr}))?")
+        }
+    }
+}
+
+/// Returns an expression reading a nullable value of `ty` (as an `Option`) from `cursor`.
+fn read_nullable_expr(ty: &FieldType, cursor: &str) -> String {
+    match ty {
+        FieldType::String => format!(
+            "if flexible {{ read_compact_nullable_string({cursor})? }} else {{ read_nullable_string({cursor})? }}"
+        ),
+        FieldType::Bytes => format!(
+            "if flexible {{ read_compact_nullable_bytes({cursor})? }} else {{ read_nullable_bytes({cursor})? }}"
+        ),
+        FieldType::Records => format!(
+            "(if flexible {{ read_compact_nullable_bytes({cursor})? }} else {{ read_nullable_bytes({cursor})? }}).map(Records::Bytes)"
+        ),
+        FieldType::Array(element) => {
+            let element_expr = read_value_expr(element, "c");
+            format!("read_nullable_array({cursor}, flexible, |c| Ok({element_expr}))?")
+        }
+        FieldType::Struct(name) => {
+            format!("if read_i8({cursor})? < 0 {{ None }} else {{ Some({name}::read({cursor}, version)?) }}")
+        }
+        other => panic!("type {other:?} cannot be nullable"),
+    }
+}
+
+/// Returns an expression reading `field` from `cursor`, producing the field's Rust type.
+fn read_field_expr(field: &Field, context: Versions, cursor: &str) -> String {
+    if !field.is_option() {
+        return read_value_expr(&field.ty, cursor);
+    }
+    let nullable = field.nullable_versions.condition(context);
+    if nullable == "true" {
+        read_nullable_expr(&field.ty, cursor)
+    } else {
+        format!(
+            "if {nullable} {{ {} }} else {{ Some({}) }}",
+            read_nullable_expr(&field.ty, cursor),
+            read_value_expr(&field.ty, cursor)
+        )
+    }
+}
+
+/// Emits statements writing the non-null value `value` (a place expression of `ty`) to `buf`.
+fn write_value_stmt(out: &mut String, ty: &FieldType, value: &str, buf: &str, indent: usize) {
+    let pad = "    ".repeat(indent);
+    match ty {
+        FieldType::Bool => writeln!(out, "{pad}write_bool({buf}, {value});"),
+        FieldType::Int8 => writeln!(out, "{pad}write_i8({buf}, {value});"),
+        FieldType::Int16 => writeln!(out, "{pad}write_i16({buf}, {value});"),
+        FieldType::Uint16 => writeln!(out, "{pad}write_u16({buf}, {value});"),
+        FieldType::Int32 => writeln!(out, "{pad}write_i32({buf}, {value});"),
+        FieldType::Uint32 => writeln!(out, "{pad}write_u32({buf}, {value});"),
+        FieldType::Int64 => writeln!(out, "{pad}write_i64({buf}, {value});"),
+        FieldType::Float64 => writeln!(out, "{pad}write_f64({buf}, {value});"),
+        FieldType::Uuid => writeln!(out, "{pad}write_uuid({buf}, &{value});"),
+        FieldType::String => writeln!(
+            out,
+            "{pad}if flexible {{ write_compact_string({buf}, &{value}) }} else {{ write_string({buf}, &{value}) }}"
+        ),
+        FieldType::Bytes => writeln!(
+            out,
+            "{pad}if flexible {{ write_compact_bytes({buf}, &{value}) }} else {{ write_bytes({buf}, &{value}) }}"
+        ),
+        FieldType::Records => writeln!(out, "{pad}write_records({buf}, &{value}, flexible);"),
+        FieldType::Struct(_) => writeln!(out, "{pad}{value}.write({buf}, version);"),
+        FieldType::Array(element) => {
+            writeln!(out, "{pad}write_array_len({buf}, Some({value}.len()), flexible);").unwrap();
+            // Copy elements are bound by value; everything else is written through a reference.
+            let pattern = if element.is_copy() { "&element" } else { "element" };
+            writeln!(out, "{pad}for {pattern} in {value}.iter() {{").unwrap();
+            write_value_stmt(out, element, "element", buf, indent + 1);
+            writeln!(out, "{pad}}}")
+        }
+    }
+    .unwrap();
+}
+
+/// Emits statements writing `value` (a place expression of `field`'s Rust type) to `buf`.
+fn write_field_stmt(
+    out: &mut String,
+    field: &Field,
+    context: Versions,
+    value: &str,
+    buf: &str,
+    indent: usize,
+) {
+    let pad = "    ".repeat(indent);
+    if !field.is_option() {
+        write_value_stmt(out, &field.ty, value, buf, indent);
+        return;
+    }
+
+    let nullable = field.nullable_versions.condition(context);
+    writeln!(out, "{pad}match &{value} {{").unwrap();
+    writeln!(out, "{pad}    Some(value) => {{").unwrap();
+    if matches!(field.ty, FieldType::Struct(_)) && nullable != "false" {
+        writeln!(out, "{pad}        if {nullable} {{ write_i8({buf}, 1); }}").unwrap();
+    }
+    write_value_stmt(out, &field.ty, "value", buf, indent + 2);
+    writeln!(out, "{pad}    }}").unwrap();
+    writeln!(out, "{pad}    None if {nullable} => {{").unwrap();
+    let null_stmt = match &field.ty {
+        FieldType::String => format!(
+            "if flexible {{ write_compact_nullable_string({buf}, None) }} else {{ write_nullable_string({buf}, None) }}"
+        ),
+        FieldType::Bytes | FieldType::Records => format!(
+            "if flexible {{ write_compact_nullable_bytes({buf}, None) }} else {{ write_nullable_bytes({buf}, None) }}"
+        ),
+        FieldType::Array(_) => format!("write_array_len({buf}, None, flexible)"),
+        FieldType::Struct(_) => format!("write_i8({buf}, -1)"),
+        other => panic!("type {other:?} cannot be nullable"),
+    };
+    writeln!(out, "{pad}        {null_stmt};").unwrap();
+    writeln!(out, "{pad}    }}").unwrap();
+    // Null in a version that doesn't allow it: write the type's empty value instead.
+    writeln!(out, "{pad}    None => {{").unwrap();
+    let empty = match &field.ty {
+        FieldType::Struct(name) => format!("{name}::default()"),
+        other => format!("{}::default()", other.rust_type().replace("Vec<", "Vec::<")),
+    };
+    writeln!(out, "{pad}        let value = {empty};").unwrap();
+    write_value_stmt(out, &field.ty, "value", buf, indent + 2);
+    writeln!(out, "{pad}    }}").unwrap();
+    writeln!(out, "{pad}}}").unwrap();
+}
+
+/// Converts `CamelCase` spec names into `snake_case`.
+fn snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut out = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_uppercase() {
+            let previous = i.checked_sub(1).map(|j| chars[j]);
+            let next = chars.get(i + 1).copied();
+            let boundary = match previous {
+                Some(p) if p.is_lowercase() || p.is_ascii_digit() => true,
+                Some(p) if p.is_uppercase() => next.is_some_and(|n| n.is_lowercase()),
+                _ => false,
+            };
+            if boundary {
+                out.push('_');
+            }
+            out.extend(c.to_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
@@ -0,0 +1,205 @@
+//! ApiVersions (key 18).
+//!
+//! Clients send ApiVersions first to discover which APIs and versions the broker implements.
+//! The answer comes straight from the [`SUPPORTED_APIS`] registry, so adding a handler and its
+//! version range there is all it takes for clients to start using it.
+//!
+//! Kafka has one special rule for this API: if the client asks for a version newer than the
+//! broker supports, the broker cannot know the layout the client expects, so it replies with
+//! `UNSUPPORTED_VERSION` in a v0-format body that still lists the supported ranges. The client
+//! then retries with the highest version both sides understand.
+
+use crate::kafka_protocol::kafka_api_keys::SUPPORTED_APIS;
+use crate::kafka_protocol::kafka_error_codes::{INVALID_REQUEST, NONE, UNSUPPORTED_VERSION};
+use crate::kafka_protocol::kafka_messages::api_versions_response::{
+    ApiVersion, FinalizedFeatureKey, SupportedFeatureKey,
+};
+use crate::kafka_protocol::kafka_messages::{ApiVersionsRequest, ApiVersionsResponse};
+use tracing::{debug, warn};
+
+/// Feature flags advertised in ApiVersions v3+, as `(name, min_version, max_version)`.
+const SUPPORTED_FEATURES: &[(&str, i16, i16)] = &[];
+
+/// Feature levels finalized for the cluster, as `(name, level)`.
+const FINALIZED_FEATURES: &[(&str, i16)] = &[];
+
+/// The version ApiVersions responses fall back to when the requested version is unsupported.
+pub const FALLBACK_VERSION: i16 = 0;
+
+/// Answers a supported-version ApiVersions request.
+pub fn handle(request: &ApiVersionsRequest, api_version: i16) -> ApiVersionsResponse {
+    if api_version >= 3 && !is_valid(request) {
+        warn!(
+            "Rejecting ApiVersions with invalid client software name/version: {:?}/{:?}",
+            request.client_software_name, request.client_software_version
+        );
+        return error_response(INVALID_REQUEST);
+    }
+
+    debug!(
+        "Answering ApiVersions v{} from {:?} {:?}",
+        api_version, request.client_software_name, request.client_software_version
+    );
+    build_response(NONE)
+}
+
+/// Answers an ApiVersions request whose version is newer than the broker supports.
+///
+/// The response must be encoded as [`FALLBACK_VERSION`] and still carries the full list of
+/// supported ranges so the client can pick a version to retry with.
+pub fn handle_unsupported_version(api_version: i16) -> ApiVersionsResponse {
+    debug!(
+        "ApiVersions v{} is not supported; replying with UNSUPPORTED_VERSION in v{} format.",
+        api_version, FALLBACK_VERSION
+    );
+    build_response(UNSUPPORTED_VERSION)
+}
+
+/// Builds a response advertising every entry of [`SUPPORTED_APIS`].
+fn build_response(error_code: i16) -> ApiVersionsResponse {
+    ApiVersionsResponse {
+        error_code,
+        api_keys: SUPPORTED_APIS
+            .iter()
+            .map(|range| ApiVersion {
+                api_key: range.api_key as i16,
+                min_version: range.min_version,
+                max_version: range.max_version,
+                ..Default::default()
+            })
+            .collect(),
+        supported_features: SUPPORTED_FEATURES
+            .iter()
+            .map(|&(name, min_version, max_version)| SupportedFeatureKey {
+                name: name.to_string(),
+                min_version,
+                max_version,
+                ..Default::default()
+            })
+            .collect(),
+        finalized_features: FINALIZED_FEATURES
+            .iter()
+            .map(|&(name, level)| FinalizedFeatureKey {
+                name: name.to_string(),
+                max_version_level: level,
+                min_version_level: level,
+                ..Default::default()
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
+
+/// Builds an error response without any API ranges, as Kafka does for invalid requests.
+fn error_response(error_code: i16) -> ApiVersionsResponse {
+    ApiVersionsResponse {
+        api_keys: Vec::new(),
+        ..build_response(error_code)
+    }
+}
+
+/// Checks the client software name and version against the pattern Kafka enforces:
+/// alphanumerics, optionally with `-` and `.` in between.
+fn is_valid(request: &ApiVersionsRequest) -> bool {
+    fn valid(s: &str) -> bool {
+        let bytes = s.as_bytes();
+        match (bytes.first(), bytes.last()) {
+            (Some(first), Some(last)) => {
+                first.is_ascii_alphanumeric()
+                    && last.is_ascii_alphanumeric()
+                    && bytes
+                        .iter()
+                        .all(|b| b.is_ascii_alphanumeric() || *b == b'-' || *b == b'.')
+            }
+            _ => false,
+        }
+    }
+    valid(&request.client_software_name) && valid(&request.client_software_version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_handlers::dispatch;
+    use crate::broker_state::BrokerState;
+    use crate::config::Config;
+    use crate::kafka_protocol::kafka_api_keys::ApiKey;
+    use crate::kafka_protocol::kafka_records::EncodeBuf;
+    use crate::kafka_protocol::kafka_request_message::KafkaRequestMessage;
+    use std::sync::Arc;
+
+    /// An ApiVersions request frame of `api_version` from client software `name`, with the
+    /// header v2 clients send from v3 on. The body of a version the broker doesn't know is
+    /// never read, so it is left empty.
+    fn request(api_version: i16, name: &str) -> Vec<u8> {
+        let mut message = Vec::new();
+        message.extend_from_slice(&(ApiKey::ApiVersions as i16).to_be_bytes());
+        message.extend_from_slice(&api_version.to_be_bytes());
+        message.extend_from_slice(&7i32.to_be_bytes());
+        message.extend_from_slice(&(-1i16).to_be_bytes()); // null client id
+        message.push(0); // no tagged fields
+        if api_version == 3 {
+            for field in [name, "1.0"] {
+                message.push(field.len() as u8 + 1);
+                message.extend_from_slice(field.as_bytes());
+            }
+            message.push(0);
+        }
+        let mut frame = (message.len() as i32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&message);
+        frame
+    }
+
+    /// Answers `request` and decodes the response body as `response_version`, checking that
+    /// it takes up the whole frame after a v0 response header.
+    async fn answer(request: &[u8], response_version: i16) -> ApiVersionsResponse {
+        let dir = tempfile::tempdir().unwrap();
+        let state = Arc::new(
+            BrokerState::load(Config::for_tests(dir.path()))
+                .await
+                .unwrap(),
+        );
+        let request = KafkaRequestMessage::from_bytes(request).unwrap();
+        let response = dispatch(request, &state, "127.0.0.1").await.unwrap();
+        let mut buf = EncodeBuf::new();
+        response.unwrap().encode_into(&mut buf);
+        let bytes = buf.into_bytes();
+
+        assert_eq!(bytes[..4], (bytes.len() as i32 - 4).to_be_bytes());
+        assert_eq!(bytes[4..8], 7i32.to_be_bytes());
+        let mut body = &bytes[8..];
+        let response = ApiVersionsResponse::read(&mut body, response_version).unwrap();
+        assert!(body.is_empty());
+        response
+    }
+
+    fn advertised(response: &ApiVersionsResponse) -> Vec<(i16, i16, i16)> {
+        response
+            .api_keys
+            .iter()
+            .map(|api| (api.api_key, api.min_version, api.max_version))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn a_version_too_new_is_answered_in_v0_with_the_supported_ranges() {
+        let response = answer(&request(99, "client"), FALLBACK_VERSION).await;
+        assert_eq!(response.error_code, UNSUPPORTED_VERSION);
+        let supported: Vec<(i16, i16, i16)> = SUPPORTED_APIS
+            .iter()
+            .map(|range| (range.api_key as i16, range.min_version, range.max_version))
+            .collect();
+        assert_eq!(advertised(&response), supported);
+    }
+
+    #[tokio::test]
+    async fn v3_requests_must_name_the_client_software() {
+        let response = answer(&request(3, "client"), 3).await;
+        assert_eq!(response.error_code, NONE);
+        assert_eq!(advertised(&response).len(), SUPPORTED_APIS.len());
+
+        let response = answer(&request(3, "-client"), 3).await;
+        assert_eq!(response.error_code, INVALID_REQUEST);
+        assert!(response.api_keys.is_empty());
+    }
+}
@@ -0,0 +1,96 @@
+//! ConsumerGroupDescribe (key 69).
+//!
+//! Admin clients describe consumer groups (KIP-848): their state, epochs and assignor, and each
+//! member's subscription, current assignment and target assignment. Groups that don't exist,
+//! or are classic groups, come back with `GROUP_ID_NOT_FOUND`.
+
+use crate::broker_state::BrokerState;
+use crate::group_coordinator::Assignment;
+use crate::kafka_protocol::kafka_error_codes::NONE;
+use crate::kafka_protocol::kafka_messages::consumer_group_describe_response::{
+    Assignment as DescribedAssignment, DescribedGroup, Member, TopicPartitions,
+};
+use crate::kafka_protocol::kafka_messages::{
+    ConsumerGroupDescribeRequest, ConsumerGroupDescribeResponse,
+};
+use std::collections::HashMap;
+use tracing::debug;
+use uuid::Uuid;
+
+/// The authorized operations reported when the client doesn't ask for them.
+const AUTHORIZED_OPERATIONS_OMITTED: i32 = i32::MIN;
+
+/// Answers a ConsumerGroupDescribe request.
+pub async fn handle(
+    request: ConsumerGroupDescribeRequest,
+    state: &BrokerState,
+) -> ConsumerGroupDescribeResponse {
+    debug!("ConsumerGroupDescribe of groups {:?}", request.group_ids);
+    let topic_names: HashMap<Uuid, String> = state
+        .topics
+        .read()
+        .await
+        .values()
+        .map(|topic| (topic.topic_id, topic.name.clone()))
+        .collect();
+    let assignment = |assignment: Assignment| DescribedAssignment {
+        topic_partitions: assignment
+            .into_iter()
+            .map(|(topic_id, partitions)| TopicPartitions {
+                topic_id,
+                topic_name: topic_names.get(&topic_id).cloned().unwrap_or_default(),
+                partitions: partitions.into_iter().collect(),
+                ..Default::default()
+            })
+            .collect(),
+        ..Default::default()
+    };
+
+    let descriptions = state
+        .group_coordinator
+        .describe_consumer_groups(&request.group_ids);
+    let groups = request
+        .group_ids
+        .into_iter()
+        .zip(descriptions)
+        .map(|(group_id, description)| match description {
+            Ok(description) => DescribedGroup {
+                error_code: NONE,
+                group_id,
+                group_state: description.state.name().to_string(),
+                group_epoch: description.group_epoch,
+                assignment_epoch: description.assignment_epoch,
+                assignor_name: description.assignor_name,
+                members: description
+                    .members
+                    .into_iter()
+                    .map(|member| Member {
+                        member_id: member.member_id,
+                        instance_id: member.instance_id,
+                        rack_id: member.rack_id,
+                        member_epoch: member.member_epoch,
+                        client_id: member.client_id,
+                        client_host: member.client_host,
+                        subscribed_topic_names: member.subscribed_topic_names,
+                        assignment: assignment(member.assignment),
+                        target_assignment: assignment(member.target_assignment),
+                        ..Default::default()
+                    })
+                    .collect(),
+                authorized_operations: AUTHORIZED_OPERATIONS_OMITTED,
+                ..Default::default()
+            },
+            Err(e) => DescribedGroup {
+                error_code: e.error_code(),
+                error_message: Some(e.to_string()),
+                group_id,
+                authorized_operations: AUTHORIZED_OPERATIONS_OMITTED,
+                ..Default::default()
+            },
+        })
+        .collect();
+    ConsumerGroupDescribeResponse {
+        groups,
+        ..Default::default()
+    }
+}
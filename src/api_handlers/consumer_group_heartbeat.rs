@@ -0,0 +1,105 @@
+//! ConsumerGroupHeartbeat (key 68).
+//!
+//! The only request members of a consumer group (KIP-848) send: joining with epoch 0, leaving
+//! with epoch -1 (or -2 for a static member keeping its place), and otherwise heartbeating with
+//! their epoch and any change to their subscription or owned partitions. The answer carries the
+//! member's epoch and, when it changed, the partitions it is to own (see
+//! [`GroupCoordinator`](crate::group_coordinator::GroupCoordinator)).
+
+use crate::broker_state::BrokerState;
+use crate::group_coordinator::{Assignment, ConsumerHeartbeatRequest, TopicMetadata};
+use crate::kafka_protocol::kafka_error_codes::NONE;
+use crate::kafka_protocol::kafka_messages::consumer_group_heartbeat_response::{
+    Assignment as ResponseAssignment, TopicPartitions,
+};
+use crate::kafka_protocol::kafka_messages::{
+    ConsumerGroupHeartbeatRequest, ConsumerGroupHeartbeatResponse,
+};
+use std::collections::HashMap;
+use tracing::{debug, info};
+
+/// Answers a ConsumerGroupHeartbeat request from `client_id` at `client_host`.
+pub async fn handle(
+    request: ConsumerGroupHeartbeatRequest,
+    client_id: &str,
+    client_host: &str,
+    state: &BrokerState,
+) -> ConsumerGroupHeartbeatResponse {
+    debug!(
+        "ConsumerGroupHeartbeat of member {:?} with epoch {} to group {}",
+        request.member_id, request.member_epoch, request.group_id
+    );
+    let topics: HashMap<String, TopicMetadata> = state
+        .topics
+        .read()
+        .await
+        .values()
+        .filter(|topic| !topic.is_internal)
+        .map(|topic| {
+            let metadata = TopicMetadata {
+                topic_id: topic.topic_id,
+                num_partitions: topic.partitions.len() as i32,
+            };
+            (topic.name.clone(), metadata)
+        })
+        .collect();
+    let heartbeat = ConsumerHeartbeatRequest {
+        group_id: request.group_id.clone(),
+        member_id: request.member_id,
+        member_epoch: request.member_epoch,
+        instance_id: request.instance_id,
+        rack_id: request.rack_id,
+        rebalance_timeout_ms: request.rebalance_timeout_ms,
+        subscribed_topic_names: request.subscribed_topic_names,
+        server_assignor: request.server_assignor,
+        owned_partitions: request.topic_partitions.map(|topics| {
+            topics
+                .into_iter()
+                .map(|topic| (topic.topic_id, topic.partitions.into_iter().collect()))
+                .collect()
+        }),
+        client_id: client_id.to_string(),
+        client_host: client_host.to_string(),
+    };
+
+    match state
+        .group_coordinator
+        .consumer_group_heartbeat(heartbeat, topics)
+        .await
+    {
+        Ok(result) => ConsumerGroupHeartbeatResponse {
+            error_code: NONE,
+            member_id: Some(result.member_id),
+            member_epoch: result.member_epoch,
+            heartbeat_interval_ms: result.heartbeat_interval_ms,
+            assignment: result.assignment.map(response_assignment),
+            ..Default::default()
+        },
+        Err(e) => {
+            info!(
+                "ConsumerGroupHeartbeat to group {} failed: {}",
+                request.group_id, e
+            );
+            ConsumerGroupHeartbeatResponse {
+                error_code: e.error_code(),
+                error_message: Some(e.to_string()),
+                ..Default::default()
+            }
+        }
+    }
+}
+
+/// `assignment` as sent to the member.
+fn response_assignment(assignment: Assignment) -> ResponseAssignment {
+    ResponseAssignment {
+        topic_partitions: assignment
+            .into_iter()
+            .map(|(topic_id, partitions)| TopicPartitions {
+                topic_id,
+                partitions: partitions.into_iter().collect(),
+                ..Default::default()
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
@@ -0,0 +1,33 @@
+//! DeleteGroups (key 42).
+//!
+//! Deletes groups along with their committed offsets. A group that still has members comes
+//! back with `NON_EMPTY_GROUP`, and one that doesn't exist with `GROUP_ID_NOT_FOUND`.
+
+use crate::broker_state::BrokerState;
+use crate::kafka_protocol::kafka_error_codes::NONE;
+use crate::kafka_protocol::kafka_messages::delete_groups_response::DeletableGroupResult;
+use crate::kafka_protocol::kafka_messages::{DeleteGroupsRequest, DeleteGroupsResponse};
+use tracing::debug;
+
+/// Answers a DeleteGroups request.
+pub async fn handle(request: DeleteGroupsRequest, state: &BrokerState) -> DeleteGroupsResponse {
+    debug!("DeleteGroups of groups {:?}", request.groups_names);
+    let results = state
+        .group_coordinator
+        .delete_groups(&request.groups_names)
+        .await;
+    let results = request
+        .groups_names
+        .into_iter()
+        .zip(results)
+        .map(|(group_id, result)| DeletableGroupResult {
+            group_id,
+            error_code: result.err().map_or(NONE, |e| e.error_code()),
+            ..Default::default()
+        })
+        .collect();
+    DeleteGroupsResponse {
+        results,
+        ..Default::default()
+    }
+}
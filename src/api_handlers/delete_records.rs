@@ -0,0 +1,146 @@
+//! DeleteRecords (key 21).
+//!
+//! Moves the log start offset of each partition forward to the requested offset (or to the
+//! high watermark for offset -1), making the records before it unreadable. The space is
+//! reclaimed later, when the retention task deletes the segments that lie entirely before the
+//! new log start offset.
+//!
+//! The deletion takes effect before the response is sent, so `timeout_ms` is never waited on.
+
+use crate::broker_state::BrokerState;
+use crate::kafka_protocol::kafka_error::{KafkaBrokerError, KafkaResult};
+use crate::kafka_protocol::kafka_error_codes::NONE;
+use crate::kafka_protocol::kafka_messages::delete_records_response::{
+    DeleteRecordsPartitionResult, DeleteRecordsTopicResult,
+};
+use crate::kafka_protocol::kafka_messages::{DeleteRecordsRequest, DeleteRecordsResponse};
+use crate::storage::{self, TopicPartition};
+use tracing::{debug, warn};
+
+/// Answers a DeleteRecords request.
+pub async fn handle(request: DeleteRecordsRequest, state: &BrokerState) -> DeleteRecordsResponse {
+    debug!("DeleteRecords for {} topic(s)", request.topics.len());
+
+    let mut topics = Vec::with_capacity(request.topics.len());
+    for topic in request.topics {
+        let mut partitions = Vec::with_capacity(topic.partitions.len());
+        for partition in topic.partitions {
+            let partition_index = partition.partition_index;
+            let result =
+                match delete_records(&topic.name, partition_index, partition.offset, state).await {
+                    Ok(low_watermark) => DeleteRecordsPartitionResult {
+                        partition_index,
+                        low_watermark,
+                        error_code: NONE,
+                        ..Default::default()
+                    },
+                    Err(e) => {
+                        warn!(
+                            "DeleteRecords on {}-{} failed: {}",
+                            topic.name, partition_index, e
+                        );
+                        DeleteRecordsPartitionResult {
+                            partition_index,
+                            low_watermark: -1,
+                            error_code: e.error_code(),
+                            ..Default::default()
+                        }
+                    }
+                };
+            partitions.push(result);
+        }
+        topics.push(DeleteRecordsTopicResult {
+            name: topic.name,
+            partitions,
+            ..Default::default()
+        });
+    }
+
+    DeleteRecordsResponse {
+        topics,
+        ..Default::default()
+    }
+}
+
+/// Deletes the records of `topic`/`partition` before `offset`. Returns the new low watermark.
+async fn delete_records(
+    topic: &str,
+    partition: i32,
+    offset: i64,
+    state: &BrokerState,
+) -> KafkaResult<i64> {
+    let log = state
+        .logs
+        .get(&TopicPartition::new(topic, partition))
+        .await
+        .ok_or_else(|| KafkaBrokerError::UnknownTopicOrPartition {
+            topic: topic.to_string(),
+            partition,
+        })?;
+    storage::with_log(&log, move |log| log.delete_records_before(offset)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::kafka_protocol::kafka_error_codes::{
+        OFFSET_OUT_OF_RANGE, UNKNOWN_TOPIC_OR_PARTITION,
+    };
+    use crate::kafka_protocol::kafka_messages::delete_records_request::{
+        DeleteRecordsPartition, DeleteRecordsTopic,
+    };
+    use crate::storage::tests::batch;
+
+    fn request(topic: &str, offsets: &[i64]) -> DeleteRecordsRequest {
+        DeleteRecordsRequest {
+            topics: vec![DeleteRecordsTopic {
+                name: topic.to_string(),
+                partitions: offsets
+                    .iter()
+                    .enumerate()
+                    .map(|(partition_index, &offset)| DeleteRecordsPartition {
+                        partition_index: partition_index as i32,
+                        offset,
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// The `(low_watermark, error_code)` of each partition in `response`.
+    fn results(response: DeleteRecordsResponse) -> Vec<(i64, i16)> {
+        response.topics[0]
+            .partitions
+            .iter()
+            .map(|partition| (partition.low_watermark, partition.error_code))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn deletes_up_to_the_high_watermark_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = BrokerState::load(Config::for_tests(dir.path()))
+            .await
+            .unwrap();
+        state.get_or_create_topic("t", 3).await.unwrap();
+        for partition in 0..3 {
+            let log = state.logs.get(&TopicPartition::new("t", partition)).await;
+            let records = batch(1000, &[("a", Some("1")), ("b", Some("1"))]);
+            storage::with_log(&log.unwrap(), move |log| log.append(&records, 0, 1000))
+                .await
+                .unwrap();
+        }
+
+        let response = handle(request("t", &[1, -1, 3]), &state).await;
+        assert_eq!(
+            results(response),
+            [(1, NONE), (2, NONE), (-1, OFFSET_OUT_OF_RANGE)]
+        );
+        let response = handle(request("unknown", &[0]), &state).await;
+        assert_eq!(results(response), [(-1, UNKNOWN_TOPIC_OR_PARTITION)]);
+    }
+}
@@ -0,0 +1,57 @@
+//! DescribeGroups (key 15).
+//!
+//! Describes classic groups: their state, protocol and members. Each member's metadata and
+//! assignment are passed on as the opaque bytes the clients sent, and only once the group is
+//! stable. Groups this broker doesn't know come back as `Dead`, without members, and consumer
+//! groups (which ConsumerGroupDescribe is for) with `GROUP_ID_NOT_FOUND`.
+
+use crate::broker_state::BrokerState;
+use crate::kafka_protocol::kafka_error_codes::NONE;
+use crate::kafka_protocol::kafka_messages::describe_groups_response::{
+    DescribedGroup, DescribedGroupMember,
+};
+use crate::kafka_protocol::kafka_messages::{DescribeGroupsRequest, DescribeGroupsResponse};
+use tracing::debug;
+
+/// Answers a DescribeGroups request.
+pub fn handle(request: DescribeGroupsRequest, state: &BrokerState) -> DescribeGroupsResponse {
+    debug!("DescribeGroups of groups {:?}", request.groups);
+    let descriptions = state.group_coordinator.describe_groups(&request.groups);
+    let groups = request
+        .groups
+        .into_iter()
+        .zip(descriptions)
+        .map(|(group_id, description)| match description {
+            Ok(description) => DescribedGroup {
+                error_code: NONE,
+                group_id,
+                group_state: description.state.name().to_string(),
+                protocol_type: description.protocol_type,
+                protocol_data: description.protocol_data,
+                members: description
+                    .members
+                    .into_iter()
+                    .map(|member| DescribedGroupMember {
+                        member_id: member.member_id,
+                        group_instance_id: member.group_instance_id,
+                        client_id: member.client_id,
+                        client_host: member.client_host,
+                        member_metadata: member.metadata,
+                        member_assignment: member.assignment,
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            },
+            Err(e) => DescribedGroup {
+                error_code: e.error_code(),
+                group_id,
+                ..Default::default()
+            },
+        })
+        .collect();
+    DescribeGroupsResponse {
+        groups,
+        ..Default::default()
+    }
+}
@@ -0,0 +1,372 @@
+//! Fetch (key 1).
+//!
+//! Consumers fetch record batches from each partition starting at an offset. Partitions are
+//! read in request order, each capped at its `partition_max_bytes`, until the response-wide
+//! `max_bytes` is used up. As in Kafka (KIP-74), the first batch of the first partition with
+//! data is returned even if it exceeds those limits, so a consumer can never get stuck behind
+//! an oversized batch.
+//!
+//! When less than `min_bytes` is available, the request is parked in the fetch
+//! [`Purgatory`](crate::purgatory::Purgatory), watching its partitions, until enough data has
+//! been appended or `max_wait_ms` expires, whichever comes first. Each append to one of the
+//! partitions wakes it to read them again.
+//!
+//! Record data is not copied into the response: it refers to the segment files it was read
+//! from, and is sent straight from them to the socket. Clients of Fetch v0-3 are the exception,
+//! as they can't read v2 record batches: their data is read into memory and down-converted into
+//! a legacy message set (magic 0 for v0-1, magic 1 for v2-3).
+//!
+//! Fetch sessions (KIP-227) are not implemented: every request is answered as a full fetch
+//! with session id 0, which tells clients not to send incremental fetches.
+
+use crate::broker_state::BrokerState;
+use crate::kafka_protocol::kafka_error::{KafkaBrokerError, KafkaResult};
+use crate::kafka_protocol::kafka_error_codes::{
+    FETCH_SESSION_ID_NOT_FOUND, NONE, UNKNOWN_TOPIC_ID,
+};
+use crate::kafka_protocol::kafka_message_set::{self, MAGIC_V0, MAGIC_V1};
+use crate::kafka_protocol::kafka_messages::fetch_request::FetchPartition;
+use crate::kafka_protocol::kafka_messages::fetch_response::{
+    FetchableTopicResponse, PartitionData,
+};
+use crate::kafka_protocol::kafka_messages::{FetchRequest, FetchResponse};
+use crate::kafka_protocol::kafka_records::Records;
+use crate::purgatory::DelayedOperationKey;
+use crate::storage::{self, TopicPartition};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// Answers a Fetch request, waiting up to `max_wait_ms` for `min_bytes` of data.
+pub async fn handle(request: FetchRequest, api_version: i16, state: &BrokerState) -> FetchResponse {
+    if request.session_id != 0 {
+        warn!(
+            "Fetch v{} refers to session {}, but fetch sessions are not supported",
+            api_version, request.session_id
+        );
+        return FetchResponse {
+            error_code: FETCH_SESSION_ID_NOT_FOUND,
+            ..Default::default()
+        };
+    }
+
+    let topics = resolve_topics(&request, api_version, state).await;
+    let min_bytes = request.min_bytes.max(0) as usize;
+    let deadline = Instant::now() + Duration::from_millis(request.max_wait_ms.max(0) as u64);
+    let keys: Vec<_> = topics
+        .iter()
+        .zip(&request.topics)
+        .filter_map(|(topic, requested)| Some((topic.name.as_ref()?, requested)))
+        .flat_map(|(name, requested)| {
+            requested.partitions.iter().map(move |partition| {
+                DelayedOperationKey::TopicPartition(TopicPartition::new(name, partition.partition))
+            })
+        })
+        .collect();
+
+    loop {
+        // Watch the partitions before reading them, so an append can't slip in between.
+        let watch = state.fetch_purgatory.watch(&keys, deadline);
+
+        let (responses, bytes, has_error) =
+            read_topics(&topics, &request, api_version, state).await;
+        if bytes >= min_bytes || has_error || watch.expired() {
+            debug!(
+                "Fetch v{} answered with {} bytes (min_bytes={})",
+                api_version, bytes, min_bytes
+            );
+            return FetchResponse {
+                error_code: NONE,
+                session_id: 0,
+                responses,
+                ..Default::default()
+            };
+        }
+
+        // Either new data arrived or the wait is over; both end in another read.
+        watch.wait().await;
+    }
+}
+
+/// A requested topic, resolved to its name (or left unresolved if unknown by id).
+struct RequestedTopic {
+    /// The topic's name, or `None` if it was requested by an unknown id.
+    name: Option<String>,
+    /// The topic id, echoed back in v13+ responses.
+    topic_id: Uuid,
+}
+
+/// Resolves the topics in the request. Versions up to 12 name topics; later versions use ids.
+async fn resolve_topics(
+    request: &FetchRequest,
+    api_version: i16,
+    state: &BrokerState,
+) -> Vec<RequestedTopic> {
+    let topics = state.topics.read().await;
+    request
+        .topics
+        .iter()
+        .map(|topic| {
+            if api_version >= 13 {
+                RequestedTopic {
+                    name: topics
+                        .values()
+                        .find(|t| t.topic_id == topic.topic_id)
+                        .map(|t| t.name.clone()),
+                    topic_id: topic.topic_id,
+                }
+            } else {
+                RequestedTopic {
+                    name: Some(topic.topic.clone()),
+                    topic_id: topics.get(&topic.topic).map_or(Uuid::nil(), |t| t.topic_id),
+                }
+            }
+        })
+        .collect()
+}
+
+/// Reads every requested partition once. Returns the response topics, the number of record
+/// bytes read, and whether any partition failed.
+async fn read_topics(
+    topics: &[RequestedTopic],
+    request: &FetchRequest,
+    api_version: i16,
+    state: &BrokerState,
+) -> (Vec<FetchableTopicResponse>, usize, bool) {
+    let mut remaining = request.max_bytes.max(0) as usize;
+    let mut bytes = 0;
+    let mut has_error = false;
+    let mut responses = Vec::with_capacity(topics.len());
+
+    for (topic, requested) in topics.iter().zip(&request.topics) {
+        let mut partitions = Vec::with_capacity(requested.partitions.len());
+        for partition in &requested.partitions {
+            let response = match &topic.name {
+                None => error_partition(partition.partition, UNKNOWN_TOPIC_ID),
+                Some(name) => {
+                    // Only the first partition with data may exceed the byte limits.
+                    let min_one_batch = bytes == 0;
+                    match read_partition(
+                        name,
+                        partition,
+                        api_version,
+                        remaining,
+                        min_one_batch,
+                        state,
+                    )
+                    .await
+                    {
+                        Ok(response) => response,
+                        Err(e) => {
+                            debug!("Fetch from {}-{} failed: {}", name, partition.partition, e);
+                            error_partition(partition.partition, e.error_code())
+                        }
+                    }
+                }
+            };
+
+            let read = response.records.as_ref().map_or(0, Records::len);
+            bytes += read;
+            remaining = remaining.saturating_sub(read);
+            has_error |= response.error_code != NONE;
+            partitions.push(response);
+        }
+        responses.push(FetchableTopicResponse {
+            topic: topic.name.clone().unwrap_or_default(),
+            topic_id: topic.topic_id,
+            partitions,
+            ..Default::default()
+        });
+    }
+
+    (responses, bytes, has_error)
+}
+
+/// Reads up to `max_bytes` (and `partition_max_bytes`) from one partition.
+async fn read_partition(
+    topic: &str,
+    partition: &FetchPartition,
+    api_version: i16,
+    max_bytes: usize,
+    min_one_batch: bool,
+    state: &BrokerState,
+) -> KafkaResult<PartitionData> {
+    let log = state
+        .logs
+        .get(&TopicPartition::new(topic, partition.partition))
+        .await
+        .ok_or_else(|| KafkaBrokerError::UnknownTopicOrPartition {
+            topic: topic.to_string(),
+            partition: partition.partition,
+        })?;
+    let partition_index = partition.partition;
+    let fetch_offset = partition.fetch_offset;
+    let max_bytes = max_bytes.min(partition.partition_max_bytes.max(0) as usize);
+    storage::with_log(&log, move |log| {
+        let mut records = log.read(fetch_offset, max_bytes, min_one_batch)?;
+        if let Some(magic) = message_format(api_version) {
+            let batches = records.into_bytes()?;
+            records = Records::Bytes(kafka_message_set::down_convert(
+                &batches,
+                magic,
+                fetch_offset,
+            )?);
+        }
+        Ok(PartitionData {
+            partition_index,
+            error_code: NONE,
+            high_watermark: log.log_end_offset(),
+            // Without transactions every offset below the high watermark is stable.
+            last_stable_offset: log.log_end_offset(),
+            log_start_offset: log.log_start_offset(),
+            aborted_transactions: None,
+            records: Some(records),
+            ..Default::default()
+        })
+    })
+    .await
+}
+
+/// The legacy message format clients of Fetch `api_version` understand, or `None` if they read
+/// v2 record batches.
+fn message_format(api_version: i16) -> Option<i8> {
+    match api_version {
+        0..=1 => Some(MAGIC_V0),
+        2..=3 => Some(MAGIC_V1),
+        _ => None,
+    }
+}
+
+/// Builds the response entry for a partition that could not be read.
+fn error_partition(partition_index: i32, error_code: i16) -> PartitionData {
+    PartitionData {
+        partition_index,
+        error_code,
+        high_watermark: -1,
+        records: Some(Records::default()),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::kafka_protocol::kafka_messages::fetch_request::FetchTopic;
+    use crate::storage::tests::batch;
+    use tokio_util::sync::CancellationToken;
+
+    /// A Fetch v12 of partitions `0..partitions` of topic "t" from offset 0.
+    fn request(partitions: i32, min_bytes: i32, max_wait_ms: i32, max_bytes: i32) -> FetchRequest {
+        FetchRequest {
+            replica_id: -1,
+            max_wait_ms,
+            min_bytes,
+            max_bytes,
+            topics: vec![FetchTopic {
+                topic: "t".to_string(),
+                partitions: (0..partitions)
+                    .map(|partition| FetchPartition {
+                        partition,
+                        fetch_offset: 0,
+                        partition_max_bytes: 1024 * 1024,
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// The number of record bytes fetched from each partition.
+    fn fetched(response: &FetchResponse) -> Vec<usize> {
+        response.responses[0]
+            .partitions
+            .iter()
+            .map(|partition| partition.records.as_ref().map_or(0, Records::len))
+            .collect()
+    }
+
+    /// Appends a batch to `partition` of topic "t", as a produce does. Returns its size.
+    async fn append(state: &BrokerState, partition: i32) -> usize {
+        let topic_partition = TopicPartition::new("t", partition);
+        let log = state.logs.get(&topic_partition).await.unwrap();
+        let records = batch(1000, &[("a", Some("1")), ("b", Some("2"))]);
+        let size = records.len();
+        storage::with_log(&log, move |log| log.append(&records, 0, 1000))
+            .await
+            .unwrap();
+        state.complete_delayed_requests(&topic_partition);
+        size
+    }
+
+    /// Runs `fetch` with the purgatory expiring its deadline. Returns the response and how
+    /// long it took.
+    async fn timed_fetch(state: &BrokerState, request: FetchRequest) -> (FetchResponse, Duration) {
+        let token = CancellationToken::new();
+        let start = Instant::now();
+        let fetch = async {
+            let response = handle(request, 12, state).await;
+            token.cancel();
+            (response, start.elapsed())
+        };
+        let (result, ()) = tokio::join!(fetch, state.run_purgatory_reapers(token.clone()));
+        result
+    }
+
+    async fn load(dir: &std::path::Path, partitions: i32) -> BrokerState {
+        let state = BrokerState::load(Config::for_tests(dir)).await.unwrap();
+        state.get_or_create_topic("t", partitions).await.unwrap();
+        state
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_fetch_without_enough_data_waits_for_max_wait_ms() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = load(dir.path(), 1).await;
+
+        let (response, elapsed) = timed_fetch(&state, request(1, 1, 500, 1024)).await;
+        assert_eq!(fetched(&response), [0]);
+        assert!(elapsed >= Duration::from_millis(500), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(600), "{:?}", elapsed);
+
+        // With enough data there is no wait.
+        let size = append(&state, 0).await;
+        let (response, elapsed) = timed_fetch(&state, request(1, 1, 500, 1024)).await;
+        assert_eq!(fetched(&response), [size]);
+        assert_eq!(elapsed, Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn an_append_wakes_a_parked_fetch() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = load(dir.path(), 2).await;
+
+        let produce = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            append(&state, 1).await
+        };
+        let ((response, elapsed), size) =
+            tokio::join!(timed_fetch(&state, request(2, 1, 10_000, 1024)), produce);
+        assert_eq!(fetched(&response), [0, size]);
+        assert!(elapsed >= Duration::from_millis(100), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn only_the_first_partition_with_data_may_exceed_max_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = load(dir.path(), 3).await;
+        append(&state, 1).await;
+        let size = append(&state, 2).await;
+
+        let (response, _) = timed_fetch(&state, request(3, 1, 500, 10)).await;
+        assert_eq!(fetched(&response), [0, size, 0]);
+
+        // Once the limit is large enough the next partition is read too.
+        let (response, _) = timed_fetch(&state, request(3, 1, 500, 2 * size as i32)).await;
+        assert_eq!(fetched(&response), [0, size, size]);
+    }
+}
@@ -0,0 +1,92 @@
+//! FindCoordinator (key 10).
+//!
+//! Clients ask which broker coordinates a group (key type 0) or a transactional id (key
+//! type 1). This broker is a single-node cluster and coordinates every group itself, so group
+//! keys always resolve to it. Transactions aren't supported, so transactional ids get
+//! `COORDINATOR_NOT_AVAILABLE`; any other key type is an `INVALID_REQUEST`.
+//!
+//! Up to v3 a request carries a single key and the answer sits at the top level of the
+//! response; from v4 on, it may batch several keys and gets one coordinator entry per key.
+
+use crate::broker_state::BrokerState;
+use crate::kafka_protocol::kafka_error_codes::{COORDINATOR_NOT_AVAILABLE, INVALID_REQUEST, NONE};
+use crate::kafka_protocol::kafka_messages::find_coordinator_response::Coordinator;
+use crate::kafka_protocol::kafka_messages::{FindCoordinatorRequest, FindCoordinatorResponse};
+use tracing::debug;
+
+/// The key type of consumer groups.
+const KEY_TYPE_GROUP: i8 = 0;
+
+/// The key type of transactional ids.
+const KEY_TYPE_TRANSACTION: i8 = 1;
+
+/// Answers a FindCoordinator request.
+pub fn handle(
+    request: FindCoordinatorRequest,
+    api_version: i16,
+    state: &BrokerState,
+) -> FindCoordinatorResponse {
+    let keys = if api_version >= 4 {
+        request.coordinator_keys
+    } else {
+        vec![request.key]
+    };
+    debug!(
+        "FindCoordinator v{} for {} key(s) of type {}",
+        api_version,
+        keys.len(),
+        request.key_type
+    );
+
+    let mut coordinators: Vec<Coordinator> = keys
+        .into_iter()
+        .map(|key| find_coordinator(key, request.key_type, state))
+        .collect();
+
+    if api_version >= 4 {
+        return FindCoordinatorResponse {
+            coordinators,
+            ..Default::default()
+        };
+    }
+    let coordinator = coordinators.pop().unwrap_or_default();
+    FindCoordinatorResponse {
+        error_code: coordinator.error_code,
+        error_message: coordinator.error_message,
+        node_id: coordinator.node_id,
+        host: coordinator.host,
+        port: coordinator.port,
+        ..Default::default()
+    }
+}
+
+/// The coordinator of `key`, of type `key_type`.
+fn find_coordinator(key: String, key_type: i8, state: &BrokerState) -> Coordinator {
+    let (error_code, error_message) = match key_type {
+        KEY_TYPE_GROUP => {
+            let config = &state.config;
+            return Coordinator {
+                key,
+                node_id: config.node_id,
+                host: config.advertised_host.clone(),
+                port: config.port as i32,
+                error_code: NONE,
+                ..Default::default()
+            };
+        }
+        KEY_TYPE_TRANSACTION => (
+            COORDINATOR_NOT_AVAILABLE,
+            "Transactions are not supported by this broker".to_string(),
+        ),
+        _ => (INVALID_REQUEST, format!("Unknown key type {}", key_type)),
+    };
+    Coordinator {
+        key,
+        node_id: -1,
+        host: String::new(),
+        port: -1,
+        error_code,
+        error_message: Some(error_message),
+        ..Default::default()
+    }
+}
@@ -0,0 +1,30 @@
+//! Heartbeat (key 12).
+//!
+//! Members heartbeat to keep their session in the group alive. While the group rebalances, the
+//! answer is `REBALANCE_IN_PROGRESS`, which tells the member to rejoin.
+
+use crate::broker_state::BrokerState;
+use crate::kafka_protocol::kafka_error_codes::NONE;
+use crate::kafka_protocol::kafka_messages::{HeartbeatRequest, HeartbeatResponse};
+use tracing::debug;
+
+/// Answers a Heartbeat request.
+pub fn handle(request: HeartbeatRequest, state: &BrokerState) -> HeartbeatResponse {
+    let result = state.group_coordinator.heartbeat(
+        &request.group_id,
+        &request.member_id,
+        request.group_instance_id.as_deref(),
+        request.generation_id,
+    );
+    let error_code = match result {
+        Ok(()) => NONE,
+        Err(e) => {
+            debug!("Heartbeat of {}: {}", request.member_id, e);
+            e.error_code()
+        }
+    };
+    HeartbeatResponse {
+        error_code,
+        ..Default::default()
+    }
+}
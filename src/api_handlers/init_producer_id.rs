@@ -0,0 +1,46 @@
+//! InitProducerId (key 22).
+//!
+//! Gives an idempotent producer (one without a transactional id) a new producer id, at epoch
+//! 0, every time it starts. The producer then numbers the records it sends to each partition,
+//! so that the partitions can drop its retried batches (see the Produce handler). Transactional
+//! producers are turned away with `COORDINATOR_NOT_AVAILABLE`, as FindCoordinator does, since
+//! the broker has no transaction coordinator.
+
+use crate::broker_state::BrokerState;
+use crate::kafka_protocol::kafka_error_codes::{COORDINATOR_NOT_AVAILABLE, INVALID_REQUEST};
+use crate::kafka_protocol::kafka_messages::{InitProducerIdRequest, InitProducerIdResponse};
+use tracing::debug;
+
+/// Answers an InitProducerId request.
+pub fn handle(request: InitProducerIdRequest, state: &BrokerState) -> InitProducerIdResponse {
+    let error_code = match request.transactional_id.as_deref() {
+        None => match state.producer_ids.generate_producer_id() {
+            Ok(producer_id) => {
+                debug!("InitProducerId allocated producer id {}", producer_id);
+                return InitProducerIdResponse {
+                    producer_id,
+                    producer_epoch: 0,
+                    ..Default::default()
+                };
+            }
+            Err(e) => {
+                debug!("InitProducerId failed: {}", e);
+                e.error_code()
+            }
+        },
+        Some("") => INVALID_REQUEST,
+        Some(transactional_id) => {
+            debug!(
+                "InitProducerId for transactional id {} rejected: transactions are not supported",
+                transactional_id
+            );
+            COORDINATOR_NOT_AVAILABLE
+        }
+    };
+    InitProducerIdResponse {
+        error_code,
+        producer_id: -1,
+        producer_epoch: -1,
+        ..Default::default()
+    }
+}
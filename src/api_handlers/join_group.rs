@@ -0,0 +1,90 @@
+//! JoinGroup (key 11).
+//!
+//! The first step of a rebalance: every member (re)joins its group, and the request is held
+//! until the join phase ends (see [`GroupCoordinator`](crate::group_coordinator::GroupCoordinator)).
+//! Each member then learns the new generation, the chosen assignor and the leader; the leader
+//! also gets every member's metadata, to compute the assignment it hands out with SyncGroup.
+//!
+//! From v4 on, a new member is first given its member id with `MEMBER_ID_REQUIRED`, and must
+//! join again with it. Before v1, the rebalance timeout is the session timeout.
+
+use crate::broker_state::BrokerState;
+use crate::group_coordinator::JoinRequest;
+use crate::kafka_protocol::kafka_error::KafkaBrokerError;
+use crate::kafka_protocol::kafka_error_codes::{MEMBER_ID_REQUIRED, NONE};
+use crate::kafka_protocol::kafka_messages::join_group_response::JoinGroupResponseMember;
+use crate::kafka_protocol::kafka_messages::{JoinGroupRequest, JoinGroupResponse};
+use tracing::{debug, info};
+
+/// Answers a JoinGroup request from `client_id` at `client_host`.
+pub async fn handle(
+    request: JoinGroupRequest,
+    api_version: i16,
+    client_id: &str,
+    client_host: &str,
+    state: &BrokerState,
+) -> JoinGroupResponse {
+    debug!(
+        "JoinGroup v{} of member {:?} to group {}",
+        api_version, request.member_id, request.group_id
+    );
+    let member_id = request.member_id.clone();
+    let join = JoinRequest {
+        group_id: request.group_id,
+        member_id: request.member_id,
+        group_instance_id: request.group_instance_id,
+        client_id: client_id.to_string(),
+        client_host: client_host.to_string(),
+        session_timeout_ms: request.session_timeout_ms,
+        rebalance_timeout_ms: if api_version == 0 {
+            request.session_timeout_ms
+        } else {
+            request.rebalance_timeout_ms
+        },
+        protocol_type: request.protocol_type,
+        protocols: request
+            .protocols
+            .into_iter()
+            .map(|protocol| (protocol.name, protocol.metadata))
+            .collect(),
+        require_known_member_id: api_version >= 4,
+        supports_skip_assignment: api_version >= 9,
+        reason: request.reason,
+    };
+
+    match state.group_coordinator.join_group(join).await {
+        Ok(result) => JoinGroupResponse {
+            error_code: NONE,
+            generation_id: result.generation_id,
+            protocol_type: result.protocol_type,
+            protocol_name: result.protocol_name,
+            leader: result.leader_id,
+            skip_assignment: result.skip_assignment,
+            member_id: result.member_id,
+            members: result
+                .members
+                .into_iter()
+                .map(|member| JoinGroupResponseMember {
+                    member_id: member.member_id,
+                    group_instance_id: member.group_instance_id,
+                    metadata: member.metadata,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        },
+        Err(KafkaBrokerError::MemberIdRequired { member_id }) => JoinGroupResponse {
+            error_code: MEMBER_ID_REQUIRED,
+            member_id,
+            ..Default::default()
+        },
+        Err(e) => {
+            info!("JoinGroup of member {:?} failed: {}", member_id, e);
+            JoinGroupResponse {
+                error_code: e.error_code(),
+                member_id,
+                ..Default::default()
+            }
+        }
+    }
+}
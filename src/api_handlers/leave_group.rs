@@ -0,0 +1,79 @@
+//! LeaveGroup (key 13).
+//!
+//! Members leave their group on shutdown, so that it rebalances at once rather than after
+//! their session expires. Up to v2 a request carries a single member id; from v3 on, it may
+//! remove several members, static ones by `group.instance.id`, each with its own error code.
+
+use crate::broker_state::BrokerState;
+use crate::group_coordinator::LeavingMember;
+use crate::kafka_protocol::kafka_error_codes::NONE;
+use crate::kafka_protocol::kafka_messages::leave_group_response::MemberResponse;
+use crate::kafka_protocol::kafka_messages::{LeaveGroupRequest, LeaveGroupResponse};
+use tracing::{debug, info};
+
+/// Answers a LeaveGroup request.
+pub async fn handle(
+    request: LeaveGroupRequest,
+    api_version: i16,
+    state: &BrokerState,
+) -> LeaveGroupResponse {
+    let members: Vec<LeavingMember> = if api_version >= 3 {
+        request
+            .members
+            .into_iter()
+            .map(|member| LeavingMember {
+                member_id: member.member_id,
+                group_instance_id: member.group_instance_id,
+            })
+            .collect()
+    } else {
+        vec![LeavingMember {
+            member_id: request.member_id,
+            group_instance_id: None,
+        }]
+    };
+    debug!(
+        "LeaveGroup v{} of {} member(s) from group {}",
+        api_version,
+        members.len(),
+        request.group_id
+    );
+
+    let results = match state
+        .group_coordinator
+        .leave_group(&request.group_id, &members)
+        .await
+    {
+        Ok(results) => results,
+        Err(e) => {
+            info!("LeaveGroup from group {} failed: {}", request.group_id, e);
+            return LeaveGroupResponse {
+                error_code: e.error_code(),
+                ..Default::default()
+            };
+        }
+    };
+    let members: Vec<MemberResponse> = members
+        .into_iter()
+        .zip(results)
+        .map(|(member, result)| MemberResponse {
+            member_id: member.member_id,
+            group_instance_id: member.group_instance_id,
+            error_code: result.map_or_else(|e| e.error_code(), |()| NONE),
+            ..Default::default()
+        })
+        .collect();
+
+    if api_version >= 3 {
+        LeaveGroupResponse {
+            error_code: NONE,
+            members,
+            ..Default::default()
+        }
+    } else {
+        LeaveGroupResponse {
+            error_code: members.first().map_or(NONE, |member| member.error_code),
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,36 @@
+//! ListGroups (key 16).
+//!
+//! Lists the groups this broker coordinates, classic and consumer groups alike. From v4 on, a
+//! request may ask only for groups in some states, and each group comes back with its state;
+//! from v5 on, likewise for the group type (`classic` or `consumer`).
+
+use crate::broker_state::BrokerState;
+use crate::kafka_protocol::kafka_error_codes::NONE;
+use crate::kafka_protocol::kafka_messages::list_groups_response::ListedGroup;
+use crate::kafka_protocol::kafka_messages::{ListGroupsRequest, ListGroupsResponse};
+use tracing::debug;
+
+/// Answers a ListGroups request.
+pub fn handle(request: ListGroupsRequest, state: &BrokerState) -> ListGroupsResponse {
+    debug!(
+        "ListGroups in states {:?} of types {:?}",
+        request.states_filter, request.types_filter
+    );
+    let groups = state
+        .group_coordinator
+        .list_groups(&request.states_filter, &request.types_filter)
+        .into_iter()
+        .map(|group| ListedGroup {
+            group_id: group.group_id,
+            protocol_type: group.protocol_type,
+            group_state: group.state.to_string(),
+            group_type: group.group_type.name().to_string(),
+            ..Default::default()
+        })
+        .collect();
+    ListGroupsResponse {
+        error_code: NONE,
+        groups,
+        ..Default::default()
+    }
+}
@@ -0,0 +1,163 @@
+//! Metadata (key 3).
+//!
+//! Clients use Metadata to learn the cluster layout: which brokers exist, where they listen,
+//! and which broker leads each partition of the topics they care about. This broker is a
+//! single-node cluster, so it lists itself as the only broker and as the controller, and leads
+//! every partition.
+//!
+//! Requested topics that don't exist are created on the fly when both the client
+//! (`allow_auto_topic_creation`, always on before v4) and the broker
+//! (`auto.create.topics.enable`) allow it; otherwise they come back with
+//! `UNKNOWN_TOPIC_OR_PARTITION`. Names that Kafka would never accept get
+//! `INVALID_TOPIC_EXCEPTION`.
+
+use crate::broker_state::{validate_topic_name, BrokerState, Topic};
+use crate::kafka_protocol::kafka_error_codes::{
+    INVALID_TOPIC_EXCEPTION, NONE, UNKNOWN_TOPIC_ID, UNKNOWN_TOPIC_OR_PARTITION,
+};
+use crate::kafka_protocol::kafka_messages::metadata_response::{
+    MetadataResponseBroker, MetadataResponsePartition, MetadataResponseTopic,
+};
+use crate::kafka_protocol::kafka_messages::{MetadataRequest, MetadataResponse};
+use tracing::{debug, error, info};
+use uuid::Uuid;
+
+/// Answers a Metadata request.
+pub async fn handle(
+    request: MetadataRequest,
+    api_version: i16,
+    state: &BrokerState,
+) -> MetadataResponse {
+    let config = &state.config;
+
+    // v0 has no null array: an empty list means "all topics". From v1 on, only null does.
+    let requested = match request.topics {
+        Some(topics) if api_version == 0 && topics.is_empty() => None,
+        topics => topics,
+    };
+    // Auto-creation can only be turned off by the client from v4 on.
+    let allow_auto_topic_creation = api_version < 4 || request.allow_auto_topic_creation;
+
+    let topics = match requested {
+        None => {
+            debug!("Metadata v{} for all topics", api_version);
+            let topics = state.topics.read().await;
+            topics.values().map(topic_metadata).collect()
+        }
+        Some(requested) => {
+            debug!("Metadata v{} for {} topic(s)", api_version, requested.len());
+            let mut topics = Vec::with_capacity(requested.len());
+            for topic in requested {
+                let metadata = match topic.name {
+                    Some(name) => {
+                        describe_topic_by_name(&name, allow_auto_topic_creation, state).await
+                    }
+                    None => describe_topic_by_id(topic.topic_id, state).await,
+                };
+                topics.push(metadata);
+            }
+            topics
+        }
+    };
+
+    MetadataResponse {
+        brokers: vec![MetadataResponseBroker {
+            node_id: config.node_id,
+            host: config.advertised_host.clone(),
+            port: config.port as i32,
+            rack: None,
+            ..Default::default()
+        }],
+        cluster_id: Some(config.cluster_id.clone()),
+        controller_id: config.node_id,
+        topics,
+        ..Default::default()
+    }
+}
+
+/// Describes the topic called `name`, creating it first if it's missing and auto-creation is
+/// allowed.
+async fn describe_topic_by_name(
+    name: &str,
+    allow_auto_topic_creation: bool,
+    state: &BrokerState,
+) -> MetadataResponseTopic {
+    if let Some(topic) = state.topics.read().await.get(name) {
+        return topic_metadata(topic);
+    }
+
+    if let Err(reason) = validate_topic_name(name) {
+        debug!("Metadata requested for invalid topic: {}", reason);
+        return topic_error(Some(name.to_string()), Uuid::nil(), INVALID_TOPIC_EXCEPTION);
+    }
+
+    if !(allow_auto_topic_creation && state.config.auto_create_topics_enable) {
+        return topic_error(
+            Some(name.to_string()),
+            Uuid::nil(),
+            UNKNOWN_TOPIC_OR_PARTITION,
+        );
+    }
+
+    let topic = match state
+        .get_or_create_topic(name, state.config.num_partitions)
+        .await
+    {
+        Ok(topic) => topic,
+        Err(e) => {
+            error!("Failed to auto-create topic {}: {}", name, e);
+            return topic_error(Some(name.to_string()), Uuid::nil(), e.error_code());
+        }
+    };
+    info!(
+        "Auto-created topic {} ({}) with {} partition(s)",
+        topic.name,
+        topic.topic_id,
+        topic.partitions.len()
+    );
+    topic_metadata(&topic)
+}
+
+/// Describes the topic with id `topic_id`. Topics are never auto-created by id.
+async fn describe_topic_by_id(topic_id: Uuid, state: &BrokerState) -> MetadataResponseTopic {
+    let topics = state.topics.read().await;
+    match topics.values().find(|topic| topic.topic_id == topic_id) {
+        Some(topic) => topic_metadata(topic),
+        None => topic_error(None, topic_id, UNKNOWN_TOPIC_ID),
+    }
+}
+
+/// Builds the Metadata entry for an existing topic.
+fn topic_metadata(topic: &Topic) -> MetadataResponseTopic {
+    MetadataResponseTopic {
+        error_code: NONE,
+        name: Some(topic.name.clone()),
+        topic_id: topic.topic_id,
+        is_internal: topic.is_internal,
+        partitions: topic
+            .partitions
+            .iter()
+            .map(|partition| MetadataResponsePartition {
+                error_code: NONE,
+                partition_index: partition.index,
+                leader_id: partition.leader_id,
+                leader_epoch: partition.leader_epoch,
+                replica_nodes: partition.replicas.clone(),
+                isr_nodes: partition.isr.clone(),
+                offline_replicas: Vec::new(),
+                ..Default::default()
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
+
+/// Builds the Metadata entry for a topic that could not be described.
+fn topic_error(name: Option<String>, topic_id: Uuid, error_code: i16) -> MetadataResponseTopic {
+    MetadataResponseTopic {
+        error_code,
+        name,
+        topic_id,
+        ..Default::default()
+    }
+}
@@ -0,0 +1,241 @@
+//! Routes parsed requests to the handler for their API.
+//!
+//! [`dispatch`] inspects the request header's `request_api_key` and `request_api_version`,
+//! hands the typed request body to the matching per-API handler, and wraps the typed response
+//! payload in a [`KafkaResponseMessage`]. A version of an API we don't implement is answered
+//! with the API's response carrying `UNSUPPORTED_VERSION` (for ApiVersions, along with the
+//! supported ranges). Unknown API keys surface as a [`KafkaBrokerError`], which the caller
+//! turns into an error response carrying [`KafkaBrokerError::error_code`].
+
+mod api_versions;
+mod consumer_group_describe;
+mod consumer_group_heartbeat;
+mod delete_groups;
+mod delete_records;
+mod describe_groups;
+mod fetch;
+mod find_coordinator;
+mod heartbeat;
+mod init_producer_id;
+mod join_group;
+mod leave_group;
+mod list_groups;
+mod metadata;
+mod offset_commit;
+mod offset_delete;
+mod offset_fetch;
+mod produce;
+mod sync_group;
+
+use crate::broker_state::SharedBrokerState;
+use crate::kafka_protocol::kafka_api_keys::ApiKey;
+use crate::kafka_protocol::kafka_error::{KafkaBrokerError, KafkaResult};
+use crate::kafka_protocol::kafka_messages::{
+    ConsumerGroupHeartbeatResponse, FetchResponse, FindCoordinatorResponse, HeartbeatResponse,
+    InitProducerIdResponse, JoinGroupResponse, LeaveGroupResponse, ListGroupsResponse,
+    OffsetDeleteResponse, OffsetFetchResponse, SyncGroupResponse,
+};
+use crate::kafka_protocol::kafka_request_message::{KafkaRequest, KafkaRequestMessage};
+use crate::kafka_protocol::kafka_response_message::{KafkaResponse, KafkaResponseMessage};
+use tracing::{debug, warn};
+
+/// Sends `request`, received from `client_host`, to the handler for its API and returns the
+/// encoded-ready response, or `None` if the request must not be answered (Produce with
+/// `acks=0`).
+///
+/// # Errors
+///
+/// Returns [`KafkaBrokerError::UnknownApiKey`] if the header carries an API key the protocol
+/// does not define.
+pub async fn dispatch(
+    request: KafkaRequestMessage,
+    state: &SharedBrokerState,
+    client_host: &str,
+) -> KafkaResult<Option<KafkaResponseMessage>> {
+    let api_key = ApiKey::try_from(request.header.request_api_key())?;
+    let api_version = request.header.request_api_version();
+    let correlation_id = request.header.correlation_id();
+    debug!(
+        "Dispatching {:?} v{} request ({} bytes) from client_id={:?}.",
+        api_key,
+        api_version,
+        request.message_size,
+        request.header.client_id()
+    );
+
+    let respond = |version, payload| {
+        KafkaResponseMessage::new(api_key as i16, correlation_id, version, payload)
+    };
+
+    let response = match request.payload {
+        KafkaRequest::Produce(body) => produce::handle(body, api_version, state)
+            .await
+            .map(|body| respond(api_version, KafkaResponse::Produce(body))),
+        KafkaRequest::Fetch(body) => Some(respond(
+            api_version,
+            KafkaResponse::Fetch(fetch::handle(body, api_version, state).await),
+        )),
+        KafkaRequest::Metadata(body) => Some(respond(
+            api_version,
+            KafkaResponse::Metadata(metadata::handle(body, api_version, state).await),
+        )),
+        KafkaRequest::OffsetCommit(body) => Some(respond(
+            api_version,
+            KafkaResponse::OffsetCommit(offset_commit::handle(body, api_version, state).await),
+        )),
+        KafkaRequest::OffsetFetch(body) => Some(respond(
+            api_version,
+            KafkaResponse::OffsetFetch(offset_fetch::handle(body, api_version, state)),
+        )),
+        KafkaRequest::FindCoordinator(body) => Some(respond(
+            api_version,
+            KafkaResponse::FindCoordinator(find_coordinator::handle(body, api_version, state)),
+        )),
+        KafkaRequest::JoinGroup(body) => {
+            let client_id = request.header.client_id().unwrap_or_default();
+            let response =
+                join_group::handle(body, api_version, client_id, client_host, state).await;
+            Some(respond(api_version, KafkaResponse::JoinGroup(response)))
+        }
+        KafkaRequest::Heartbeat(body) => Some(respond(
+            api_version,
+            KafkaResponse::Heartbeat(heartbeat::handle(body, state)),
+        )),
+        KafkaRequest::LeaveGroup(body) => Some(respond(
+            api_version,
+            KafkaResponse::LeaveGroup(leave_group::handle(body, api_version, state).await),
+        )),
+        KafkaRequest::SyncGroup(body) => Some(respond(
+            api_version,
+            KafkaResponse::SyncGroup(sync_group::handle(body, api_version, state).await),
+        )),
+        KafkaRequest::DescribeGroups(body) => Some(respond(
+            api_version,
+            KafkaResponse::DescribeGroups(describe_groups::handle(body, state)),
+        )),
+        KafkaRequest::ListGroups(body) => Some(respond(
+            api_version,
+            KafkaResponse::ListGroups(list_groups::handle(body, state)),
+        )),
+        KafkaRequest::ApiVersions(body) => Some(respond(
+            api_version,
+            KafkaResponse::ApiVersions(api_versions::handle(&body, api_version)),
+        )),
+        KafkaRequest::DeleteRecords(body) => Some(respond(
+            api_version,
+            KafkaResponse::DeleteRecords(delete_records::handle(body, state).await),
+        )),
+        KafkaRequest::InitProducerId(body) => Some(respond(
+            api_version,
+            KafkaResponse::InitProducerId(init_producer_id::handle(body, state)),
+        )),
+        KafkaRequest::DeleteGroups(body) => Some(respond(
+            api_version,
+            KafkaResponse::DeleteGroups(delete_groups::handle(body, state).await),
+        )),
+        KafkaRequest::OffsetDelete(body) => Some(respond(
+            api_version,
+            KafkaResponse::OffsetDelete(offset_delete::handle(body, state).await),
+        )),
+        KafkaRequest::ConsumerGroupHeartbeat(body) => {
+            let client_id = request.header.client_id().unwrap_or_default();
+            let response =
+                consumer_group_heartbeat::handle(body, client_id, client_host, state).await;
+            Some(respond(
+                api_version,
+                KafkaResponse::ConsumerGroupHeartbeat(response),
+            ))
+        }
+        KafkaRequest::ConsumerGroupDescribe(body) => Some(respond(
+            api_version,
+            KafkaResponse::ConsumerGroupDescribe(
+                consumer_group_describe::handle(body, state).await,
+            ),
+        )),
+        KafkaRequest::Unsupported if api_key == ApiKey::ApiVersions => Some(respond(
+            api_versions::FALLBACK_VERSION,
+            KafkaResponse::ApiVersions(api_versions::handle_unsupported_version(api_version)),
+        )),
+        KafkaRequest::Unsupported => {
+            warn!(
+                "No handler for {:?} v{}; replying with an error response.",
+                api_key, api_version
+            );
+            let error = KafkaBrokerError::UnsupportedVersion {
+                api_key: api_key as i16,
+                api_version,
+            };
+            Some(respond(
+                api_version,
+                error_response(api_key, error.error_code()),
+            ))
+        }
+    };
+
+    Ok(response)
+}
+
+/// The response to an `api_key` request the broker can't serve: the API's default response
+/// with `error_code` as its top-level error code, or just the error code if the API has no
+/// top-level error code or isn't implemented at all.
+fn error_response(api_key: ApiKey, error_code: i16) -> KafkaResponse {
+    match api_key {
+        ApiKey::Fetch => KafkaResponse::Fetch(FetchResponse {
+            error_code,
+            ..Default::default()
+        }),
+        ApiKey::OffsetFetch => KafkaResponse::OffsetFetch(OffsetFetchResponse {
+            error_code,
+            ..Default::default()
+        }),
+        ApiKey::FindCoordinator => KafkaResponse::FindCoordinator(FindCoordinatorResponse {
+            error_code,
+            ..Default::default()
+        }),
+        ApiKey::JoinGroup => KafkaResponse::JoinGroup(JoinGroupResponse {
+            error_code,
+            ..Default::default()
+        }),
+        ApiKey::Heartbeat => KafkaResponse::Heartbeat(HeartbeatResponse {
+            error_code,
+            ..Default::default()
+        }),
+        ApiKey::LeaveGroup => KafkaResponse::LeaveGroup(LeaveGroupResponse {
+            error_code,
+            ..Default::default()
+        }),
+        ApiKey::SyncGroup => KafkaResponse::SyncGroup(SyncGroupResponse {
+            error_code,
+            ..Default::default()
+        }),
+        ApiKey::ListGroups => KafkaResponse::ListGroups(ListGroupsResponse {
+            error_code,
+            ..Default::default()
+        }),
+        ApiKey::InitProducerId => KafkaResponse::InitProducerId(InitProducerIdResponse {
+            error_code,
+            producer_id: -1,
+            producer_epoch: -1,
+            ..Default::default()
+        }),
+        ApiKey::OffsetDelete => KafkaResponse::OffsetDelete(OffsetDeleteResponse {
+            error_code,
+            ..Default::default()
+        }),
+        ApiKey::ConsumerGroupHeartbeat => {
+            KafkaResponse::ConsumerGroupHeartbeat(ConsumerGroupHeartbeatResponse {
+                error_code,
+                ..Default::default()
+            })
+        }
+        // The remaining APIs report errors per topic, partition or group only.
+        ApiKey::Produce => KafkaResponse::Produce(Default::default()),
+        ApiKey::Metadata => KafkaResponse::Metadata(Default::default()),
+        ApiKey::OffsetCommit => KafkaResponse::OffsetCommit(Default::default()),
+        ApiKey::DescribeGroups => KafkaResponse::DescribeGroups(Default::default()),
+        ApiKey::DeleteRecords => KafkaResponse::DeleteRecords(Default::default()),
+        ApiKey::DeleteGroups => KafkaResponse::DeleteGroups(Default::default()),
+        ApiKey::ConsumerGroupDescribe => KafkaResponse::ConsumerGroupDescribe(Default::default()),
+        _ => KafkaResponse::Error { error_code },
+    }
+}
@@ -0,0 +1,113 @@
+//! OffsetCommit (key 8).
+//!
+//! Consumers commit the position they have reached in each partition, so that the group can
+//! resume from it after a rebalance or restart. Members of a group commit with their
+//! generation; anyone else (a consumer assigning itself partitions, or an admin tool) commits
+//! with generation -1, which is only allowed while the group has no members.
+//!
+//! v1 lets the client set the commit timestamp, and v2-v4 a retention time for the offsets;
+//! later versions always use the broker's time and `offsets.retention.minutes`.
+
+use crate::broker_state::BrokerState;
+use crate::group_coordinator::{CommitRequest, OffsetAndMetadata};
+use crate::kafka_protocol::kafka_error_codes::{NONE, UNKNOWN_TOPIC_OR_PARTITION};
+use crate::kafka_protocol::kafka_messages::offset_commit_response::{
+    OffsetCommitResponsePartition, OffsetCommitResponseTopic,
+};
+use crate::kafka_protocol::kafka_messages::{OffsetCommitRequest, OffsetCommitResponse};
+use crate::storage::{now_ms, TopicPartition};
+use tracing::{debug, info};
+
+/// Answers an OffsetCommit request.
+pub async fn handle(
+    request: OffsetCommitRequest,
+    api_version: i16,
+    state: &BrokerState,
+) -> OffsetCommitResponse {
+    debug!(
+        "OffsetCommit v{} of group {} for generation {} ({} topic(s))",
+        api_version,
+        request.group_id,
+        request.generation_id_or_member_epoch,
+        request.topics.len()
+    );
+    let now_ms = now_ms();
+    let expire_timestamp =
+        (request.retention_time_ms != -1).then(|| now_ms + request.retention_time_ms);
+
+    // Partitions of unknown topics are answered straight away; the others are committed
+    // together, remembering where their result goes in the response.
+    let mut topics = Vec::with_capacity(request.topics.len());
+    let mut offsets = Vec::new();
+    let mut positions = Vec::new();
+    {
+        let known_topics = state.topics.read().await;
+        for topic in request.topics {
+            let partition_count = known_topics
+                .get(&topic.name)
+                .map_or(0, |known| known.partitions.len());
+            let mut partitions = Vec::with_capacity(topic.partitions.len());
+            for partition in topic.partitions {
+                let index = partition.partition_index;
+                let error_code = if index >= 0 && (index as usize) < partition_count {
+                    positions.push((topics.len(), partitions.len()));
+                    offsets.push((
+                        TopicPartition::new(&topic.name, index),
+                        OffsetAndMetadata {
+                            offset: partition.committed_offset,
+                            leader_epoch: partition.committed_leader_epoch,
+                            metadata: partition.committed_metadata.unwrap_or_default(),
+                            commit_timestamp: match partition.commit_timestamp {
+                                -1 => now_ms,
+                                timestamp => timestamp,
+                            },
+                            expire_timestamp,
+                        },
+                    ));
+                    NONE
+                } else {
+                    UNKNOWN_TOPIC_OR_PARTITION
+                };
+                partitions.push(OffsetCommitResponsePartition {
+                    partition_index: index,
+                    error_code,
+                    ..Default::default()
+                });
+            }
+            topics.push(OffsetCommitResponseTopic {
+                name: topic.name,
+                partitions,
+                ..Default::default()
+            });
+        }
+    }
+
+    if !offsets.is_empty() {
+        let group_id = request.group_id.clone();
+        let commit = CommitRequest {
+            group_id: request.group_id,
+            generation_id: request.generation_id_or_member_epoch,
+            member_id: request.member_id,
+            group_instance_id: request.group_instance_id,
+            offsets,
+        };
+        let error_codes: Vec<i16> = match state.group_coordinator.commit_offsets(commit).await {
+            Ok(results) => results
+                .into_iter()
+                .map(|result| result.map_or_else(|e| e.error_code(), |()| NONE))
+                .collect(),
+            Err(e) => {
+                info!("OffsetCommit of group {} failed: {}", group_id, e);
+                vec![e.error_code(); positions.len()]
+            }
+        };
+        for ((topic, partition), error_code) in positions.into_iter().zip(error_codes) {
+            topics[topic].partitions[partition].error_code = error_code;
+        }
+    }
+
+    OffsetCommitResponse {
+        topics,
+        ..Default::default()
+    }
+}
@@ -0,0 +1,73 @@
+//! OffsetDelete (key 47).
+//!
+//! Deletes some of a group's committed offsets. A group that doesn't exist is answered with
+//! `GROUP_ID_NOT_FOUND`, and a classic group with members that the broker can't tell the
+//! subscriptions of with `NON_EMPTY_GROUP`. Otherwise each partition comes back on its own,
+//! with `GROUP_SUBSCRIBED_TO_TOPIC` if a member of the group consumes its topic.
+
+use crate::broker_state::BrokerState;
+use crate::kafka_protocol::kafka_error_codes::NONE;
+use crate::kafka_protocol::kafka_messages::offset_delete_response::{
+    OffsetDeleteResponsePartition, OffsetDeleteResponseTopic,
+};
+use crate::kafka_protocol::kafka_messages::{OffsetDeleteRequest, OffsetDeleteResponse};
+use crate::storage::TopicPartition;
+use tracing::{debug, info};
+
+/// Answers an OffsetDelete request.
+pub async fn handle(request: OffsetDeleteRequest, state: &BrokerState) -> OffsetDeleteResponse {
+    debug!(
+        "OffsetDelete of group {} for {} topic(s)",
+        request.group_id,
+        request.topics.len()
+    );
+    let partitions: Vec<TopicPartition> = request
+        .topics
+        .iter()
+        .flat_map(|topic| {
+            topic
+                .partitions
+                .iter()
+                .map(|partition| TopicPartition::new(&topic.name, partition.partition_index))
+        })
+        .collect();
+    let results = match state
+        .group_coordinator
+        .delete_offsets(&request.group_id, &partitions)
+        .await
+    {
+        Ok(results) => results,
+        Err(e) => {
+            info!("OffsetDelete of group {} failed: {}", request.group_id, e);
+            return OffsetDeleteResponse {
+                error_code: e.error_code(),
+                ..Default::default()
+            };
+        }
+    };
+
+    let mut results = results.into_iter();
+    let topics = request
+        .topics
+        .into_iter()
+        .map(|topic| OffsetDeleteResponseTopic {
+            name: topic.name,
+            partitions: topic
+                .partitions
+                .into_iter()
+                .zip(results.by_ref())
+                .map(|(partition, result)| OffsetDeleteResponsePartition {
+                    partition_index: partition.partition_index,
+                    error_code: result.err().map_or(NONE, |e| e.error_code()),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        })
+        .collect();
+    OffsetDeleteResponse {
+        error_code: NONE,
+        topics,
+        ..Default::default()
+    }
+}
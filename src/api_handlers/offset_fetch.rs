@@ -0,0 +1,198 @@
+//! OffsetFetch (key 9).
+//!
+//! Consumers fetch the offsets their group has committed to know where to resume. Up to v7 a
+//! request is for a single group; from v8 on, it may ask for several, each answered with its
+//! own error code. From v2 on, asking for no topics (null) returns every committed offset of
+//! the group. Partitions without a committed offset, and groups this broker doesn't know, come
+//! back with offset -1.
+//!
+//! From v9 on, a member of a consumer group fetches with its member id and epoch, which must be
+//! current while the group has members.
+
+use crate::broker_state::BrokerState;
+use crate::group_coordinator::OffsetAndMetadata;
+use crate::kafka_protocol::kafka_error::KafkaResult;
+use crate::kafka_protocol::kafka_error_codes::NONE;
+use crate::kafka_protocol::kafka_messages::offset_fetch_response::{
+    OffsetFetchResponseGroup, OffsetFetchResponsePartition, OffsetFetchResponsePartitions,
+    OffsetFetchResponseTopic, OffsetFetchResponseTopics,
+};
+use crate::kafka_protocol::kafka_messages::{OffsetFetchRequest, OffsetFetchResponse};
+use crate::storage::TopicPartition;
+use tracing::{debug, info};
+
+/// The offsets of one topic: each partition with its committed offset, if any.
+type TopicOffsets = (String, Vec<(i32, Option<OffsetAndMetadata>)>);
+
+/// Answers an OffsetFetch request.
+pub fn handle(
+    request: OffsetFetchRequest,
+    api_version: i16,
+    state: &BrokerState,
+) -> OffsetFetchResponse {
+    if api_version >= 8 {
+        let groups = request
+            .groups
+            .into_iter()
+            .map(|group| {
+                let topics = group.topics.map(|topics| {
+                    topics
+                        .into_iter()
+                        .map(|topic| (topic.name, topic.partition_indexes))
+                        .collect()
+                });
+                let member = match &group.member_id {
+                    Some(member_id) => Some((member_id.as_str(), group.member_epoch)),
+                    None if group.member_epoch >= 0 => Some(("", group.member_epoch)),
+                    None => None,
+                };
+                let offsets = match fetch(&group.group_id, member, topics, api_version, state) {
+                    Ok(offsets) => offsets,
+                    Err(e) => {
+                        info!("OffsetFetch of group {} failed: {}", group.group_id, e);
+                        return OffsetFetchResponseGroup {
+                            group_id: group.group_id,
+                            error_code: e.error_code(),
+                            ..Default::default()
+                        };
+                    }
+                };
+                let topics = offsets
+                    .into_iter()
+                    .map(|(name, partitions)| OffsetFetchResponseTopics {
+                        name,
+                        partitions: partitions
+                            .into_iter()
+                            .map(|(partition_index, offset)| {
+                                let offset = offset.unwrap_or_else(no_offset);
+                                OffsetFetchResponsePartitions {
+                                    partition_index,
+                                    committed_offset: offset.offset,
+                                    committed_leader_epoch: offset.leader_epoch,
+                                    metadata: Some(offset.metadata),
+                                    error_code: NONE,
+                                    ..Default::default()
+                                }
+                            })
+                            .collect(),
+                        ..Default::default()
+                    })
+                    .collect();
+                OffsetFetchResponseGroup {
+                    group_id: group.group_id,
+                    topics,
+                    error_code: NONE,
+                    ..Default::default()
+                }
+            })
+            .collect();
+        return OffsetFetchResponse {
+            groups,
+            ..Default::default()
+        };
+    }
+
+    let topics = request.topics.map(|topics| {
+        topics
+            .into_iter()
+            .map(|topic| (topic.name, topic.partition_indexes))
+            .collect()
+    });
+    let offsets = match fetch(&request.group_id, None, topics, api_version, state) {
+        Ok(offsets) => offsets,
+        Err(e) => {
+            return OffsetFetchResponse {
+                error_code: e.error_code(),
+                ..Default::default()
+            }
+        }
+    };
+    let topics = offsets
+        .into_iter()
+        .map(|(name, partitions)| OffsetFetchResponseTopic {
+            name,
+            partitions: partitions
+                .into_iter()
+                .map(|(partition_index, offset)| {
+                    let offset = offset.unwrap_or_else(no_offset);
+                    OffsetFetchResponsePartition {
+                        partition_index,
+                        committed_offset: offset.offset,
+                        committed_leader_epoch: offset.leader_epoch,
+                        metadata: Some(offset.metadata),
+                        error_code: NONE,
+                        ..Default::default()
+                    }
+                })
+                .collect(),
+            ..Default::default()
+        })
+        .collect();
+    OffsetFetchResponse {
+        topics,
+        error_code: NONE,
+        ..Default::default()
+    }
+}
+
+/// The offsets committed by `group_id` for `topics`, or for every partition it has committed
+/// to if `None`, grouped by topic. `member` is the member id and epoch of the fetching
+/// consumer group member, if any.
+fn fetch(
+    group_id: &str,
+    member: Option<(&str, i32)>,
+    topics: Option<Vec<(String, Vec<i32>)>>,
+    api_version: i16,
+    state: &BrokerState,
+) -> KafkaResult<Vec<TopicOffsets>> {
+    match &topics {
+        Some(topics) => debug!(
+            "OffsetFetch v{} of group {} for {} topic(s)",
+            api_version,
+            group_id,
+            topics.len()
+        ),
+        None => debug!(
+            "OffsetFetch v{} of group {} for all topics",
+            api_version, group_id
+        ),
+    }
+    let partitions = topics.map(|topics| {
+        topics
+            .into_iter()
+            .flat_map(|(name, indexes)| {
+                indexes
+                    .into_iter()
+                    .map(move |index| TopicPartition::new(name.clone(), index))
+            })
+            .collect()
+    });
+
+    let mut offsets: Vec<TopicOffsets> = Vec::new();
+    let committed = state
+        .group_coordinator
+        .fetch_offsets(group_id, member, partitions)?;
+    for (topic_partition, offset) in committed {
+        match offsets.last_mut() {
+            Some((name, partitions)) if *name == topic_partition.topic => {
+                partitions.push((topic_partition.partition, offset))
+            }
+            _ => offsets.push((
+                topic_partition.topic,
+                vec![(topic_partition.partition, offset)],
+            )),
+        }
+    }
+    Ok(offsets)
+}
+
+/// What a partition without a committed offset is answered with.
+fn no_offset() -> OffsetAndMetadata {
+    OffsetAndMetadata {
+        offset: -1,
+        leader_epoch: -1,
+        metadata: String::new(),
+        commit_timestamp: -1,
+        expire_timestamp: None,
+    }
+}
@@ -0,0 +1,354 @@
+//! Produce (key 0).
+//!
+//! Each partition in the request carries one record batch, which is validated and appended to
+//! the partition's log. The response reports, per partition, either the base offset the batch
+//! was assigned or the error that kept it out of the log; one bad partition never fails the
+//! others.
+//!
+//! Versions 0-2 may instead carry a legacy (magic 0 or 1) message set, which the log
+//! up-converts into a record batch. From version 3 on only v2 batches are allowed.
+//!
+//! Batches from idempotent producers (with a producer id from InitProducerId) must follow the
+//! producer's previous batch to the partition, or fail with `OUT_OF_ORDER_SEQUENCE_NUMBER`. A
+//! retry of one of its last five batches is answered with the base offset the batch got the
+//! first time, without appending it again.
+//!
+//! With `acks=1` a partition is answered as soon as its batch is in the leader's log. With
+//! `acks=-1` the request is parked in the produce [`Purgatory`](crate::purgatory::Purgatory)
+//! until the high watermark of every partition has passed the appended records, or `timeout_ms`
+//! expires and the partitions still short of it fail with `REQUEST_TIMED_OUT`. This broker is
+//! the sole replica of every partition, so its high watermark moves with each append and the
+//! wait ends at the first check. With `acks=0` the producer expects no response at all.
+
+use crate::broker_state::BrokerState;
+use crate::kafka_protocol::kafka_error::{KafkaBrokerError, KafkaResult};
+use crate::kafka_protocol::kafka_error_codes::{INVALID_REQUIRED_ACKS, NONE, REQUEST_TIMED_OUT};
+use crate::kafka_protocol::kafka_messages::produce_request::PartitionProduceData;
+use crate::kafka_protocol::kafka_messages::produce_response::{
+    PartitionProduceResponse, TopicProduceResponse,
+};
+use crate::kafka_protocol::kafka_messages::{ProduceRequest, ProduceResponse};
+use crate::kafka_protocol::kafka_record_batch::{peek_magic, MAGIC_V2};
+use crate::purgatory::DelayedOperationKey;
+use crate::storage::{self, now_ms, LogAppendInfo, TopicPartition};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+/// Answers a Produce request, or returns `None` when the producer asked for no response
+/// (`acks=0`).
+pub async fn handle(
+    request: ProduceRequest,
+    api_version: i16,
+    state: &BrokerState,
+) -> Option<ProduceResponse> {
+    let acks = request.acks;
+    debug!(
+        "Produce v{} with acks={} to {} topic(s)",
+        api_version,
+        acks,
+        request.topic_data.len()
+    );
+
+    let valid_acks = matches!(acks, -1..=1);
+    let mut responses = Vec::with_capacity(request.topic_data.len());
+    // The partitions an `acks=-1` request waits on: where their response is, and the offset the
+    // high watermark must reach.
+    let mut pending = Vec::new();
+    for topic in request.topic_data {
+        let mut partition_responses = Vec::with_capacity(topic.partition_data.len());
+        for partition in topic.partition_data {
+            let index = partition.index;
+            let response = if !valid_acks {
+                error_response(index, INVALID_REQUIRED_ACKS, None)
+            } else {
+                match append(&topic.name, partition, api_version, state).await {
+                    Ok(info) => {
+                        if acks == -1 {
+                            pending.push(PendingAck {
+                                topic: responses.len(),
+                                partition: partition_responses.len(),
+                                topic_partition: TopicPartition::new(&topic.name, index),
+                                required_offset: info.last_offset + 1,
+                            });
+                        }
+                        PartitionProduceResponse {
+                            index,
+                            error_code: NONE,
+                            base_offset: info.first_offset,
+                            log_append_time_ms: info.log_append_time,
+                            log_start_offset: info.log_start_offset,
+                            ..Default::default()
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Produce to {}-{} failed: {}", topic.name, index, e);
+                        error_response(index, e.error_code(), Some(e.to_string()))
+                    }
+                }
+            };
+            partition_responses.push(response);
+        }
+        responses.push(TopicProduceResponse {
+            name: topic.name,
+            partition_responses,
+            ..Default::default()
+        });
+    }
+
+    if acks == 0 {
+        return None;
+    }
+    for timed_out in wait_for_high_watermarks(pending, request.timeout_ms, state).await {
+        warn!(
+            "Produce to {} timed out waiting for the high watermark to reach {}",
+            timed_out.topic_partition, timed_out.required_offset
+        );
+        let response = &mut responses[timed_out.topic].partition_responses[timed_out.partition];
+        *response = error_response(response.index, REQUEST_TIMED_OUT, None);
+    }
+    Some(ProduceResponse {
+        responses,
+        ..Default::default()
+    })
+}
+
+/// A partition of an `acks=-1` request whose records must be below the high watermark before
+/// it is answered.
+struct PendingAck {
+    /// The index of the topic in the response.
+    topic: usize,
+    /// The index of the partition in the topic's response.
+    partition: usize,
+    topic_partition: TopicPartition,
+    /// The offset after the last appended record.
+    required_offset: i64,
+}
+
+/// Waits until the high watermark of every partition in `pending` has reached its required
+/// offset, or `timeout_ms` has passed. Returns the partitions still short of it.
+async fn wait_for_high_watermarks(
+    mut pending: Vec<PendingAck>,
+    timeout_ms: i32,
+    state: &BrokerState,
+) -> Vec<PendingAck> {
+    let deadline = Instant::now() + Duration::from_millis(timeout_ms.max(0) as u64);
+    while !pending.is_empty() {
+        let keys: Vec<_> = pending
+            .iter()
+            .map(|ack| DelayedOperationKey::TopicPartition(ack.topic_partition.clone()))
+            .collect();
+        // Watch the partitions before checking them, so a move can't slip in between.
+        let watch = state.produce_purgatory.watch(&keys, deadline);
+
+        let mut remaining = Vec::new();
+        for ack in pending {
+            let high_watermark = match state.logs.get(&ack.topic_partition).await {
+                // With a single replica, the high watermark is the log end offset.
+                Some(log) => log.lock().await.log_end_offset(),
+                None => -1,
+            };
+            if high_watermark < ack.required_offset {
+                remaining.push(ack);
+            }
+        }
+        pending = remaining;
+        if pending.is_empty() || watch.expired() {
+            break;
+        }
+        watch.wait().await;
+    }
+    pending
+}
+
+/// Appends the batch in `partition` to the log of `topic`/`partition.index`.
+async fn append(
+    topic: &str,
+    partition: PartitionProduceData,
+    api_version: i16,
+    state: &BrokerState,
+) -> KafkaResult<LogAppendInfo> {
+    let unknown = || KafkaBrokerError::UnknownTopicOrPartition {
+        topic: topic.to_string(),
+        partition: partition.index,
+    };
+
+    let leader_epoch = {
+        let topics = state.topics.read().await;
+        let topic = topics.get(topic).ok_or_else(unknown)?;
+        topic
+            .partitions
+            .iter()
+            .find(|p| p.index == partition.index)
+            .ok_or_else(unknown)?
+            .leader_epoch
+    };
+    let topic_partition = TopicPartition::new(topic, partition.index);
+    let log = state.logs.get(&topic_partition).await.ok_or_else(unknown)?;
+
+    // Requests are parsed into memory, so this never reads a file.
+    let records = partition.records.unwrap_or_default().into_bytes()?;
+    if api_version >= 3 {
+        if let Some(magic) = peek_magic(&records).filter(|&magic| magic != MAGIC_V2) {
+            return Err(KafkaBrokerError::InvalidRecord(format!(
+                "Produce requests with version 3+ must use record batch magic v2, got v{}",
+                magic
+            )));
+        }
+    }
+    let now_ms = now_ms();
+    let info =
+        storage::with_log(&log, move |log| log.append(&records, leader_epoch, now_ms)).await?;
+    state.complete_delayed_requests(&topic_partition);
+    Ok(info)
+}
+
+/// Builds the response entry for a partition whose batch was rejected.
+fn error_response(
+    index: i32,
+    error_code: i16,
+    error_message: Option<String>,
+) -> PartitionProduceResponse {
+    PartitionProduceResponse {
+        index,
+        error_code,
+        base_offset: -1,
+        error_message,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::kafka_protocol::kafka_error_codes::{INVALID_RECORD, UNKNOWN_TOPIC_OR_PARTITION};
+    use crate::kafka_protocol::kafka_message_set::{LegacyMessage, MAGIC_V1};
+    use crate::kafka_protocol::kafka_messages::produce_request::TopicProduceData;
+    use crate::kafka_protocol::kafka_records::Records;
+    use crate::storage::tests::batch;
+
+    /// A produce of `records` to each partition of `partitions`, by topic.
+    fn request(acks: i16, partitions: &[(&str, &[i32])], records: &[u8]) -> ProduceRequest {
+        ProduceRequest {
+            acks,
+            timeout_ms: 1000,
+            topic_data: partitions
+                .iter()
+                .map(|&(name, indexes)| TopicProduceData {
+                    name: name.to_string(),
+                    partition_data: indexes
+                        .iter()
+                        .map(|&index| PartitionProduceData {
+                            index,
+                            records: Some(Records::Bytes(records.to_vec())),
+                            ..Default::default()
+                        })
+                        .collect(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn records() -> Vec<u8> {
+        batch(1000, &[("a", Some("1")), ("b", Some("2"))])
+    }
+
+    /// The `(topic, index, error_code, base_offset)` of each partition in `response`.
+    fn results(response: ProduceResponse) -> Vec<(String, i32, i16, i64)> {
+        response
+            .responses
+            .into_iter()
+            .flat_map(|topic| {
+                let name = topic.name;
+                topic.partition_responses.into_iter().map(move |partition| {
+                    (
+                        name.clone(),
+                        partition.index,
+                        partition.error_code,
+                        partition.base_offset,
+                    )
+                })
+            })
+            .collect()
+    }
+
+    async fn log_end_offset(state: &BrokerState, partition: i32) -> i64 {
+        let log = state.logs.get(&TopicPartition::new("t", partition)).await;
+        log.unwrap().lock().await.log_end_offset()
+    }
+
+    async fn load(dir: &std::path::Path) -> BrokerState {
+        let state = BrokerState::load(Config::for_tests(dir)).await.unwrap();
+        state.get_or_create_topic("t", 2).await.unwrap();
+        state
+    }
+
+    #[tokio::test]
+    async fn acks_0_appends_without_a_response() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = load(dir.path()).await;
+
+        let response = handle(request(0, &[("t", &[0])], &records()), 9, &state).await;
+        assert!(response.is_none());
+        assert_eq!(log_end_offset(&state, 0).await, 2);
+    }
+
+    #[tokio::test]
+    async fn invalid_acks_fail_every_partition_without_appending() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = load(dir.path()).await;
+
+        let response = handle(request(2, &[("t", &[0, 1])], &records()), 9, &state).await;
+        let expected = [0, 1].map(|index| ("t".to_string(), index, INVALID_REQUIRED_ACKS, -1));
+        assert_eq!(results(response.unwrap()), expected);
+        assert_eq!(log_end_offset(&state, 0).await, 0);
+        assert_eq!(log_end_offset(&state, 1).await, 0);
+    }
+
+    #[tokio::test]
+    async fn unknown_partitions_fail_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = load(dir.path()).await;
+        let partitions: &[(&str, &[i32])] = &[("t", &[1, 5, 0]), ("unknown", &[0]), ("t", &[1])];
+
+        let response = handle(request(-1, partitions, &records()), 9, &state).await;
+        assert_eq!(
+            results(response.unwrap()),
+            [
+                ("t".to_string(), 1, NONE, 0),
+                ("t".to_string(), 5, UNKNOWN_TOPIC_OR_PARTITION, -1),
+                ("t".to_string(), 0, NONE, 0),
+                ("unknown".to_string(), 0, UNKNOWN_TOPIC_OR_PARTITION, -1),
+                ("t".to_string(), 1, NONE, 2),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn version_3_and_later_only_take_record_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = load(dir.path()).await;
+        let message = LegacyMessage {
+            offset: 0,
+            magic: MAGIC_V1,
+            attributes: 0,
+            timestamp: 1000,
+            key: None,
+            value: Some(b"v".to_vec()),
+        };
+        let mut message_set = Vec::new();
+        message.write(&mut message_set);
+
+        let response = handle(request(1, &[("t", &[0])], &message_set), 3, &state).await;
+        assert_eq!(
+            results(response.unwrap()),
+            [("t".to_string(), 0, INVALID_RECORD, -1)]
+        );
+        let response = handle(request(1, &[("t", &[0])], &message_set), 2, &state).await;
+        assert_eq!(results(response.unwrap()), [("t".to_string(), 0, NONE, 0)]);
+        assert_eq!(log_end_offset(&state, 0).await, 1);
+    }
+}
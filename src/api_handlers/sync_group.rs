@@ -0,0 +1,54 @@
+//! SyncGroup (key 14).
+//!
+//! The second step of a rebalance: the leader sends the assignment it computed for every
+//! member, and each member receives its own. Requests from the other members are held until
+//! the leader's arrives.
+
+use crate::broker_state::BrokerState;
+use crate::group_coordinator::SyncRequest;
+use crate::kafka_protocol::kafka_error_codes::NONE;
+use crate::kafka_protocol::kafka_messages::{SyncGroupRequest, SyncGroupResponse};
+use tracing::{debug, info};
+
+/// Answers a SyncGroup request.
+pub async fn handle(
+    request: SyncGroupRequest,
+    api_version: i16,
+    state: &BrokerState,
+) -> SyncGroupResponse {
+    debug!(
+        "SyncGroup v{} of member {} of group {} for generation {}",
+        api_version, request.member_id, request.group_id, request.generation_id
+    );
+    let member_id = request.member_id.clone();
+    let sync = SyncRequest {
+        group_id: request.group_id,
+        generation_id: request.generation_id,
+        member_id: request.member_id,
+        group_instance_id: request.group_instance_id,
+        protocol_type: request.protocol_type,
+        protocol_name: request.protocol_name,
+        assignments: request
+            .assignments
+            .into_iter()
+            .map(|assignment| (assignment.member_id, assignment.assignment))
+            .collect(),
+    };
+
+    match state.group_coordinator.sync_group(sync).await {
+        Ok(result) => SyncGroupResponse {
+            error_code: NONE,
+            protocol_type: result.protocol_type,
+            protocol_name: result.protocol_name,
+            assignment: result.assignment,
+            ..Default::default()
+        },
+        Err(e) => {
+            info!("SyncGroup of member {} failed: {}", member_id, e);
+            SyncGroupResponse {
+                error_code: e.error_code(),
+                ..Default::default()
+            }
+        }
+    }
+}
@@ -4,9 +4,25 @@
 //! consumer groups, offsets, or any other data we need to share between client
 //! handlers.
 
+use crate::config::Config;
+use crate::group_coordinator::{GroupConfig, GroupCoordinator, GROUP_METADATA_TOPIC_NAME};
+use crate::kafka_protocol::kafka_error::KafkaResult;
+use crate::producer_id_manager::ProducerIdManager;
+use crate::purgatory::{DelayedOperationKey, Purgatory};
+use crate::storage::{CleanupPolicy, CompressionType, LogConfig, LogManager, TopicPartition};
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+use uuid::Uuid;
+
+/// The longest topic name Kafka accepts.
+const MAX_TOPIC_NAME_LENGTH: usize = 249;
+
+/// The topics Kafka reports as internal: those of the group and transaction coordinators.
+/// Other topics starting with `__` are ordinary topics.
+const INTERNAL_TOPICS: [&str; 2] = [GROUP_METADATA_TOPIC_NAME, "__transaction_state"];
 
 /// A shared state structure that holds Kafka-related broker data.
 ///
@@ -14,18 +30,278 @@ use tokio::sync::RwLock;
 /// The `RwLock` allows concurrent reads but exclusive writes.
 /// Use `Arc<BrokerState>` when sharing across tasks.
 pub struct BrokerState {
-    /// A placeholder map. Replace `String` with more complex structs if needed.
-    pub _topics: RwLock<HashMap<String, String>>,
+    /// The broker configuration (node id, advertised address, topic defaults, ...).
+    pub config: Config,
+
+    /// All topics in the cluster, keyed by name.
+    pub topics: RwLock<HashMap<String, Topic>>,
+
+    /// The logs of every partition hosted by this broker.
+    pub logs: Arc<LogManager>,
+
+    /// Fetches waiting for `min_bytes` of data, keyed by partition.
+    pub fetch_purgatory: Purgatory,
+
+    /// `acks=-1` produces waiting for the high watermark to pass their records, keyed by
+    /// partition.
+    pub produce_purgatory: Purgatory,
+
+    /// The consumer groups coordinated by this broker.
+    pub group_coordinator: GroupCoordinator,
+
+    /// Hands out the ids of idempotent producers.
+    pub producer_ids: ProducerIdManager,
+}
+
+/// A topic and the assignment of its partitions.
+#[derive(Debug, Clone)]
+pub struct Topic {
+    /// The topic name.
+    pub name: String,
+    /// The topic id, assigned when the topic is created.
+    pub topic_id: Uuid,
+    /// Whether this is an internal topic (e.g. `__consumer_offsets`).
+    pub is_internal: bool,
+    /// The topic's partitions, indexed by partition number.
+    pub partitions: Vec<Partition>,
+}
+
+/// The replica assignment of a single partition.
+#[derive(Debug, Clone)]
+pub struct Partition {
+    /// The partition index within its topic.
+    pub index: i32,
+    /// The broker currently leading the partition.
+    pub leader_id: i32,
+    /// The epoch of the current leader.
+    pub leader_epoch: i32,
+    /// Every broker holding a replica of the partition.
+    pub replicas: Vec<i32>,
+    /// The replicas that are in sync with the leader.
+    pub isr: Vec<i32>,
 }
 
 impl BrokerState {
-    /// Constructs a new, empty `BrokerState`.
-    pub fn new() -> Self {
+    /// Constructs the `BrokerState`, loading the partition logs found in the configured log
+    /// directories and recreating their topics. The `__consumer_offsets` topic is created if
+    /// needed, and the consumer groups are rebuilt from it. Producer ids resume after the last
+    /// block reserved in the first log directory.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a log directory can't be read or holds a damaged log.
+    pub async fn load(config: Config) -> KafkaResult<Self> {
+        // Check every topic-level override up front, so a typo fails the startup rather than
+        // the topic's first use.
+        for topic in config.topic_configs.keys() {
+            log_config(&config, topic)?;
+        }
+        let logs = Arc::new(LogManager::open(config.log_dirs.clone(), |topic| {
+            log_config(&config, topic)
+        })?);
+        let producer_ids = ProducerIdManager::open(
+            config
+                .log_dirs
+                .first()
+                .expect("at least one log directory is configured"),
+        )?;
+
+        let mut topics: HashMap<String, Topic> = HashMap::new();
+        for (topic_partition, topic_id) in logs.partitions().await {
+            let num_partitions = topics
+                .get(&topic_partition.topic)
+                .map_or(0, |topic| topic.partitions.len() as i32)
+                .max(topic_partition.partition + 1);
+            topics.insert(
+                topic_partition.topic.clone(),
+                Topic {
+                    topic_id,
+                    ..Topic::new(&topic_partition.topic, num_partitions, config.node_id)
+                },
+            );
+        }
+        info!("Loaded {} topics from the log directories", topics.len());
+
+        // The number of partitions of an existing __consumer_offsets topic can't change, as
+        // groups would move to other partitions.
+        let offsets_partitions = topics
+            .get(GROUP_METADATA_TOPIC_NAME)
+            .map_or(config.offsets_topic_num_partitions, |topic| {
+                topic.partitions.len() as i32
+            });
+        let state = Self {
+            group_coordinator: GroupCoordinator::new(
+                GroupConfig::from_config(&config),
+                logs.clone(),
+                offsets_partitions,
+            ),
+            config,
+            topics: RwLock::new(topics),
+            logs,
+            fetch_purgatory: Purgatory::new("fetches"),
+            produce_purgatory: Purgatory::new("produces"),
+            producer_ids,
+        };
+        state
+            .get_or_create_topic(GROUP_METADATA_TOPIC_NAME, offsets_partitions)
+            .await?;
+        state.group_coordinator.load().await?;
+        Ok(state)
+    }
+
+    /// Creates `name` with `num_partitions` partitions, all led by this broker, unless it
+    /// already exists. Returns the topic either way.
+    ///
+    /// A new topic gets an empty log per partition, configured from the broker's topic
+    /// defaults and the topic's overrides. The caller is responsible for validating the name with
+    /// [`validate_topic_name`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the topic's overrides are invalid or a partition log can't be
+    /// created.
+    pub async fn get_or_create_topic(&self, name: &str, num_partitions: i32) -> KafkaResult<Topic> {
+        let mut topics = self.topics.write().await;
+        if let Some(topic) = topics.get(name) {
+            return Ok(topic.clone());
+        }
+
+        let topic = Topic::new(name, num_partitions, self.config.node_id);
+        let log_config = log_config(&self.config, name)?;
+        for partition in &topic.partitions {
+            self.logs
+                .get_or_create(
+                    &TopicPartition::new(name, partition.index),
+                    topic.topic_id,
+                    &log_config,
+                )
+                .await?;
+        }
+        topics.insert(name.to_string(), topic.clone());
+        Ok(topic)
+    }
+
+    /// Wakes the fetches and `acks=-1` produces waiting on `topic_partition`, after an append
+    /// has moved its log end offset and, with it, its high watermark.
+    pub fn complete_delayed_requests(&self, topic_partition: &TopicPartition) {
+        let key = DelayedOperationKey::TopicPartition(topic_partition.clone());
+        self.fetch_purgatory.check_and_complete(&key);
+        self.produce_purgatory.check_and_complete(&key);
+    }
+
+    /// Expires delayed requests as their deadlines pass, until `shutdown_token` is cancelled.
+    /// Requests still waiting then are answered at once.
+    pub async fn run_purgatory_reapers(&self, shutdown_token: CancellationToken) {
+        tokio::join!(
+            self.fetch_purgatory.run_reaper(shutdown_token.clone()),
+            self.produce_purgatory.run_reaper(shutdown_token),
+        );
+    }
+}
+
+/// The log settings of `topic`: the broker's topic defaults, with the topic's overrides from
+/// `TOPIC_CONFIGS` applied. `__consumer_offsets` is compacted, keeps whatever the coordinator
+/// writes uncompressed, and has its own segment size (`offsets.topic.segment.bytes`).
+fn log_config(config: &Config, topic: &str) -> KafkaResult<LogConfig> {
+    let mut log_config = LogConfig {
+        message_timestamp_type: config.log_message_timestamp_type,
+        max_message_bytes: config.message_max_bytes,
+        compression_type: config.compression_type,
+        segment_bytes: config.log_segment_bytes,
+        segment_ms: config.log_roll_ms,
+        index_interval_bytes: config.log_index_interval_bytes,
+        retention_ms: config.log_retention_ms,
+        retention_bytes: config.log_retention_bytes,
+        cleanup_policy: config.log_cleanup_policy,
+        delete_retention_ms: config.log_cleaner_delete_retention_ms,
+        min_cleanable_dirty_ratio: config.log_cleaner_min_cleanable_ratio,
+        min_compaction_lag_ms: config.log_cleaner_min_compaction_lag_ms,
+        max_compaction_lag_ms: config.log_cleaner_max_compaction_lag_ms,
+        flush_messages: config.log_flush_interval_messages,
+        flush_ms: config.log_flush_interval_ms,
+        producer_id_expiration_ms: config.producer_id_expiration_ms,
+    };
+    if topic == GROUP_METADATA_TOPIC_NAME {
+        log_config.cleanup_policy = CleanupPolicy {
+            delete: false,
+            compact: true,
+        };
+        log_config.compression_type = CompressionType::Producer;
+        log_config.segment_bytes = config.offsets_topic_segment_bytes;
+    }
+    for (name, value) in config.topic_configs.get(topic).into_iter().flatten() {
+        log_config.set(name, value)?;
+    }
+    Ok(log_config)
+}
+
+impl Topic {
+    /// Creates a topic with a fresh id whose partitions are all hosted and led by `node_id`.
+    pub fn new(name: &str, num_partitions: i32, node_id: i32) -> Self {
         Self {
-            _topics: RwLock::new(HashMap::new()),
+            name: name.to_string(),
+            topic_id: Uuid::new_v4(),
+            is_internal: INTERNAL_TOPICS.contains(&name),
+            partitions: (0..num_partitions)
+                .map(|index| Partition {
+                    index,
+                    leader_id: node_id,
+                    leader_epoch: 0,
+                    replicas: vec![node_id],
+                    isr: vec![node_id],
+                })
+                .collect(),
         }
     }
 }
 
+/// Checks `name` against Kafka's topic naming rules: 1 to 249 characters from `[a-zA-Z0-9._-]`,
+/// and neither `.` nor `..`.
+///
+/// # Errors
+///
+/// Returns a description of the rule the name breaks.
+pub fn validate_topic_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("Topic name is illegal, it can't be empty".to_string());
+    }
+    if name == "." || name == ".." {
+        return Err("Topic name cannot be \".\" or \"..\"".to_string());
+    }
+    if name.len() > MAX_TOPIC_NAME_LENGTH {
+        return Err(format!(
+            "Topic name is illegal, it can't be longer than {} characters, topic name: {}",
+            MAX_TOPIC_NAME_LENGTH, name
+        ));
+    }
+    if !name
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'_' || b == b'-')
+    {
+        return Err(format!(
+            "Topic name \"{}\" is illegal, it contains a character other than ASCII alphanumerics, '.', '_' and '-'",
+            name
+        ));
+    }
+    Ok(())
+}
+
 /// A helper type alias if you like to reference `Arc<BrokerState>` often.
 pub type SharedBrokerState = Arc<BrokerState>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_coordinator_topics_are_internal() {
+        for (name, internal) in [
+            ("__consumer_offsets", true),
+            ("__transaction_state", true),
+            ("__my_topic", false),
+            ("t", false),
+        ] {
+            assert_eq!(Topic::new(name, 1, 0).is_internal, internal, "{}", name);
+        }
+    }
+}
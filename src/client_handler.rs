@@ -11,131 +11,363 @@
 //!
 //! The client will likely need to maintain a connection to multiple brokers, as data is partitioned and the clients will need to talk to the server that has their data. However it should not generally be necessary to maintain multiple connections to a single broker from a single client instance (i.e. connection pooling).
 //!
-//! The server guarantees that on a single TCP connection, requests will be processed in the order they are sent and responses will return in that order as well. The broker's request processing allows only a single in-flight request per connection in order to guarantee this ordering. Note that clients can (and ideally should) use non-blocking IO to implement request pipelining and achieve higher throughput. i.e., clients can send requests even while awaiting responses for preceding requests since the outstanding requests will be buffered in the underlying OS socket buffer. All requests are initiated by the client, and result in a corresponding response message from the server except where noted.
+//! The server guarantees that on a single TCP connection, requests will be processed in the order they are sent and responses will return in that order as well. This broker processes up to [`MAX_IN_FLIGHT_REQUESTS`] requests of a connection concurrently, so that a request parked in a purgatory doesn't hold up the ones sent after it, but queues their responses so that they still go out in request order. Note that clients can (and ideally should) use non-blocking IO to implement request pipelining and achieve higher throughput. i.e., clients can send requests even while awaiting responses for preceding requests since the outstanding requests will be buffered in the underlying OS socket buffer. All requests are initiated by the client, and result in a corresponding response message from the server except where noted.
 //!
 //! The server has a configurable maximum limit on request size and any request that exceeds this limit will result in the socket being disconnected.
+use crate::api_handlers;
 use crate::broker_state::SharedBrokerState;
+use crate::kafka_protocol::kafka_frame_codec::KafkaFrameCodec;
+use crate::kafka_protocol::kafka_records::{Chunk, EncodeBuf, FileRegion};
 use crate::kafka_protocol::kafka_request_message::KafkaRequestMessage;
+use crate::kafka_protocol::kafka_response_message::{KafkaResponse, KafkaResponseMessage};
 use anyhow::{Context, Result};
+use futures::stream::FuturesOrdered;
+use futures::StreamExt;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    io::AsyncWriteExt,
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    select,
 };
-use tracing::{debug, info, instrument, trace};
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::FramedRead;
+use tracing::{debug, info, instrument, trace, warn};
+
+/// The most requests of one connection processed at the same time. Further requests stay in
+/// the socket buffer until the oldest one has been answered.
+const MAX_IN_FLIGHT_REQUESTS: usize = 16;
 
 /// Handles a single client connection, continuously reading requests and sending responses
 /// until the client disconnects or an unrecoverable error occurs.
 ///
 /// ## Workflow
 ///
-/// 1. **Read** the raw bytes from the socket via [`read_request`].
-/// 2. **Parse** the bytes into a request object via [`parse_request`].
-/// 3. **Construct** an appropriate response using [`create_response`].
-/// 4. **Send** the response back to the client via [`send_response`].
+/// 1. **Read** the next size-delimited frame from the socket via [`read_request`].
+/// 2. **Parse** the frame into a request object via [`KafkaRequestMessage::from_bytes`].
+/// 3. **Construct** an appropriate response using [`create_response`], concurrently with the
+///    requests already in flight.
+/// 4. **Send** the oldest response back to the client via [`send_response`] once it is ready.
+///
+/// Pipelined requests are handled concurrently: while earlier requests are still being
+/// processed (a fetch waiting for data, say), later ones are read and processed too, up to
+/// [`MAX_IN_FLIGHT_REQUESTS`]. Responses are queued in the order the requests arrived, so a
+/// response that is ready early waits for those before it.
 ///
-/// This loop continues until the socket returns 0 bytes (indicating the client closed the connection)
-/// or an unrecoverable error is encountered. Errors bubble up as [`anyhow::Error`] and are handled
-/// by the caller.
+/// This loop continues until the client closes the connection and the requests in flight have
+/// been answered, or an unrecoverable error is encountered (including a frame larger than
+/// `max_request_bytes`, which closes the socket). Errors bubble up as [`anyhow::Error`] and are
+/// handled by the caller.
 ///
 /// # Parameters
 ///
 /// * `socket` - The client connection stream over which requests and responses flow.
 /// * `state` - An [`SharedBrokerState`] that contains shared broker data (topic metadata, offsets, etc.).
+/// * `max_request_bytes` - The largest request accepted (`socket.request.max.bytes`).
 #[instrument(skip(socket, state))]
-pub async fn handle_client(mut socket: TcpStream, state: SharedBrokerState) -> Result<()> {
+pub async fn handle_client(
+    socket: TcpStream,
+    state: SharedBrokerState,
+    max_request_bytes: usize,
+) -> Result<()> {
     info!("Starting client handler loop for a new connection.");
 
+    // The client's address as group members report it (`/<ip>`).
+    let client_host = socket
+        .peer_addr()
+        .map(|addr| format!("/{}", addr.ip()))
+        .unwrap_or_default();
+    let (reader, mut writer) = socket.into_split();
+    let mut frames = FramedRead::new(reader, KafkaFrameCodec::new(max_request_bytes));
+    // The requests being processed, yielding their responses in request order.
+    let mut in_flight = FuturesOrdered::new();
+    // Response buffers are reused once sent, so a connection holds at most one per request in
+    // flight.
+    let mut free_buffers: Vec<EncodeBuf> = Vec::new();
+    let mut reading = true;
+
     loop {
-        // 1) Read the request data
-        let raw_data = read_request(&mut socket).await?;
+        let can_read = reading && in_flight.len() < MAX_IN_FLIGHT_REQUESTS;
+        select! {
+            // 1) Read the next request frame, unless too many requests are in flight
+            frame = read_request(&mut frames), if can_read => {
+                let Some(raw_data) = frame? else {
+                    info!(
+                        "Client appears to have disconnected; finishing {} in-flight request(s).",
+                        in_flight.len()
+                    );
+                    reading = false;
+                    continue;
+                };
 
-        // If no data is returned, it typically means the client closed the connection.
-        if raw_data.is_empty() {
-            info!("Client appears to have disconnected; ending client handler loop.");
-            break;
-        }
+                // 2) Parse the request
+                debug!(
+                    "Attempting to parse request ({} bytes) from client.",
+                    raw_data.len()
+                );
+                let request_message = KafkaRequestMessage::from_bytes(&raw_data)?;
 
-        // 2) Parse the request
-        debug!(
-            "Attempting to parse request ({} bytes) from client.",
-            raw_data.len()
-        );
-        let request_message = KafkaRequestMessage::from_bytes(&raw_data)?;
+                // Log the parsed request object at debug level:
+                debug!("Parsed KafkaRequestMessage: {:#?}", request_message);
 
-        // Log the parsed request object at debug level:
-        debug!("Parsed KafkaRequestMessage: {:#?}", request_message);
+                // 3) Create the response alongside the requests already in flight
+                debug!("Generating response based on the parsed request.");
+                let mut response = free_buffers.pop().unwrap_or_default();
+                let state = state.clone();
+                let client_host = client_host.clone();
+                in_flight.push_back(async move {
+                    response.clear();
+                    create_response(request_message, &state, &client_host, &mut response)
+                        .await
+                        .map(|()| response)
+                });
+            }
 
-        // 3) Create the response
-        debug!("Generating response based on the parsed request.");
-        let response = create_response(request_message, &state)?;
+            // 4) Send back the oldest response once it is ready, unless the request expects
+            //    none (Produce with acks=0)
+            Some(response) = in_flight.next() => {
+                let response = response?;
+                if response.is_empty() {
+                    debug!("Request expects no response; nothing to send.");
+                } else {
+                    debug!(
+                        "Sending response ({} bytes) to client.",
+                        response.encoded_len()
+                    );
+                    send_response(&mut writer, &response).await?;
+                }
+                free_buffers.push(response);
+            }
 
-        // 4) Send back the response
-        debug!("Sending response ({} bytes) to client.", response.len());
-        send_response(&mut socket, &response).await?;
+            else => break,
+        }
     }
 
     info!("Client handler loop has finished normally.");
     Ok(())
 }
 
-/// Reads a chunk of bytes from the socket into memory (up to 1024 bytes).
+/// Reads the next complete request frame (size prefix included) from the socket.
 ///
-/// In a real Kafka broker, you'd likely use a protocol-aware reader to handle
-/// variable-length messages or correlation IDs. For now, we do a simple, single read call.
+/// Frames are cut out of the byte stream by [`KafkaFrameCodec`], so requests that span several
+/// TCP segments are reassembled and several requests arriving in one read are returned one by one.
+///
+/// Returns `Ok(None)` once the client has closed the connection.
 ///
 /// # Errors
 ///
-/// Returns a [`std::io::Error`] (wrapped in [`anyhow::Error`]) if the read fails.
-/// This could be due to network issues, client disconnection, or other I/O errors.
-async fn read_request(socket: &mut TcpStream) -> Result<Vec<u8>> {
-    let mut buf = [0u8; 1024];
-    let bytes_read = socket
-        .read(&mut buf)
-        .await
-        .context("Failed to read from socket")?;
-
-    debug!("read_request: read {} bytes from the socket.", bytes_read);
+/// Returns an [`anyhow::Error`] if the read fails (network issues, a connection closed in the
+/// middle of a frame) or if the frame exceeds `socket.request.max.bytes`.
+async fn read_request(
+    frames: &mut FramedRead<OwnedReadHalf, KafkaFrameCodec>,
+) -> Result<Option<BytesMut>> {
+    let Some(frame) = frames.next().await else {
+        return Ok(None);
+    };
+    let frame = frame.context("Failed to read request frame from socket")?;
 
-    // If `bytes_read` is 0, the client is likely done (EOF).
-    Ok(buf[..bytes_read].to_vec())
+    debug!(
+        "read_request: read a {} byte frame from the socket.",
+        frame.len()
+    );
+    Ok(Some(frame))
 }
 
-/// Constructs a response based on the parsed request and the shared broker state.
+/// Constructs a response based on the parsed request, received from `client_host`, and the
+/// shared broker state.
 ///
-/// For instance, if the request is a "fetch" request, you might look up the topic data
-/// in [`BrokerState`], assemble the latest messages, and encode them into a response frame.
-/// In a real implementation, you’d also handle errors (e.g., unknown topic) gracefully.
+/// The request is routed to its per-API handler via [`api_handlers::dispatch`], and the
+/// resulting payload is framed together with the response header and appended to `buf`.
+/// Requests that must not be answered (Produce with `acks=0`) leave `buf` untouched. If the
+/// dispatcher cannot serve the request (an unknown API key), the error is logged and the
+/// client receives an error response carrying
+/// [`KafkaBrokerError::error_code`](crate::kafka_protocol::kafka_error::KafkaBrokerError::error_code)
+/// instead of having its socket dropped.
 ///
 /// # Errors
 ///
-/// Returns an [`anyhow::Error`] if any error occurs while building the response (for example,
-/// if data required from [`BrokerState`] is unavailable or the serialization fails).
-fn create_response(
-    _request_message: KafkaRequestMessage,
-    _state: &SharedBrokerState,
-) -> Result<Vec<u8>> {
-    // Eventually, this will produce a meaningful byte buffer representing the response.
-    todo!("Implement response creation logic (e.g., produce/fetch responses)");
+/// Currently infallible; the `Result` leaves room for failures while encoding the response.
+async fn create_response(
+    request_message: KafkaRequestMessage,
+    state: &SharedBrokerState,
+    client_host: &str,
+    buf: &mut EncodeBuf,
+) -> Result<()> {
+    let api_key = request_message.header.request_api_key();
+    let correlation_id = request_message.header.correlation_id();
+    let api_version = request_message.header.request_api_version();
+
+    let response = match api_handlers::dispatch(request_message, state, client_host).await {
+        Ok(Some(response)) => response,
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            warn!("Request {} failed: {}", correlation_id, e);
+            KafkaResponseMessage::new(
+                api_key,
+                correlation_id,
+                api_version,
+                KafkaResponse::Error {
+                    error_code: e.error_code(),
+                },
+            )
+        }
+    };
+
+    response.encode_into(buf);
+    Ok(())
 }
 
-/// Sends the response bytes back to the client by writing them to the TCP socket.
+/// Sends the response back to the client by writing it to the TCP socket.
 ///
-/// This function handles writing the entire buffer, retrying as needed until all bytes
-/// are sent or an error occurs. In a production scenario, you might split large responses
-/// into multiple chunks or handle backpressure scenarios differently.
+/// The encoded bytes (headers, metadata, in-memory records) are written as they are, while the
+/// file regions between them are sent straight from the segment files with [`send_file`], so
+/// record data never passes through user space on Linux.
 ///
 /// # Errors
 ///
-/// Returns an [`anyhow::Error`] if the write operation fails (e.g., client disconnects mid-write).
-async fn send_response(socket: &mut TcpStream, response: &[u8]) -> Result<()> {
-    socket
-        .write_all(response)
-        .await
-        .context("Failed to write response to socket")?;
+/// Returns an [`anyhow::Error`] if the write operation fails (e.g., client disconnects mid-write)
+/// or a file region can't be read.
+async fn send_response(socket: &mut OwnedWriteHalf, response: &EncodeBuf) -> Result<()> {
+    for chunk in response.chunks() {
+        match chunk {
+            Chunk::Bytes(bytes) => socket
+                .write_all(bytes)
+                .await
+                .context("Failed to write response to socket")?,
+            Chunk::File(region) => send_file(socket, region)
+                .await
+                .context("Failed to send record data to socket")?,
+        }
+    }
 
     trace!(
         "send_response: successfully wrote {} bytes to the client.",
-        response.len()
+        response.encoded_len()
     );
     Ok(())
 }
+
+/// Sends `region` to the socket with `sendfile(2)`, which copies it from the page cache to the
+/// socket inside the kernel.
+///
+/// # Errors
+///
+/// Returns an error if `sendfile` fails, or the file ends before the region does.
+#[cfg(target_os = "linux")]
+async fn send_file(socket: &mut OwnedWriteHalf, region: &FileRegion) -> std::io::Result<()> {
+    use std::io;
+    use std::os::fd::AsRawFd;
+    use tokio::io::Interest;
+
+    let stream = socket.as_ref();
+    let file_fd = region.file().as_raw_fd();
+    let mut offset = region.position() as libc::off_t;
+    let mut remaining = region.len();
+    while remaining > 0 {
+        stream.writable().await?;
+        let sent = stream.try_io(Interest::WRITABLE, || {
+            // SAFETY: both descriptors stay open for the duration of the call, and `offset` is
+            // a valid pointer to an `off_t`.
+            let sent =
+                unsafe { libc::sendfile(stream.as_raw_fd(), file_fd, &mut offset, remaining) };
+            if sent < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(sent as usize)
+        });
+        match sent {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "segment file ended before the fetched region",
+                ))
+            }
+            Ok(sent) => remaining -= sent,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Sends `region` to the socket by reading it into memory first, where `sendfile(2)` isn't
+/// available.
+///
+/// # Errors
+///
+/// Returns an error if the region can't be read or written.
+#[cfg(not(target_os = "linux"))]
+async fn send_file(socket: &mut OwnedWriteHalf, region: &FileRegion) -> std::io::Result<()> {
+    socket.write_all(&region.read()?).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker_state::BrokerState;
+    use crate::config::Config;
+    use crate::kafka_protocol::kafka_api_keys::ApiKey;
+    use crate::kafka_protocol::kafka_error_codes::{NONE, UNSUPPORTED_VERSION};
+    use std::sync::Arc;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    /// Frames a request with header v2 (v1 if `flexible` is false) and an empty body.
+    fn request(api_key: ApiKey, api_version: i16, correlation_id: i32, flexible: bool) -> Vec<u8> {
+        let mut message = Vec::new();
+        message.extend_from_slice(&(api_key as i16).to_be_bytes());
+        message.extend_from_slice(&api_version.to_be_bytes());
+        message.extend_from_slice(&correlation_id.to_be_bytes());
+        message.extend_from_slice(&(-1i16).to_be_bytes()); // null client id
+        if flexible {
+            message.push(0); // no tagged fields
+        }
+        let mut frame = (message.len() as i32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&message);
+        frame
+    }
+
+    async fn read_response(socket: &mut TcpStream) -> Vec<u8> {
+        let size = socket.read_i32().await.unwrap();
+        let mut response = vec![0; size as usize];
+        socket.read_exact(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn unsupported_versions_get_an_error_response_and_keep_the_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = Arc::new(
+            BrokerState::load(Config::for_tests(dir.path()))
+                .await
+                .unwrap(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        tokio::spawn(handle_client(socket, state, 1 << 20));
+
+        // Heartbeat v99 is answered as the latest (flexible) Heartbeat: a response header with
+        // tagged fields, then throttle_time_ms and the error code.
+        client
+            .write_all(&request(ApiKey::Heartbeat, 99, 7, true))
+            .await
+            .unwrap();
+        let response = read_response(&mut client).await;
+        assert_eq!(response[..4], 7i32.to_be_bytes());
+        assert_eq!(response[4], 0);
+        assert_eq!(response[9..11], UNSUPPORTED_VERSION.to_be_bytes());
+
+        // The connection is still usable.
+        client
+            .write_all(&request(ApiKey::ApiVersions, 0, 8, false))
+            .await
+            .unwrap();
+        let response = read_response(&mut client).await;
+        assert_eq!(response[..4], 8i32.to_be_bytes());
+        assert_eq!(response[4..6], NONE.to_be_bytes());
+    }
+}
@@ -1,14 +1,19 @@
 //! Defines configuration for our Kafka broker, including reading
 //! from environment variables or an optional `.env` file.
 
+use crate::group_coordinator::Assignor;
+use crate::storage::{CleanupPolicy, CompressionType, TimestampType};
+use anyhow::bail;
+use std::collections::HashMap;
 use std::env;
+use std::path::PathBuf;
 use tracing::{debug, info, warn};
 
 /// Represents the runtime configuration for the Kafka broker.
 ///
 /// Constructed by reading environment variables (optionally from a `.env` file)
 /// and falling back to sensible defaults if missing.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Config {
     /// The host/IP address to bind the broker to.
     pub host: String,
@@ -16,6 +21,105 @@ pub struct Config {
     pub port: u16,
     /// Timeout in seconds for draining client tasks during shutdown.
     pub client_drain_timeout_secs: u64,
+    /// The largest request (in bytes) a client may send (`socket.request.max.bytes`).
+    /// Connections sending anything larger are closed.
+    pub socket_request_max_bytes: usize,
+    /// The host advertised to clients in Metadata responses. Defaults to `host`.
+    pub advertised_host: String,
+    /// This broker's id (`node.id`).
+    pub node_id: i32,
+    /// The id of the cluster this broker belongs to, reported in Metadata responses.
+    pub cluster_id: String,
+    /// The number of partitions given to auto-created topics (`num.partitions`).
+    pub num_partitions: i32,
+    /// Whether topics are created on first use (`auto.create.topics.enable`).
+    pub auto_create_topics_enable: bool,
+    /// The default `message.timestamp.type` of topics (`log.message.timestamp.type`).
+    pub log_message_timestamp_type: TimestampType,
+    /// The default `max.message.bytes` of topics (`message.max.bytes`).
+    pub message_max_bytes: usize,
+    /// The default `compression.type` of topics.
+    pub compression_type: CompressionType,
+    /// The directories partition logs are stored in (`log.dirs`).
+    pub log_dirs: Vec<PathBuf>,
+    /// The default `segment.bytes` of topics (`log.segment.bytes`).
+    pub log_segment_bytes: u64,
+    /// The default `segment.ms` of topics (`log.roll.ms`).
+    pub log_roll_ms: i64,
+    /// The default `index.interval.bytes` of topics (`log.index.interval.bytes`).
+    pub log_index_interval_bytes: usize,
+    /// The default `retention.ms` of topics (`log.retention.ms`); -1 keeps records forever.
+    pub log_retention_ms: i64,
+    /// The default `retention.bytes` of topics (`log.retention.bytes`); -1 means no limit.
+    pub log_retention_bytes: i64,
+    /// How often logs are checked for segments to delete (`log.retention.check.interval.ms`).
+    pub log_retention_check_interval_ms: u64,
+    /// The default `cleanup.policy` of topics (`log.cleanup.policy`).
+    pub log_cleanup_policy: CleanupPolicy,
+    /// Whether compacted logs are cleaned (`log.cleaner.enable`).
+    pub log_cleaner_enable: bool,
+    /// How long the cleaner waits between passes over the logs (`log.cleaner.backoff.ms`).
+    pub log_cleaner_backoff_ms: u64,
+    /// The default `delete.retention.ms` of topics (`log.cleaner.delete.retention.ms`).
+    pub log_cleaner_delete_retention_ms: i64,
+    /// The default `min.cleanable.dirty.ratio` of topics (`log.cleaner.min.cleanable.ratio`).
+    pub log_cleaner_min_cleanable_ratio: f64,
+    /// The default `min.compaction.lag.ms` of topics (`log.cleaner.min.compaction.lag.ms`).
+    pub log_cleaner_min_compaction_lag_ms: i64,
+    /// The default `max.compaction.lag.ms` of topics (`log.cleaner.max.compaction.lag.ms`).
+    pub log_cleaner_max_compaction_lag_ms: i64,
+    /// The default `flush.messages` of topics (`log.flush.interval.messages`).
+    pub log_flush_interval_messages: i64,
+    /// The default `flush.ms` of topics (`log.flush.interval.ms`).
+    pub log_flush_interval_ms: i64,
+    /// How often logs are checked for a flush due by `flush.ms`
+    /// (`log.flush.scheduler.interval.ms`).
+    pub log_flush_scheduler_interval_ms: u64,
+    /// How often the recovery points of logs are checkpointed
+    /// (`log.flush.offset.checkpoint.interval.ms`).
+    pub log_flush_offset_checkpoint_interval_ms: u64,
+    /// How long the state of an idempotent producer that stopped writing to a partition is kept
+    /// (`producer.id.expiration.ms`).
+    pub producer_id_expiration_ms: i64,
+    /// The shortest session timeout a group member may ask for
+    /// (`group.min.session.timeout.ms`).
+    pub group_min_session_timeout_ms: i32,
+    /// The longest session timeout a group member may ask for
+    /// (`group.max.session.timeout.ms`).
+    pub group_max_session_timeout_ms: i32,
+    /// How long the first rebalance of an empty group waits for more members to join
+    /// (`group.initial.rebalance.delay.ms`).
+    pub group_initial_rebalance_delay_ms: i32,
+    /// The most members a group may have (`group.max.size`).
+    pub group_max_size: usize,
+    /// How long a consumer group member may go without heartbeating
+    /// (`group.consumer.session.timeout.ms`).
+    pub group_consumer_session_timeout_ms: i32,
+    /// How often consumer group members are told to heartbeat
+    /// (`group.consumer.heartbeat.interval.ms`).
+    pub group_consumer_heartbeat_interval_ms: i32,
+    /// The most members a consumer group may have (`group.consumer.max.size`).
+    pub group_consumer_max_size: usize,
+    /// The server assignors consumer groups may use, the first being the default
+    /// (`group.consumer.assignors`).
+    pub group_consumer_assignors: Vec<Assignor>,
+    /// The number of partitions of `__consumer_offsets`, when it is created
+    /// (`offsets.topic.num.partitions`).
+    pub offsets_topic_num_partitions: i32,
+    /// The `segment.bytes` of `__consumer_offsets` (`offsets.topic.segment.bytes`).
+    pub offsets_topic_segment_bytes: u64,
+    /// How long the committed offsets of a group without members are kept
+    /// (`offsets.retention.minutes`).
+    pub offsets_retention_minutes: i64,
+    /// How often committed offsets are checked for expiration
+    /// (`offsets.retention.check.interval.ms`).
+    pub offsets_retention_check_interval_ms: u64,
+    /// The longest metadata a client may commit along with an offset
+    /// (`offset.metadata.max.bytes`).
+    pub offset_metadata_max_bytes: usize,
+    /// Topic-level settings overriding the defaults above, keyed by topic name, as
+    /// `(name, value)` pairs using the topic config names (e.g. `flush.messages`).
+    pub topic_configs: HashMap<String, Vec<(String, String)>>,
 }
 
 impl Config {
@@ -25,8 +129,9 @@ impl Config {
     ///
     /// # Errors
     ///
-    /// Returns an error if a `.env` file is found but cannot be parsed. If the
-    /// file is merely missing, a warning is logged instead of returning an error.
+    /// Returns an error if a `.env` file is found but cannot be parsed, or if
+    /// `TOPIC_CONFIGS` is malformed. If the file is merely missing, a warning is
+    /// logged instead of returning an error.
     pub fn from_env() -> anyhow::Result<Self> {
         // Attempt to load environment variables from `.env`.
         match dotenvy::dotenv() {
@@ -47,23 +152,354 @@ impl Config {
         // For debug purposes, log all environment variables.
         debug!("Environment variables: {:#?}", env::vars());
 
+        Self::from_vars(|name| env::var(name))
+    }
+
+    /// Builds the configuration from the variables `var` looks up, falling back to the
+    /// defaults for those it doesn't find.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `TOPIC_CONFIGS` or `GROUP_CONSUMER_ASSIGNORS` is malformed.
+    fn from_vars(var: impl Fn(&str) -> Result<String, env::VarError>) -> anyhow::Result<Self> {
         // Read the host/port from the environment, with defaults if missing.
-        let host = env::var("SERVER_HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
-        let port: u16 = env::var("SERVER_PORT")
+        let host = var("SERVER_HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
+        let port: u16 = var("SERVER_PORT")
             .ok()
             .and_then(|p| p.parse().ok())
             .unwrap_or(9092);
 
         // Read the drain timeout (in seconds) from environment, default to 5 if not set.
-        let client_drain_timeout_secs: u64 = env::var("CLIENT_DRAIN_TIMEOUT_SECS")
+        let client_drain_timeout_secs: u64 = var("CLIENT_DRAIN_TIMEOUT_SECS")
             .ok()
             .and_then(|v| v.parse().ok())
             .unwrap_or(5);
 
+        // Read the maximum request size, defaulting to Kafka's 100 MiB.
+        let socket_request_max_bytes: usize = var("SOCKET_REQUEST_MAX_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(104_857_600);
+
+        // Read the host advertised to clients, defaulting to the bind host.
+        let advertised_host = var("ADVERTISED_HOST").unwrap_or_else(|_| host.clone());
+
+        // Read the broker and cluster identity.
+        let node_id: i32 = var("NODE_ID")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1);
+        let cluster_id =
+            var("CLUSTER_ID").unwrap_or_else(|_| "kafka-broker-rs-cluster".to_string());
+
+        // Read the topic auto-creation settings, defaulting to Kafka's.
+        let num_partitions: i32 = var("NUM_PARTITIONS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(1);
+        let auto_create_topics_enable: bool = var("AUTO_CREATE_TOPICS_ENABLE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(true);
+
+        // Read the topic defaults for appended batches, defaulting to Kafka's.
+        let log_message_timestamp_type = var("LOG_MESSAGE_TIMESTAMP_TYPE")
+            .ok()
+            .and_then(|v| TimestampType::parse(&v))
+            .unwrap_or(TimestampType::CreateTime);
+        let message_max_bytes: usize = var("MESSAGE_MAX_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1_048_588);
+        let compression_type = var("COMPRESSION_TYPE")
+            .ok()
+            .and_then(|v| CompressionType::parse(&v))
+            .unwrap_or(CompressionType::Producer);
+
+        // Read where and how logs are stored, defaulting to Kafka's.
+        let log_dirs: Vec<PathBuf> = var("LOG_DIRS")
+            .ok()
+            .map(|v| {
+                v.split(',')
+                    .map(str::trim)
+                    .filter(|dir| !dir.is_empty())
+                    .map(PathBuf::from)
+                    .collect()
+            })
+            .filter(|dirs: &Vec<PathBuf>| !dirs.is_empty())
+            .unwrap_or_else(|| vec![PathBuf::from("/tmp/kafka-logs")]);
+        let log_segment_bytes: u64 = var("LOG_SEGMENT_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1_073_741_824);
+        let log_roll_ms: i64 = var("LOG_ROLL_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(604_800_000);
+        let log_index_interval_bytes: usize = var("LOG_INDEX_INTERVAL_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(4096);
+
+        // Read the retention settings, defaulting to Kafka's (7 days, no size limit, checked
+        // every 5 minutes).
+        let log_retention_ms: i64 = var("LOG_RETENTION_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(604_800_000);
+        let log_retention_bytes: i64 = var("LOG_RETENTION_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(-1);
+        let log_retention_check_interval_ms: u64 = var("LOG_RETENTION_CHECK_INTERVAL_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&ms| ms > 0)
+            .unwrap_or(300_000);
+
+        // Read the compaction settings, defaulting to Kafka's (delete policy, cleaner enabled,
+        // tombstones kept for a day, logs cleaned once half dirty).
+        let log_cleanup_policy = var("LOG_CLEANUP_POLICY")
+            .ok()
+            .and_then(|v| CleanupPolicy::parse(&v))
+            .unwrap_or(CleanupPolicy {
+                delete: true,
+                compact: false,
+            });
+        let log_cleaner_enable: bool = var("LOG_CLEANER_ENABLE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(true);
+        let log_cleaner_backoff_ms: u64 = var("LOG_CLEANER_BACKOFF_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&ms| ms > 0)
+            .unwrap_or(15_000);
+        let log_cleaner_delete_retention_ms: i64 = var("LOG_CLEANER_DELETE_RETENTION_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(86_400_000);
+        let log_cleaner_min_cleanable_ratio: f64 = var("LOG_CLEANER_MIN_CLEANABLE_RATIO")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|ratio| (0.0..=1.0).contains(ratio))
+            .unwrap_or(0.5);
+        let log_cleaner_min_compaction_lag_ms: i64 = var("LOG_CLEANER_MIN_COMPACTION_LAG_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        let log_cleaner_max_compaction_lag_ms: i64 = var("LOG_CLEANER_MAX_COMPACTION_LAG_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(i64::MAX);
+
+        // Read the flush settings, defaulting to Kafka's (leave syncing to the OS, checkpoint
+        // recovery points every minute).
+        let log_flush_interval_messages: i64 = var("LOG_FLUSH_INTERVAL_MESSAGES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(i64::MAX);
+        let log_flush_interval_ms: i64 = var("LOG_FLUSH_INTERVAL_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&ms| ms >= 0)
+            .unwrap_or(i64::MAX);
+        let log_flush_scheduler_interval_ms: u64 = var("LOG_FLUSH_SCHEDULER_INTERVAL_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&ms| ms > 0)
+            .unwrap_or(3000);
+        let log_flush_offset_checkpoint_interval_ms: u64 =
+            var("LOG_FLUSH_OFFSET_CHECKPOINT_INTERVAL_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|&ms| ms > 0)
+                .unwrap_or(60_000);
+        let producer_id_expiration_ms: i64 = var("PRODUCER_ID_EXPIRATION_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&ms| ms > 0)
+            .unwrap_or(86_400_000);
+
+        // Read the group coordinator settings, defaulting to Kafka's (sessions of 6 seconds to
+        // 30 minutes, a 3 second delay before the first rebalance, no limit on group size).
+        let group_min_session_timeout_ms: i32 = var("GROUP_MIN_SESSION_TIMEOUT_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&ms| ms > 0)
+            .unwrap_or(6000);
+        let group_max_session_timeout_ms: i32 = var("GROUP_MAX_SESSION_TIMEOUT_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&ms| ms >= group_min_session_timeout_ms)
+            .unwrap_or(1_800_000.max(group_min_session_timeout_ms));
+        let group_initial_rebalance_delay_ms: i32 = var("GROUP_INITIAL_REBALANCE_DELAY_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&ms| ms >= 0)
+            .unwrap_or(3000);
+        let group_max_size: usize = var("GROUP_MAX_SIZE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(usize::MAX);
+
+        // Read the consumer group (KIP-848) settings, defaulting to Kafka's (sessions of 45
+        // seconds, heartbeats every 5 seconds, the uniform assignor then the range one).
+        let group_consumer_session_timeout_ms: i32 = var("GROUP_CONSUMER_SESSION_TIMEOUT_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&ms| ms > 0)
+            .unwrap_or(45_000);
+        let group_consumer_heartbeat_interval_ms: i32 = var("GROUP_CONSUMER_HEARTBEAT_INTERVAL_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&ms| ms > 0 && ms < group_consumer_session_timeout_ms)
+            .unwrap_or(5000.min(group_consumer_session_timeout_ms / 2));
+        let group_consumer_max_size: usize = var("GROUP_CONSUMER_MAX_SIZE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(usize::MAX);
+        let group_consumer_assignors = match var("GROUP_CONSUMER_ASSIGNORS") {
+            Ok(v) => parse_assignors(&v)?,
+            Err(_) => vec![Assignor::Uniform, Assignor::Range],
+        };
+
+        // Read the committed offset settings, defaulting to Kafka's (50 partitions of 100 MiB
+        // segments, offsets of empty groups kept for 7 days and checked every 10 minutes).
+        let offsets_topic_num_partitions: i32 = var("OFFSETS_TOPIC_NUM_PARTITIONS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(50);
+        let offsets_topic_segment_bytes: u64 = var("OFFSETS_TOPIC_SEGMENT_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(104_857_600);
+        let offsets_retention_minutes: i64 = var("OFFSETS_RETENTION_MINUTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&m| m > 0)
+            .unwrap_or(10_080);
+        let offsets_retention_check_interval_ms: u64 = var("OFFSETS_RETENTION_CHECK_INTERVAL_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&ms| ms > 0)
+            .unwrap_or(600_000);
+        let offset_metadata_max_bytes: usize = var("OFFSET_METADATA_MAX_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(4096);
+
+        // Read the topic-level overrides, as `<topic>:<name>=<value>,...` entries separated by
+        // semicolons, e.g. `events:flush.messages=1,flush.ms=1000;audit:retention.ms=-1`.
+        let topic_configs = match var("TOPIC_CONFIGS") {
+            Ok(v) => parse_topic_configs(&v)?,
+            Err(_) => HashMap::new(),
+        };
+
         Ok(Self {
             host,
             port,
             client_drain_timeout_secs,
+            socket_request_max_bytes,
+            advertised_host,
+            node_id,
+            cluster_id,
+            num_partitions,
+            auto_create_topics_enable,
+            log_message_timestamp_type,
+            message_max_bytes,
+            compression_type,
+            log_dirs,
+            log_segment_bytes,
+            log_roll_ms,
+            log_index_interval_bytes,
+            log_retention_ms,
+            log_retention_bytes,
+            log_retention_check_interval_ms,
+            log_cleanup_policy,
+            log_cleaner_enable,
+            log_cleaner_backoff_ms,
+            log_cleaner_delete_retention_ms,
+            log_cleaner_min_cleanable_ratio,
+            log_cleaner_min_compaction_lag_ms,
+            log_cleaner_max_compaction_lag_ms,
+            log_flush_interval_messages,
+            log_flush_interval_ms,
+            log_flush_scheduler_interval_ms,
+            log_flush_offset_checkpoint_interval_ms,
+            producer_id_expiration_ms,
+            group_min_session_timeout_ms,
+            group_max_session_timeout_ms,
+            group_initial_rebalance_delay_ms,
+            group_max_size,
+            group_consumer_session_timeout_ms,
+            group_consumer_heartbeat_interval_ms,
+            group_consumer_max_size,
+            group_consumer_assignors,
+            offsets_topic_num_partitions,
+            offsets_topic_segment_bytes,
+            offsets_retention_minutes,
+            offsets_retention_check_interval_ms,
+            offset_metadata_max_bytes,
+            topic_configs,
         })
     }
+
+    /// The default configuration, storing logs in `log_dir`, with a single `__consumer_offsets`
+    /// partition.
+    #[cfg(test)]
+    pub fn for_tests(log_dir: &std::path::Path) -> Self {
+        Self {
+            log_dirs: vec![log_dir.to_path_buf()],
+            offsets_topic_num_partitions: 1,
+            ..Self::from_vars(|_| Err(env::VarError::NotPresent)).unwrap()
+        }
+    }
+}
+
+/// Parses the `GROUP_CONSUMER_ASSIGNORS` variable, a comma-separated list of assignor names.
+fn parse_assignors(value: &str) -> anyhow::Result<Vec<Assignor>> {
+    let mut assignors = Vec::new();
+    for name in value.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        let Some(assignor) = Assignor::parse(name) else {
+            bail!("Unknown assignor {:?} in GROUP_CONSUMER_ASSIGNORS", name);
+        };
+        assignors.push(assignor);
+    }
+    if assignors.is_empty() {
+        bail!("GROUP_CONSUMER_ASSIGNORS must name at least one assignor");
+    }
+    Ok(assignors)
+}
+
+/// Parses the `TOPIC_CONFIGS` variable. The values themselves are only checked when the topic's
+/// log is configured.
+fn parse_topic_configs(value: &str) -> anyhow::Result<HashMap<String, Vec<(String, String)>>> {
+    let mut topic_configs: HashMap<String, Vec<(String, String)>> = HashMap::new();
+    for entry in value.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        let Some((topic, settings)) = entry.split_once(':') else {
+            bail!(
+                "Malformed TOPIC_CONFIGS entry {:?}: expected <topic>:<name>=<value>",
+                entry
+            );
+        };
+        let overrides = topic_configs.entry(topic.trim().to_string()).or_default();
+        for setting in settings.split(',').map(str::trim) {
+            let Some((name, value)) = setting.split_once('=') else {
+                bail!(
+                    "Malformed TOPIC_CONFIGS setting {:?} for topic {}",
+                    setting,
+                    topic
+                );
+            };
+            overrides.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    Ok(topic_configs)
 }
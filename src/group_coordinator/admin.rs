@@ -0,0 +1,547 @@
+//! Group administration: ListGroups, DescribeGroups, DeleteGroups and OffsetDelete.
+//!
+//! These are answered from the coordinator's own state, for classic and consumer groups alike.
+//! A group can only be deleted once it has no members, along with all its committed offsets.
+//! Offsets can be deleted one by one from a group with members too, as long as no member
+//! consumes their topic (which the broker can only tell for consumer groups, and classic
+//! groups of the `consumer` protocol type).
+//!
+//! Deletions are written to the group's `__consumer_offsets` partition as tombstones before
+//! the group is changed, holding the partition log's lock throughout like offset commits do.
+
+use crate::group_coordinator::group::{GroupDescription, CONSUMER_PROTOCOL_TYPE};
+use crate::group_coordinator::records::GroupRecord;
+use crate::group_coordinator::{GroupCoordinator, GroupState, OffsetAndMetadata};
+use crate::kafka_protocol::kafka_error::{KafkaBrokerError, KafkaResult};
+use crate::storage::TopicPartition;
+use std::collections::HashMap;
+use tracing::{error, info};
+
+/// The type of a group: which protocol its members speak.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupType {
+    /// A group of the classic group protocol (JoinGroup and SyncGroup).
+    Classic,
+    /// A group of the consumer group protocol (ConsumerGroupHeartbeat).
+    Consumer,
+}
+
+impl GroupType {
+    /// The type's name, as ListGroups reports it.
+    pub fn name(self) -> &'static str {
+        match self {
+            GroupType::Classic => "classic",
+            GroupType::Consumer => "consumer",
+        }
+    }
+}
+
+/// A group as reported by ListGroups.
+#[derive(Debug, Clone)]
+pub struct GroupOverview {
+    /// The group id.
+    pub group_id: String,
+    /// The group's protocol type, or empty if it has none.
+    pub protocol_type: String,
+    /// The name of the group's state.
+    pub state: &'static str,
+    /// The type of the group.
+    pub group_type: GroupType,
+}
+
+impl GroupCoordinator {
+    /// Every group, sorted by group id. Only groups in one of `states` and of one of `types`
+    /// are listed, unless those are empty; both are matched regardless of case.
+    pub fn list_groups(&self, states: &[String], types: &[String]) -> Vec<GroupOverview> {
+        let groups = self.groups.lock().unwrap();
+        let consumer_groups = self.consumer_groups.lock().unwrap();
+        let classic = groups
+            .iter()
+            .filter(|(_, group)| group.state() != GroupState::Dead)
+            .map(|(group_id, group)| GroupOverview {
+                group_id: group_id.clone(),
+                protocol_type: group.protocol_type().unwrap_or_default().to_string(),
+                state: group.state().name(),
+                group_type: GroupType::Classic,
+            });
+        let consumer = consumer_groups
+            .iter()
+            .map(|(group_id, group)| GroupOverview {
+                group_id: group_id.clone(),
+                protocol_type: CONSUMER_PROTOCOL_TYPE.to_string(),
+                state: group.state().name(),
+                group_type: GroupType::Consumer,
+            });
+        let matches = |filter: &[String], name: &str| {
+            filter.is_empty() || filter.iter().any(|f| f.eq_ignore_ascii_case(name))
+        };
+        let mut listed: Vec<GroupOverview> = classic
+            .chain(consumer)
+            .filter(|group| matches(states, group.state) && matches(types, group.group_type.name()))
+            .collect();
+        listed.sort_by(|a, b| a.group_id.cmp(&b.group_id));
+        listed
+    }
+
+    /// Describes the classic groups `group_ids`, in order. Groups that don't exist are
+    /// reported `Dead`, without members.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::GroupIdNotFound`] for a consumer group, which only
+    /// ConsumerGroupDescribe describes.
+    pub fn describe_groups(&self, group_ids: &[String]) -> Vec<KafkaResult<GroupDescription>> {
+        let groups = self.groups.lock().unwrap();
+        let consumer_groups = self.consumer_groups.lock().unwrap();
+        group_ids
+            .iter()
+            .map(|group_id| {
+                if consumer_groups.contains_key(group_id) {
+                    return Err(KafkaBrokerError::GroupIdNotFound(group_id.clone()));
+                }
+                Ok(match groups.get(group_id) {
+                    Some(group) => group.describe(),
+                    None => GroupDescription {
+                        state: GroupState::Dead,
+                        protocol_type: String::new(),
+                        protocol_data: String::new(),
+                        members: Vec::new(),
+                    },
+                })
+            })
+            .collect()
+    }
+
+    /// Deletes the groups `group_ids` and their committed offsets. Returns the outcome for
+    /// each group, in order.
+    ///
+    /// # Errors
+    ///
+    /// - [`KafkaBrokerError::GroupIdNotFound`] for a group that doesn't exist.
+    /// - [`KafkaBrokerError::NonEmptyGroup`] for a group that has members.
+    /// - [`KafkaBrokerError::CoordinatorNotAvailable`] if the group's `__consumer_offsets`
+    ///   partition isn't available.
+    pub async fn delete_groups(&self, group_ids: &[String]) -> Vec<KafkaResult<()>> {
+        let mut results = Vec::with_capacity(group_ids.len());
+        for group_id in group_ids {
+            let result = self.delete_group(group_id).await;
+            match &result {
+                Ok(()) => info!("Deleted group {}", group_id),
+                Err(e) => info!("Failed to delete group {}: {}", group_id, e),
+            }
+            results.push(result);
+        }
+        results
+    }
+
+    /// Deletes the offsets committed by `group_id` for `partitions`. Returns the outcome for
+    /// each partition, in order; partitions without a committed offset are deleted trivially.
+    ///
+    /// # Errors
+    ///
+    /// - [`KafkaBrokerError::GroupIdNotFound`] for a group that doesn't exist.
+    /// - [`KafkaBrokerError::NonEmptyGroup`] for a classic group with members, unless it is of
+    ///   the `consumer` protocol type.
+    /// - [`KafkaBrokerError::GroupSubscribedToTopic`], for a partition, if a member of the
+    ///   group consumes its topic.
+    /// - [`KafkaBrokerError::CoordinatorNotAvailable`] if the group's `__consumer_offsets`
+    ///   partition isn't available.
+    pub async fn delete_offsets(
+        &self,
+        group_id: &str,
+        partitions: &[TopicPartition],
+    ) -> KafkaResult<Vec<KafkaResult<()>>> {
+        let log = self.partition_log(group_id).await?;
+        let log = log.lock_owned().await;
+
+        let (results, deleted) = {
+            let groups = self.groups.lock().unwrap();
+            let consumer_groups = self.consumer_groups.lock().unwrap();
+            match (consumer_groups.get(group_id), groups.get(group_id)) {
+                (Some(group), _) => offset_deletions(group_id, partitions, group.offsets(), |t| {
+                    group.is_subscribed_to(t)
+                }),
+                (None, Some(group)) if group.state() == GroupState::Dead => {
+                    return Err(KafkaBrokerError::GroupIdNotFound(group_id.to_string()))
+                }
+                (None, Some(group)) if group.state() == GroupState::Empty => {
+                    offset_deletions(group_id, partitions, group.offsets(), |_| false)
+                }
+                (None, Some(group)) if group.protocol_type() == Some(CONSUMER_PROTOCOL_TYPE) => {
+                    offset_deletions(group_id, partitions, group.offsets(), |t| {
+                        group.is_subscribed_to(t)
+                    })
+                }
+                (None, Some(_)) => {
+                    return Err(KafkaBrokerError::NonEmptyGroup(group_id.to_string()))
+                }
+                (None, None) => {
+                    return Err(KafkaBrokerError::GroupIdNotFound(group_id.to_string()))
+                }
+            }
+        };
+        if deleted.is_empty() {
+            return Ok(results);
+        }
+
+        let records: Vec<GroupRecord> = deleted
+            .iter()
+            .map(|topic_partition| GroupRecord::OffsetCommit {
+                group_id: group_id.to_string(),
+                topic_partition: topic_partition.clone(),
+                offset: None,
+            })
+            .collect();
+        let (_log, result) = Self::append_records(log, &records).await;
+        if let Err(e) = result {
+            error!("Failed to delete offsets of group {}: {}", group_id, e);
+            return Err(KafkaBrokerError::CoordinatorNotAvailable(
+                group_id.to_string(),
+            ));
+        }
+
+        let mut groups = self.groups.lock().unwrap();
+        let mut consumer_groups = self.consumer_groups.lock().unwrap();
+        for topic_partition in &deleted {
+            if let Some(group) = consumer_groups.get_mut(group_id) {
+                group.remove_offset(topic_partition);
+            } else if let Some(group) = groups.get_mut(group_id) {
+                group.remove_offset(topic_partition);
+            }
+        }
+        info!("Deleted {} offsets of group {}", deleted.len(), group_id);
+        Ok(results)
+    }
+
+    /// Deletes `group_id`, writing tombstones for its offsets and metadata.
+    async fn delete_group(&self, group_id: &str) -> KafkaResult<()> {
+        let log = self.partition_log(group_id).await?;
+        let log = log.lock_owned().await;
+
+        let mut pending = Vec::new();
+        let records = {
+            let groups = self.groups.lock().unwrap();
+            let mut consumer_groups = self.consumer_groups.lock().unwrap();
+            let (offsets, mut tombstones) = match consumer_groups.get_mut(group_id) {
+                Some(group) if group.size() > 0 => {
+                    return Err(KafkaBrokerError::NonEmptyGroup(group_id.to_string()))
+                }
+                Some(group) => {
+                    pending = group.take_records();
+                    (
+                        group.offsets().keys().cloned().collect(),
+                        group.tombstones(),
+                    )
+                }
+                None => match groups.get(group_id) {
+                    Some(group) if group.state() == GroupState::Dead => {
+                        return Err(KafkaBrokerError::GroupIdNotFound(group_id.to_string()))
+                    }
+                    Some(group) if group.state() != GroupState::Empty || group.size() > 0 => {
+                        return Err(KafkaBrokerError::NonEmptyGroup(group_id.to_string()))
+                    }
+                    Some(group) => {
+                        let tombstone = GroupRecord::GroupMetadata {
+                            group_id: group_id.to_string(),
+                            metadata: None,
+                        };
+                        let offsets: Vec<TopicPartition> =
+                            group.offsets().keys().cloned().collect();
+                        (offsets, vec![tombstone])
+                    }
+                    None => return Err(KafkaBrokerError::GroupIdNotFound(group_id.to_string())),
+                },
+            };
+            let mut records = pending.clone();
+            records.extend(
+                offsets
+                    .into_iter()
+                    .map(|topic_partition| GroupRecord::OffsetCommit {
+                        group_id: group_id.to_string(),
+                        topic_partition,
+                        offset: None,
+                    }),
+            );
+            records.append(&mut tombstones);
+            records
+        };
+        let (_log, result) = Self::append_records(log, &records).await;
+        if let Err(e) = result {
+            error!("Failed to delete group {}: {}", group_id, e);
+            if let Some(group) = self.consumer_groups.lock().unwrap().get_mut(group_id) {
+                group.requeue_records(pending);
+            }
+            return Err(KafkaBrokerError::CoordinatorNotAvailable(
+                group_id.to_string(),
+            ));
+        }
+
+        let mut groups = self.groups.lock().unwrap();
+        let mut consumer_groups = self.consumer_groups.lock().unwrap();
+        if consumer_groups.remove(group_id).is_some() {
+            return Ok(());
+        }
+        match groups.get_mut(group_id) {
+            Some(group) if group.state() == GroupState::Empty && group.size() == 0 => {
+                groups.remove(group_id);
+            }
+            Some(group) => {
+                // A member joined meanwhile: the group must be written again, without the
+                // offsets that are gone from the log.
+                let offsets: Vec<TopicPartition> = group.offsets().keys().cloned().collect();
+                for topic_partition in &offsets {
+                    group.remove_offset(topic_partition);
+                }
+                group.mark_needs_store();
+            }
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+/// The outcome of deleting the offsets of `partitions` from a group with `offsets`, whose
+/// members consume the topics for which `is_subscribed_to` holds, and the partitions whose
+/// offsets must be deleted.
+fn offset_deletions(
+    group_id: &str,
+    partitions: &[TopicPartition],
+    offsets: &HashMap<TopicPartition, OffsetAndMetadata>,
+    is_subscribed_to: impl Fn(&str) -> bool,
+) -> (Vec<KafkaResult<()>>, Vec<TopicPartition>) {
+    let mut results = Vec::with_capacity(partitions.len());
+    let mut deleted = Vec::new();
+    for topic_partition in partitions {
+        if is_subscribed_to(&topic_partition.topic) {
+            results.push(Err(KafkaBrokerError::GroupSubscribedToTopic {
+                group_id: group_id.to_string(),
+                topic: topic_partition.topic.clone(),
+            }));
+            continue;
+        }
+        if offsets.contains_key(topic_partition) {
+            deleted.push(topic_partition.clone());
+        }
+        results.push(Ok(()));
+    }
+    (results, deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker_state::BrokerState;
+    use crate::config::Config;
+    use crate::group_coordinator::{
+        CommitRequest, ConsumerHeartbeatRequest, JoinRequest, SyncRequest,
+    };
+    use crate::kafka_protocol::kafka_error_codes::{GROUP_ID_NOT_FOUND, NON_EMPTY_GROUP};
+    use std::path::Path;
+
+    /// A broker whose groups rebalance as soon as every member has joined.
+    async fn load(dir: &Path) -> BrokerState {
+        let mut config = Config::for_tests(dir);
+        config.group_initial_rebalance_delay_ms = 0;
+        BrokerState::load(config).await.unwrap()
+    }
+
+    /// Makes a member join classic group `group_id`, of a protocol type other than `consumer`
+    /// so the broker can't tell which topics it consumes, and the group stable.
+    async fn join_classic(coordinator: &GroupCoordinator, group_id: &str) {
+        let joined = coordinator
+            .join_group(JoinRequest {
+                group_id: group_id.to_string(),
+                member_id: String::new(),
+                group_instance_id: None,
+                client_id: "c".to_string(),
+                client_host: "/127.0.0.1".to_string(),
+                session_timeout_ms: 10_000,
+                rebalance_timeout_ms: 60_000,
+                protocol_type: "connect".to_string(),
+                protocols: vec![("default".to_string(), Vec::new())],
+                require_known_member_id: false,
+                supports_skip_assignment: false,
+                reason: None,
+            })
+            .await
+            .unwrap();
+        coordinator
+            .sync_group(SyncRequest {
+                group_id: group_id.to_string(),
+                generation_id: joined.generation_id,
+                member_id: joined.member_id.clone(),
+                group_instance_id: None,
+                protocol_type: None,
+                protocol_name: None,
+                assignments: vec![(joined.member_id, Vec::new())],
+            })
+            .await
+            .unwrap();
+    }
+
+    /// Commits an offset for partition 0 of `topic` to `group_id` from outside the group,
+    /// creating it if needed.
+    async fn commit(coordinator: &GroupCoordinator, group_id: &str, topic: &str) {
+        let results = coordinator
+            .commit_offsets(CommitRequest {
+                group_id: group_id.to_string(),
+                generation_id: -1,
+                member_id: String::new(),
+                group_instance_id: None,
+                offsets: vec![(
+                    TopicPartition::new(topic, 0),
+                    OffsetAndMetadata {
+                        offset: 1,
+                        leader_epoch: -1,
+                        metadata: String::new(),
+                        commit_timestamp: 1000,
+                        expire_timestamp: None,
+                    },
+                )],
+            })
+            .await
+            .unwrap();
+        assert!(results.iter().all(Result::is_ok));
+    }
+
+    /// Makes a member join consumer group `group_id`.
+    async fn join_consumer(coordinator: &GroupCoordinator, group_id: &str) {
+        let request = ConsumerHeartbeatRequest {
+            group_id: group_id.to_string(),
+            member_id: String::new(),
+            member_epoch: 0,
+            instance_id: None,
+            rack_id: None,
+            rebalance_timeout_ms: 60_000,
+            subscribed_topic_names: Some(vec!["t".to_string()]),
+            server_assignor: None,
+            owned_partitions: None,
+            client_id: "c".to_string(),
+            client_host: "/127.0.0.1".to_string(),
+        };
+        coordinator
+            .consumer_group_heartbeat(request, HashMap::new())
+            .await
+            .unwrap();
+    }
+
+    fn error_codes(results: &[KafkaResult<()>]) -> Vec<Option<i16>> {
+        results
+            .iter()
+            .map(|result| result.as_ref().err().map(KafkaBrokerError::error_code))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn only_empty_groups_that_exist_can_be_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = load(dir.path()).await;
+        let coordinator = &state.group_coordinator;
+        join_classic(coordinator, "busy").await;
+        commit(coordinator, "idle", "t").await;
+        join_consumer(coordinator, "members").await;
+
+        let group_ids = ["busy", "missing", "idle", "members"].map(str::to_string);
+        let results = coordinator.delete_groups(&group_ids).await;
+        assert_eq!(
+            error_codes(&results),
+            [
+                Some(NON_EMPTY_GROUP),
+                Some(GROUP_ID_NOT_FOUND),
+                None,
+                Some(NON_EMPTY_GROUP)
+            ]
+        );
+        let listed: Vec<String> = coordinator
+            .list_groups(&[], &[])
+            .into_iter()
+            .map(|group| group.group_id)
+            .collect();
+        assert_eq!(listed, ["busy", "members"]);
+
+        // Deleting the idle group again finds nothing.
+        let results = coordinator.delete_groups(&["idle".to_string()]).await;
+        assert_eq!(error_codes(&results), [Some(GROUP_ID_NOT_FOUND)]);
+    }
+
+    #[tokio::test]
+    async fn offsets_can_only_be_deleted_from_groups_that_allow_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = load(dir.path()).await;
+        let coordinator = &state.group_coordinator;
+        join_classic(coordinator, "busy").await;
+        commit(coordinator, "idle", "t").await;
+        commit(coordinator, "idle", "u").await;
+
+        let error = coordinator
+            .delete_offsets("busy", &[TopicPartition::new("t", 0)])
+            .await
+            .unwrap_err();
+        assert_eq!(error.error_code(), NON_EMPTY_GROUP);
+        let error = coordinator
+            .delete_offsets("missing", &[TopicPartition::new("t", 0)])
+            .await
+            .unwrap_err();
+        assert_eq!(error.error_code(), GROUP_ID_NOT_FOUND);
+
+        let results = coordinator
+            .delete_offsets(
+                "idle",
+                &[TopicPartition::new("t", 0), TopicPartition::new("v", 0)],
+            )
+            .await
+            .unwrap();
+        assert_eq!(error_codes(&results), [None, None]);
+        let groups = coordinator.groups.lock().unwrap();
+        let remaining: Vec<&TopicPartition> = groups["idle"].offsets().keys().collect();
+        assert_eq!(remaining, [&TopicPartition::new("u", 0)]);
+    }
+
+    #[tokio::test]
+    async fn groups_are_listed_by_state_and_type() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = load(dir.path()).await;
+        let coordinator = &state.group_coordinator;
+        join_classic(coordinator, "busy").await;
+        commit(coordinator, "idle", "t").await;
+        join_consumer(coordinator, "members").await;
+
+        let list = |states: &[&str], types: &[&str]| -> Vec<(String, &'static str, GroupType)> {
+            let states: Vec<String> = states.iter().map(|s| s.to_string()).collect();
+            let types: Vec<String> = types.iter().map(|t| t.to_string()).collect();
+            coordinator
+                .list_groups(&states, &types)
+                .into_iter()
+                .map(|group| (group.group_id, group.state, group.group_type))
+                .collect()
+        };
+
+        assert_eq!(
+            list(&[], &[]),
+            [
+                ("busy".to_string(), "Stable", GroupType::Classic),
+                ("idle".to_string(), "Empty", GroupType::Classic),
+                ("members".to_string(), "Stable", GroupType::Consumer),
+            ]
+        );
+        assert_eq!(
+            list(&["stable"], &[]),
+            [
+                ("busy".to_string(), "Stable", GroupType::Classic),
+                ("members".to_string(), "Stable", GroupType::Consumer),
+            ]
+        );
+        assert_eq!(
+            list(&[], &["CONSUMER"]),
+            [("members".to_string(), "Stable", GroupType::Consumer)]
+        );
+        assert_eq!(
+            list(&["Stable", "Empty"], &["classic"]),
+            [
+                ("busy".to_string(), "Stable", GroupType::Classic),
+                ("idle".to_string(), "Empty", GroupType::Classic),
+            ]
+        );
+        assert!(list(&["Dead"], &[]).is_empty());
+    }
+}
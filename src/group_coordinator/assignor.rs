@@ -0,0 +1,353 @@
+//! Server-side assignors of consumer groups (KIP-848).
+//!
+//! Under the consumer group protocol, the coordinator itself computes which partitions each
+//! member is to own, whenever the group's members, their subscriptions or the subscribed topics
+//! change. Two assignors are available, as on a Kafka broker:
+//!
+//! - `uniform`: spreads the partitions of every subscribed topic as evenly as possible over
+//!   the members subscribed to it, and keeps partitions with their current owner as long as
+//!   that doesn't unbalance the group, so that a rebalance moves as few partitions as it can.
+//! - `range`: gives each member subscribed to a topic a contiguous range of its partitions,
+//!   topic by topic, so that members subscribed to the same topics get the same partition
+//!   numbers of each (handy to co-partition joins).
+//!
+//! Members and partitions are always visited in the same order, so the same input gives the
+//! same assignment.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use uuid::Uuid;
+
+/// The partitions of a member, by topic id.
+pub type Assignment = BTreeMap<Uuid, BTreeSet<i32>>;
+
+/// A subscribed topic, as the assignors see it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicMetadata {
+    /// The topic id.
+    pub topic_id: Uuid,
+    /// The number of partitions of the topic.
+    pub num_partitions: i32,
+}
+
+/// A member to assign partitions to.
+#[derive(Debug)]
+pub struct MemberSubscription<'a> {
+    /// The member id.
+    pub member_id: &'a str,
+    /// The topics the member subscribes to, by name.
+    pub topics: &'a BTreeSet<String>,
+    /// The member's current target assignment, which the uniform assignor sticks to.
+    pub current: Option<&'a Assignment>,
+}
+
+/// A server-side assignor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assignor {
+    /// Even spread, sticking to the current assignment.
+    Uniform,
+    /// Contiguous ranges of partitions per topic.
+    Range,
+}
+
+impl Assignor {
+    /// The assignor named `name`, as clients ask for it.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "uniform" => Some(Assignor::Uniform),
+            "range" => Some(Assignor::Range),
+            _ => None,
+        }
+    }
+
+    /// The assignor's name.
+    pub fn name(self) -> &'static str {
+        match self {
+            Assignor::Uniform => "uniform",
+            Assignor::Range => "range",
+        }
+    }
+
+    /// Assigns the partitions of `topics`, the subscribed topics that exist, to `members`.
+    /// Every member gets an entry, empty if it has nothing to consume.
+    pub fn assign(
+        self,
+        members: &[MemberSubscription<'_>],
+        topics: &BTreeMap<String, TopicMetadata>,
+    ) -> HashMap<String, Assignment> {
+        let mut members: Vec<&MemberSubscription<'_>> = members.iter().collect();
+        members.sort_by_key(|member| member.member_id);
+        match self {
+            Assignor::Uniform => assign_uniform(&members, topics),
+            Assignor::Range => assign_range(&members, topics),
+        }
+    }
+}
+
+/// The uniform assignor: every partition stays with its current owner if it is still
+/// subscribed to the topic, the others go to the subscribed member with the fewest partitions,
+/// and partitions are then moved from the most loaded members to less loaded ones until no
+/// move would make the spread more even.
+fn assign_uniform(
+    members: &[&MemberSubscription<'_>],
+    topics: &BTreeMap<String, TopicMetadata>,
+) -> HashMap<String, Assignment> {
+    // The members each partition may go to, by index into `members`.
+    let mut partitions: Vec<(Uuid, i32, Vec<usize>)> = Vec::new();
+    for (name, topic) in topics {
+        let subscribers: Vec<usize> = (0..members.len())
+            .filter(|&i| members[i].topics.contains(name))
+            .collect();
+        if subscribers.is_empty() {
+            continue;
+        }
+        for partition in 0..topic.num_partitions {
+            partitions.push((topic.topic_id, partition, subscribers.clone()));
+        }
+    }
+
+    let mut owners: Vec<Option<usize>> = partitions
+        .iter()
+        .map(|(topic_id, partition, subscribers)| {
+            subscribers.iter().copied().find(|&i| {
+                members[i]
+                    .current
+                    .and_then(|current| current.get(topic_id))
+                    .is_some_and(|owned| owned.contains(partition))
+            })
+        })
+        .collect();
+    let mut counts = vec![0usize; members.len()];
+    for owner in owners.iter().flatten() {
+        counts[*owner] += 1;
+    }
+    for (owner, (_, _, subscribers)) in owners.iter_mut().zip(&partitions) {
+        if owner.is_none() {
+            let least_loaded = subscribers.iter().copied().min_by_key(|&i| counts[i]);
+            if let Some(member) = least_loaded {
+                counts[member] += 1;
+                *owner = Some(member);
+            }
+        }
+    }
+
+    // Every move lowers the sum of the squared counts, so this ends.
+    loop {
+        let mut moved = false;
+        for (owner, (_, _, subscribers)) in owners.iter_mut().zip(&partitions) {
+            let Some(from) = *owner else {
+                continue;
+            };
+            let least_loaded = subscribers.iter().copied().min_by_key(|&i| counts[i]);
+            if let Some(to) = least_loaded.filter(|&to| counts[to] + 1 < counts[from]) {
+                counts[from] -= 1;
+                counts[to] += 1;
+                *owner = Some(to);
+                moved = true;
+            }
+        }
+        if !moved {
+            break;
+        }
+    }
+
+    let mut assignments = empty_assignments(members);
+    for (owner, (topic_id, partition, _)) in owners.into_iter().zip(partitions) {
+        if let Some(owner) = owner {
+            assignments
+                .get_mut(members[owner].member_id)
+                .expect("every member has an assignment")
+                .entry(topic_id)
+                .or_default()
+                .insert(partition);
+        }
+    }
+    assignments
+}
+
+/// The range assignor: the partitions of each topic are split into as many contiguous ranges
+/// as members subscribe to it, the first members (by member id) getting one more partition
+/// when they don't divide evenly.
+fn assign_range(
+    members: &[&MemberSubscription<'_>],
+    topics: &BTreeMap<String, TopicMetadata>,
+) -> HashMap<String, Assignment> {
+    let mut assignments = empty_assignments(members);
+    for (name, topic) in topics {
+        let subscribers: Vec<&str> = members
+            .iter()
+            .filter(|member| member.topics.contains(name))
+            .map(|member| member.member_id)
+            .collect();
+        if subscribers.is_empty() {
+            continue;
+        }
+        let per_member = topic.num_partitions as usize / subscribers.len();
+        let extra = topic.num_partitions as usize % subscribers.len();
+        let mut start = 0;
+        for (i, member_id) in subscribers.into_iter().enumerate() {
+            let count = per_member + usize::from(i < extra);
+            if count > 0 {
+                let range = (start..start + count).map(|p| p as i32);
+                assignments
+                    .get_mut(member_id)
+                    .expect("every member has an assignment")
+                    .insert(topic.topic_id, range.collect());
+            }
+            start += count;
+        }
+    }
+    assignments
+}
+
+/// An empty assignment for every member.
+fn empty_assignments(members: &[&MemberSubscription<'_>]) -> HashMap<String, Assignment> {
+    members
+        .iter()
+        .map(|member| (member.member_id.to_string(), Assignment::new()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topics(partitions: &[(&str, i32)]) -> BTreeMap<String, TopicMetadata> {
+        partitions
+            .iter()
+            .map(|&(name, num_partitions)| {
+                let topic_id = Uuid::new_v4();
+                (
+                    name.to_string(),
+                    TopicMetadata {
+                        topic_id,
+                        num_partitions,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn subscriptions(names: &[&str]) -> BTreeSet<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn partitions_of(assignment: &Assignment, topic: &TopicMetadata) -> Vec<i32> {
+        assignment
+            .get(&topic.topic_id)
+            .map(|partitions| partitions.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn range_gives_contiguous_partitions_with_the_extra_ones_first() {
+        let topics = topics(&[("a", 5), ("b", 2)]);
+        let both = subscriptions(&["a", "b"]);
+        let a_only = subscriptions(&["a"]);
+        let members = [
+            MemberSubscription {
+                member_id: "m3",
+                topics: &a_only,
+                current: None,
+            },
+            MemberSubscription {
+                member_id: "m2",
+                topics: &both,
+                current: None,
+            },
+            MemberSubscription {
+                member_id: "m1",
+                topics: &both,
+                current: None,
+            },
+        ];
+
+        let assignments = Assignor::Range.assign(&members, &topics);
+
+        let (a, b) = (&topics["a"], &topics["b"]);
+        assert_eq!(partitions_of(&assignments["m1"], a), [0, 1]);
+        assert_eq!(partitions_of(&assignments["m2"], a), [2, 3]);
+        assert_eq!(partitions_of(&assignments["m3"], a), [4]);
+        assert_eq!(partitions_of(&assignments["m1"], b), [0]);
+        assert_eq!(partitions_of(&assignments["m2"], b), [1]);
+        assert_eq!(partitions_of(&assignments["m3"], b), [] as [i32; 0]);
+    }
+
+    #[test]
+    fn uniform_spreads_every_partition_evenly() {
+        let topics = topics(&[("a", 7)]);
+        let subscribed = subscriptions(&["a"]);
+        let members: Vec<MemberSubscription<'_>> = ["m1", "m2", "m3"]
+            .into_iter()
+            .map(|member_id| MemberSubscription {
+                member_id,
+                topics: &subscribed,
+                current: None,
+            })
+            .collect();
+
+        let assignments = Assignor::Uniform.assign(&members, &topics);
+
+        let mut counts: Vec<usize> = assignments
+            .values()
+            .map(|assignment| partitions_of(assignment, &topics["a"]).len())
+            .collect();
+        counts.sort();
+        assert_eq!(counts, [2, 2, 3]);
+        let mut all: Vec<i32> = assignments
+            .values()
+            .flat_map(|assignment| partitions_of(assignment, &topics["a"]))
+            .collect();
+        all.sort();
+        assert_eq!(all, (0..7).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn uniform_moves_as_few_partitions_as_it_can() {
+        let topics = topics(&[("a", 4)]);
+        let topic_id = topics["a"].topic_id;
+        let subscribed = subscriptions(&["a"]);
+        let m1_current = Assignment::from([(topic_id, BTreeSet::from([0, 1, 2]))]);
+        let m2_current = Assignment::from([(topic_id, BTreeSet::from([3]))]);
+        let members = [
+            MemberSubscription {
+                member_id: "m1",
+                topics: &subscribed,
+                current: Some(&m1_current),
+            },
+            MemberSubscription {
+                member_id: "m2",
+                topics: &subscribed,
+                current: Some(&m2_current),
+            },
+            MemberSubscription {
+                member_id: "m3",
+                topics: &subscribed,
+                current: None,
+            },
+        ];
+
+        let assignments = Assignor::Uniform.assign(&members, &topics);
+
+        // Only one partition moves, from the most loaded member to the new one.
+        let a = &topics["a"];
+        assert_eq!(partitions_of(&assignments["m1"], a), [1, 2]);
+        assert_eq!(partitions_of(&assignments["m2"], a), [3]);
+        assert_eq!(partitions_of(&assignments["m3"], a), [0]);
+    }
+
+    #[test]
+    fn members_of_missing_topics_get_empty_assignments() {
+        let topics = topics(&[("a", 2)]);
+        let subscribed = subscriptions(&["missing"]);
+        let members = [MemberSubscription {
+            member_id: "m1",
+            topics: &subscribed,
+            current: None,
+        }];
+
+        for assignor in [Assignor::Uniform, Assignor::Range] {
+            let assignments = assignor.assign(&members, &topics);
+            assert_eq!(assignments["m1"], Assignment::new());
+        }
+    }
+}
@@ -0,0 +1,321 @@
+//! Consumer groups (KIP-848): ConsumerGroupHeartbeat, ConsumerGroupDescribe and the expiration
+//! of members.
+//!
+//! Consumer groups are kept apart from classic groups, but share their group ids and
+//! `__consumer_offsets` partitions: a group id belongs to one type at a time. The first member
+//! of a consumer group may take over an empty classic group of the same id, keeping its
+//! committed offsets, while a non-empty classic group, or a JoinGroup for a consumer group, is
+//! answered with `GROUP_ID_NOT_FOUND`.
+//!
+//! Every change to a consumer group is appended to its `__consumer_offsets` partition before
+//! the heartbeat that caused it is answered, holding the partition log's lock throughout so the
+//! log and the group agree on the order of changes. A change that can't be written is logged
+//! and kept, to be written along with the group's next one.
+
+use crate::group_coordinator::assignor::{Assignment, TopicMetadata};
+use crate::group_coordinator::consumer_group::{
+    ConsumerGroup, ConsumerGroupDescription, LEAVE_GROUP_STATIC_MEMBER_EPOCH,
+};
+use crate::group_coordinator::records::GroupRecord;
+use crate::group_coordinator::{GroupCoordinator, GroupState};
+use crate::kafka_protocol::kafka_error::{KafkaBrokerError, KafkaResult};
+use crate::kafka_protocol::kafka_error_codes::INVALID_REQUEST;
+use crate::storage::LogGuard;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use tokio::time::Instant;
+use tracing::{error, info};
+
+/// A member's ConsumerGroupHeartbeat, as the coordinator sees it.
+#[derive(Debug, Clone)]
+pub struct ConsumerHeartbeatRequest {
+    /// The group id.
+    pub group_id: String,
+    /// The member id, or empty for a member joining for the first time.
+    pub member_id: String,
+    /// The member epoch: 0 to join, -1 to leave, -2 for a static member to leave while keeping
+    /// its place, else the epoch the member has.
+    pub member_epoch: i32,
+    /// The `group.instance.id` of a static member.
+    pub instance_id: Option<String>,
+    /// The member's rack, if it changed.
+    pub rack_id: Option<String>,
+    /// How long the member may take to revoke its partitions, or -1 if unchanged.
+    pub rebalance_timeout_ms: i32,
+    /// The topics the member subscribes to, if they changed.
+    pub subscribed_topic_names: Option<Vec<String>>,
+    /// The server assignor the member asks for, if it changed.
+    pub server_assignor: Option<String>,
+    /// The partitions the member owns, if they changed.
+    pub owned_partitions: Option<Assignment>,
+    /// The client id from the request header.
+    pub client_id: String,
+    /// The address the request came from.
+    pub client_host: String,
+}
+
+/// What a member gets back from ConsumerGroupHeartbeat.
+#[derive(Debug, Clone)]
+pub struct ConsumerHeartbeatResult {
+    /// The member id, generated for a member that joined without one.
+    pub member_id: String,
+    /// The member's epoch.
+    pub member_epoch: i32,
+    /// How long the member should wait before heartbeating again.
+    pub heartbeat_interval_ms: i32,
+    /// The partitions the member is to own, if they changed.
+    pub assignment: Option<Assignment>,
+}
+
+impl GroupCoordinator {
+    /// Handles a ConsumerGroupHeartbeat: joins, keeps alive or removes a member of a consumer
+    /// group, creating the group for its first member. `topics` holds every topic of the
+    /// cluster, by name, for the assignors.
+    ///
+    /// # Errors
+    ///
+    /// - [`KafkaBrokerError::MalformedRequest`] for a request missing what its epoch needs.
+    /// - [`KafkaBrokerError::UnsupportedAssignor`] for an assignor the broker doesn't have.
+    /// - [`KafkaBrokerError::GroupIdNotFound`] for an unknown group with a member epoch, or a
+    ///   classic group that has members.
+    /// - [`KafkaBrokerError::UnknownMemberId`], [`KafkaBrokerError::FencedMemberEpoch`],
+    ///   [`KafkaBrokerError::UnreleasedInstanceId`], [`KafkaBrokerError::FencedInstanceId`] or
+    ///   [`KafkaBrokerError::GroupMaxSizeReached`] as detailed by [`ConsumerGroup::heartbeat`].
+    /// - [`KafkaBrokerError::CoordinatorNotAvailable`] if the group's `__consumer_offsets`
+    ///   partition isn't available.
+    pub async fn consumer_group_heartbeat(
+        &self,
+        request: ConsumerHeartbeatRequest,
+        topics: HashMap<String, TopicMetadata>,
+    ) -> KafkaResult<ConsumerHeartbeatResult> {
+        self.validate_heartbeat(&request)?;
+        let group_id = &request.group_id;
+        let log = self.partition_log(group_id).await?;
+        let log = log.lock_owned().await;
+
+        let (result, records) = {
+            let mut groups = self.groups.lock().unwrap();
+            let mut consumer_groups = self.consumer_groups.lock().unwrap();
+            let group = match consumer_groups.entry(group_id.clone()) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) if request.member_epoch == 0 => {
+                    let group = match groups.get(group_id) {
+                        Some(classic) if classic.state() == GroupState::Empty => {
+                            info!(
+                                "Converting empty classic group {} to a consumer group",
+                                group_id
+                            );
+                            let offsets = classic.offsets().clone();
+                            groups.remove(group_id);
+                            ConsumerGroup::convert_from_classic(group_id, offsets)
+                        }
+                        Some(_) => return Err(KafkaBrokerError::GroupIdNotFound(group_id.clone())),
+                        None => {
+                            info!("Creating consumer group {}", group_id);
+                            ConsumerGroup::new(group_id)
+                        }
+                    };
+                    entry.insert(group)
+                }
+                Entry::Vacant(_) => {
+                    return Err(KafkaBrokerError::GroupIdNotFound(group_id.clone()))
+                }
+            };
+            let result = group.heartbeat(&request, &topics, &self.config, Instant::now());
+            (result, group.take_records())
+        };
+        self.store_consumer_group(log, group_id, records).await;
+        result
+    }
+
+    /// Describes the consumer groups `group_ids`, in order. Groups that don't exist, or are
+    /// classic groups, come back as [`KafkaBrokerError::GroupIdNotFound`].
+    pub fn describe_consumer_groups(
+        &self,
+        group_ids: &[String],
+    ) -> Vec<KafkaResult<ConsumerGroupDescription>> {
+        let consumer_groups = self.consumer_groups.lock().unwrap();
+        group_ids
+            .iter()
+            .map(|group_id| match consumer_groups.get(group_id) {
+                Some(group) => Ok(group.describe(&self.config.consumer_assignors)),
+                None => Err(KafkaBrokerError::GroupIdNotFound(group_id.clone())),
+            })
+            .collect()
+    }
+
+    /// Removes the consumer group members whose session, or time to revoke their partitions,
+    /// has run out by `now`.
+    pub(super) async fn expire_consumer_group_members(&self, now: Instant) {
+        let group_ids: Vec<String> = self
+            .consumer_groups
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, group)| !group.expired_members(now).is_empty())
+            .map(|(group_id, _)| group_id.clone())
+            .collect();
+        for group_id in group_ids {
+            let log = match self.partition_log(&group_id).await {
+                Ok(log) => log,
+                Err(e) => {
+                    error!("Failed to expire the members of group {}: {}", group_id, e);
+                    continue;
+                }
+            };
+            let log = log.lock_owned().await;
+            let records = {
+                let mut consumer_groups = self.consumer_groups.lock().unwrap();
+                let Some(group) = consumer_groups.get_mut(&group_id) else {
+                    continue;
+                };
+                for member_id in group.expired_members(Instant::now()) {
+                    info!(
+                        "Member {} of consumer group {} has expired",
+                        member_id, group_id
+                    );
+                    group.remove_member(&member_id);
+                }
+                group.take_records()
+            };
+            self.store_consumer_group(log, &group_id, records).await;
+        }
+    }
+
+    /// Deletes the consumer group offsets that have expired by `now_ms`, and the empty consumer
+    /// groups left without offsets. Returns how many offsets and groups were removed.
+    pub(super) async fn expire_consumer_group_offsets(&self, now_ms: i64) -> (usize, usize) {
+        let group_ids: Vec<String> = self
+            .consumer_groups
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        let mut expired_offsets = 0;
+        let mut removed_groups = 0;
+        for group_id in group_ids {
+            let log = match self.partition_log(&group_id).await {
+                Ok(log) => log,
+                Err(e) => {
+                    error!("Failed to expire the offsets of group {}: {}", group_id, e);
+                    continue;
+                }
+            };
+            let log = log.lock_owned().await;
+
+            let (expired, remove_group, pending, records) =
+                {
+                    let mut consumer_groups = self.consumer_groups.lock().unwrap();
+                    let Some(group) = consumer_groups.get_mut(&group_id) else {
+                        continue;
+                    };
+                    let expired = group.expired_offsets(now_ms, self.config.offsets_retention_ms);
+                    let remove_group = group.size() == 0 && group.offsets().len() == expired.len();
+                    if expired.is_empty() && !remove_group {
+                        continue;
+                    }
+                    let pending = group.take_records();
+                    let mut records = pending.clone();
+                    records.extend(expired.iter().map(|topic_partition| {
+                        GroupRecord::OffsetCommit {
+                            group_id: group_id.clone(),
+                            topic_partition: topic_partition.clone(),
+                            offset: None,
+                        }
+                    }));
+                    if remove_group {
+                        records.extend(group.tombstones());
+                    }
+                    (expired, remove_group, pending, records)
+                };
+            let (_log, result) = Self::append_records(log, &records).await;
+
+            let mut consumer_groups = self.consumer_groups.lock().unwrap();
+            let Some(group) = consumer_groups.get_mut(&group_id) else {
+                continue;
+            };
+            if let Err(e) = result {
+                error!("Failed to expire the offsets of group {}: {}", group_id, e);
+                group.requeue_records(pending);
+                continue;
+            }
+            for topic_partition in &expired {
+                group.remove_offset(topic_partition);
+            }
+            expired_offsets += expired.len();
+            if remove_group {
+                consumer_groups.remove(&group_id);
+                removed_groups += 1;
+            }
+        }
+        (expired_offsets, removed_groups)
+    }
+
+    /// Checks that a heartbeat carries what its member epoch needs.
+    fn validate_heartbeat(&self, request: &ConsumerHeartbeatRequest) -> KafkaResult<()> {
+        let invalid = |reason: &str| {
+            Err(KafkaBrokerError::MalformedRequest {
+                code: INVALID_REQUEST,
+                reason: reason.to_string(),
+            })
+        };
+        if request.group_id.is_empty() {
+            return invalid("GroupId can't be empty");
+        }
+        if request.instance_id.as_deref() == Some("") {
+            return invalid("InstanceId can't be empty");
+        }
+        if let Some(assignor) = &request.server_assignor {
+            let known = self
+                .config
+                .consumer_assignors
+                .iter()
+                .any(|known| known.name() == assignor);
+            if !known {
+                return Err(KafkaBrokerError::UnsupportedAssignor(assignor.clone()));
+            }
+        }
+        match request.member_epoch {
+            0 => {
+                if request.rebalance_timeout_ms < 0 {
+                    return invalid("RebalanceTimeoutMs must be provided when joining");
+                }
+                if request.subscribed_topic_names.is_none() {
+                    return invalid("SubscribedTopicNames must be set when joining");
+                }
+                if request
+                    .owned_partitions
+                    .as_ref()
+                    .is_some_and(|owned| !owned.is_empty())
+                {
+                    return invalid("TopicPartitions must be empty when joining");
+                }
+            }
+            epoch if epoch < LEAVE_GROUP_STATIC_MEMBER_EPOCH => {
+                return invalid("MemberEpoch is invalid");
+            }
+            LEAVE_GROUP_STATIC_MEMBER_EPOCH if request.instance_id.is_none() => {
+                return invalid("InstanceId can't be null when leaving as a static member");
+            }
+            _ if request.member_id.is_empty() => return invalid("MemberId can't be empty"),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Appends `records`, the changes to consumer group `group_id`, to `log`. If that fails,
+    /// they are logged and queued again, to be written with the group's next change.
+    async fn store_consumer_group(&self, log: LogGuard, group_id: &str, records: Vec<GroupRecord>) {
+        if records.is_empty() {
+            return;
+        }
+        let (_log, result) = Self::append_records(log, &records).await;
+        if let Err(e) = result {
+            error!("Failed to store consumer group {}: {}", group_id, e);
+            if let Some(group) = self.consumer_groups.lock().unwrap().get_mut(group_id) {
+                group.requeue_records(records);
+            }
+        }
+    }
+}
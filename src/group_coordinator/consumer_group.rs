@@ -0,0 +1,1233 @@
+//! The state of one consumer group under the consumer group protocol (KIP-848), and the
+//! reconciliation of its members' assignments.
+//!
+//! Unlike classic groups, consumer groups have no rebalance barrier: each member heartbeats on
+//! its own, and the coordinator computes the assignment. Three epochs drive the group:
+//!
+//! - The group epoch is bumped whenever a member joins or leaves, changes its subscription or
+//!   assignor, or a subscribed topic is created, deleted or gains partitions.
+//! - The assignment epoch is the group epoch the target assignment was computed for. It falls
+//!   behind the group epoch until the next heartbeat has the assignor run again.
+//! - Each member's epoch is the assignment epoch it has caught up with. A member moves to the
+//!   target assignment in steps: it first gives up the partitions it must revoke
+//!   (`UnrevokedPartitions`, keeping its epoch until it reports them revoked), then gets its
+//!   new epoch and the partitions no other member owns any more, waiting for the others
+//!   (`UnreleasedPartitions`) until their previous owners have revoked them.
+//!
+//! A member that fails to heartbeat within `group.consumer.session.timeout.ms`, or to revoke
+//! its partitions within its rebalance timeout, is removed. A static member (with a
+//! `group.instance.id`) leaving with epoch -2 keeps its place and assignment until its session
+//! expires, so a restarted instance carries on where it left off.
+//!
+//! The methods here only change the group. Every change is also queued as records for
+//! `__consumer_offsets` (see [`ConsumerGroup::take_records`]), which the coordinator writes
+//! before answering.
+
+use crate::group_coordinator::assignor::{Assignment, Assignor, MemberSubscription, TopicMetadata};
+use crate::group_coordinator::records::GroupRecord;
+use crate::group_coordinator::{
+    ConsumerHeartbeatRequest, ConsumerHeartbeatResult, GroupConfig, OffsetAndMetadata,
+};
+use crate::kafka_protocol::kafka_error::{KafkaBrokerError, KafkaResult};
+use crate::kafka_protocol::kafka_messages::{
+    consumer_group_current_member_assignment_value, consumer_group_partition_metadata_value,
+    consumer_group_target_assignment_member_value, ConsumerGroupCurrentMemberAssignmentValue,
+    ConsumerGroupMemberMetadataValue, ConsumerGroupMetadataValue,
+    ConsumerGroupPartitionMetadataValue, ConsumerGroupTargetAssignmentMemberValue,
+    ConsumerGroupTargetAssignmentMetadataValue,
+};
+use crate::storage::TopicPartition;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::info;
+use uuid::Uuid;
+
+/// The member epoch with which a member leaves its group.
+pub const LEAVE_GROUP_MEMBER_EPOCH: i32 = -1;
+
+/// The member epoch with which a static member leaves its group, keeping its place for a new
+/// instance of it.
+pub const LEAVE_GROUP_STATIC_MEMBER_EPOCH: i32 = -2;
+
+/// The state of a consumer group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsumerGroupState {
+    /// No members.
+    Empty,
+    /// The target assignment is behind the group epoch.
+    Assigning,
+    /// Members are moving to the target assignment.
+    Reconciling,
+    /// Every member has its target assignment.
+    Stable,
+}
+
+impl ConsumerGroupState {
+    /// The state's name, as ConsumerGroupDescribe reports it.
+    pub fn name(self) -> &'static str {
+        match self {
+            ConsumerGroupState::Empty => "Empty",
+            ConsumerGroupState::Assigning => "Assigning",
+            ConsumerGroupState::Reconciling => "Reconciling",
+            ConsumerGroupState::Stable => "Stable",
+        }
+    }
+}
+
+/// Where a member is in moving to its target assignment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MemberState {
+    /// The member owns its target assignment, as of its epoch.
+    Stable,
+    /// The member must revoke partitions before it gets its next epoch.
+    UnrevokedPartitions,
+    /// The member waits for partitions that other members have yet to revoke.
+    UnreleasedPartitions,
+}
+
+impl MemberState {
+    /// The state as written to `__consumer_offsets`.
+    fn value(self) -> i8 {
+        match self {
+            MemberState::Stable => 0,
+            MemberState::UnrevokedPartitions => 1,
+            MemberState::UnreleasedPartitions => 2,
+        }
+    }
+
+    /// The state written to `__consumer_offsets` as `value`.
+    fn from_value(value: i8) -> Self {
+        match value {
+            1 => MemberState::UnrevokedPartitions,
+            2 => MemberState::UnreleasedPartitions,
+            _ => MemberState::Stable,
+        }
+    }
+}
+
+/// A consumer group as reported by ConsumerGroupDescribe.
+#[derive(Debug, Clone)]
+pub struct ConsumerGroupDescription {
+    /// The state of the group.
+    pub state: ConsumerGroupState,
+    /// The group epoch.
+    pub group_epoch: i32,
+    /// The group epoch the target assignment was computed for.
+    pub assignment_epoch: i32,
+    /// The assignor the group uses.
+    pub assignor_name: String,
+    /// The members, by member id.
+    pub members: Vec<ConsumerMemberDescription>,
+}
+
+/// A consumer group member as reported by ConsumerGroupDescribe.
+#[derive(Debug, Clone)]
+pub struct ConsumerMemberDescription {
+    /// The member id.
+    pub member_id: String,
+    /// The member's `group.instance.id`, if it is a static member.
+    pub instance_id: Option<String>,
+    /// The member's rack.
+    pub rack_id: Option<String>,
+    /// The member epoch.
+    pub member_epoch: i32,
+    /// The client id of the member.
+    pub client_id: String,
+    /// The host the member connects from.
+    pub client_host: String,
+    /// The topics the member subscribes to.
+    pub subscribed_topic_names: Vec<String>,
+    /// The partitions the member owns.
+    pub assignment: Assignment,
+    /// The partitions the member is to own.
+    pub target_assignment: Assignment,
+}
+
+/// A member of a consumer group.
+#[derive(Debug)]
+struct ConsumerMember {
+    member_id: String,
+    instance_id: Option<String>,
+    rack_id: Option<String>,
+    client_id: String,
+    client_host: String,
+    subscribed_topic_names: BTreeSet<String>,
+    server_assignor: Option<String>,
+    rebalance_timeout: Duration,
+    member_epoch: i32,
+    previous_member_epoch: i32,
+    state: MemberState,
+    /// The partitions the member owns.
+    assigned: Assignment,
+    /// The partitions the member must revoke before it gets its next epoch.
+    pending_revocation: Assignment,
+    /// When the member is removed unless it heartbeats again.
+    session_deadline: Instant,
+    /// When the member is removed unless it has revoked its partitions, while it has some to
+    /// revoke.
+    revocation_deadline: Option<Instant>,
+}
+
+impl ConsumerMember {
+    /// A member that hasn't got an assignment yet.
+    fn new(member_id: String, session_deadline: Instant) -> Self {
+        Self {
+            member_id,
+            instance_id: None,
+            rack_id: None,
+            client_id: String::new(),
+            client_host: String::new(),
+            subscribed_topic_names: BTreeSet::new(),
+            server_assignor: None,
+            rebalance_timeout: Duration::ZERO,
+            member_epoch: 0,
+            previous_member_epoch: 0,
+            state: MemberState::Stable,
+            assigned: Assignment::new(),
+            pending_revocation: Assignment::new(),
+            session_deadline,
+            revocation_deadline: None,
+        }
+    }
+
+    /// Takes the member's subscription and client details from `request`, leaving those it
+    /// didn't send unchanged. Returns whether the metadata changed, and whether the change
+    /// calls for a new target assignment.
+    fn update(&mut self, request: &ConsumerHeartbeatRequest, joining: bool) -> (bool, bool) {
+        let mut changed = false;
+        let mut assignment_changed = false;
+        if let Some(names) = &request.subscribed_topic_names {
+            let names: BTreeSet<String> = names.iter().cloned().collect();
+            if names != self.subscribed_topic_names {
+                self.subscribed_topic_names = names;
+                assignment_changed = true;
+            }
+        }
+        if (joining || request.server_assignor.is_some())
+            && request.server_assignor != self.server_assignor
+        {
+            self.server_assignor = request.server_assignor.clone();
+            assignment_changed = true;
+        }
+        if joining {
+            changed |= replace(&mut self.instance_id, request.instance_id.clone());
+            changed |= replace(&mut self.client_id, request.client_id.clone());
+            changed |= replace(&mut self.client_host, request.client_host.clone());
+        }
+        if request.rack_id.is_some() {
+            changed |= replace(&mut self.rack_id, request.rack_id.clone());
+        }
+        if request.rebalance_timeout_ms >= 0 {
+            let timeout = Duration::from_millis(request.rebalance_timeout_ms as u64);
+            changed |= replace(&mut self.rebalance_timeout, timeout);
+        }
+        (changed || assignment_changed, assignment_changed)
+    }
+
+    /// Takes the member's subscription and client details from a record of
+    /// `__consumer_offsets`.
+    fn restore_metadata(&mut self, value: ConsumerGroupMemberMetadataValue) {
+        self.instance_id = value.instance_id;
+        self.rack_id = value.rack_id;
+        self.client_id = value.client_id;
+        self.client_host = value.client_host;
+        self.subscribed_topic_names = value.subscribed_topic_names.into_iter().collect();
+        self.server_assignor = value.server_assignor;
+        self.rebalance_timeout = Duration::from_millis(value.rebalance_timeout_ms.max(0) as u64);
+    }
+
+    /// The member's subscription and client details, as written to `__consumer_offsets`.
+    fn metadata_value(&self) -> ConsumerGroupMemberMetadataValue {
+        ConsumerGroupMemberMetadataValue {
+            instance_id: self.instance_id.clone(),
+            rack_id: self.rack_id.clone(),
+            client_id: self.client_id.clone(),
+            client_host: self.client_host.clone(),
+            subscribed_topic_names: self.subscribed_topic_names.iter().cloned().collect(),
+            subscribed_topic_regex: None,
+            server_assignor: self.server_assignor.clone(),
+            rebalance_timeout_ms: self.rebalance_timeout.as_millis() as i32,
+            ..Default::default()
+        }
+    }
+
+    /// The member's epochs and partitions, as written to `__consumer_offsets`.
+    fn assignment_value(&self) -> ConsumerGroupCurrentMemberAssignmentValue {
+        use consumer_group_current_member_assignment_value::TopicPartitions;
+        let topic_partitions = |assignment: &Assignment| {
+            assignment
+                .iter()
+                .map(|(topic_id, partitions)| TopicPartitions {
+                    topic_id: *topic_id,
+                    partitions: partitions.iter().copied().collect(),
+                    ..Default::default()
+                })
+                .collect()
+        };
+        ConsumerGroupCurrentMemberAssignmentValue {
+            member_epoch: self.member_epoch,
+            previous_member_epoch: self.previous_member_epoch,
+            state: self.state.value(),
+            assigned_partitions: topic_partitions(&self.assigned),
+            partitions_pending_revocation: topic_partitions(&self.pending_revocation),
+            ..Default::default()
+        }
+    }
+
+    /// Whether the member has left as a static member, keeping its place for a new instance.
+    fn has_left(&self) -> bool {
+        self.member_epoch == LEAVE_GROUP_STATIC_MEMBER_EPOCH
+    }
+}
+
+/// A consumer group.
+#[derive(Debug)]
+pub struct ConsumerGroup {
+    group_id: String,
+    group_epoch: i32,
+    /// The subscribed topics that exist, as of the group epoch, by name.
+    subscribed_topics: BTreeMap<String, TopicMetadata>,
+    /// The group epoch the target assignment was computed for.
+    assignment_epoch: i32,
+    /// The partitions each member is to own, by member id.
+    target_assignment: HashMap<String, Assignment>,
+    /// The members, by member id.
+    members: BTreeMap<String, ConsumerMember>,
+    /// The committed offsets.
+    offsets: HashMap<TopicPartition, OffsetAndMetadata>,
+    /// The changes yet to be written to `__consumer_offsets`, in order.
+    records: Vec<GroupRecord>,
+}
+
+impl ConsumerGroup {
+    /// Creates an empty group.
+    pub fn new(group_id: &str) -> Self {
+        Self {
+            group_id: group_id.to_string(),
+            group_epoch: 0,
+            subscribed_topics: BTreeMap::new(),
+            assignment_epoch: 0,
+            target_assignment: HashMap::new(),
+            members: BTreeMap::new(),
+            offsets: HashMap::new(),
+            records: Vec::new(),
+        }
+    }
+
+    /// Creates an empty group in place of the empty classic group of the same id, taking over
+    /// its `offsets`. The classic group's metadata is deleted.
+    pub fn convert_from_classic(
+        group_id: &str,
+        offsets: HashMap<TopicPartition, OffsetAndMetadata>,
+    ) -> Self {
+        let mut group = Self::new(group_id);
+        group.offsets = offsets;
+        group.records.push(GroupRecord::GroupMetadata {
+            group_id: group_id.to_string(),
+            metadata: None,
+        });
+        group
+    }
+
+    /// Applies `record`, one of the group's records loaded from `__consumer_offsets`. Members
+    /// restored from it have until `session_deadline` to heartbeat, or to revoke their
+    /// partitions.
+    pub fn replay(&mut self, record: GroupRecord, session_deadline: Instant) {
+        match record {
+            GroupRecord::ConsumerGroupMetadata { value, .. } => {
+                self.group_epoch = value.map_or(0, |value| value.epoch);
+            }
+            GroupRecord::ConsumerGroupPartitionMetadata { value, .. } => {
+                self.subscribed_topics = value
+                    .map(|value| {
+                        value
+                            .topics
+                            .into_iter()
+                            .map(|topic| {
+                                let metadata = TopicMetadata {
+                                    topic_id: topic.topic_id,
+                                    num_partitions: topic.num_partitions,
+                                };
+                                (topic.topic_name, metadata)
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default();
+            }
+            GroupRecord::ConsumerGroupMemberMetadata {
+                member_id, value, ..
+            } => match value {
+                Some(value) => self
+                    .members
+                    .entry(member_id)
+                    .or_insert_with_key(|member_id| {
+                        ConsumerMember::new(member_id.clone(), session_deadline)
+                    })
+                    .restore_metadata(value),
+                None => {
+                    self.members.remove(&member_id);
+                }
+            },
+            GroupRecord::ConsumerGroupTargetAssignmentMetadata { value, .. } => {
+                self.assignment_epoch = value.map_or(0, |value| value.assignment_epoch);
+            }
+            GroupRecord::ConsumerGroupTargetAssignmentMember {
+                member_id, value, ..
+            } => match value {
+                Some(value) => {
+                    let assignment = value
+                        .topic_partitions
+                        .into_iter()
+                        .map(|topic| (topic.topic_id, topic.partitions.into_iter().collect()))
+                        .collect();
+                    self.target_assignment.insert(member_id, assignment);
+                }
+                None => {
+                    self.target_assignment.remove(&member_id);
+                }
+            },
+            GroupRecord::ConsumerGroupCurrentMemberAssignment {
+                member_id,
+                value: Some(value),
+                ..
+            } => {
+                let Some(member) = self.members.get_mut(&member_id) else {
+                    return;
+                };
+                let assignment = |topics: Vec<
+                    consumer_group_current_member_assignment_value::TopicPartitions,
+                >| {
+                    topics
+                        .into_iter()
+                        .map(|topic| (topic.topic_id, topic.partitions.into_iter().collect()))
+                        .collect()
+                };
+                member.member_epoch = value.member_epoch;
+                member.previous_member_epoch = value.previous_member_epoch;
+                member.state = MemberState::from_value(value.state);
+                member.assigned = assignment(value.assigned_partitions);
+                member.pending_revocation = assignment(value.partitions_pending_revocation);
+                member.revocation_deadline =
+                    (member.state == MemberState::UnrevokedPartitions).then_some(session_deadline);
+            }
+            _ => {}
+        }
+    }
+
+    /// The changes to be written to `__consumer_offsets`, in order, clearing them.
+    pub fn take_records(&mut self) -> Vec<GroupRecord> {
+        std::mem::take(&mut self.records)
+    }
+
+    /// Queues `records` again, ahead of any newer ones, as writing them failed.
+    pub fn requeue_records(&mut self, mut records: Vec<GroupRecord>) {
+        records.append(&mut self.records);
+        self.records = records;
+    }
+
+    /// The state of the group.
+    pub fn state(&self) -> ConsumerGroupState {
+        if self.members.is_empty() {
+            ConsumerGroupState::Empty
+        } else if self.group_epoch > self.assignment_epoch {
+            ConsumerGroupState::Assigning
+        } else if self.members.values().any(|member| {
+            !member.has_left()
+                && (member.state != MemberState::Stable
+                    || member.member_epoch != self.assignment_epoch)
+        }) {
+            ConsumerGroupState::Reconciling
+        } else {
+            ConsumerGroupState::Stable
+        }
+    }
+
+    /// The number of members.
+    pub fn size(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Whether a member subscribes to `topic`.
+    pub fn is_subscribed_to(&self, topic: &str) -> bool {
+        self.members
+            .values()
+            .any(|member| member.subscribed_topic_names.contains(topic))
+    }
+
+    /// The group as reported by ConsumerGroupDescribe, using `assignors` to tell which
+    /// assignor it uses.
+    pub fn describe(&self, assignors: &[Assignor]) -> ConsumerGroupDescription {
+        ConsumerGroupDescription {
+            state: self.state(),
+            group_epoch: self.group_epoch,
+            assignment_epoch: self.assignment_epoch,
+            assignor_name: self.preferred_assignor(assignors).name().to_string(),
+            members: self
+                .members
+                .values()
+                .map(|member| ConsumerMemberDescription {
+                    member_id: member.member_id.clone(),
+                    instance_id: member.instance_id.clone(),
+                    rack_id: member.rack_id.clone(),
+                    member_epoch: member.member_epoch,
+                    client_id: member.client_id.clone(),
+                    client_host: member.client_host.clone(),
+                    subscribed_topic_names: member.subscribed_topic_names.iter().cloned().collect(),
+                    assignment: member.assigned.clone(),
+                    target_assignment: self
+                        .target_assignment
+                        .get(&member.member_id)
+                        .cloned()
+                        .unwrap_or_default(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Handles a ConsumerGroupHeartbeat: adds, updates or removes the member, recomputes the
+    /// target assignment if the group epoch has moved on, and moves the member towards its
+    /// target. `topics` holds every topic of the cluster, by name.
+    ///
+    /// # Errors
+    ///
+    /// - [`KafkaBrokerError::UnknownMemberId`] for a member the group doesn't have.
+    /// - [`KafkaBrokerError::FencedMemberEpoch`] for an epoch other than the member's.
+    /// - [`KafkaBrokerError::UnreleasedInstanceId`] for a static member whose previous
+    ///   instance hasn't left.
+    /// - [`KafkaBrokerError::FencedInstanceId`] for a static member leaving with another
+    ///   member's id.
+    /// - [`KafkaBrokerError::GroupMaxSizeReached`] if the group is full.
+    ///
+    /// The group is left unchanged on error.
+    pub fn heartbeat(
+        &mut self,
+        request: &ConsumerHeartbeatRequest,
+        topics: &HashMap<String, TopicMetadata>,
+        config: &GroupConfig,
+        now: Instant,
+    ) -> KafkaResult<ConsumerHeartbeatResult> {
+        let member_epoch = request.member_epoch;
+        if member_epoch == LEAVE_GROUP_MEMBER_EPOCH
+            || member_epoch == LEAVE_GROUP_STATIC_MEMBER_EPOCH
+        {
+            self.leave(request)?;
+            return Ok(ConsumerHeartbeatResult {
+                member_id: request.member_id.clone(),
+                member_epoch,
+                heartbeat_interval_ms: 0,
+                assignment: None,
+            });
+        }
+
+        let joining = member_epoch == 0;
+        let member_id = if joining {
+            self.join(request, config, now)?
+        } else {
+            self.validate_member_epoch(request)?;
+            request.member_id.clone()
+        };
+
+        let member = self.members.get_mut(&member_id).expect("the member exists");
+        member.session_deadline = now + config.consumer_session_timeout;
+        let previous_assignment = member.assigned.clone();
+        let (metadata_changed, assignment_changed) = member.update(request, joining);
+        if metadata_changed {
+            let value = member.metadata_value();
+            self.records.push(GroupRecord::ConsumerGroupMemberMetadata {
+                group_id: self.group_id.clone(),
+                member_id: member_id.clone(),
+                value: Some(value),
+            });
+        }
+        let topics_changed = self.refresh_subscribed_topics(Some(topics));
+        if assignment_changed || topics_changed {
+            self.bump_group_epoch();
+        }
+        if self.group_epoch > self.assignment_epoch {
+            self.compute_target_assignment(&config.consumer_assignors);
+        }
+        self.reconcile(&member_id, request.owned_partitions.as_ref(), now);
+
+        let member = &self.members[&member_id];
+        let send_assignment = joining
+            || member.assigned != previous_assignment
+            || request
+                .owned_partitions
+                .as_ref()
+                .is_some_and(|owned| *owned != member.assigned);
+        Ok(ConsumerHeartbeatResult {
+            member_id,
+            member_epoch: member.member_epoch,
+            heartbeat_interval_ms: config.consumer_heartbeat_interval_ms,
+            assignment: send_assignment.then(|| member.assigned.clone()),
+        })
+    }
+
+    /// Adds the member joining with `request` (epoch 0), or takes it back if it rejoins.
+    /// Returns its member id.
+    fn join(
+        &mut self,
+        request: &ConsumerHeartbeatRequest,
+        config: &GroupConfig,
+        now: Instant,
+    ) -> KafkaResult<String> {
+        let member_id = match request.member_id.as_str() {
+            "" => Uuid::new_v4().to_string(),
+            member_id => member_id.to_string(),
+        };
+        if let Some(instance_id) = &request.instance_id {
+            let previous = self
+                .members
+                .values()
+                .find(|member| member.instance_id.as_ref() == Some(instance_id));
+            match previous {
+                Some(previous) if previous.member_id == member_id => return Ok(member_id),
+                Some(previous) if previous.has_left() => {
+                    let previous_id = previous.member_id.clone();
+                    self.replace_static_member(&previous_id, &member_id);
+                    return Ok(member_id);
+                }
+                Some(_) => {
+                    return Err(KafkaBrokerError::UnreleasedInstanceId {
+                        group_id: self.group_id.clone(),
+                        instance_id: instance_id.clone(),
+                    })
+                }
+                None => {}
+            }
+        }
+        if self.members.contains_key(&member_id) {
+            return Ok(member_id);
+        }
+        if self.members.len() >= config.consumer_max_size {
+            return Err(KafkaBrokerError::GroupMaxSizeReached(self.group_id.clone()));
+        }
+        info!(
+            "Member {} (client {}, host {}) joined consumer group {}",
+            member_id, request.client_id, request.client_host, self.group_id
+        );
+        let session_deadline = now + config.consumer_session_timeout;
+        self.members.insert(
+            member_id.clone(),
+            ConsumerMember::new(member_id.clone(), session_deadline),
+        );
+        Ok(member_id)
+    }
+
+    /// Hands the place of static member `previous_id`, which has left, to a new instance of it
+    /// joining as `member_id`. The new instance carries on with the previous one's epoch and
+    /// assignment.
+    fn replace_static_member(&mut self, previous_id: &str, member_id: &str) {
+        info!(
+            "Static member {} of consumer group {} replaced by {}",
+            previous_id, self.group_id, member_id
+        );
+        let Some(mut member) = self.members.remove(previous_id) else {
+            return;
+        };
+        self.push_member_tombstones(previous_id);
+        member.member_id = member_id.to_string();
+        member.member_epoch = member.previous_member_epoch;
+        let value = member.metadata_value();
+        self.records.push(GroupRecord::ConsumerGroupMemberMetadata {
+            group_id: self.group_id.clone(),
+            member_id: member_id.to_string(),
+            value: Some(value),
+        });
+        if let Some(target) = self.target_assignment.remove(previous_id) {
+            self.records
+                .push(self.target_assignment_record(member_id, &target));
+            self.target_assignment.insert(member_id.to_string(), target);
+        }
+        self.records.push(self.current_assignment_record(&member));
+        self.members.insert(member_id.to_string(), member);
+    }
+
+    /// Handles a member leaving with epoch -1, or -2 for a static member keeping its place.
+    fn leave(&mut self, request: &ConsumerHeartbeatRequest) -> KafkaResult<()> {
+        let Some(member) = self.members.get_mut(&request.member_id) else {
+            return Err(self.unknown_member(&request.member_id));
+        };
+        if request.member_epoch == LEAVE_GROUP_MEMBER_EPOCH {
+            info!(
+                "Member {} has left consumer group {}",
+                request.member_id, self.group_id
+            );
+            self.remove_member(&request.member_id);
+            return Ok(());
+        }
+        if member.instance_id != request.instance_id {
+            return Err(KafkaBrokerError::FencedInstanceId {
+                group_id: self.group_id.clone(),
+                instance_id: request.instance_id.clone().unwrap_or_default(),
+            });
+        }
+        info!(
+            "Static member {} has left consumer group {}, keeping its assignment",
+            request.member_id, self.group_id
+        );
+        if !member.has_left() {
+            member.previous_member_epoch = member.member_epoch;
+            member.member_epoch = LEAVE_GROUP_STATIC_MEMBER_EPOCH;
+            member.revocation_deadline = None;
+        }
+        let record = self.current_assignment_record(&self.members[&request.member_id]);
+        self.records.push(record);
+        Ok(())
+    }
+
+    /// Checks the epoch a member heartbeats with. A member may still use its previous epoch if
+    /// it only owns partitions of its current assignment, having missed the response that
+    /// gave it its new epoch.
+    fn validate_member_epoch(&self, request: &ConsumerHeartbeatRequest) -> KafkaResult<()> {
+        let Some(member) = self.members.get(&request.member_id) else {
+            return Err(self.unknown_member(&request.member_id));
+        };
+        if request.member_epoch == member.member_epoch {
+            return Ok(());
+        }
+        let missed_response = request.member_epoch == member.previous_member_epoch
+            && request
+                .owned_partitions
+                .as_ref()
+                .is_some_and(|owned| is_subset(owned, &member.assigned));
+        if missed_response {
+            return Ok(());
+        }
+        Err(KafkaBrokerError::FencedMemberEpoch {
+            group_id: self.group_id.clone(),
+            member_id: request.member_id.clone(),
+            member_epoch: request.member_epoch,
+        })
+    }
+
+    /// Checks that the sender of an OffsetCommit or OffsetFetch may use the group's offsets:
+    /// anyone while the group has no members, otherwise a member with its current epoch.
+    ///
+    /// # Errors
+    ///
+    /// - [`KafkaBrokerError::UnknownMemberId`] for a member the group doesn't have.
+    /// - [`KafkaBrokerError::StaleMemberEpoch`] for an epoch other than the member's.
+    pub fn validate_offsets_request(&self, member_id: &str, member_epoch: i32) -> KafkaResult<()> {
+        if member_epoch < 0 && self.members.is_empty() {
+            return Ok(());
+        }
+        let Some(member) = self.members.get(member_id) else {
+            return Err(self.unknown_member(member_id));
+        };
+        if member_epoch != member.member_epoch {
+            return Err(KafkaBrokerError::StaleMemberEpoch {
+                group_id: self.group_id.clone(),
+                member_epoch,
+            });
+        }
+        Ok(())
+    }
+
+    /// The ids of the members that have failed to heartbeat, or to revoke their partitions,
+    /// by `now`.
+    pub fn expired_members(&self, now: Instant) -> Vec<String> {
+        self.members
+            .values()
+            .filter(|member| {
+                member.session_deadline <= now
+                    || member
+                        .revocation_deadline
+                        .is_some_and(|deadline| deadline <= now)
+            })
+            .map(|member| member.member_id.clone())
+            .collect()
+    }
+
+    /// Removes `member_id`, releasing its partitions, and bumps the group epoch so that they
+    /// are assigned again.
+    pub fn remove_member(&mut self, member_id: &str) {
+        if self.members.remove(member_id).is_none() {
+            return;
+        }
+        self.push_member_tombstones(member_id);
+        self.target_assignment.remove(member_id);
+        self.refresh_subscribed_topics(None);
+        self.bump_group_epoch();
+    }
+
+    /// The records deleting the group, once it has no members or offsets left.
+    pub fn tombstones(&self) -> Vec<GroupRecord> {
+        let group_id = || self.group_id.clone();
+        vec![
+            GroupRecord::ConsumerGroupTargetAssignmentMetadata {
+                group_id: group_id(),
+                value: None,
+            },
+            GroupRecord::ConsumerGroupPartitionMetadata {
+                group_id: group_id(),
+                value: None,
+            },
+            GroupRecord::ConsumerGroupMetadata {
+                group_id: group_id(),
+                value: None,
+            },
+        ]
+    }
+
+    /// Every committed offset.
+    pub fn offsets(&self) -> &HashMap<TopicPartition, OffsetAndMetadata> {
+        &self.offsets
+    }
+
+    /// Commits `offset` for `topic_partition`.
+    pub fn commit_offset(&mut self, topic_partition: TopicPartition, offset: OffsetAndMetadata) {
+        self.offsets.insert(topic_partition, offset);
+    }
+
+    /// Deletes the offset committed for `topic_partition`.
+    pub fn remove_offset(&mut self, topic_partition: &TopicPartition) {
+        self.offsets.remove(topic_partition);
+    }
+
+    /// The partitions whose offsets have expired by `now_ms`. Offsets committed with a
+    /// retention time expire when it runs out; others are kept as long as the group has
+    /// members, then for `retention_ms` since their commit.
+    pub fn expired_offsets(&self, now_ms: i64, retention_ms: i64) -> Vec<TopicPartition> {
+        let is_empty = self.members.is_empty();
+        self.offsets
+            .iter()
+            .filter(|(_, offset)| match offset.expire_timestamp {
+                Some(expire_timestamp) => now_ms >= expire_timestamp,
+                None => is_empty && now_ms - offset.commit_timestamp >= retention_ms,
+            })
+            .map(|(topic_partition, _)| topic_partition.clone())
+            .collect()
+    }
+
+    /// Bumps the group epoch, so that the target assignment is computed again.
+    fn bump_group_epoch(&mut self) {
+        self.group_epoch += 1;
+        self.records.push(GroupRecord::ConsumerGroupMetadata {
+            group_id: self.group_id.clone(),
+            value: Some(ConsumerGroupMetadataValue {
+                epoch: self.group_epoch,
+                ..Default::default()
+            }),
+        });
+    }
+
+    /// Updates the metadata of the subscribed topics: those the members subscribe to, taken
+    /// from `topics` if given, else from the current metadata. Returns whether it changed.
+    fn refresh_subscribed_topics(
+        &mut self,
+        topics: Option<&HashMap<String, TopicMetadata>>,
+    ) -> bool {
+        let subscribed: BTreeSet<&String> = self
+            .members
+            .values()
+            .flat_map(|member| &member.subscribed_topic_names)
+            .collect();
+        let subscribed_topics: BTreeMap<String, TopicMetadata> = subscribed
+            .into_iter()
+            .filter_map(|name| {
+                let metadata = match topics {
+                    Some(topics) => topics.get(name),
+                    None => self.subscribed_topics.get(name),
+                };
+                metadata.map(|metadata| (name.clone(), metadata.clone()))
+            })
+            .collect();
+        if subscribed_topics == self.subscribed_topics {
+            return false;
+        }
+        self.subscribed_topics = subscribed_topics;
+        self.records
+            .push(GroupRecord::ConsumerGroupPartitionMetadata {
+                group_id: self.group_id.clone(),
+                value: Some(ConsumerGroupPartitionMetadataValue {
+                    topics: self
+                        .subscribed_topics
+                        .iter()
+                        .map(|(name, metadata)| {
+                            consumer_group_partition_metadata_value::TopicMetadata {
+                                topic_id: metadata.topic_id,
+                                topic_name: name.clone(),
+                                num_partitions: metadata.num_partitions,
+                                ..Default::default()
+                            }
+                        })
+                        .collect(),
+                    ..Default::default()
+                }),
+            });
+        true
+    }
+
+    /// The assignor the members ask for most, the first of `assignors` if none asks for one.
+    fn preferred_assignor(&self, assignors: &[Assignor]) -> Assignor {
+        let mut votes: HashMap<&str, usize> = HashMap::new();
+        for member in self.members.values() {
+            if let Some(name) = &member.server_assignor {
+                *votes.entry(name.as_str()).or_default() += 1;
+            }
+        }
+        // Ties go to the assignor configured first.
+        let mut preferred = assignors[0];
+        let mut most_votes = 0;
+        for &assignor in assignors {
+            let votes = votes.get(assignor.name()).copied().unwrap_or(0);
+            if votes > most_votes {
+                preferred = assignor;
+                most_votes = votes;
+            }
+        }
+        preferred
+    }
+
+    /// Has the preferred assignor compute the target assignment for the group epoch.
+    fn compute_target_assignment(&mut self, assignors: &[Assignor]) {
+        let assignor = self.preferred_assignor(assignors);
+        let subscriptions: Vec<MemberSubscription<'_>> = self
+            .members
+            .values()
+            .map(|member| MemberSubscription {
+                member_id: &member.member_id,
+                topics: &member.subscribed_topic_names,
+                current: self.target_assignment.get(&member.member_id),
+            })
+            .collect();
+        let target = assignor.assign(&subscriptions, &self.subscribed_topics);
+
+        let mut member_ids: Vec<&String> = target.keys().collect();
+        member_ids.sort();
+        for member_id in member_ids {
+            let assignment = &target[member_id];
+            if self.target_assignment.get(member_id) != Some(assignment) {
+                self.records
+                    .push(self.target_assignment_record(member_id, assignment));
+            }
+        }
+        self.target_assignment = target;
+        self.assignment_epoch = self.group_epoch;
+        self.records
+            .push(GroupRecord::ConsumerGroupTargetAssignmentMetadata {
+                group_id: self.group_id.clone(),
+                value: Some(ConsumerGroupTargetAssignmentMetadataValue {
+                    assignment_epoch: self.assignment_epoch,
+                    ..Default::default()
+                }),
+            });
+        info!(
+            "Computed the target assignment of consumer group {} for epoch {} with the {} \
+             assignor",
+            self.group_id,
+            self.assignment_epoch,
+            assignor.name()
+        );
+    }
+
+    /// Moves `member_id` towards its target assignment, `owned` being the partitions it
+    /// reports owning, if it sent them.
+    fn reconcile(&mut self, member_id: &str, owned: Option<&Assignment>, now: Instant) {
+        let target = self
+            .target_assignment
+            .get(member_id)
+            .cloned()
+            .unwrap_or_default();
+        let target_epoch = self.assignment_epoch;
+        // The partitions other members still own or have yet to revoke.
+        let taken: HashSet<(Uuid, i32)> = self
+            .members
+            .values()
+            .filter(|member| member.member_id != member_id)
+            .flat_map(|member| {
+                partitions(&member.assigned).chain(partitions(&member.pending_revocation))
+            })
+            .collect();
+
+        let Some(member) = self.members.get_mut(member_id) else {
+            return;
+        };
+        match member.state {
+            MemberState::UnrevokedPartitions => {
+                let revoked =
+                    owned.is_some_and(|owned| !intersects(owned, &member.pending_revocation));
+                if !revoked {
+                    return;
+                }
+                member.pending_revocation.clear();
+            }
+            MemberState::Stable if member.member_epoch == target_epoch => return,
+            _ => {}
+        }
+
+        let before = (
+            member.member_epoch,
+            member.state,
+            member.assigned.clone(),
+            member.pending_revocation.clone(),
+        );
+        let kept = intersection(&member.assigned, &target);
+        let to_revoke = difference(&member.assigned, &target);
+        if !to_revoke.is_empty() {
+            // The member must give up partitions before it moves on to the new epoch.
+            member.assigned = kept;
+            member.pending_revocation = to_revoke;
+            member.state = MemberState::UnrevokedPartitions;
+            member.revocation_deadline = Some(now + member.rebalance_timeout);
+        } else {
+            let mut assigned = kept;
+            let mut unreleased = false;
+            for (topic_id, partition) in partitions(&difference(&target, &member.assigned)) {
+                if taken.contains(&(topic_id, partition)) {
+                    unreleased = true;
+                } else {
+                    assigned.entry(topic_id).or_default().insert(partition);
+                }
+            }
+            member.assigned = assigned;
+            if member.member_epoch != target_epoch {
+                member.previous_member_epoch = member.member_epoch;
+                member.member_epoch = target_epoch;
+            }
+            member.state = if unreleased {
+                MemberState::UnreleasedPartitions
+            } else {
+                MemberState::Stable
+            };
+            member.revocation_deadline = None;
+        }
+        let after = (
+            member.member_epoch,
+            member.state,
+            member.assigned.clone(),
+            member.pending_revocation.clone(),
+        );
+        if before != after {
+            let record = self.current_assignment_record(&self.members[member_id]);
+            self.records.push(record);
+        }
+    }
+
+    /// Queues the tombstones of a removed member: its current assignment, its target
+    /// assignment, then its metadata.
+    fn push_member_tombstones(&mut self, member_id: &str) {
+        let group_id = self.group_id.clone();
+        self.records.extend([
+            GroupRecord::ConsumerGroupCurrentMemberAssignment {
+                group_id: group_id.clone(),
+                member_id: member_id.to_string(),
+                value: None,
+            },
+            GroupRecord::ConsumerGroupTargetAssignmentMember {
+                group_id: group_id.clone(),
+                member_id: member_id.to_string(),
+                value: None,
+            },
+            GroupRecord::ConsumerGroupMemberMetadata {
+                group_id,
+                member_id: member_id.to_string(),
+                value: None,
+            },
+        ]);
+    }
+
+    /// The record of `member_id`'s target assignment.
+    fn target_assignment_record(&self, member_id: &str, assignment: &Assignment) -> GroupRecord {
+        use consumer_group_target_assignment_member_value::TopicPartition;
+        GroupRecord::ConsumerGroupTargetAssignmentMember {
+            group_id: self.group_id.clone(),
+            member_id: member_id.to_string(),
+            value: Some(ConsumerGroupTargetAssignmentMemberValue {
+                topic_partitions: assignment
+                    .iter()
+                    .map(|(topic_id, partitions)| TopicPartition {
+                        topic_id: *topic_id,
+                        partitions: partitions.iter().copied().collect(),
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// The record of `member`'s current assignment.
+    fn current_assignment_record(&self, member: &ConsumerMember) -> GroupRecord {
+        GroupRecord::ConsumerGroupCurrentMemberAssignment {
+            group_id: self.group_id.clone(),
+            member_id: member.member_id.clone(),
+            value: Some(member.assignment_value()),
+        }
+    }
+
+    /// The error for a request from `member_id`, which the group doesn't have.
+    fn unknown_member(&self, member_id: &str) -> KafkaBrokerError {
+        KafkaBrokerError::UnknownMemberId {
+            group_id: self.group_id.clone(),
+            member_id: member_id.to_string(),
+        }
+    }
+}
+
+/// Sets `field` to `value`, returning whether that changed it.
+fn replace<T: PartialEq>(field: &mut T, value: T) -> bool {
+    if *field == value {
+        return false;
+    }
+    *field = value;
+    true
+}
+
+/// Every partition of `assignment`.
+fn partitions(assignment: &Assignment) -> impl Iterator<Item = (Uuid, i32)> + '_ {
+    assignment.iter().flat_map(|(topic_id, partitions)| {
+        partitions
+            .iter()
+            .map(move |&partition| (*topic_id, partition))
+    })
+}
+
+/// The partitions both in `a` and in `b`.
+fn intersection(a: &Assignment, b: &Assignment) -> Assignment {
+    a.iter()
+        .filter_map(|(topic_id, partitions)| {
+            let other = b.get(topic_id)?;
+            let common: BTreeSet<i32> = partitions.intersection(other).copied().collect();
+            (!common.is_empty()).then_some((*topic_id, common))
+        })
+        .collect()
+}
+
+/// The partitions in `a` but not in `b`.
+fn difference(a: &Assignment, b: &Assignment) -> Assignment {
+    a.iter()
+        .filter_map(|(topic_id, partitions)| {
+            let rest: BTreeSet<i32> = match b.get(topic_id) {
+                Some(other) => partitions.difference(other).copied().collect(),
+                None => partitions.clone(),
+            };
+            (!rest.is_empty()).then_some((*topic_id, rest))
+        })
+        .collect()
+}
+
+/// Whether every partition of `a` is in `b`.
+fn is_subset(a: &Assignment, b: &Assignment) -> bool {
+    difference(a, b).is_empty()
+}
+
+/// Whether `a` and `b` have a partition in common.
+fn intersects(a: &Assignment, b: &Assignment) -> bool {
+    !intersection(a, b).is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::path::Path;
+
+    fn heartbeat_request(
+        member_id: &str,
+        member_epoch: i32,
+        owned: Option<Assignment>,
+    ) -> ConsumerHeartbeatRequest {
+        ConsumerHeartbeatRequest {
+            group_id: "g".to_string(),
+            member_id: member_id.to_string(),
+            member_epoch,
+            instance_id: None,
+            rack_id: None,
+            rebalance_timeout_ms: if member_epoch == 0 { 60_000 } else { -1 },
+            subscribed_topic_names: (member_epoch == 0).then(|| vec!["t".to_string()]),
+            server_assignor: None,
+            owned_partitions: owned,
+            client_id: "client".to_string(),
+            client_host: "/127.0.0.1".to_string(),
+        }
+    }
+
+    #[test]
+    fn a_member_gets_its_new_epoch_only_once_it_has_revoked_its_partitions() {
+        let config = GroupConfig::from_config(&Config::for_tests(Path::new(".")));
+        let topic_id = Uuid::new_v4();
+        let topics = HashMap::from([(
+            "t".to_string(),
+            TopicMetadata {
+                topic_id,
+                num_partitions: 2,
+            },
+        )]);
+        let owning = |partitions: &[i32]| {
+            Assignment::from([(topic_id, partitions.iter().copied().collect())])
+        };
+        let now = Instant::now();
+        let mut group = ConsumerGroup::new("g");
+
+        let first = group
+            .heartbeat(&heartbeat_request("m1", 0, None), &topics, &config, now)
+            .unwrap();
+        assert_eq!(first.member_epoch, 1);
+        assert_eq!(first.assignment, Some(owning(&[0, 1])));
+
+        // The second member's target takes a partition from the first, which still owns it.
+        let second = group
+            .heartbeat(&heartbeat_request("m2", 0, None), &topics, &config, now)
+            .unwrap();
+        assert_eq!(second.member_epoch, 2);
+        assert_eq!(second.assignment, Some(Assignment::new()));
+        assert_eq!(group.state(), ConsumerGroupState::Reconciling);
+
+        // The first member is told to revoke it, and keeps its epoch until it has.
+        let first = group
+            .heartbeat(
+                &heartbeat_request("m1", 1, Some(owning(&[0, 1]))),
+                &topics,
+                &config,
+                now,
+            )
+            .unwrap();
+        assert_eq!(first.member_epoch, 1);
+        assert_eq!(first.assignment, Some(owning(&[1])));
+        let first = group
+            .heartbeat(
+                &heartbeat_request("m1", 1, Some(owning(&[0, 1]))),
+                &topics,
+                &config,
+                now,
+            )
+            .unwrap();
+        assert_eq!(first.member_epoch, 1);
+        let second = group
+            .heartbeat(
+                &heartbeat_request("m2", 2, Some(Assignment::new())),
+                &topics,
+                &config,
+                now,
+            )
+            .unwrap();
+        assert_eq!(second.assignment, None);
+
+        let first = group
+            .heartbeat(
+                &heartbeat_request("m1", 1, Some(owning(&[1]))),
+                &topics,
+                &config,
+                now,
+            )
+            .unwrap();
+        assert_eq!(first.member_epoch, 2);
+        assert_eq!(first.assignment, None);
+
+        // Only now is the partition released to the second member.
+        let second = group
+            .heartbeat(
+                &heartbeat_request("m2", 2, Some(Assignment::new())),
+                &topics,
+                &config,
+                now,
+            )
+            .unwrap();
+        assert_eq!(second.member_epoch, 2);
+        assert_eq!(second.assignment, Some(owning(&[0])));
+        assert_eq!(group.state(), ConsumerGroupState::Stable);
+    }
+}
@@ -0,0 +1,1051 @@
+//! The state of one classic consumer group, and the transitions of the rebalance protocol.
+//!
+//! A group moves between the states of Kafka's classic group protocol:
+//!
+//! - `Empty`: the group has no members (it may still have committed offsets).
+//! - `PreparingRebalance`: members are (re)joining. The join phase ends once every member has
+//!   rejoined, or when the rebalance deadline passes and the dynamic members that haven't are
+//!   removed. The first rebalance of an empty group instead always waits
+//!   `group.initial.rebalance.delay.ms` past the latest join, so that a whole fleet of
+//!   consumers starting together lands in one generation.
+//! - `CompletingRebalance`: a new generation has been formed, and its members wait for the
+//!   leader's SyncGroup to hand out the assignment.
+//! - `Stable`: every member has its assignment, and only heartbeats.
+//! - `Dead`: the group is being removed.
+//!
+//! Members joining or leaving, changing their protocols or letting their session expire send
+//! the group back to `PreparingRebalance`.
+//!
+//! The methods here only change the group; the coordinator wakes the requests waiting on it,
+//! and writes the group to `__consumer_offsets` whenever a generation is formed or the group
+//! becomes empty (see [`Group::take_needs_store`]).
+
+use crate::group_coordinator::{JoinRequest, OffsetAndMetadata};
+use crate::kafka_protocol::kafka_codec::read_i16;
+use crate::kafka_protocol::kafka_error::{KafkaBrokerError, KafkaResult};
+use crate::kafka_protocol::kafka_messages::group_metadata_value::MemberMetadata;
+use crate::kafka_protocol::kafka_messages::{ConsumerProtocolSubscription, GroupMetadataValue};
+use crate::storage::now_ms;
+use crate::storage::TopicPartition;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::info;
+
+/// The protocol type of groups of Kafka consumers.
+pub const CONSUMER_PROTOCOL_TYPE: &str = "consumer";
+
+/// The state of a group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupState {
+    /// No members.
+    Empty,
+    /// Members are (re)joining.
+    PreparingRebalance,
+    /// Waiting for the leader's assignment.
+    CompletingRebalance,
+    /// Every member has its assignment.
+    Stable,
+    /// The group is being removed.
+    Dead,
+}
+
+impl GroupState {
+    /// The state's name, as ListGroups and DescribeGroups report it.
+    pub fn name(self) -> &'static str {
+        match self {
+            GroupState::Empty => "Empty",
+            GroupState::PreparingRebalance => "PreparingRebalance",
+            GroupState::CompletingRebalance => "CompletingRebalance",
+            GroupState::Stable => "Stable",
+            GroupState::Dead => "Dead",
+        }
+    }
+}
+
+/// What a member gets back from JoinGroup.
+#[derive(Debug, Clone)]
+pub struct JoinGroupResult {
+    /// The generation the member joined.
+    pub generation_id: i32,
+    /// The group's protocol type (e.g. `consumer`).
+    pub protocol_type: Option<String>,
+    /// The assignor chosen for the generation.
+    pub protocol_name: Option<String>,
+    /// The member id of the leader.
+    pub leader_id: String,
+    /// The member's own id.
+    pub member_id: String,
+    /// Every member with its metadata for the chosen assignor. Only the leader gets them.
+    pub members: Vec<JoinedMember>,
+    /// Whether the leader must not compute a new assignment, because the group is already
+    /// stable (a static leader rejoining).
+    pub skip_assignment: bool,
+}
+
+/// A member as listed to the leader in a JoinGroup response.
+#[derive(Debug, Clone)]
+pub struct JoinedMember {
+    /// The member id.
+    pub member_id: String,
+    /// The member's `group.instance.id`, if it is a static member.
+    pub group_instance_id: Option<String>,
+    /// The member's metadata for the chosen assignor.
+    pub metadata: Vec<u8>,
+}
+
+/// What a member gets back from SyncGroup.
+#[derive(Debug, Clone)]
+pub struct SyncGroupResult {
+    /// The group's protocol type.
+    pub protocol_type: Option<String>,
+    /// The assignor chosen for the generation.
+    pub protocol_name: Option<String>,
+    /// The member's assignment, as computed by the leader.
+    pub assignment: Vec<u8>,
+}
+
+/// A group as reported by DescribeGroups.
+#[derive(Debug, Clone)]
+pub struct GroupDescription {
+    /// The state of the group.
+    pub state: GroupState,
+    /// The group's protocol type, or empty if it has none.
+    pub protocol_type: String,
+    /// The assignor of the current generation, once the group is stable.
+    pub protocol_data: String,
+    /// The members, with their metadata and assignment once the group is stable.
+    pub members: Vec<MemberDescription>,
+}
+
+/// A member as reported by DescribeGroups.
+#[derive(Debug, Clone)]
+pub struct MemberDescription {
+    /// The member id.
+    pub member_id: String,
+    /// The member's `group.instance.id`, if it is a static member.
+    pub group_instance_id: Option<String>,
+    /// The client id of the member.
+    pub client_id: String,
+    /// The host the member connects from.
+    pub client_host: String,
+    /// The member's metadata for the current assignor.
+    pub metadata: Vec<u8>,
+    /// The member's assignment, as computed by the leader.
+    pub assignment: Vec<u8>,
+}
+
+/// A member of a group.
+#[derive(Debug)]
+pub struct Member {
+    member_id: String,
+    group_instance_id: Option<String>,
+    client_id: String,
+    client_host: String,
+    session_timeout: Duration,
+    rebalance_timeout: Duration,
+    protocol_type: String,
+    /// The assignors the member supports, most preferred first, with its metadata for each.
+    protocols: Vec<(String, Vec<u8>)>,
+    assignment: Vec<u8>,
+    /// Whether the member has (re)joined during the current rebalance.
+    awaiting_join: bool,
+    /// Whether the member's SyncGroup is waiting for the leader's.
+    awaiting_sync: bool,
+    /// Whether the member has yet to send SyncGroup for the current generation.
+    pending_sync: bool,
+    /// The outcome of the member's JoinGroup, once the join phase has ended.
+    join_result: Option<KafkaResult<JoinGroupResult>>,
+    /// The outcome of the member's SyncGroup, once the leader has sent the assignment.
+    sync_result: Option<KafkaResult<SyncGroupResult>>,
+    /// When the member is removed unless it heartbeats again. Not enforced while the member
+    /// waits in JoinGroup or SyncGroup.
+    heartbeat_deadline: Instant,
+}
+
+impl Member {
+    /// A member joining with `request`, under id `member_id`.
+    pub fn new(member_id: String, request: &JoinRequest, now: Instant) -> Self {
+        let session_timeout = Duration::from_millis(request.session_timeout_ms as u64);
+        Self {
+            member_id,
+            group_instance_id: request.group_instance_id.clone(),
+            client_id: request.client_id.clone(),
+            client_host: request.client_host.clone(),
+            session_timeout,
+            rebalance_timeout: Duration::from_millis(request.rebalance_timeout_ms as u64),
+            protocol_type: request.protocol_type.clone(),
+            protocols: request.protocols.clone(),
+            assignment: Vec::new(),
+            awaiting_join: false,
+            awaiting_sync: false,
+            pending_sync: false,
+            join_result: None,
+            sync_result: None,
+            heartbeat_deadline: now + session_timeout,
+        }
+    }
+
+    /// A member of a group loaded from `__consumer_offsets`, with the metadata it had for
+    /// the group's assignor `protocol`. Its session starts over at `now`.
+    pub fn restore(
+        member: MemberMetadata,
+        protocol_type: &str,
+        protocol: &str,
+        now: Instant,
+    ) -> Self {
+        let session_timeout = Duration::from_millis(member.session_timeout as u64);
+        Self {
+            member_id: member.member_id,
+            group_instance_id: member.group_instance_id,
+            client_id: member.client_id,
+            client_host: member.client_host,
+            session_timeout,
+            rebalance_timeout: Duration::from_millis(member.rebalance_timeout as u64),
+            protocol_type: protocol_type.to_string(),
+            protocols: vec![(protocol.to_string(), member.subscription)],
+            assignment: member.assignment,
+            awaiting_join: false,
+            awaiting_sync: false,
+            pending_sync: false,
+            join_result: None,
+            sync_result: None,
+            heartbeat_deadline: now + session_timeout,
+        }
+    }
+
+    /// Whether the member's protocols differ from those in `request`.
+    pub fn protocols_changed(&self, request: &JoinRequest) -> bool {
+        self.protocols != request.protocols
+    }
+
+    /// Whether the member supports assignor `protocol`.
+    fn supports(&self, protocol: &str) -> bool {
+        self.protocols.iter().any(|(name, _)| name == protocol)
+    }
+
+    /// The member's metadata for assignor `protocol`.
+    fn metadata(&self, protocol: Option<&str>) -> Vec<u8> {
+        self.protocols
+            .iter()
+            .find(|(name, _)| Some(name.as_str()) == protocol)
+            .map(|(_, metadata)| metadata.clone())
+            .unwrap_or_default()
+    }
+
+    /// Takes the settings of a rejoining member from `request`, and marks it as joined.
+    pub fn rejoin(&mut self, request: &JoinRequest, now: Instant) {
+        self.client_id.clone_from(&request.client_id);
+        self.client_host.clone_from(&request.client_host);
+        self.session_timeout = Duration::from_millis(request.session_timeout_ms as u64);
+        self.rebalance_timeout = Duration::from_millis(request.rebalance_timeout_ms as u64);
+        self.protocols.clone_from(&request.protocols);
+        self.awaiting_join = true;
+        self.heartbeat(now);
+    }
+
+    /// Whether the member's JoinGroup is waiting for the join phase to end.
+    pub fn is_awaiting_join(&self) -> bool {
+        self.awaiting_join
+    }
+
+    /// Whether the member's SyncGroup is waiting for the leader's.
+    pub fn is_awaiting_sync(&self) -> bool {
+        self.awaiting_sync
+    }
+
+    /// Takes the outcome of the member's JoinGroup, once there is one.
+    pub fn take_join_result(&mut self) -> Option<KafkaResult<JoinGroupResult>> {
+        self.join_result.take()
+    }
+
+    /// Takes the outcome of the member's SyncGroup, once there is one.
+    pub fn take_sync_result(&mut self) -> Option<KafkaResult<SyncGroupResult>> {
+        self.sync_result.take()
+    }
+
+    /// Pushes the member's session deadline forward.
+    pub fn heartbeat(&mut self, now: Instant) {
+        self.heartbeat_deadline = now + self.session_timeout;
+    }
+}
+
+/// A classic consumer group.
+#[derive(Debug)]
+pub struct Group {
+    group_id: String,
+    state: GroupState,
+    generation_id: i32,
+    protocol_type: Option<String>,
+    protocol_name: Option<String>,
+    leader_id: Option<String>,
+    /// The members, in the order they joined.
+    members: Vec<Member>,
+    /// New members that were given an id with `MEMBER_ID_REQUIRED`, with the deadline to join
+    /// again with it.
+    pending_members: Vec<(String, Instant)>,
+    /// When the join phase ends at the latest.
+    rebalance_deadline: Instant,
+    /// During the first rebalance of an empty group, how far the join phase may be extended.
+    initial_rebalance_limit: Option<Instant>,
+    /// When members that haven't sent SyncGroup for the new generation are removed.
+    sync_deadline: Instant,
+    /// When the group entered its current state, in milliseconds since the Unix epoch, or -1
+    /// if unknown. The offsets of an empty group expire counting from it.
+    current_state_timestamp: i64,
+    /// Whether the group has changed in a way that must be written to `__consumer_offsets`.
+    needs_store: bool,
+    /// The committed offsets.
+    offsets: HashMap<TopicPartition, OffsetAndMetadata>,
+}
+
+impl Group {
+    /// Creates an empty group.
+    pub fn new(group_id: &str, now: Instant) -> Self {
+        Self {
+            group_id: group_id.to_string(),
+            state: GroupState::Empty,
+            generation_id: 0,
+            protocol_type: None,
+            protocol_name: None,
+            leader_id: None,
+            members: Vec::new(),
+            pending_members: Vec::new(),
+            rebalance_deadline: now,
+            initial_rebalance_limit: None,
+            sync_deadline: now,
+            current_state_timestamp: now_ms(),
+            needs_store: false,
+            offsets: HashMap::new(),
+        }
+    }
+
+    /// Takes the group's generation and members from `value`, as loaded from
+    /// `__consumer_offsets`. The group is stable if it has members, and empty otherwise.
+    pub fn restore(&mut self, value: GroupMetadataValue, now: Instant) {
+        self.generation_id = value.generation;
+        self.protocol_type = Some(value.protocol_type).filter(|t| !t.is_empty());
+        self.protocol_name = value.protocol;
+        self.leader_id = value.leader;
+        self.current_state_timestamp = value.current_state_timestamp;
+        let protocol_type = self.protocol_type.clone().unwrap_or_default();
+        let protocol = self.protocol_name.clone().unwrap_or_default();
+        self.members = value
+            .members
+            .into_iter()
+            .map(|member| Member::restore(member, &protocol_type, &protocol, now))
+            .collect();
+        self.state = if self.members.is_empty() {
+            GroupState::Empty
+        } else {
+            GroupState::Stable
+        };
+    }
+
+    /// The group's generation and members, as written to `__consumer_offsets`.
+    pub fn metadata_value(&self) -> GroupMetadataValue {
+        GroupMetadataValue {
+            protocol_type: self.protocol_type.clone().unwrap_or_default(),
+            generation: self.generation_id,
+            protocol: self.protocol_name.clone(),
+            leader: self.leader_id.clone(),
+            current_state_timestamp: self.current_state_timestamp,
+            members: self
+                .members
+                .iter()
+                .map(|m| MemberMetadata {
+                    member_id: m.member_id.clone(),
+                    group_instance_id: m.group_instance_id.clone(),
+                    client_id: m.client_id.clone(),
+                    client_host: m.client_host.clone(),
+                    rebalance_timeout: m.rebalance_timeout.as_millis() as i32,
+                    session_timeout: m.session_timeout.as_millis() as i32,
+                    subscription: m.metadata(self.protocol_name.as_deref()),
+                    assignment: m.assignment.clone(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Whether the group must be written to `__consumer_offsets`, clearing the flag.
+    pub fn take_needs_store(&mut self) -> bool {
+        std::mem::take(&mut self.needs_store)
+    }
+
+    /// Has the group written to `__consumer_offsets` again, as its last write failed or was
+    /// superseded.
+    pub fn mark_needs_store(&mut self) {
+        self.needs_store = true;
+    }
+
+    /// Moves the group to `state`.
+    fn transition_to(&mut self, state: GroupState) {
+        self.state = state;
+        self.current_state_timestamp = now_ms();
+    }
+
+    /// The state of the group.
+    pub fn state(&self) -> GroupState {
+        self.state
+    }
+
+    /// The current generation.
+    pub fn generation_id(&self) -> i32 {
+        self.generation_id
+    }
+
+    /// The group's protocol type.
+    pub fn protocol_type(&self) -> Option<&str> {
+        self.protocol_type.as_deref()
+    }
+
+    /// The assignor chosen for the current generation.
+    pub fn protocol_name(&self) -> Option<&str> {
+        self.protocol_name.as_deref()
+    }
+
+    /// When the current join phase ends at the latest.
+    pub fn rebalance_deadline(&self) -> Instant {
+        self.rebalance_deadline
+    }
+
+    /// When members that haven't synced the current generation are removed.
+    pub fn sync_deadline(&self) -> Instant {
+        self.sync_deadline
+    }
+
+    /// The number of members, counting those that have yet to rejoin with their new id.
+    pub fn size(&self) -> usize {
+        self.members.len() + self.pending_members.len()
+    }
+
+    /// Whether `member_id` is the leader.
+    pub fn is_leader(&self, member_id: &str) -> bool {
+        self.leader_id.as_deref() == Some(member_id)
+    }
+
+    /// The member with id `member_id`.
+    pub fn member(&self, member_id: &str) -> Option<&Member> {
+        self.members.iter().find(|m| m.member_id == member_id)
+    }
+
+    /// The member with id `member_id`.
+    pub fn member_mut(&mut self, member_id: &str) -> Option<&mut Member> {
+        self.members.iter_mut().find(|m| m.member_id == member_id)
+    }
+
+    /// The member id of the static member with `group_instance_id`.
+    pub fn static_member_id(&self, group_instance_id: &str) -> Option<&str> {
+        self.members
+            .iter()
+            .find(|m| m.group_instance_id.as_deref() == Some(group_instance_id))
+            .map(|m| m.member_id.as_str())
+    }
+
+    /// Checks that `member_id` is a member, and is the current instance if `group_instance_id`
+    /// is set.
+    ///
+    /// # Errors
+    ///
+    /// - [`KafkaBrokerError::UnknownMemberId`] if the group has no such member.
+    /// - [`KafkaBrokerError::FencedInstanceId`] if the instance is now known by another
+    ///   member id.
+    pub fn validate_member(
+        &self,
+        member_id: &str,
+        group_instance_id: Option<&str>,
+    ) -> KafkaResult<()> {
+        if let Some(instance_id) = group_instance_id {
+            match self.static_member_id(instance_id) {
+                Some(current) if current != member_id => {
+                    return Err(KafkaBrokerError::FencedInstanceId {
+                        group_id: self.group_id.clone(),
+                        instance_id: instance_id.to_string(),
+                    })
+                }
+                _ => {}
+            }
+        }
+        if self.member(member_id).is_none() {
+            return Err(self.unknown_member(member_id));
+        }
+        Ok(())
+    }
+
+    /// The error for a request from `member_id`, which isn't a member.
+    pub fn unknown_member(&self, member_id: &str) -> KafkaBrokerError {
+        KafkaBrokerError::UnknownMemberId {
+            group_id: self.group_id.clone(),
+            member_id: member_id.to_string(),
+        }
+    }
+
+    /// Whether a member of `protocol_type` supporting `protocols` can join: the group must be
+    /// of the same type, and at least one of the assignors must be supported by every member.
+    pub fn supports_protocols(&self, protocol_type: &str, protocols: &[(String, Vec<u8>)]) -> bool {
+        if protocol_type.is_empty() || protocols.is_empty() {
+            return false;
+        }
+        if self.members.is_empty() {
+            return true;
+        }
+        self.protocol_type.as_deref() == Some(protocol_type)
+            && protocols
+                .iter()
+                .any(|(name, _)| self.members.iter().all(|m| m.supports(name)))
+    }
+
+    /// The group as reported by DescribeGroups. Metadata and assignments are only reported
+    /// once the group is stable, as they may not match the members' assignor before.
+    pub fn describe(&self) -> GroupDescription {
+        let stable = self.state == GroupState::Stable;
+        GroupDescription {
+            state: self.state,
+            protocol_type: self.protocol_type.clone().unwrap_or_default(),
+            protocol_data: match &self.protocol_name {
+                Some(protocol_name) if stable => protocol_name.clone(),
+                _ => String::new(),
+            },
+            members: self
+                .members
+                .iter()
+                .map(|m| MemberDescription {
+                    member_id: m.member_id.clone(),
+                    group_instance_id: m.group_instance_id.clone(),
+                    client_id: m.client_id.clone(),
+                    client_host: m.client_host.clone(),
+                    metadata: if stable {
+                        m.metadata(self.protocol_name.as_deref())
+                    } else {
+                        Vec::new()
+                    },
+                    assignment: if stable {
+                        m.assignment.clone()
+                    } else {
+                        Vec::new()
+                    },
+                })
+                .collect(),
+        }
+    }
+
+    /// Whether a member of a `consumer` group subscribes to `topic`, going by its metadata for
+    /// the current assignor. Other groups have no subscriptions the broker can read.
+    pub fn is_subscribed_to(&self, topic: &str) -> bool {
+        if self.protocol_type.as_deref() != Some(CONSUMER_PROTOCOL_TYPE) {
+            return false;
+        }
+        self.members.iter().any(|m| {
+            let metadata = m.metadata(self.protocol_name.as_deref());
+            let mut cursor = metadata.as_slice();
+            // Newer versions only add fields, so they are read as the latest one we know.
+            read_i16(&mut cursor)
+                .and_then(|version| ConsumerProtocolSubscription::read(&mut cursor, version.min(3)))
+                .is_ok_and(|subscription| subscription.topics.iter().any(|t| t == topic))
+        })
+    }
+
+    /// Every committed offset.
+    pub fn offsets(&self) -> &HashMap<TopicPartition, OffsetAndMetadata> {
+        &self.offsets
+    }
+
+    /// The number of committed offsets.
+    pub fn offset_count(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// Commits `offset` for `topic_partition`.
+    pub fn commit_offset(&mut self, topic_partition: TopicPartition, offset: OffsetAndMetadata) {
+        self.offsets.insert(topic_partition, offset);
+    }
+
+    /// Deletes the offset committed for `topic_partition`.
+    pub fn remove_offset(&mut self, topic_partition: &TopicPartition) {
+        self.offsets.remove(topic_partition);
+    }
+
+    /// The partitions whose offsets have expired by `now_ms`. Offsets committed with a
+    /// retention time expire when it runs out. Others are kept as long as the group has
+    /// members, then for `retention_ms` since it became empty, or since their commit for a
+    /// group that never had members.
+    pub fn expired_offsets(&self, now_ms: i64, retention_ms: i64) -> Vec<TopicPartition> {
+        let is_empty = self.state == GroupState::Empty;
+        self.offsets
+            .iter()
+            .filter(|(_, offset)| match offset.expire_timestamp {
+                Some(expire_timestamp) => now_ms >= expire_timestamp,
+                None if !is_empty => false,
+                None => {
+                    let base = match self.protocol_type {
+                        Some(_) if self.current_state_timestamp >= 0 => {
+                            self.current_state_timestamp
+                        }
+                        _ => offset.commit_timestamp,
+                    };
+                    now_ms - base >= retention_ms
+                }
+            })
+            .map(|(topic_partition, _)| topic_partition.clone())
+            .collect()
+    }
+
+    /// Records `member_id` as a new member that must join again with it before `deadline`.
+    pub fn add_pending_member(&mut self, member_id: String, deadline: Instant) {
+        self.pending_members.push((member_id, deadline));
+    }
+
+    /// Removes `member_id` from the pending members. Returns whether it was one.
+    pub fn remove_pending_member(&mut self, member_id: &str) -> bool {
+        let count = self.pending_members.len();
+        self.pending_members.retain(|(id, _)| id != member_id);
+        self.pending_members.len() != count
+    }
+
+    /// Adds `member`, which has joined the current rebalance.
+    pub fn add_member(&mut self, mut member: Member) {
+        if self.members.is_empty() {
+            self.protocol_type = Some(member.protocol_type.clone());
+        }
+        if self.leader_id.is_none() {
+            self.leader_id = Some(member.member_id.clone());
+        }
+        member.awaiting_join = true;
+        self.members.push(member);
+    }
+
+    /// Gives the static member `old_member_id` the new id `new_member_id`, as a new instance of
+    /// it has joined.
+    pub fn replace_static_member(&mut self, old_member_id: &str, new_member_id: &str) {
+        if let Some(member) = self.member_mut(old_member_id) {
+            member.member_id = new_member_id.to_string();
+            // Whatever the old instance was waiting for is now the new instance's business.
+            member.awaiting_join = false;
+            member.awaiting_sync = false;
+            member.join_result = None;
+            member.sync_result = None;
+        }
+        if self.is_leader(old_member_id) {
+            self.leader_id = Some(new_member_id.to_string());
+        }
+        self.needs_store = true;
+    }
+
+    /// Removes `member_id` and rebalances the rest of the group. Returns whether it was a
+    /// member.
+    pub fn remove_member_and_rebalance(
+        &mut self,
+        member_id: &str,
+        now: Instant,
+        initial_delay: Duration,
+        reason: &str,
+    ) -> bool {
+        let Some(index) = self.members.iter().position(|m| m.member_id == member_id) else {
+            return false;
+        };
+        self.members.remove(index);
+        if self.is_leader(member_id) {
+            self.leader_id = self.members.first().map(|m| m.member_id.clone());
+        }
+        match self.state {
+            GroupState::Stable | GroupState::CompletingRebalance => {
+                self.prepare_rebalance(now, initial_delay, reason)
+            }
+            GroupState::PreparingRebalance => {
+                self.maybe_complete_join(now);
+            }
+            GroupState::Empty | GroupState::Dead => {}
+        }
+        true
+    }
+
+    /// Starts a rebalance, unless one is already under way. Members waiting in SyncGroup are
+    /// told to rejoin.
+    pub fn prepare_rebalance(&mut self, now: Instant, initial_delay: Duration, reason: &str) {
+        match self.state {
+            GroupState::PreparingRebalance | GroupState::Dead => return,
+            GroupState::CompletingRebalance => {
+                for member in self.members.iter_mut().filter(|m| m.awaiting_sync) {
+                    member.awaiting_sync = false;
+                    member.sync_result = Some(Err(KafkaBrokerError::RebalanceInProgress(
+                        self.group_id.clone(),
+                    )));
+                }
+            }
+            GroupState::Empty | GroupState::Stable => {}
+        }
+        for member in &mut self.members {
+            member.pending_sync = false;
+        }
+
+        let rebalance_timeout = self
+            .members
+            .iter()
+            .map(|m| m.rebalance_timeout)
+            .max()
+            .unwrap_or_default();
+        if self.state == GroupState::Empty && !initial_delay.is_zero() {
+            let limit = now + rebalance_timeout;
+            self.initial_rebalance_limit = Some(limit);
+            self.rebalance_deadline = (now + initial_delay).min(limit);
+        } else {
+            self.initial_rebalance_limit = None;
+            self.rebalance_deadline = now + rebalance_timeout;
+        }
+
+        info!(
+            "Preparing to rebalance group {} in state {:?} with old generation {} (reason: {})",
+            self.group_id, self.state, self.generation_id, reason
+        );
+        self.transition_to(GroupState::PreparingRebalance);
+    }
+
+    /// Pushes the end of the first rebalance of the group `initial_delay` past now, as a new
+    /// member has joined, without going past the rebalance timeout.
+    pub fn extend_initial_rebalance(&mut self, now: Instant, initial_delay: Duration) {
+        if let Some(limit) = self.initial_rebalance_limit {
+            self.rebalance_deadline = (now + initial_delay).min(limit);
+        }
+    }
+
+    /// Ends the join phase if every member has rejoined (outside of the first rebalance), or
+    /// the rebalance deadline has passed. Returns whether it ended.
+    pub fn maybe_complete_join(&mut self, now: Instant) -> bool {
+        if self.state != GroupState::PreparingRebalance {
+            return false;
+        }
+        let all_joined = self.initial_rebalance_limit.is_none()
+            && self.pending_members.is_empty()
+            && self.members.iter().all(|m| m.awaiting_join);
+        if !all_joined && now < self.rebalance_deadline {
+            return false;
+        }
+        self.complete_join(now);
+        true
+    }
+
+    /// Forms the next generation from the members that have rejoined, and hands every one of
+    /// them its JoinGroup result.
+    fn complete_join(&mut self, now: Instant) {
+        // Dynamic members that haven't rejoined are gone; static members keep their place.
+        let group_id = &self.group_id;
+        self.members.retain(|m| {
+            let keep = m.awaiting_join || m.group_instance_id.is_some();
+            if !keep {
+                info!(
+                    "Removing member {} from group {}: it didn't rejoin in time",
+                    m.member_id, group_id
+                );
+            }
+            keep
+        });
+        if !self
+            .leader_id
+            .as_deref()
+            .and_then(|leader| self.member(leader))
+            .is_some_and(|leader| leader.awaiting_join)
+        {
+            self.leader_id = self
+                .members
+                .iter()
+                .find(|m| m.awaiting_join)
+                .or(self.members.first())
+                .map(|m| m.member_id.clone());
+        }
+
+        self.generation_id += 1;
+        self.initial_rebalance_limit = None;
+        if self.members.is_empty() {
+            self.protocol_name = None;
+            self.transition_to(GroupState::Empty);
+            self.needs_store = true;
+            info!(
+                "Group {} with generation {} is now empty",
+                self.group_id, self.generation_id
+            );
+            return;
+        }
+
+        self.protocol_name = self.select_protocol();
+        self.transition_to(GroupState::CompletingRebalance);
+        self.sync_deadline = now
+            + self
+                .members
+                .iter()
+                .map(|m| m.rebalance_timeout)
+                .max()
+                .unwrap_or_default();
+        info!(
+            "Stabilized group {} generation {} with {} members (protocol {:?})",
+            self.group_id,
+            self.generation_id,
+            self.members.len(),
+            self.protocol_name
+        );
+
+        let leader_id = self.leader_id.clone().unwrap_or_default();
+        let members = self.joined_members();
+        for member in &mut self.members {
+            member.pending_sync = true;
+            member.heartbeat(now);
+            if member.awaiting_join {
+                member.awaiting_join = false;
+                member.join_result = Some(Ok(JoinGroupResult {
+                    generation_id: self.generation_id,
+                    protocol_type: self.protocol_type.clone(),
+                    protocol_name: self.protocol_name.clone(),
+                    leader_id: leader_id.clone(),
+                    member_id: member.member_id.clone(),
+                    members: if member.member_id == leader_id {
+                        members.clone()
+                    } else {
+                        Vec::new()
+                    },
+                    skip_assignment: false,
+                }));
+            }
+        }
+    }
+
+    /// The JoinGroup result for `member_id` in the current generation, for a member whose
+    /// rejoin doesn't need a rebalance.
+    pub fn current_join_result(&self, member_id: &str, skip_assignment: bool) -> JoinGroupResult {
+        let is_leader = self.is_leader(member_id);
+        JoinGroupResult {
+            generation_id: self.generation_id,
+            protocol_type: self.protocol_type.clone(),
+            protocol_name: self.protocol_name.clone(),
+            leader_id: self.leader_id.clone().unwrap_or_default(),
+            member_id: member_id.to_string(),
+            members: if is_leader {
+                self.joined_members()
+            } else {
+                Vec::new()
+            },
+            skip_assignment: skip_assignment && is_leader,
+        }
+    }
+
+    /// Every member with its metadata for the chosen assignor, as sent to the leader.
+    fn joined_members(&self) -> Vec<JoinedMember> {
+        self.members
+            .iter()
+            .map(|m| JoinedMember {
+                member_id: m.member_id.clone(),
+                group_instance_id: m.group_instance_id.clone(),
+                metadata: m.metadata(self.protocol_name.as_deref()),
+            })
+            .collect()
+    }
+
+    /// Picks the assignor for the new generation: among those every member supports, the one
+    /// preferred by the most members.
+    fn select_protocol(&self) -> Option<String> {
+        let first = self.members.first()?;
+        let candidates: Vec<&str> = first
+            .protocols
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .filter(|name| self.members.iter().all(|m| m.supports(name)))
+            .collect();
+        let mut votes = vec![0usize; candidates.len()];
+        for member in &self.members {
+            let preferred = member
+                .protocols
+                .iter()
+                .find_map(|(name, _)| candidates.iter().position(|c| c == name));
+            if let Some(index) = preferred {
+                votes[index] += 1;
+            }
+        }
+        let mut best: Option<(usize, &str)> = None;
+        for (candidate, count) in candidates.iter().zip(votes) {
+            if best.is_none_or(|(most, _)| count > most) {
+                best = Some((count, candidate));
+            }
+        }
+        best.map(|(_, name)| name.to_string())
+    }
+
+    /// Records that `member_id` has sent SyncGroup for the current generation.
+    pub fn mark_synced(&mut self, member_id: &str, now: Instant) {
+        if let Some(member) = self.member_mut(member_id) {
+            member.pending_sync = false;
+            member.heartbeat(now);
+        }
+    }
+
+    /// Makes `member_id` wait in SyncGroup for the leader's assignment.
+    pub fn await_sync(&mut self, member_id: &str) {
+        if let Some(member) = self.member_mut(member_id) {
+            member.awaiting_sync = true;
+        }
+    }
+
+    /// Installs the leader's `assignments` and makes the group stable, handing the members
+    /// waiting in SyncGroup their assignment. Members the leader left out get an empty one.
+    pub fn complete_sync(&mut self, mut assignments: Vec<(String, Vec<u8>)>) {
+        for member in &mut self.members {
+            member.assignment = assignments
+                .iter_mut()
+                .find(|(id, _)| *id == member.member_id)
+                .map(|(_, assignment)| std::mem::take(assignment))
+                .unwrap_or_default();
+            if member.awaiting_sync {
+                member.awaiting_sync = false;
+                member.sync_result = Some(Ok(SyncGroupResult {
+                    protocol_type: self.protocol_type.clone(),
+                    protocol_name: self.protocol_name.clone(),
+                    assignment: member.assignment.clone(),
+                }));
+            }
+        }
+        self.transition_to(GroupState::Stable);
+        self.needs_store = true;
+        info!(
+            "Assignment received from leader {:?} for group {} for generation {}",
+            self.leader_id, self.group_id, self.generation_id
+        );
+    }
+
+    /// The SyncGroup result for `member_id` in the current generation.
+    pub fn current_sync_result(&self, member_id: &str) -> SyncGroupResult {
+        SyncGroupResult {
+            protocol_type: self.protocol_type.clone(),
+            protocol_name: self.protocol_name.clone(),
+            assignment: self
+                .member(member_id)
+                .map(|m| m.assignment.clone())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Applies every deadline that has passed by `now`: new members that never rejoined with
+    /// their id are dropped, members whose session has expired are removed, members that
+    /// didn't sync the new generation in time are removed, and a join phase that is due ends.
+    /// Returns whether the group changed.
+    pub fn expire(&mut self, now: Instant, initial_delay: Duration) -> bool {
+        let pending = self.pending_members.len();
+        self.pending_members.retain(|&(_, deadline)| deadline > now);
+        let mut changed = self.pending_members.len() != pending;
+
+        let expired: Vec<String> = self
+            .members
+            .iter()
+            .filter(|m| !m.awaiting_join && !m.awaiting_sync && m.heartbeat_deadline <= now)
+            .map(|m| m.member_id.clone())
+            .collect();
+        for member_id in expired {
+            info!(
+                "Member {} in group {} has failed, removing it from the group",
+                member_id, self.group_id
+            );
+            self.remove_member_and_rebalance(&member_id, now, initial_delay, "session expired");
+            changed = true;
+        }
+
+        if self.state == GroupState::CompletingRebalance && now >= self.sync_deadline {
+            let unsynced: Vec<String> = self
+                .members
+                .iter()
+                .filter(|m| m.pending_sync)
+                .map(|m| m.member_id.clone())
+                .collect();
+            for member_id in unsynced {
+                info!(
+                    "Member {} in group {} didn't sync generation {} in time, removing it",
+                    member_id, self.group_id, self.generation_id
+                );
+                self.remove_member_and_rebalance(
+                    &member_id,
+                    now,
+                    initial_delay,
+                    "members failed to sync",
+                );
+                changed = true;
+            }
+        }
+
+        self.maybe_complete_join(now) || changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A dynamic member's JoinGroup for group "g".
+    fn join_request() -> JoinRequest {
+        JoinRequest {
+            group_id: "g".to_string(),
+            member_id: String::new(),
+            group_instance_id: None,
+            client_id: "c".to_string(),
+            client_host: "/127.0.0.1".to_string(),
+            session_timeout_ms: 10_000,
+            rebalance_timeout_ms: 60_000,
+            protocol_type: CONSUMER_PROTOCOL_TYPE.to_string(),
+            protocols: vec![("range".to_string(), Vec::new())],
+            require_known_member_id: false,
+            supports_skip_assignment: false,
+            reason: None,
+        }
+    }
+
+    fn add_member(group: &mut Group, member_id: &str, now: Instant) {
+        group.add_member(Member::new(member_id.to_string(), &join_request(), now));
+    }
+
+    #[test]
+    fn a_group_goes_through_a_rebalance_to_stable() {
+        let now = Instant::now();
+        let initial_delay = Duration::from_secs(3);
+        let mut group = Group::new("g", now);
+        assert_eq!(group.state(), GroupState::Empty);
+
+        add_member(&mut group, "m1", now);
+        group.prepare_rebalance(now, initial_delay, "adding new member m1");
+        assert_eq!(group.state(), GroupState::PreparingRebalance);
+        // The first rebalance waits for more members, each new one pushing the end back.
+        assert!(!group.maybe_complete_join(now));
+        let later = now + Duration::from_secs(1);
+        add_member(&mut group, "m2", later);
+        group.extend_initial_rebalance(later, initial_delay);
+        assert!(!group.maybe_complete_join(now + initial_delay));
+        assert!(group.maybe_complete_join(later + initial_delay));
+
+        assert_eq!(group.state(), GroupState::CompletingRebalance);
+        assert_eq!(group.generation_id(), 1);
+        assert_eq!(group.protocol_name(), Some("range"));
+        let leader_join = group.member_mut("m1").unwrap().take_join_result();
+        let leader_join = leader_join.unwrap().unwrap();
+        assert_eq!(leader_join.leader_id, "m1");
+        assert_eq!(leader_join.members.len(), 2);
+        let follower_join = group.member_mut("m2").unwrap().take_join_result();
+        assert!(follower_join.unwrap().unwrap().members.is_empty());
+
+        // The follower waits for the leader's assignment.
+        group.mark_synced("m2", later);
+        group.await_sync("m2");
+        group.mark_synced("m1", later);
+        group.complete_sync(vec![
+            ("m1".to_string(), b"a".to_vec()),
+            ("m2".to_string(), b"b".to_vec()),
+        ]);
+        assert_eq!(group.state(), GroupState::Stable);
+        let follower_sync = group.member_mut("m2").unwrap().take_sync_result();
+        assert_eq!(follower_sync.unwrap().unwrap().assignment, b"b");
+        assert_eq!(group.current_sync_result("m1").assignment, b"a");
+        assert!(group.take_needs_store());
+
+        // A member leaving sends the group back to rebalancing; once the last one is gone, it
+        // is empty again.
+        assert!(group.remove_member_and_rebalance("m1", later, initial_delay, "m1 left"));
+        assert_eq!(group.state(), GroupState::PreparingRebalance);
+        assert!(group.remove_member_and_rebalance("m2", later, initial_delay, "m2 left"));
+        assert_eq!(group.state(), GroupState::Empty);
+        assert_eq!(group.generation_id(), 2);
+    }
+}
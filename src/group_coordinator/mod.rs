@@ -0,0 +1,1132 @@
+//! The group coordinator: membership of consumer groups under Kafka's classic group protocol,
+//! and under the consumer group protocol of KIP-848.
+//!
+//! This broker coordinates every group, so FindCoordinator always points clients back at it.
+//! Members of classic groups then go through JoinGroup and SyncGroup to form a generation and
+//! receive their assignment from the leader, heartbeat to keep their session alive, and
+//! LeaveGroup when they shut down (see [`group`] for the states a group moves through).
+//! Members of consumer groups only send ConsumerGroupHeartbeat, and the coordinator assigns
+//! the partitions itself (see [`consumer`], [`consumer_group`] and [`assignor`]).
+//!
+//! JoinGroup and SyncGroup requests that must wait for the rest of their group are parked in a
+//! [`Purgatory`], keyed by [`DelayedOperationKey::Group`]: every change to a group wakes them to
+//! check whether their result is in. Deadlines that no request is waiting on (sessions, new
+//! members that never rejoin) are enforced by [`GroupCoordinator::run`].
+//!
+//! Operators list, describe and delete groups and their offsets through the requests of
+//! [`admin`].
+//!
+//! Groups and their committed offsets are kept in the internal topic `__consumer_offsets` (see
+//! [`records`] and [`offsets`]), and rebuilt from it by [`GroupCoordinator::load`] when the
+//! broker starts. Members of a group that was stable carry on where they left off, as long as
+//! they heartbeat before their session expires.
+
+mod admin;
+mod assignor;
+mod consumer;
+mod consumer_group;
+mod group;
+mod offsets;
+mod records;
+
+pub use assignor::{Assignment, Assignor, TopicMetadata};
+pub use consumer::{ConsumerHeartbeatRequest, ConsumerHeartbeatResult};
+pub use group::{GroupState, JoinGroupResult, SyncGroupResult};
+pub use offsets::{CommitRequest, OffsetAndMetadata};
+pub use records::GROUP_METADATA_TOPIC_NAME;
+
+use crate::config::Config;
+use crate::kafka_protocol::kafka_error::{KafkaBrokerError, KafkaResult};
+use crate::purgatory::{DelayedOperationKey, Purgatory};
+use crate::storage::{self, now_ms, LogGuard, LogManager, PartitionLog, TopicPartition};
+use consumer_group::ConsumerGroup;
+use group::{Group, Member};
+use records::GroupRecord;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::select;
+use tokio::time::{self, Instant, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+use uuid::Uuid;
+
+/// How often session timeouts and other group deadlines are checked.
+const EXPIRATION_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The leader epoch of `__consumer_offsets` partitions. This broker is the only one, and leads
+/// every partition from the start.
+const LEADER_EPOCH: i32 = 0;
+
+/// A member's JoinGroup, as the coordinator sees it.
+#[derive(Debug, Clone)]
+pub struct JoinRequest {
+    /// The group to join.
+    pub group_id: String,
+    /// The member id, or empty for a member joining for the first time.
+    pub member_id: String,
+    /// The `group.instance.id` of a static member.
+    pub group_instance_id: Option<String>,
+    /// The client id from the request header.
+    pub client_id: String,
+    /// The address the request came from.
+    pub client_host: String,
+    /// How long the member may go without heartbeating.
+    pub session_timeout_ms: i32,
+    /// How long the member may take to rejoin once a rebalance starts.
+    pub rebalance_timeout_ms: i32,
+    /// The group's protocol type (e.g. `consumer`).
+    pub protocol_type: String,
+    /// The assignors the member supports, most preferred first, with its metadata for each.
+    pub protocols: Vec<(String, Vec<u8>)>,
+    /// Whether a new member must be given its id with `MEMBER_ID_REQUIRED` before it can join
+    /// (JoinGroup v4+).
+    pub require_known_member_id: bool,
+    /// Whether a static leader rejoining a stable group can be told to keep the current
+    /// assignment rather than trigger a rebalance (JoinGroup v9+).
+    pub supports_skip_assignment: bool,
+    /// Why the member is joining, for logging.
+    pub reason: Option<String>,
+}
+
+/// A member's SyncGroup, as the coordinator sees it.
+#[derive(Debug, Clone)]
+pub struct SyncRequest {
+    /// The group id.
+    pub group_id: String,
+    /// The generation the member joined.
+    pub generation_id: i32,
+    /// The member id.
+    pub member_id: String,
+    /// The `group.instance.id` of a static member.
+    pub group_instance_id: Option<String>,
+    /// The protocol type the member expects, if it sent one (SyncGroup v5+).
+    pub protocol_type: Option<String>,
+    /// The assignor the member expects, if it sent one (SyncGroup v5+).
+    pub protocol_name: Option<String>,
+    /// The assignment of every member, if the sender is the leader.
+    pub assignments: Vec<(String, Vec<u8>)>,
+}
+
+/// A member leaving its group: by member id, or by `group.instance.id` for static members.
+#[derive(Debug, Clone)]
+pub struct LeavingMember {
+    /// The member id; may be empty when the instance id is set.
+    pub member_id: String,
+    /// The `group.instance.id` of a static member.
+    pub group_instance_id: Option<String>,
+}
+
+/// The group coordinator's settings.
+#[derive(Debug, Clone)]
+pub struct GroupConfig {
+    /// The shortest session timeout a member may ask for.
+    pub min_session_timeout_ms: i32,
+    /// The longest session timeout a member may ask for.
+    pub max_session_timeout_ms: i32,
+    /// How long the first rebalance of an empty group waits for more members.
+    pub initial_rebalance_delay: Duration,
+    /// The most members a group may have.
+    pub max_size: usize,
+    /// How long offsets are kept once they are no longer in use.
+    pub offsets_retention_ms: i64,
+    /// How often expired offsets are deleted.
+    pub offsets_retention_check_interval: Duration,
+    /// The longest metadata a committed offset may carry.
+    pub offset_metadata_max_bytes: usize,
+    /// How long a consumer group member may go without heartbeating.
+    pub consumer_session_timeout: Duration,
+    /// How often consumer group members are told to heartbeat.
+    pub consumer_heartbeat_interval_ms: i32,
+    /// The most members a consumer group may have.
+    pub consumer_max_size: usize,
+    /// The server assignors consumer groups may use, the first being the default.
+    pub consumer_assignors: Vec<Assignor>,
+}
+
+impl GroupConfig {
+    /// The group settings from the broker configuration.
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            min_session_timeout_ms: config.group_min_session_timeout_ms,
+            max_session_timeout_ms: config.group_max_session_timeout_ms,
+            initial_rebalance_delay: Duration::from_millis(
+                config.group_initial_rebalance_delay_ms as u64,
+            ),
+            max_size: config.group_max_size,
+            offsets_retention_ms: config.offsets_retention_minutes * 60 * 1000,
+            offsets_retention_check_interval: Duration::from_millis(
+                config.offsets_retention_check_interval_ms,
+            ),
+            offset_metadata_max_bytes: config.offset_metadata_max_bytes,
+            consumer_session_timeout: Duration::from_millis(
+                config.group_consumer_session_timeout_ms as u64,
+            ),
+            consumer_heartbeat_interval_ms: config.group_consumer_heartbeat_interval_ms,
+            consumer_max_size: config.group_consumer_max_size,
+            consumer_assignors: config.group_consumer_assignors.clone(),
+        }
+    }
+}
+
+/// How a JoinGroup or SyncGroup proceeds once the group has been updated.
+enum Outcome<T> {
+    /// The result is known straight away.
+    Done(T),
+    /// The member must wait for the rest of its group, until `deadline` at the latest.
+    Waiting {
+        member_id: String,
+        deadline: Instant,
+    },
+}
+
+/// Every consumer group coordinated by this broker.
+pub struct GroupCoordinator {
+    config: GroupConfig,
+    /// The classic groups.
+    groups: Mutex<HashMap<String, Group>>,
+    /// The consumer groups. Locked after `groups` when both are needed.
+    consumer_groups: Mutex<HashMap<String, ConsumerGroup>>,
+    /// JoinGroup and SyncGroup requests waiting on their group.
+    purgatory: Purgatory,
+    /// Where the `__consumer_offsets` partitions are stored.
+    logs: Arc<LogManager>,
+    /// The number of `__consumer_offsets` partitions.
+    num_partitions: i32,
+}
+
+impl GroupCoordinator {
+    /// Creates a coordinator without any groups, keeping them in the `num_partitions`
+    /// partitions of `__consumer_offsets` in `logs`.
+    pub fn new(config: GroupConfig, logs: Arc<LogManager>, num_partitions: i32) -> Self {
+        Self {
+            config,
+            groups: Mutex::new(HashMap::new()),
+            consumer_groups: Mutex::new(HashMap::new()),
+            purgatory: Purgatory::new("group joins and syncs"),
+            logs,
+            num_partitions,
+        }
+    }
+
+    /// Rebuilds every classic and consumer group and its committed offsets by replaying the
+    /// `__consumer_offsets` partitions.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if a partition can't be read, or
+    /// [`KafkaBrokerError::CorruptMessage`] if it holds a damaged record.
+    pub async fn load(&self) -> KafkaResult<()> {
+        let now = Instant::now();
+        let session_deadline = now + self.config.consumer_session_timeout;
+        let mut groups: HashMap<String, Group> = HashMap::new();
+        let mut consumer_groups: HashMap<String, ConsumerGroup> = HashMap::new();
+        // Offsets are kept apart until every record has been read, as a group id may change
+        // type along the way.
+        let mut offsets: HashMap<String, HashMap<TopicPartition, OffsetAndMetadata>> =
+            HashMap::new();
+        for partition in 0..self.num_partitions {
+            let topic_partition = TopicPartition::new(GROUP_METADATA_TOPIC_NAME, partition);
+            let Some(log) = self.logs.get(&topic_partition).await else {
+                continue;
+            };
+            let records = storage::with_log(&log, |log| records::read_log(log)).await?;
+            for record in records {
+                match record {
+                    GroupRecord::OffsetCommit {
+                        group_id,
+                        topic_partition,
+                        offset: Some(offset),
+                    } => {
+                        offsets
+                            .entry(group_id)
+                            .or_default()
+                            .insert(topic_partition, offset);
+                    }
+                    GroupRecord::OffsetCommit {
+                        group_id,
+                        topic_partition,
+                        offset: None,
+                    } => {
+                        if let Some(offsets) = offsets.get_mut(&group_id) {
+                            offsets.remove(&topic_partition);
+                        }
+                    }
+                    GroupRecord::GroupMetadata {
+                        group_id,
+                        metadata: Some(metadata),
+                    } => groups
+                        .entry(group_id)
+                        .or_insert_with_key(|group_id| Group::new(group_id, now))
+                        .restore(metadata, now),
+                    GroupRecord::GroupMetadata {
+                        group_id,
+                        metadata: None,
+                    } => {
+                        groups.remove(&group_id);
+                    }
+                    GroupRecord::ConsumerGroupMetadata {
+                        group_id,
+                        value: None,
+                    } => {
+                        consumer_groups.remove(&group_id);
+                    }
+                    record => {
+                        let group_id = record.group_id();
+                        match consumer_groups.get_mut(group_id) {
+                            Some(group) => group.replay(record, session_deadline),
+                            // The tombstones of a deleted group may outlive its other records.
+                            None if record.is_tombstone() => {}
+                            None => {
+                                let mut group = ConsumerGroup::new(group_id);
+                                let group_id = group_id.to_string();
+                                group.replay(record, session_deadline);
+                                consumer_groups.insert(group_id, group);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        let offset_count: usize = offsets.values().map(HashMap::len).sum();
+        for (group_id, offsets) in offsets.into_iter().filter(|(_, o)| !o.is_empty()) {
+            if let Some(group) = consumer_groups.get_mut(&group_id) {
+                for (topic_partition, offset) in offsets {
+                    group.commit_offset(topic_partition, offset);
+                }
+                continue;
+            }
+            let group = groups
+                .entry(group_id)
+                .or_insert_with_key(|group_id| Group::new(group_id, now));
+            for (topic_partition, offset) in offsets {
+                group.commit_offset(topic_partition, offset);
+            }
+        }
+        info!(
+            "Loaded {} classic groups, {} consumer groups and {} committed offsets from {}",
+            groups.len(),
+            consumer_groups.len(),
+            offset_count,
+            GROUP_METADATA_TOPIC_NAME
+        );
+        *self.groups.lock().unwrap() = groups;
+        *self.consumer_groups.lock().unwrap() = consumer_groups;
+        Ok(())
+    }
+
+    /// Adds a member to its group, or has a known member rejoin it, and waits for the join
+    /// phase to end. Returns the member's view of the new generation.
+    ///
+    /// # Errors
+    ///
+    /// - [`KafkaBrokerError::InvalidGroupId`] for an empty group id.
+    /// - [`KafkaBrokerError::InvalidSessionTimeout`] for a session timeout out of range.
+    /// - [`KafkaBrokerError::InconsistentGroupProtocol`] if the member's protocols don't match
+    ///   the group's.
+    /// - [`KafkaBrokerError::MemberIdRequired`] for a new member that must rejoin with the
+    ///   member id it is given.
+    /// - [`KafkaBrokerError::UnknownMemberId`] or [`KafkaBrokerError::FencedInstanceId`] for a
+    ///   member the group doesn't know (any more).
+    /// - [`KafkaBrokerError::GroupMaxSizeReached`] if the group is full.
+    /// - [`KafkaBrokerError::GroupIdNotFound`] if the group is a consumer group.
+    /// - [`KafkaBrokerError::CoordinatorNotAvailable`] if the broker shuts down meanwhile.
+    pub async fn join_group(&self, request: JoinRequest) -> KafkaResult<JoinGroupResult> {
+        let outcome = self.join(&request, Instant::now());
+        self.group_changed(&request.group_id).await;
+        match outcome? {
+            Outcome::Done(result) => Ok(result),
+            Outcome::Waiting {
+                member_id,
+                deadline,
+            } => {
+                self.wait_for(
+                    &request.group_id,
+                    &member_id,
+                    deadline,
+                    |group, member_id| {
+                        let deadline = group.rebalance_deadline();
+                        let Some(member) = group.member_mut(member_id) else {
+                            return Err(group.unknown_member(member_id));
+                        };
+                        Ok((
+                            member.take_join_result(),
+                            member.is_awaiting_join(),
+                            deadline,
+                        ))
+                    },
+                )
+                .await
+            }
+        }
+    }
+
+    /// Handles a SyncGroup: the leader's hands out the assignment and completes the rebalance,
+    /// while the other members wait for it. Returns the member's assignment.
+    ///
+    /// # Errors
+    ///
+    /// - [`KafkaBrokerError::UnknownMemberId`] or [`KafkaBrokerError::FencedInstanceId`] for a
+    ///   member the group doesn't know (any more).
+    /// - [`KafkaBrokerError::IllegalGeneration`] for a generation other than the current one.
+    /// - [`KafkaBrokerError::InconsistentGroupProtocol`] if the expected protocol doesn't match.
+    /// - [`KafkaBrokerError::RebalanceInProgress`] if a new rebalance has started.
+    /// - [`KafkaBrokerError::CoordinatorNotAvailable`] if the broker shuts down meanwhile.
+    pub async fn sync_group(&self, request: SyncRequest) -> KafkaResult<SyncGroupResult> {
+        let outcome = self.sync(request.clone(), Instant::now());
+        self.group_changed(&request.group_id).await;
+        match outcome? {
+            Outcome::Done(result) => Ok(result),
+            Outcome::Waiting {
+                member_id,
+                deadline,
+            } => {
+                self.wait_for(
+                    &request.group_id,
+                    &member_id,
+                    deadline,
+                    |group, member_id| {
+                        let deadline = group.sync_deadline();
+                        let Some(member) = group.member_mut(member_id) else {
+                            return Err(group.unknown_member(member_id));
+                        };
+                        Ok((
+                            member.take_sync_result(),
+                            member.is_awaiting_sync(),
+                            deadline,
+                        ))
+                    },
+                )
+                .await
+            }
+        }
+    }
+
+    /// Keeps a member's session alive.
+    ///
+    /// # Errors
+    ///
+    /// - [`KafkaBrokerError::UnknownMemberId`] or [`KafkaBrokerError::FencedInstanceId`] for a
+    ///   member the group doesn't know (any more).
+    /// - [`KafkaBrokerError::IllegalGeneration`] for a generation other than the current one.
+    /// - [`KafkaBrokerError::RebalanceInProgress`] if the member must rejoin.
+    pub fn heartbeat(
+        &self,
+        group_id: &str,
+        member_id: &str,
+        group_instance_id: Option<&str>,
+        generation_id: i32,
+    ) -> KafkaResult<()> {
+        let mut groups = self.groups.lock().unwrap();
+        let group = self.existing_group(&mut groups, group_id, member_id)?;
+        group.validate_member(member_id, group_instance_id)?;
+        if generation_id != group.generation_id() {
+            return Err(KafkaBrokerError::IllegalGeneration {
+                group_id: group_id.to_string(),
+                generation_id,
+            });
+        }
+        if let Some(member) = group.member_mut(member_id) {
+            member.heartbeat(Instant::now());
+        }
+        match group.state() {
+            GroupState::PreparingRebalance => {
+                Err(KafkaBrokerError::RebalanceInProgress(group_id.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes `members` from their group, which then rebalances. Returns the outcome for each
+    /// member, in order.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::CoordinatorNotAvailable`] if the group is being removed.
+    pub async fn leave_group(
+        &self,
+        group_id: &str,
+        members: &[LeavingMember],
+    ) -> KafkaResult<Vec<KafkaResult<()>>> {
+        let results = {
+            let mut groups = self.groups.lock().unwrap();
+            let Some(group) = groups.get_mut(group_id) else {
+                return Ok(members
+                    .iter()
+                    .map(|m| {
+                        Err(KafkaBrokerError::UnknownMemberId {
+                            group_id: group_id.to_string(),
+                            member_id: m.member_id.clone(),
+                        })
+                    })
+                    .collect());
+            };
+            if group.state() == GroupState::Dead {
+                return Err(KafkaBrokerError::CoordinatorNotAvailable(
+                    group_id.to_string(),
+                ));
+            }
+
+            let now = Instant::now();
+            members
+                .iter()
+                .map(|leaving| {
+                    let member_id = match &leaving.group_instance_id {
+                        Some(instance_id) => {
+                            let member_id = group
+                                .static_member_id(instance_id)
+                                .ok_or_else(|| group.unknown_member(&leaving.member_id))?
+                                .to_string();
+                            if !leaving.member_id.is_empty() && leaving.member_id != member_id {
+                                return Err(KafkaBrokerError::FencedInstanceId {
+                                    group_id: group_id.to_string(),
+                                    instance_id: instance_id.clone(),
+                                });
+                            }
+                            member_id
+                        }
+                        None if group.remove_pending_member(&leaving.member_id) => return Ok(()),
+                        None => leaving.member_id.clone(),
+                    };
+                    if !group.remove_member_and_rebalance(
+                        &member_id,
+                        now,
+                        self.config.initial_rebalance_delay,
+                        "member left the group",
+                    ) {
+                        return Err(group.unknown_member(&member_id));
+                    }
+                    info!("Member {} has left group {}", member_id, group_id);
+                    Ok(())
+                })
+                .collect()
+        };
+        self.group_changed(group_id).await;
+        Ok(results)
+    }
+
+    /// Enforces session timeouts and other group deadlines, expires waiting JoinGroup and
+    /// SyncGroup requests, and deletes expired offsets, until `shutdown_token` is cancelled.
+    pub async fn run(&self, shutdown_token: CancellationToken) {
+        let expiration = async {
+            let mut ticker = time::interval(EXPIRATION_CHECK_INTERVAL);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                select! {
+                    _ = ticker.tick() => self.expire_groups(Instant::now()).await,
+                    _ = shutdown_token.cancelled() => break,
+                }
+            }
+        };
+        let retention = async {
+            let mut ticker = time::interval(self.config.offsets_retention_check_interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                select! {
+                    _ = ticker.tick() => self.expire_offsets(now_ms()).await,
+                    _ = shutdown_token.cancelled() => break,
+                }
+            }
+        };
+        tokio::join!(
+            self.purgatory.run_reaper(shutdown_token.clone()),
+            expiration,
+            retention
+        );
+        debug!("Group coordinator task stopped");
+    }
+
+    /// Updates the group for a JoinGroup, returning its result if it doesn't have to wait.
+    fn join(&self, request: &JoinRequest, now: Instant) -> KafkaResult<Outcome<JoinGroupResult>> {
+        if request.group_id.is_empty() {
+            return Err(KafkaBrokerError::InvalidGroupId(request.group_id.clone()));
+        }
+        let session_timeout = request.session_timeout_ms;
+        if session_timeout < self.config.min_session_timeout_ms
+            || session_timeout > self.config.max_session_timeout_ms
+        {
+            return Err(KafkaBrokerError::InvalidSessionTimeout(session_timeout));
+        }
+        if request.protocol_type.is_empty() || request.protocols.is_empty() {
+            return Err(KafkaBrokerError::InconsistentGroupProtocol(
+                request.group_id.clone(),
+            ));
+        }
+
+        let mut groups = self.groups.lock().unwrap();
+        if self
+            .consumer_groups
+            .lock()
+            .unwrap()
+            .contains_key(&request.group_id)
+        {
+            return Err(KafkaBrokerError::GroupIdNotFound(request.group_id.clone()));
+        }
+        let is_new_member = request.member_id.is_empty();
+        let group = match groups.entry(request.group_id.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) if is_new_member => {
+                info!("Creating group {}", request.group_id);
+                entry.insert(Group::new(&request.group_id, now))
+            }
+            Entry::Vacant(_) => {
+                return Err(KafkaBrokerError::UnknownMemberId {
+                    group_id: request.group_id.clone(),
+                    member_id: request.member_id.clone(),
+                })
+            }
+        };
+        if group.state() == GroupState::Dead {
+            return Err(KafkaBrokerError::CoordinatorNotAvailable(
+                request.group_id.clone(),
+            ));
+        }
+        if !group.supports_protocols(&request.protocol_type, &request.protocols) {
+            return Err(KafkaBrokerError::InconsistentGroupProtocol(
+                request.group_id.clone(),
+            ));
+        }
+
+        if is_new_member {
+            self.join_new_member(group, request, now)
+        } else {
+            self.join_known_member(group, request, now)
+        }
+    }
+
+    /// Handles the JoinGroup of a member without a member id.
+    fn join_new_member(
+        &self,
+        group: &mut Group,
+        request: &JoinRequest,
+        now: Instant,
+    ) -> KafkaResult<Outcome<JoinGroupResult>> {
+        let existing_static_member = request
+            .group_instance_id
+            .as_deref()
+            .and_then(|instance_id| group.static_member_id(instance_id))
+            .map(str::to_string);
+        if existing_static_member.is_none() && group.size() >= self.config.max_size {
+            return Err(KafkaBrokerError::GroupMaxSizeReached(
+                request.group_id.clone(),
+            ));
+        }
+
+        let prefix = request
+            .group_instance_id
+            .as_deref()
+            .unwrap_or(&request.client_id);
+        let member_id = format!("{}-{}", prefix, Uuid::new_v4());
+        if let Some(old_member_id) = existing_static_member {
+            return Ok(self.replace_static_member(group, &old_member_id, member_id, request, now));
+        }
+        if request.group_instance_id.is_none() && request.require_known_member_id {
+            // The member must come back with its id, so that a client retrying a JoinGroup
+            // whose response it lost doesn't leave a ghost member behind.
+            let session_timeout = Duration::from_millis(request.session_timeout_ms as u64);
+            group.add_pending_member(member_id.clone(), now + session_timeout);
+            return Err(KafkaBrokerError::MemberIdRequired { member_id });
+        }
+        Ok(self.add_member_and_rebalance(group, member_id, request, now))
+    }
+
+    /// Handles the JoinGroup of a member with a member id.
+    fn join_known_member(
+        &self,
+        group: &mut Group,
+        request: &JoinRequest,
+        now: Instant,
+    ) -> KafkaResult<Outcome<JoinGroupResult>> {
+        let member_id = &request.member_id;
+        if group.remove_pending_member(member_id) {
+            return Ok(self.add_member_and_rebalance(group, member_id.clone(), request, now));
+        }
+        group.validate_member(member_id, request.group_instance_id.as_deref())?;
+
+        let is_leader = group.is_leader(member_id);
+        let protocols_changed = group
+            .member(member_id)
+            .is_some_and(|member| member.protocols_changed(request));
+        let rejoin_now = match group.state() {
+            GroupState::PreparingRebalance => true,
+            // The member didn't get its JoinGroup response: send it again, unless the member
+            // has changed its protocols, which needs another rebalance.
+            GroupState::CompletingRebalance => protocols_changed,
+            // Only the leader, or a member whose protocols have changed, can trigger a
+            // rebalance of a stable group; anyone else gets the current generation.
+            GroupState::Stable => is_leader || protocols_changed,
+            GroupState::Empty | GroupState::Dead => return Err(group.unknown_member(member_id)),
+        };
+        if !rejoin_now {
+            return Ok(Outcome::Done(group.current_join_result(member_id, false)));
+        }
+
+        if let Some(member) = group.member_mut(member_id) {
+            member.rejoin(request, now);
+        }
+        let reason = request
+            .reason
+            .as_deref()
+            .unwrap_or("member rejoined the group");
+        group.prepare_rebalance(now, self.config.initial_rebalance_delay, reason);
+        group.maybe_complete_join(now);
+        Ok(Outcome::Waiting {
+            member_id: member_id.clone(),
+            deadline: group.rebalance_deadline(),
+        })
+    }
+
+    /// Hands the place of static member `old_member_id` to a new instance of it, which joined
+    /// without a member id and is given `member_id`.
+    fn replace_static_member(
+        &self,
+        group: &mut Group,
+        old_member_id: &str,
+        member_id: String,
+        request: &JoinRequest,
+        now: Instant,
+    ) -> Outcome<JoinGroupResult> {
+        info!(
+            "Static member {:?} of group {} rejoined: replacing member {} with {}",
+            request.group_instance_id, request.group_id, old_member_id, member_id
+        );
+        let protocols_changed = group
+            .member(old_member_id)
+            .is_some_and(|member| member.protocols_changed(request));
+        group.replace_static_member(old_member_id, &member_id);
+
+        // A stable group can carry on with the current assignment. The leader is told not to
+        // compute a new one if it supports that; otherwise it needs a rebalance to learn about
+        // the other members.
+        let is_leader = group.is_leader(&member_id);
+        if group.state() == GroupState::Stable
+            && !protocols_changed
+            && (!is_leader || request.supports_skip_assignment)
+        {
+            if let Some(member) = group.member_mut(&member_id) {
+                member.heartbeat(now);
+            }
+            return Outcome::Done(group.current_join_result(&member_id, true));
+        }
+
+        if let Some(member) = group.member_mut(&member_id) {
+            member.rejoin(request, now);
+        }
+        group.prepare_rebalance(
+            now,
+            self.config.initial_rebalance_delay,
+            "static member rejoined",
+        );
+        group.maybe_complete_join(now);
+        Outcome::Waiting {
+            member_id,
+            deadline: group.rebalance_deadline(),
+        }
+    }
+
+    /// Adds a new member to `group` and rebalances it.
+    fn add_member_and_rebalance(
+        &self,
+        group: &mut Group,
+        member_id: String,
+        request: &JoinRequest,
+        now: Instant,
+    ) -> Outcome<JoinGroupResult> {
+        debug!(
+            "Adding member {} (client {}, host {}) to group {}",
+            member_id, request.client_id, request.client_host, request.group_id
+        );
+        group.add_member(Member::new(member_id.clone(), request, now));
+        let initial_delay = self.config.initial_rebalance_delay;
+        if group.state() == GroupState::PreparingRebalance {
+            group.extend_initial_rebalance(now, initial_delay);
+        } else {
+            let reason = format!("adding new member {}", member_id);
+            group.prepare_rebalance(now, initial_delay, &reason);
+        }
+        group.maybe_complete_join(now);
+        Outcome::Waiting {
+            member_id,
+            deadline: group.rebalance_deadline(),
+        }
+    }
+
+    /// Updates the group for a SyncGroup, returning the result if it doesn't have to wait.
+    fn sync(&self, request: SyncRequest, now: Instant) -> KafkaResult<Outcome<SyncGroupResult>> {
+        let mut groups = self.groups.lock().unwrap();
+        let group = self.existing_group(&mut groups, &request.group_id, &request.member_id)?;
+        group.validate_member(&request.member_id, request.group_instance_id.as_deref())?;
+        if request.generation_id != group.generation_id() {
+            return Err(KafkaBrokerError::IllegalGeneration {
+                group_id: request.group_id,
+                generation_id: request.generation_id,
+            });
+        }
+        let protocol_matches = |expected: &Option<String>, actual: Option<&str>| {
+            expected.is_none() || expected.as_deref() == actual
+        };
+        if !protocol_matches(&request.protocol_type, group.protocol_type())
+            || !protocol_matches(&request.protocol_name, group.protocol_name())
+        {
+            return Err(KafkaBrokerError::InconsistentGroupProtocol(
+                request.group_id,
+            ));
+        }
+
+        match group.state() {
+            GroupState::PreparingRebalance => {
+                Err(KafkaBrokerError::RebalanceInProgress(request.group_id))
+            }
+            GroupState::CompletingRebalance => {
+                group.mark_synced(&request.member_id, now);
+                if group.is_leader(&request.member_id) {
+                    group.complete_sync(request.assignments);
+                    Ok(Outcome::Done(group.current_sync_result(&request.member_id)))
+                } else {
+                    group.await_sync(&request.member_id);
+                    Ok(Outcome::Waiting {
+                        member_id: request.member_id,
+                        deadline: group.sync_deadline(),
+                    })
+                }
+            }
+            GroupState::Stable => {
+                group.mark_synced(&request.member_id, now);
+                Ok(Outcome::Done(group.current_sync_result(&request.member_id)))
+            }
+            GroupState::Empty | GroupState::Dead => Err(group.unknown_member(&request.member_id)),
+        }
+    }
+
+    /// Waits until `member_id` of `group_id` has a result, as taken from the group by `poll`.
+    /// `poll` returns the result if there is one, whether the member is still waiting for it,
+    /// and the deadline by which it will have it.
+    async fn wait_for<T>(
+        &self,
+        group_id: &str,
+        member_id: &str,
+        mut deadline: Instant,
+        poll: impl Fn(&mut Group, &str) -> KafkaResult<(Option<KafkaResult<T>>, bool, Instant)>,
+    ) -> KafkaResult<T> {
+        let keys = [DelayedOperationKey::Group(group_id.to_string())];
+        loop {
+            let watch_deadline = deadline;
+            let watch = self.purgatory.watch(&keys, watch_deadline);
+            let (result, changed) = {
+                let mut groups = self.groups.lock().unwrap();
+                let group = self.existing_group(&mut groups, group_id, member_id)?;
+                let changed = group.expire(Instant::now(), self.config.initial_rebalance_delay);
+                let (result, waiting, next_deadline) = poll(group, member_id)?;
+                deadline = next_deadline;
+                let result = match result {
+                    Some(result) => Some(result),
+                    // Another request of the member took the result, or the member was told to
+                    // rejoin: this one has been superseded.
+                    None if !waiting => Some(Err(KafkaBrokerError::RebalanceInProgress(
+                        group_id.to_string(),
+                    ))),
+                    None => None,
+                };
+                (result, changed)
+            };
+            if changed {
+                self.group_changed(group_id).await;
+            }
+            if let Some(result) = result {
+                return result;
+            }
+
+            if watch.expired() {
+                if Instant::now() < watch_deadline {
+                    // The purgatory has closed: the broker is shutting down.
+                    return Err(KafkaBrokerError::CoordinatorNotAvailable(
+                        group_id.to_string(),
+                    ));
+                }
+                // The group's deadline has moved on since the watch began.
+                continue;
+            }
+            watch.wait().await;
+        }
+    }
+
+    /// The group `group_id`, for a request from `member_id`.
+    fn existing_group<'a>(
+        &self,
+        groups: &'a mut HashMap<String, Group>,
+        group_id: &str,
+        member_id: &str,
+    ) -> KafkaResult<&'a mut Group> {
+        match groups.get_mut(group_id) {
+            Some(group) if group.state() == GroupState::Dead => Err(
+                KafkaBrokerError::CoordinatorNotAvailable(group_id.to_string()),
+            ),
+            Some(group) => Ok(group),
+            None => Err(KafkaBrokerError::UnknownMemberId {
+                group_id: group_id.to_string(),
+                member_id: member_id.to_string(),
+            }),
+        }
+    }
+
+    /// Applies the deadlines of every group that have passed by `now`.
+    async fn expire_groups(&self, now: Instant) {
+        let changed: Vec<String> = self
+            .groups
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .filter_map(|(group_id, group)| {
+                group
+                    .expire(now, self.config.initial_rebalance_delay)
+                    .then(|| group_id.clone())
+            })
+            .collect();
+        for group_id in changed {
+            self.group_changed(&group_id).await;
+        }
+        self.expire_consumer_group_members(now).await;
+    }
+
+    /// Wakes the requests waiting on `group_id` after a change to the group, and writes the
+    /// group to `__consumer_offsets` if the change must be kept.
+    async fn group_changed(&self, group_id: &str) {
+        self.purgatory
+            .check_and_complete(&DelayedOperationKey::Group(group_id.to_string()));
+        self.store_group(group_id).await;
+    }
+
+    /// Writes the metadata of `group_id` to `__consumer_offsets` if it has changed since it was
+    /// last written. A failed write is logged, and retried on the group's next change.
+    async fn store_group(&self, group_id: &str) {
+        let log = match self.partition_log(group_id).await {
+            Ok(log) => log,
+            Err(e) => {
+                error!("Failed to store the metadata of group {}: {}", group_id, e);
+                return;
+            }
+        };
+        let log = log.lock_owned().await;
+        let record = {
+            let mut groups = self.groups.lock().unwrap();
+            let Some(group) = groups.get_mut(group_id) else {
+                return;
+            };
+            if !group.take_needs_store() {
+                return;
+            }
+            GroupRecord::GroupMetadata {
+                group_id: group_id.to_string(),
+                metadata: Some(group.metadata_value()),
+            }
+        };
+        let (_log, result) = Self::append_records(log, &[record]).await;
+        if let Err(e) = result {
+            error!("Failed to store the metadata of group {}: {}", group_id, e);
+            if let Some(group) = self.groups.lock().unwrap().get_mut(group_id) {
+                group.mark_needs_store();
+            }
+        }
+    }
+
+    /// The `__consumer_offsets` partition holding the records of `group_id`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::CoordinatorNotAvailable`] if the partition doesn't exist.
+    async fn partition_log(
+        &self,
+        group_id: &str,
+    ) -> KafkaResult<Arc<tokio::sync::Mutex<PartitionLog>>> {
+        let partition = records::partition_for(group_id, self.num_partitions);
+        let topic_partition = TopicPartition::new(GROUP_METADATA_TOPIC_NAME, partition);
+        self.logs
+            .get(&topic_partition)
+            .await
+            .ok_or_else(|| KafkaBrokerError::CoordinatorNotAvailable(group_id.to_string()))
+    }
+
+    /// Appends `records` to `log`, a `__consumer_offsets` partition, as one batch. The log is
+    /// handed back, still locked, so that the groups can be updated before anything else is
+    /// written to it.
+    async fn append_records(log: LogGuard, records: &[GroupRecord]) -> (LogGuard, KafkaResult<()>) {
+        let now_ms = now_ms();
+        let batch = records::encode_batch(records, now_ms);
+        storage::run_blocking(log, move |log| {
+            log.append(&batch, LEADER_EPOCH, now_ms).map(|_| ())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker_state::BrokerState;
+    use crate::kafka_protocol::kafka_error_codes::{
+        FENCED_INSTANCE_ID, ILLEGAL_GENERATION, MEMBER_ID_REQUIRED, UNKNOWN_MEMBER_ID,
+    };
+    use std::path::Path;
+
+    const SESSION_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// A broker whose groups rebalance as soon as every member has joined.
+    async fn load(dir: &Path) -> BrokerState {
+        let mut config = Config::for_tests(dir);
+        config.group_initial_rebalance_delay_ms = 0;
+        BrokerState::load(config).await.unwrap()
+    }
+
+    /// The JoinGroup of `member_id` in group "g", a static member if `instance_id` is set.
+    fn join_request(member_id: &str, instance_id: Option<&str>) -> JoinRequest {
+        JoinRequest {
+            group_id: "g".to_string(),
+            member_id: member_id.to_string(),
+            group_instance_id: instance_id.map(str::to_string),
+            client_id: "c".to_string(),
+            client_host: "/127.0.0.1".to_string(),
+            session_timeout_ms: SESSION_TIMEOUT.as_millis() as i32,
+            rebalance_timeout_ms: 60_000,
+            protocol_type: "consumer".to_string(),
+            protocols: vec![("range".to_string(), Vec::new())],
+            require_known_member_id: false,
+            supports_skip_assignment: false,
+            reason: None,
+        }
+    }
+
+    fn sync_request(member_id: &str, generation_id: i32) -> SyncRequest {
+        SyncRequest {
+            group_id: "g".to_string(),
+            generation_id,
+            member_id: member_id.to_string(),
+            group_instance_id: None,
+            protocol_type: None,
+            protocol_name: None,
+            assignments: vec![(member_id.to_string(), b"a".to_vec())],
+        }
+    }
+
+    /// Makes a new member the only one of a stable group "g". Returns its member id.
+    async fn join_and_sync(coordinator: &GroupCoordinator, instance_id: Option<&str>) -> String {
+        let joined = coordinator
+            .join_group(join_request("", instance_id))
+            .await
+            .unwrap();
+        let synced = coordinator
+            .sync_group(sync_request(&joined.member_id, joined.generation_id))
+            .await
+            .unwrap();
+        assert_eq!(synced.assignment, b"a");
+        joined.member_id
+    }
+
+    fn group_state(coordinator: &GroupCoordinator) -> GroupState {
+        coordinator.groups.lock().unwrap()["g"].state()
+    }
+
+    fn error_code<T: std::fmt::Debug>(result: KafkaResult<T>) -> i16 {
+        result.unwrap_err().error_code()
+    }
+
+    #[tokio::test]
+    async fn a_first_join_must_come_back_with_its_member_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = load(dir.path()).await;
+        let coordinator = &state.group_coordinator;
+        let mut request = join_request("", None);
+        request.require_known_member_id = true;
+
+        let error = coordinator.join_group(request.clone()).await.unwrap_err();
+        assert_eq!(error.error_code(), MEMBER_ID_REQUIRED);
+        let KafkaBrokerError::MemberIdRequired { member_id } = error else {
+            unreachable!();
+        };
+        // No rebalance starts until the member joins again with its id.
+        assert_eq!(group_state(coordinator), GroupState::Empty);
+
+        request.member_id = member_id.clone();
+        let joined = coordinator.join_group(request).await.unwrap();
+        assert_eq!(joined.member_id, member_id);
+        assert_eq!(joined.generation_id, 1);
+        assert_eq!(group_state(coordinator), GroupState::CompletingRebalance);
+    }
+
+    #[tokio::test]
+    async fn requests_from_unknown_members_or_other_generations_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = load(dir.path()).await;
+        let coordinator = &state.group_coordinator;
+        let member_id = join_and_sync(coordinator, None).await;
+        assert_eq!(group_state(coordinator), GroupState::Stable);
+
+        coordinator.heartbeat("g", &member_id, None, 1).unwrap();
+        assert_eq!(
+            error_code(coordinator.heartbeat("g", &member_id, None, 2)),
+            ILLEGAL_GENERATION
+        );
+        assert_eq!(
+            error_code(coordinator.sync_group(sync_request(&member_id, 0)).await),
+            ILLEGAL_GENERATION
+        );
+        assert_eq!(
+            error_code(coordinator.heartbeat("g", "unknown", None, 1)),
+            UNKNOWN_MEMBER_ID
+        );
+        assert_eq!(
+            error_code(coordinator.heartbeat("other", &member_id, None, 1)),
+            UNKNOWN_MEMBER_ID
+        );
+        assert_eq!(
+            error_code(coordinator.join_group(join_request("unknown", None)).await),
+            UNKNOWN_MEMBER_ID
+        );
+    }
+
+    #[tokio::test]
+    async fn a_new_instance_of_a_static_member_fences_the_old_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = load(dir.path()).await;
+        let coordinator = &state.group_coordinator;
+        let old_member_id = join_and_sync(coordinator, Some("i")).await;
+
+        // The stable group keeps its assignment: the new instance, now the leader, is told
+        // not to compute another one.
+        let mut request = join_request("", Some("i"));
+        request.supports_skip_assignment = true;
+        let joined = coordinator.join_group(request).await.unwrap();
+        assert_ne!(joined.member_id, old_member_id);
+        assert_eq!(joined.generation_id, 1);
+        assert!(joined.skip_assignment);
+        assert_eq!(group_state(coordinator), GroupState::Stable);
+
+        assert_eq!(
+            error_code(coordinator.heartbeat("g", &old_member_id, Some("i"), 1)),
+            FENCED_INSTANCE_ID
+        );
+        coordinator
+            .heartbeat("g", &joined.member_id, Some("i"), 1)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn members_are_removed_once_their_session_expires() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = load(dir.path()).await;
+        let coordinator = &state.group_coordinator;
+        let member_id = join_and_sync(coordinator, None).await;
+        let start = Instant::now();
+
+        coordinator.expire_groups(start + SESSION_TIMEOUT / 2).await;
+        assert_eq!(group_state(coordinator), GroupState::Stable);
+
+        coordinator.expire_groups(start + SESSION_TIMEOUT).await;
+        assert_eq!(group_state(coordinator), GroupState::Empty);
+        assert_eq!(coordinator.groups.lock().unwrap()["g"].generation_id(), 2);
+        assert_eq!(
+            error_code(coordinator.heartbeat("g", &member_id, None, 2)),
+            UNKNOWN_MEMBER_ID
+        );
+    }
+}
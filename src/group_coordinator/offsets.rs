@@ -0,0 +1,449 @@
+//! Committed offsets: OffsetCommit, OffsetFetch and the expiration of old offsets.
+//!
+//! A commit is appended to the group's `__consumer_offsets` partition before it is applied to
+//! the group, so an offset a client has been told is committed survives a restart. The
+//! partition log's lock is held from the validation of a commit until it has been applied, so
+//! the log and the groups agree on the order of commits.
+//!
+//! Offsets are kept for `offsets.retention.minutes` once they are no longer in use: counting
+//! from when a group with members became empty, or from the commit for groups that only commit
+//! offsets (without ever joining). An offset committed with an explicit retention time
+//! (OffsetCommit v2-v4) expires when that runs out instead. Expired offsets are deleted with a
+//! tombstone, and so is an empty group once it has no offsets left.
+//!
+//! Consumer groups keep their offsets the same way, but only their members may commit or fetch
+//! them while they have members, using their current member epoch. The offsets of an empty
+//! consumer group expire counting from their commit.
+
+use crate::group_coordinator::group::Group;
+use crate::group_coordinator::records::GroupRecord;
+use crate::group_coordinator::{GroupCoordinator, GroupState};
+use crate::kafka_protocol::kafka_error::{KafkaBrokerError, KafkaResult};
+use crate::storage::TopicPartition;
+use tokio::time::Instant;
+use tracing::{error, info};
+
+/// An offset committed by a group for one partition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OffsetAndMetadata {
+    /// The committed offset: the next one the group will consume.
+    pub offset: i64,
+    /// The leader epoch of the last consumed record, or -1 if unknown.
+    pub leader_epoch: i32,
+    /// Whatever the client attached to the commit.
+    pub metadata: String,
+    /// When the offset was committed, in milliseconds since the Unix epoch.
+    pub commit_timestamp: i64,
+    /// When the offset expires, if the commit asked for a retention time (OffsetCommit v2-v4).
+    pub expire_timestamp: Option<i64>,
+}
+
+/// A group's OffsetCommit, as the coordinator sees it.
+#[derive(Debug, Clone)]
+pub struct CommitRequest {
+    /// The group id.
+    pub group_id: String,
+    /// The generation of the committing member (its member epoch in a consumer group), or -1
+    /// for a commit from outside the group.
+    pub generation_id: i32,
+    /// The member id, or empty for a commit from outside the group.
+    pub member_id: String,
+    /// The `group.instance.id` of a static member.
+    pub group_instance_id: Option<String>,
+    /// The offsets to commit.
+    pub offsets: Vec<(TopicPartition, OffsetAndMetadata)>,
+}
+
+impl GroupCoordinator {
+    /// Commits offsets for a group, writing them to `__consumer_offsets`. A commit from
+    /// outside the group (generation -1) creates the group if needed. Returns the outcome for
+    /// each partition, in order.
+    ///
+    /// # Errors
+    ///
+    /// - [`KafkaBrokerError::IllegalGeneration`] for an unknown group with a generation, or a
+    ///   generation other than the current one.
+    /// - [`KafkaBrokerError::UnknownMemberId`] or [`KafkaBrokerError::FencedInstanceId`] for a
+    ///   member the group doesn't know (any more).
+    /// - [`KafkaBrokerError::RebalanceInProgress`] while the group waits for its assignment.
+    /// - [`KafkaBrokerError::StaleMemberEpoch`] for a consumer group member whose epoch isn't
+    ///   its current one.
+    /// - [`KafkaBrokerError::CoordinatorNotAvailable`] if the group is being removed, or its
+    ///   `__consumer_offsets` partition isn't available.
+    pub async fn commit_offsets(
+        &self,
+        request: CommitRequest,
+    ) -> KafkaResult<Vec<KafkaResult<()>>> {
+        let group_id = &request.group_id;
+        let log = self.partition_log(group_id).await?;
+        let log = log.lock_owned().await;
+
+        // A commit from outside any group creates an empty one right away, so that a member
+        // joining before the offsets are stored joins the group they were validated against.
+        let mut created_group = false;
+        let mut results: Vec<KafkaResult<()>> = {
+            let mut groups = self.groups.lock().unwrap();
+            let consumer_groups = self.consumer_groups.lock().unwrap();
+            if let Some(group) = consumer_groups.get(group_id) {
+                group.validate_offsets_request(&request.member_id, request.generation_id)?;
+            } else if let Some(group) = groups.get(group_id) {
+                validate_commit(group, &request)?;
+            } else if request.generation_id >= 0 {
+                return Err(KafkaBrokerError::IllegalGeneration {
+                    group_id: group_id.clone(),
+                    generation_id: request.generation_id,
+                });
+            } else {
+                groups.insert(group_id.clone(), Group::new(group_id, Instant::now()));
+                created_group = true;
+            }
+            let max = self.config.offset_metadata_max_bytes;
+            request
+                .offsets
+                .iter()
+                .map(|(_, offset)| match offset.metadata.len() {
+                    size if size > max => {
+                        Err(KafkaBrokerError::OffsetMetadataTooLarge { size, max })
+                    }
+                    _ => Ok(()),
+                })
+                .collect()
+        };
+
+        let records: Vec<GroupRecord> = request
+            .offsets
+            .iter()
+            .zip(&results)
+            .filter(|(_, result)| result.is_ok())
+            .map(|((topic_partition, offset), _)| GroupRecord::OffsetCommit {
+                group_id: group_id.clone(),
+                topic_partition: topic_partition.clone(),
+                offset: Some(offset.clone()),
+            })
+            .collect();
+        if records.is_empty() {
+            self.remove_unused_group(group_id, created_group);
+            return Ok(results);
+        }
+        let (_log, result) = Self::append_records(log, &records).await;
+        if let Err(e) = result {
+            self.remove_unused_group(group_id, created_group);
+            let too_large = matches!(e, KafkaBrokerError::MessageTooLarge { .. });
+            if !too_large {
+                error!(
+                    "Failed to store offsets committed by group {}: {}",
+                    group_id, e
+                );
+            }
+            for result in results.iter_mut().filter(|result| result.is_ok()) {
+                *result = Err(if too_large {
+                    KafkaBrokerError::InvalidCommitOffsetSize(group_id.clone())
+                } else {
+                    KafkaBrokerError::CoordinatorNotAvailable(group_id.clone())
+                });
+            }
+            return Ok(results);
+        }
+
+        let mut groups = self.groups.lock().unwrap();
+        let mut consumer_groups = self.consumer_groups.lock().unwrap();
+        if let Some(group) = consumer_groups.get_mut(group_id) {
+            for ((topic_partition, offset), result) in request.offsets.into_iter().zip(&results) {
+                if result.is_ok() {
+                    group.commit_offset(topic_partition, offset);
+                }
+            }
+        } else if let Some(group) = groups.get_mut(group_id) {
+            for ((topic_partition, offset), result) in request.offsets.into_iter().zip(&results) {
+                if result.is_ok() {
+                    group.commit_offset(topic_partition, offset);
+                }
+            }
+            if let Some(member) = group.member_mut(&request.member_id) {
+                member.heartbeat(Instant::now());
+            }
+        }
+        Ok(results)
+    }
+
+    /// Removes the group a failed commit from outside the group created (if `created`),
+    /// unless it has been used since. The partition log's lock is still held, so the group
+    /// can't have been written to the log.
+    fn remove_unused_group(&self, group_id: &str, created: bool) {
+        let mut groups = self.groups.lock().unwrap();
+        let unused = groups.get(group_id).is_some_and(|group| {
+            group.state() == GroupState::Empty && group.size() == 0 && group.offset_count() == 0
+        });
+        if created && unused {
+            groups.remove(group_id);
+        }
+    }
+
+    /// The offsets committed by `group_id` for `partitions`, or if `None` for every partition
+    /// it has committed to, sorted by topic and partition. Partitions without a committed
+    /// offset come back as `None`. `member` is the member id and epoch of a consumer group
+    /// member fetching its group's offsets (OffsetFetch v9+).
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::UnknownMemberId`] or [`KafkaBrokerError::StaleMemberEpoch`]
+    /// if `member` isn't a current member of a consumer group with members.
+    pub fn fetch_offsets(
+        &self,
+        group_id: &str,
+        member: Option<(&str, i32)>,
+        partitions: Option<Vec<TopicPartition>>,
+    ) -> KafkaResult<Vec<(TopicPartition, Option<OffsetAndMetadata>)>> {
+        let groups = self.groups.lock().unwrap();
+        let consumer_groups = self.consumer_groups.lock().unwrap();
+        let offsets = match consumer_groups.get(group_id) {
+            Some(group) => {
+                if let Some((member_id, member_epoch)) = member {
+                    group.validate_offsets_request(member_id, member_epoch)?;
+                }
+                Some(group.offsets())
+            }
+            None => groups.get(group_id).map(Group::offsets),
+        };
+        let offsets = match partitions {
+            Some(partitions) => partitions
+                .into_iter()
+                .map(|topic_partition| {
+                    let offset = offsets
+                        .and_then(|offsets| offsets.get(&topic_partition))
+                        .cloned();
+                    (topic_partition, offset)
+                })
+                .collect(),
+            None => {
+                let mut offsets: Vec<_> = offsets
+                    .into_iter()
+                    .flatten()
+                    .map(|(topic_partition, offset)| {
+                        (topic_partition.clone(), Some(offset.clone()))
+                    })
+                    .collect();
+                offsets.sort_by(|(a, _), (b, _)| {
+                    (&a.topic, a.partition).cmp(&(&b.topic, b.partition))
+                });
+                offsets
+            }
+        };
+        Ok(offsets)
+    }
+
+    /// Deletes the offsets that have expired by `now_ms`, and the empty groups left without
+    /// offsets, of classic and consumer groups. A group whose tombstones can't be written is
+    /// logged and skipped.
+    pub(super) async fn expire_offsets(&self, now_ms: i64) {
+        let group_ids: Vec<String> = self.groups.lock().unwrap().keys().cloned().collect();
+        let (mut expired_offsets, mut removed_groups) =
+            self.expire_consumer_group_offsets(now_ms).await;
+        for group_id in group_ids {
+            let log = match self.partition_log(&group_id).await {
+                Ok(log) => log,
+                Err(e) => {
+                    error!("Failed to expire the offsets of group {}: {}", group_id, e);
+                    continue;
+                }
+            };
+            let log = log.lock_owned().await;
+
+            let (expired, remove_group) = {
+                let groups = self.groups.lock().unwrap();
+                let Some(group) = groups.get(&group_id) else {
+                    continue;
+                };
+                let expired = group.expired_offsets(now_ms, self.config.offsets_retention_ms);
+                let remove_group = group.state() == GroupState::Empty
+                    && group.size() == 0
+                    && group.offset_count() == expired.len();
+                (expired, remove_group)
+            };
+            if expired.is_empty() && !remove_group {
+                continue;
+            }
+            let mut records: Vec<GroupRecord> = expired
+                .iter()
+                .map(|topic_partition| GroupRecord::OffsetCommit {
+                    group_id: group_id.clone(),
+                    topic_partition: topic_partition.clone(),
+                    offset: None,
+                })
+                .collect();
+            if remove_group {
+                records.push(GroupRecord::GroupMetadata {
+                    group_id: group_id.clone(),
+                    metadata: None,
+                });
+            }
+            let (_log, result) = Self::append_records(log, &records).await;
+            if let Err(e) = result {
+                error!("Failed to expire the offsets of group {}: {}", group_id, e);
+                continue;
+            }
+
+            let mut groups = self.groups.lock().unwrap();
+            let Some(group) = groups.get_mut(&group_id) else {
+                continue;
+            };
+            for topic_partition in &expired {
+                group.remove_offset(topic_partition);
+            }
+            expired_offsets += expired.len();
+            if remove_group {
+                if group.state() == GroupState::Empty && group.size() == 0 {
+                    groups.remove(&group_id);
+                    removed_groups += 1;
+                } else {
+                    // A member joined meanwhile: the group must be written again.
+                    group.mark_needs_store();
+                }
+            }
+        }
+        if expired_offsets > 0 || removed_groups > 0 {
+            info!(
+                "Removed {} expired offsets and {} empty groups",
+                expired_offsets, removed_groups
+            );
+        }
+    }
+}
+
+/// Checks that `request` may commit offsets for `group`.
+fn validate_commit(group: &Group, request: &CommitRequest) -> KafkaResult<()> {
+    let group_id = &request.group_id;
+    if group.state() == GroupState::Dead {
+        return Err(KafkaBrokerError::CoordinatorNotAvailable(group_id.clone()));
+    }
+    // A commit from outside the group, e.g. by a consumer assigning itself partitions or an
+    // admin resetting offsets, is only allowed while nobody consumes as part of the group.
+    if request.generation_id < 0 && group.state() == GroupState::Empty {
+        return Ok(());
+    }
+    group.validate_member(&request.member_id, request.group_instance_id.as_deref())?;
+    if request.generation_id != group.generation_id() {
+        return Err(KafkaBrokerError::IllegalGeneration {
+            group_id: group_id.clone(),
+            generation_id: request.generation_id,
+        });
+    }
+    if group.state() == GroupState::CompletingRebalance {
+        return Err(KafkaBrokerError::RebalanceInProgress(group_id.clone()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker_state::BrokerState;
+    use crate::config::Config;
+    use std::path::Path;
+
+    async fn load(dir: &Path) -> BrokerState {
+        BrokerState::load(Config::for_tests(dir)).await.unwrap()
+    }
+
+    /// Shuts `state` down and loads it again from `dir`, as a broker restart does.
+    async fn restart(dir: &Path, state: BrokerState) -> BrokerState {
+        state.logs.shutdown().await.unwrap();
+        drop(state);
+        load(dir).await
+    }
+
+    /// An offset committed at time 1000, expiring at `expire_timestamp` if set.
+    fn offset(offset: i64, expire_timestamp: Option<i64>) -> OffsetAndMetadata {
+        OffsetAndMetadata {
+            offset,
+            leader_epoch: -1,
+            metadata: String::new(),
+            commit_timestamp: 1000,
+            expire_timestamp,
+        }
+    }
+
+    /// A commit of `offsets` from outside group "g".
+    fn commit(offsets: Vec<(TopicPartition, OffsetAndMetadata)>) -> CommitRequest {
+        CommitRequest {
+            group_id: "g".to_string(),
+            generation_id: -1,
+            member_id: String::new(),
+            group_instance_id: None,
+            offsets,
+        }
+    }
+
+    /// The offsets committed by group "g", by partition of topic "t".
+    fn committed(state: &BrokerState) -> Vec<(i32, i64)> {
+        state
+            .group_coordinator
+            .fetch_offsets("g", None, None)
+            .unwrap()
+            .into_iter()
+            .map(|(topic_partition, offset)| (topic_partition.partition, offset.unwrap().offset))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn offsets_are_loaded_back_after_a_restart_until_they_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = load(dir.path()).await;
+        let results = state
+            .group_coordinator
+            .commit_offsets(commit(vec![
+                (TopicPartition::new("t", 0), offset(10, None)),
+                (TopicPartition::new("t", 1), offset(20, Some(5000))),
+            ]))
+            .await
+            .unwrap();
+        assert!(results.iter().all(Result::is_ok));
+
+        let state = restart(dir.path(), state).await;
+        assert_eq!(committed(&state), [(0, 10), (1, 20)]);
+
+        // The offset committed with a retention time expires first, the other one
+        // `offsets.retention.minutes` after its commit, taking the group with it.
+        state.group_coordinator.expire_offsets(5000).await;
+        let state = restart(dir.path(), state).await;
+        assert_eq!(committed(&state), [(0, 10)]);
+
+        let retention_ms = state.group_coordinator.config.offsets_retention_ms;
+        state
+            .group_coordinator
+            .expire_offsets(1000 + retention_ms)
+            .await;
+        let state = restart(dir.path(), state).await;
+        assert_eq!(committed(&state), []);
+        assert!(!state
+            .group_coordinator
+            .groups
+            .lock()
+            .unwrap()
+            .contains_key("g"));
+    }
+
+    #[tokio::test]
+    async fn a_rejected_commit_from_outside_leaves_no_group_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = load(dir.path()).await;
+        let mut too_large = offset(10, None);
+        too_large.metadata = "m".repeat(state.config.offset_metadata_max_bytes + 1);
+
+        let results = state
+            .group_coordinator
+            .commit_offsets(commit(vec![(TopicPartition::new("t", 0), too_large)]))
+            .await
+            .unwrap();
+        assert!(matches!(
+            results[..],
+            [Err(KafkaBrokerError::OffsetMetadataTooLarge { .. })]
+        ));
+        assert!(!state
+            .group_coordinator
+            .groups
+            .lock()
+            .unwrap()
+            .contains_key("g"));
+    }
+}
@@ -0,0 +1,552 @@
+//! The records the group coordinator keeps in `__consumer_offsets`.
+//!
+//! Committed offsets and group metadata are stored as records of the compacted internal topic
+//! `__consumer_offsets`, in the same partition logs as user topics. Every record of a group
+//! goes to the partition `abs(hash(group_id)) % partitions`, with `hash` being Java's
+//! `String.hashCode`, so a group lives in the same partition as it would on a Kafka broker.
+//!
+//! Keys and values are the structs generated from Kafka's record specs (`OffsetCommitKey.json`
+//! and friends), each prefixed with its int16 version. The key version tells the record types
+//! apart:
+//!
+//! - 0 and 1: a committed offset, keyed by group, topic and partition.
+//! - 2: the metadata of a classic group, keyed by group.
+//! - 3 to 8: the state of a consumer group (KIP-848), each record keyed by group, and by member
+//!   for those about one member:
+//!   - 3: the group epoch.
+//!   - 4: the metadata of the subscribed topics, as of the group epoch.
+//!   - 5: a member's subscription and client details.
+//!   - 6: the epoch of the target assignment.
+//!   - 7: a member's target assignment.
+//!   - 8: a member's current assignment: its epoch, the partitions it owns and those it must
+//!     revoke.
+//!
+//! A record without a value is a tombstone: the offset or group has been deleted, and
+//! compaction eventually drops every record of the key.
+
+use crate::group_coordinator::OffsetAndMetadata;
+use crate::kafka_protocol::kafka_codec::{read_i16, write_i16};
+use crate::kafka_protocol::kafka_error::{KafkaBrokerError, KafkaResult};
+use crate::kafka_protocol::kafka_messages::{
+    ConsumerGroupCurrentMemberAssignmentKey, ConsumerGroupCurrentMemberAssignmentValue,
+    ConsumerGroupMemberMetadataKey, ConsumerGroupMemberMetadataValue, ConsumerGroupMetadataKey,
+    ConsumerGroupMetadataValue, ConsumerGroupPartitionMetadataKey,
+    ConsumerGroupPartitionMetadataValue, ConsumerGroupTargetAssignmentMemberKey,
+    ConsumerGroupTargetAssignmentMemberValue, ConsumerGroupTargetAssignmentMetadataKey,
+    ConsumerGroupTargetAssignmentMetadataValue, GroupMetadataKey, GroupMetadataValue,
+    OffsetCommitKey, OffsetCommitValue,
+};
+use crate::kafka_protocol::kafka_record_batch::{Record, RecordBatch};
+use crate::kafka_protocol::kafka_records::EncodeBuf;
+use crate::storage::{PartitionLog, TopicPartition};
+
+/// The internal topic holding committed offsets and group metadata.
+pub const GROUP_METADATA_TOPIC_NAME: &str = "__consumer_offsets";
+
+/// How much of a `__consumer_offsets` partition is read at a time while loading it
+/// (Kafka's `offsets.load.buffer.size` default).
+const LOAD_BUFFER_SIZE: usize = 5 * 1024 * 1024;
+
+/// The key version of committed offsets written by this broker.
+const OFFSET_COMMIT_KEY_VERSION: i16 = 1;
+
+/// The key version of classic group metadata.
+const GROUP_METADATA_KEY_VERSION: i16 = 2;
+
+/// The key version of a consumer group's epoch.
+const CONSUMER_GROUP_METADATA_KEY_VERSION: i16 = 3;
+
+/// The key version of the metadata of a consumer group's subscribed topics.
+const CONSUMER_GROUP_PARTITION_METADATA_KEY_VERSION: i16 = 4;
+
+/// The key version of a consumer group member's metadata.
+const CONSUMER_GROUP_MEMBER_METADATA_KEY_VERSION: i16 = 5;
+
+/// The key version of a consumer group's target assignment epoch.
+const CONSUMER_GROUP_TARGET_ASSIGNMENT_METADATA_KEY_VERSION: i16 = 6;
+
+/// The key version of a consumer group member's target assignment.
+const CONSUMER_GROUP_TARGET_ASSIGNMENT_MEMBER_KEY_VERSION: i16 = 7;
+
+/// The key version of a consumer group member's current assignment.
+const CONSUMER_GROUP_CURRENT_MEMBER_ASSIGNMENT_KEY_VERSION: i16 = 8;
+
+/// The value version of every consumer group record.
+const CONSUMER_GROUP_VALUE_VERSION: i16 = 0;
+
+/// The value version of committed offsets without an expiration timestamp.
+const OFFSET_COMMIT_VALUE_VERSION: i16 = 3;
+
+/// The value version of committed offsets with an expiration timestamp, the only one carrying
+/// it.
+const OFFSET_COMMIT_VALUE_EXPIRING_VERSION: i16 = 1;
+
+/// The value version of classic group metadata.
+const GROUP_METADATA_VALUE_VERSION: i16 = 3;
+
+/// A record of `__consumer_offsets`.
+#[derive(Debug, Clone)]
+pub enum GroupRecord {
+    /// An offset committed by a group, or deleted if `offset` is `None`.
+    OffsetCommit {
+        /// The group id.
+        group_id: String,
+        /// The partition the offset is for.
+        topic_partition: TopicPartition,
+        /// The committed offset.
+        offset: Option<OffsetAndMetadata>,
+    },
+    /// The metadata of a classic group, or its deletion if `metadata` is `None`.
+    GroupMetadata {
+        /// The group id.
+        group_id: String,
+        /// The group's generation and members.
+        metadata: Option<GroupMetadataValue>,
+    },
+    /// The epoch of a consumer group. Its tombstone, written last, deletes the group.
+    ConsumerGroupMetadata {
+        /// The group id.
+        group_id: String,
+        /// The group epoch.
+        value: Option<ConsumerGroupMetadataValue>,
+    },
+    /// The metadata of the topics a consumer group subscribes to.
+    ConsumerGroupPartitionMetadata {
+        /// The group id.
+        group_id: String,
+        /// The subscribed topics that exist.
+        value: Option<ConsumerGroupPartitionMetadataValue>,
+    },
+    /// A consumer group member's subscription, or its removal.
+    ConsumerGroupMemberMetadata {
+        /// The group id.
+        group_id: String,
+        /// The member id.
+        member_id: String,
+        /// The member's subscription and client details.
+        value: Option<ConsumerGroupMemberMetadataValue>,
+    },
+    /// The epoch of a consumer group's target assignment.
+    ConsumerGroupTargetAssignmentMetadata {
+        /// The group id.
+        group_id: String,
+        /// The group epoch the target assignment was computed for.
+        value: Option<ConsumerGroupTargetAssignmentMetadataValue>,
+    },
+    /// A consumer group member's target assignment.
+    ConsumerGroupTargetAssignmentMember {
+        /// The group id.
+        group_id: String,
+        /// The member id.
+        member_id: String,
+        /// The partitions the member is to own.
+        value: Option<ConsumerGroupTargetAssignmentMemberValue>,
+    },
+    /// A consumer group member's current assignment.
+    ConsumerGroupCurrentMemberAssignment {
+        /// The group id.
+        group_id: String,
+        /// The member id.
+        member_id: String,
+        /// The member's epoch and partitions.
+        value: Option<ConsumerGroupCurrentMemberAssignmentValue>,
+    },
+}
+
+impl GroupRecord {
+    /// The id of the group the record is about.
+    pub fn group_id(&self) -> &str {
+        match self {
+            GroupRecord::OffsetCommit { group_id, .. }
+            | GroupRecord::GroupMetadata { group_id, .. }
+            | GroupRecord::ConsumerGroupMetadata { group_id, .. }
+            | GroupRecord::ConsumerGroupPartitionMetadata { group_id, .. }
+            | GroupRecord::ConsumerGroupMemberMetadata { group_id, .. }
+            | GroupRecord::ConsumerGroupTargetAssignmentMetadata { group_id, .. }
+            | GroupRecord::ConsumerGroupTargetAssignmentMember { group_id, .. }
+            | GroupRecord::ConsumerGroupCurrentMemberAssignment { group_id, .. } => group_id,
+        }
+    }
+
+    /// Whether the record is a tombstone, deleting its key.
+    pub fn is_tombstone(&self) -> bool {
+        match self {
+            GroupRecord::OffsetCommit { offset, .. } => offset.is_none(),
+            GroupRecord::GroupMetadata { metadata, .. } => metadata.is_none(),
+            GroupRecord::ConsumerGroupMetadata { value, .. } => value.is_none(),
+            GroupRecord::ConsumerGroupPartitionMetadata { value, .. } => value.is_none(),
+            GroupRecord::ConsumerGroupMemberMetadata { value, .. } => value.is_none(),
+            GroupRecord::ConsumerGroupTargetAssignmentMetadata { value, .. } => value.is_none(),
+            GroupRecord::ConsumerGroupTargetAssignmentMember { value, .. } => value.is_none(),
+            GroupRecord::ConsumerGroupCurrentMemberAssignment { value, .. } => value.is_none(),
+        }
+    }
+
+    /// The encoded record key.
+    fn key(&self) -> Vec<u8> {
+        match self {
+            GroupRecord::OffsetCommit {
+                group_id,
+                topic_partition,
+                ..
+            } => encode(OFFSET_COMMIT_KEY_VERSION, |buf, version| {
+                OffsetCommitKey {
+                    group: group_id.clone(),
+                    topic: topic_partition.topic.clone(),
+                    partition: topic_partition.partition,
+                    ..Default::default()
+                }
+                .write(buf, version)
+            }),
+            GroupRecord::GroupMetadata { group_id, .. } => {
+                encode(GROUP_METADATA_KEY_VERSION, |buf, version| {
+                    GroupMetadataKey {
+                        group: group_id.clone(),
+                        ..Default::default()
+                    }
+                    .write(buf, version)
+                })
+            }
+            GroupRecord::ConsumerGroupMetadata { group_id, .. } => {
+                encode(CONSUMER_GROUP_METADATA_KEY_VERSION, |buf, version| {
+                    ConsumerGroupMetadataKey {
+                        group_id: group_id.clone(),
+                        ..Default::default()
+                    }
+                    .write(buf, version)
+                })
+            }
+            GroupRecord::ConsumerGroupPartitionMetadata { group_id, .. } => encode(
+                CONSUMER_GROUP_PARTITION_METADATA_KEY_VERSION,
+                |buf, version| {
+                    ConsumerGroupPartitionMetadataKey {
+                        group_id: group_id.clone(),
+                        ..Default::default()
+                    }
+                    .write(buf, version)
+                },
+            ),
+            GroupRecord::ConsumerGroupMemberMetadata {
+                group_id,
+                member_id,
+                ..
+            } => encode(
+                CONSUMER_GROUP_MEMBER_METADATA_KEY_VERSION,
+                |buf, version| {
+                    ConsumerGroupMemberMetadataKey {
+                        group_id: group_id.clone(),
+                        member_id: member_id.clone(),
+                        ..Default::default()
+                    }
+                    .write(buf, version)
+                },
+            ),
+            GroupRecord::ConsumerGroupTargetAssignmentMetadata { group_id, .. } => encode(
+                CONSUMER_GROUP_TARGET_ASSIGNMENT_METADATA_KEY_VERSION,
+                |buf, version| {
+                    ConsumerGroupTargetAssignmentMetadataKey {
+                        group_id: group_id.clone(),
+                        ..Default::default()
+                    }
+                    .write(buf, version)
+                },
+            ),
+            GroupRecord::ConsumerGroupTargetAssignmentMember {
+                group_id,
+                member_id,
+                ..
+            } => encode(
+                CONSUMER_GROUP_TARGET_ASSIGNMENT_MEMBER_KEY_VERSION,
+                |buf, version| {
+                    ConsumerGroupTargetAssignmentMemberKey {
+                        group_id: group_id.clone(),
+                        member_id: member_id.clone(),
+                        ..Default::default()
+                    }
+                    .write(buf, version)
+                },
+            ),
+            GroupRecord::ConsumerGroupCurrentMemberAssignment {
+                group_id,
+                member_id,
+                ..
+            } => encode(
+                CONSUMER_GROUP_CURRENT_MEMBER_ASSIGNMENT_KEY_VERSION,
+                |buf, version| {
+                    ConsumerGroupCurrentMemberAssignmentKey {
+                        group_id: group_id.clone(),
+                        member_id: member_id.clone(),
+                        ..Default::default()
+                    }
+                    .write(buf, version)
+                },
+            ),
+        }
+    }
+
+    /// The encoded record value, or `None` for a tombstone.
+    fn value(&self) -> Option<Vec<u8>> {
+        match self {
+            GroupRecord::OffsetCommit { offset, .. } => offset.as_ref().map(|offset| {
+                let version = match offset.expire_timestamp {
+                    Some(_) => OFFSET_COMMIT_VALUE_EXPIRING_VERSION,
+                    None => OFFSET_COMMIT_VALUE_VERSION,
+                };
+                encode(version, |buf, version| {
+                    OffsetCommitValue {
+                        offset: offset.offset,
+                        leader_epoch: offset.leader_epoch,
+                        metadata: offset.metadata.clone(),
+                        commit_timestamp: offset.commit_timestamp,
+                        expire_timestamp: offset.expire_timestamp.unwrap_or(-1),
+                        ..Default::default()
+                    }
+                    .write(buf, version)
+                })
+            }),
+            GroupRecord::GroupMetadata { metadata, .. } => metadata.as_ref().map(|metadata| {
+                encode(GROUP_METADATA_VALUE_VERSION, |buf, version| {
+                    metadata.write(buf, version)
+                })
+            }),
+            GroupRecord::ConsumerGroupMetadata { value, .. } => value
+                .as_ref()
+                .map(|value| encode_value(|buf, v| value.write(buf, v))),
+            GroupRecord::ConsumerGroupPartitionMetadata { value, .. } => value
+                .as_ref()
+                .map(|value| encode_value(|buf, v| value.write(buf, v))),
+            GroupRecord::ConsumerGroupMemberMetadata { value, .. } => value
+                .as_ref()
+                .map(|value| encode_value(|buf, v| value.write(buf, v))),
+            GroupRecord::ConsumerGroupTargetAssignmentMetadata { value, .. } => value
+                .as_ref()
+                .map(|value| encode_value(|buf, v| value.write(buf, v))),
+            GroupRecord::ConsumerGroupTargetAssignmentMember { value, .. } => value
+                .as_ref()
+                .map(|value| encode_value(|buf, v| value.write(buf, v))),
+            GroupRecord::ConsumerGroupCurrentMemberAssignment { value, .. } => value
+                .as_ref()
+                .map(|value| encode_value(|buf, v| value.write(buf, v))),
+        }
+    }
+
+    /// Decodes `record`. Returns `None` for records without a key, and for record types this
+    /// broker doesn't know, which belong to newer group types.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::CorruptMessage`] if the key or value can't be decoded.
+    fn decode(record: &Record) -> KafkaResult<Option<Self>> {
+        let Some(key) = &record.key else {
+            return Ok(None);
+        };
+        let mut cursor = key.as_slice();
+        let key_version = read_i16(&mut cursor).map_err(corrupt)?;
+        let decoded = match key_version {
+            0 | 1 => {
+                let key = OffsetCommitKey::read(&mut cursor, key_version).map_err(corrupt)?;
+                let offset = match &record.value {
+                    Some(value) => {
+                        let (value, _) = decode_value(value, OffsetCommitValue::read)?;
+                        Some(OffsetAndMetadata {
+                            offset: value.offset,
+                            leader_epoch: value.leader_epoch,
+                            metadata: value.metadata,
+                            commit_timestamp: value.commit_timestamp,
+                            expire_timestamp: Some(value.expire_timestamp)
+                                .filter(|&timestamp| timestamp >= 0),
+                        })
+                    }
+                    None => None,
+                };
+                GroupRecord::OffsetCommit {
+                    group_id: key.group,
+                    topic_partition: TopicPartition::new(key.topic, key.partition),
+                    offset,
+                }
+            }
+            GROUP_METADATA_KEY_VERSION => {
+                let key = GroupMetadataKey::read(&mut cursor, key_version).map_err(corrupt)?;
+                let metadata = match &record.value {
+                    Some(value) => Some(decode_value(value, GroupMetadataValue::read)?.0),
+                    None => None,
+                };
+                GroupRecord::GroupMetadata {
+                    group_id: key.group,
+                    metadata,
+                }
+            }
+            CONSUMER_GROUP_METADATA_KEY_VERSION => {
+                let key =
+                    ConsumerGroupMetadataKey::read(&mut cursor, key_version).map_err(corrupt)?;
+                GroupRecord::ConsumerGroupMetadata {
+                    group_id: key.group_id,
+                    value: decode_optional_value(record, ConsumerGroupMetadataValue::read)?,
+                }
+            }
+            CONSUMER_GROUP_PARTITION_METADATA_KEY_VERSION => {
+                let key = ConsumerGroupPartitionMetadataKey::read(&mut cursor, key_version)
+                    .map_err(corrupt)?;
+                GroupRecord::ConsumerGroupPartitionMetadata {
+                    group_id: key.group_id,
+                    value: decode_optional_value(
+                        record,
+                        ConsumerGroupPartitionMetadataValue::read,
+                    )?,
+                }
+            }
+            CONSUMER_GROUP_MEMBER_METADATA_KEY_VERSION => {
+                let key = ConsumerGroupMemberMetadataKey::read(&mut cursor, key_version)
+                    .map_err(corrupt)?;
+                GroupRecord::ConsumerGroupMemberMetadata {
+                    group_id: key.group_id,
+                    member_id: key.member_id,
+                    value: decode_optional_value(record, ConsumerGroupMemberMetadataValue::read)?,
+                }
+            }
+            CONSUMER_GROUP_TARGET_ASSIGNMENT_METADATA_KEY_VERSION => {
+                let key = ConsumerGroupTargetAssignmentMetadataKey::read(&mut cursor, key_version)
+                    .map_err(corrupt)?;
+                GroupRecord::ConsumerGroupTargetAssignmentMetadata {
+                    group_id: key.group_id,
+                    value: decode_optional_value(
+                        record,
+                        ConsumerGroupTargetAssignmentMetadataValue::read,
+                    )?,
+                }
+            }
+            CONSUMER_GROUP_TARGET_ASSIGNMENT_MEMBER_KEY_VERSION => {
+                let key = ConsumerGroupTargetAssignmentMemberKey::read(&mut cursor, key_version)
+                    .map_err(corrupt)?;
+                GroupRecord::ConsumerGroupTargetAssignmentMember {
+                    group_id: key.group_id,
+                    member_id: key.member_id,
+                    value: decode_optional_value(
+                        record,
+                        ConsumerGroupTargetAssignmentMemberValue::read,
+                    )?,
+                }
+            }
+            CONSUMER_GROUP_CURRENT_MEMBER_ASSIGNMENT_KEY_VERSION => {
+                let key = ConsumerGroupCurrentMemberAssignmentKey::read(&mut cursor, key_version)
+                    .map_err(corrupt)?;
+                GroupRecord::ConsumerGroupCurrentMemberAssignment {
+                    group_id: key.group_id,
+                    member_id: key.member_id,
+                    value: decode_optional_value(
+                        record,
+                        ConsumerGroupCurrentMemberAssignmentValue::read,
+                    )?,
+                }
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(decoded))
+    }
+}
+
+/// The partition of `__consumer_offsets`, out of `num_partitions`, holding the records of
+/// `group_id`.
+pub fn partition_for(group_id: &str, num_partitions: i32) -> i32 {
+    // Java's String.hashCode, over UTF-16 code units.
+    let hash = group_id.encode_utf16().fold(0i32, |hash, unit| {
+        hash.wrapping_mul(31).wrapping_add(unit as i32)
+    });
+    (hash & 0x7fff_ffff) % num_partitions
+}
+
+/// Encodes `records` as one uncompressed batch, ready to be appended to a `__consumer_offsets`
+/// partition.
+pub fn encode_batch(records: &[GroupRecord], now_ms: i64) -> Vec<u8> {
+    let records: Vec<Record> = records
+        .iter()
+        .enumerate()
+        .map(|(offset_delta, record)| Record {
+            attributes: 0,
+            timestamp_delta: 0,
+            offset_delta: offset_delta as i32,
+            key: Some(record.key()),
+            value: record.value(),
+            headers: Vec::new(),
+        })
+        .collect();
+    let batch = RecordBatch::new(0, now_ms, &records);
+    let mut data = Vec::with_capacity(batch.size_in_bytes());
+    batch.write(&mut data);
+    data
+}
+
+/// Reads every record of `log`, a `__consumer_offsets` partition, in offset order. Control
+/// batches and records of unknown types are skipped.
+///
+/// # Errors
+///
+/// Returns [`KafkaBrokerError::Io`] if the log can't be read, or
+/// [`KafkaBrokerError::CorruptMessage`] if a batch or record is damaged.
+pub fn read_log(log: &PartitionLog) -> KafkaResult<Vec<GroupRecord>> {
+    let mut records = Vec::new();
+    let mut offset = log.log_start_offset();
+    while offset < log.log_end_offset() {
+        let data = log.read(offset, LOAD_BUFFER_SIZE, true)?.into_bytes()?;
+        if data.is_empty() {
+            break;
+        }
+        let mut cursor = data.as_slice();
+        while !cursor.is_empty() {
+            let batch = RecordBatch::read(&mut cursor)?;
+            offset = batch.last_offset() + 1;
+            if batch.is_control_batch() {
+                continue;
+            }
+            for record in batch.records()? {
+                if let Some(record) = GroupRecord::decode(&record)? {
+                    records.push(record);
+                }
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// Encodes a key or value: its `version`, then the struct `write` encodes for it.
+fn encode(version: i16, write: impl FnOnce(&mut EncodeBuf, i16)) -> Vec<u8> {
+    let mut buf = EncodeBuf::new();
+    write_i16(&mut buf, version);
+    write(&mut buf, version);
+    buf.into_bytes()
+}
+
+/// Encodes the value of a consumer group record, all of which have the same version.
+fn encode_value(write: impl FnOnce(&mut EncodeBuf, i16)) -> Vec<u8> {
+    encode(CONSUMER_GROUP_VALUE_VERSION, write)
+}
+
+/// Decodes the value of `record` with `read`, or returns `None` for a tombstone.
+fn decode_optional_value<T>(
+    record: &Record,
+    read: impl FnOnce(&mut &[u8], i16) -> KafkaResult<T>,
+) -> KafkaResult<Option<T>> {
+    match &record.value {
+        Some(value) => Ok(Some(decode_value(value, read)?.0)),
+        None => Ok(None),
+    }
+}
+
+/// Decodes a value with `read`, returning it with its version.
+fn decode_value<T>(
+    value: &[u8],
+    read: impl FnOnce(&mut &[u8], i16) -> KafkaResult<T>,
+) -> KafkaResult<(T, i16)> {
+    let mut cursor = value;
+    let version = read_i16(&mut cursor).map_err(corrupt)?;
+    let value = read(&mut cursor, version).map_err(corrupt)?;
+    Ok((value, version))
+}
+
+/// Reports a key or value that can't be decoded as a damaged record.
+fn corrupt(error: KafkaBrokerError) -> KafkaBrokerError {
+    KafkaBrokerError::CorruptMessage(format!(
+        "Invalid record in {}: {}",
+        GROUP_METADATA_TOPIC_NAME, error
+    ))
+}
@@ -0,0 +1,439 @@
+//! # KafkaApiKeys Module
+//!
+//! Every Kafka request starts with a 16-bit `request_api_key` that identifies which API the
+//! payload belongs to. This module maps those raw numbers onto the [`ApiKey`] enum so the
+//! request dispatcher can match on a typed value instead of magic numbers.
+//!
+//! For the full list of API keys, see:
+//! <https://kafka.apache.org/protocol#protocol_api_keys>
+
+use crate::kafka_protocol::kafka_error::KafkaBrokerError;
+
+/// All API keys defined by the Kafka protocol.
+///
+/// The discriminant of each variant is the value sent on the wire in the request header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(i16)]
+pub enum ApiKey {
+    Produce = 0,
+    Fetch = 1,
+    ListOffsets = 2,
+    Metadata = 3,
+    LeaderAndIsr = 4,
+    StopReplica = 5,
+    UpdateMetadata = 6,
+    ControlledShutdown = 7,
+    OffsetCommit = 8,
+    OffsetFetch = 9,
+    FindCoordinator = 10,
+    JoinGroup = 11,
+    Heartbeat = 12,
+    LeaveGroup = 13,
+    SyncGroup = 14,
+    DescribeGroups = 15,
+    ListGroups = 16,
+    SaslHandshake = 17,
+    ApiVersions = 18,
+    CreateTopics = 19,
+    DeleteTopics = 20,
+    DeleteRecords = 21,
+    InitProducerId = 22,
+    OffsetForLeaderEpoch = 23,
+    AddPartitionsToTxn = 24,
+    AddOffsetsToTxn = 25,
+    EndTxn = 26,
+    WriteTxnMarkers = 27,
+    TxnOffsetCommit = 28,
+    DescribeAcls = 29,
+    CreateAcls = 30,
+    DeleteAcls = 31,
+    DescribeConfigs = 32,
+    AlterConfigs = 33,
+    AlterReplicaLogDirs = 34,
+    DescribeLogDirs = 35,
+    SaslAuthenticate = 36,
+    CreatePartitions = 37,
+    CreateDelegationToken = 38,
+    RenewDelegationToken = 39,
+    ExpireDelegationToken = 40,
+    DescribeDelegationToken = 41,
+    DeleteGroups = 42,
+    ElectLeaders = 43,
+    IncrementalAlterConfigs = 44,
+    AlterPartitionReassignments = 45,
+    ListPartitionReassignments = 46,
+    OffsetDelete = 47,
+    DescribeClientQuotas = 48,
+    AlterClientQuotas = 49,
+    DescribeUserScramCredentials = 50,
+    AlterUserScramCredentials = 51,
+    Vote = 52,
+    BeginQuorumEpoch = 53,
+    EndQuorumEpoch = 54,
+    DescribeQuorum = 55,
+    AlterPartition = 56,
+    UpdateFeatures = 57,
+    Envelope = 58,
+    FetchSnapshot = 59,
+    DescribeCluster = 60,
+    DescribeProducers = 61,
+    BrokerRegistration = 62,
+    BrokerHeartbeat = 63,
+    UnregisterBroker = 64,
+    DescribeTransactions = 65,
+    ListTransactions = 66,
+    AllocateProducerIds = 67,
+    ConsumerGroupHeartbeat = 68,
+    ConsumerGroupDescribe = 69,
+    ControllerRegistration = 70,
+    GetTelemetrySubscriptions = 71,
+    PushTelemetry = 72,
+    AssignReplicasToDirs = 73,
+    ListClientMetricsResources = 74,
+    DescribeTopicPartitions = 75,
+}
+
+impl TryFrom<i16> for ApiKey {
+    type Error = KafkaBrokerError;
+
+    /// Converts a raw `request_api_key` into an [`ApiKey`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::UnknownApiKey`] if the value does not correspond to any
+    /// API defined by the protocol.
+    fn try_from(value: i16) -> Result<Self, Self::Error> {
+        let api_key = match value {
+            0 => ApiKey::Produce,
+            1 => ApiKey::Fetch,
+            2 => ApiKey::ListOffsets,
+            3 => ApiKey::Metadata,
+            4 => ApiKey::LeaderAndIsr,
+            5 => ApiKey::StopReplica,
+            6 => ApiKey::UpdateMetadata,
+            7 => ApiKey::ControlledShutdown,
+            8 => ApiKey::OffsetCommit,
+            9 => ApiKey::OffsetFetch,
+            10 => ApiKey::FindCoordinator,
+            11 => ApiKey::JoinGroup,
+            12 => ApiKey::Heartbeat,
+            13 => ApiKey::LeaveGroup,
+            14 => ApiKey::SyncGroup,
+            15 => ApiKey::DescribeGroups,
+            16 => ApiKey::ListGroups,
+            17 => ApiKey::SaslHandshake,
+            18 => ApiKey::ApiVersions,
+            19 => ApiKey::CreateTopics,
+            20 => ApiKey::DeleteTopics,
+            21 => ApiKey::DeleteRecords,
+            22 => ApiKey::InitProducerId,
+            23 => ApiKey::OffsetForLeaderEpoch,
+            24 => ApiKey::AddPartitionsToTxn,
+            25 => ApiKey::AddOffsetsToTxn,
+            26 => ApiKey::EndTxn,
+            27 => ApiKey::WriteTxnMarkers,
+            28 => ApiKey::TxnOffsetCommit,
+            29 => ApiKey::DescribeAcls,
+            30 => ApiKey::CreateAcls,
+            31 => ApiKey::DeleteAcls,
+            32 => ApiKey::DescribeConfigs,
+            33 => ApiKey::AlterConfigs,
+            34 => ApiKey::AlterReplicaLogDirs,
+            35 => ApiKey::DescribeLogDirs,
+            36 => ApiKey::SaslAuthenticate,
+            37 => ApiKey::CreatePartitions,
+            38 => ApiKey::CreateDelegationToken,
+            39 => ApiKey::RenewDelegationToken,
+            40 => ApiKey::ExpireDelegationToken,
+            41 => ApiKey::DescribeDelegationToken,
+            42 => ApiKey::DeleteGroups,
+            43 => ApiKey::ElectLeaders,
+            44 => ApiKey::IncrementalAlterConfigs,
+            45 => ApiKey::AlterPartitionReassignments,
+            46 => ApiKey::ListPartitionReassignments,
+            47 => ApiKey::OffsetDelete,
+            48 => ApiKey::DescribeClientQuotas,
+            49 => ApiKey::AlterClientQuotas,
+            50 => ApiKey::DescribeUserScramCredentials,
+            51 => ApiKey::AlterUserScramCredentials,
+            52 => ApiKey::Vote,
+            53 => ApiKey::BeginQuorumEpoch,
+            54 => ApiKey::EndQuorumEpoch,
+            55 => ApiKey::DescribeQuorum,
+            56 => ApiKey::AlterPartition,
+            57 => ApiKey::UpdateFeatures,
+            58 => ApiKey::Envelope,
+            59 => ApiKey::FetchSnapshot,
+            60 => ApiKey::DescribeCluster,
+            61 => ApiKey::DescribeProducers,
+            62 => ApiKey::BrokerRegistration,
+            63 => ApiKey::BrokerHeartbeat,
+            64 => ApiKey::UnregisterBroker,
+            65 => ApiKey::DescribeTransactions,
+            66 => ApiKey::ListTransactions,
+            67 => ApiKey::AllocateProducerIds,
+            68 => ApiKey::ConsumerGroupHeartbeat,
+            69 => ApiKey::ConsumerGroupDescribe,
+            70 => ApiKey::ControllerRegistration,
+            71 => ApiKey::GetTelemetrySubscriptions,
+            72 => ApiKey::PushTelemetry,
+            73 => ApiKey::AssignReplicasToDirs,
+            74 => ApiKey::ListClientMetricsResources,
+            75 => ApiKey::DescribeTopicPartitions,
+            unknown => return Err(KafkaBrokerError::UnknownApiKey(unknown)),
+        };
+        Ok(api_key)
+    }
+}
+
+/// A contiguous range of versions the broker implements for a single API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApiVersionRange {
+    /// The API this range applies to.
+    pub api_key: ApiKey,
+    /// The oldest version the broker can parse and answer.
+    pub min_version: i16,
+    /// The newest version the broker can parse and answer.
+    pub max_version: i16,
+}
+
+/// The central registry of APIs implemented by this broker.
+///
+/// ApiVersions advertises exactly these ranges to clients, and requests for any API or version
+/// outside of them are answered with `UNSUPPORTED_VERSION` instead of being parsed.
+pub const SUPPORTED_APIS: &[ApiVersionRange] = &[
+    ApiVersionRange {
+        api_key: ApiKey::Produce,
+        min_version: 0,
+        max_version: 11,
+    },
+    ApiVersionRange {
+        api_key: ApiKey::Fetch,
+        min_version: 0,
+        max_version: 16,
+    },
+    ApiVersionRange {
+        api_key: ApiKey::Metadata,
+        min_version: 0,
+        max_version: 12,
+    },
+    ApiVersionRange {
+        api_key: ApiKey::OffsetCommit,
+        min_version: 0,
+        max_version: 9,
+    },
+    ApiVersionRange {
+        api_key: ApiKey::OffsetFetch,
+        min_version: 0,
+        max_version: 9,
+    },
+    ApiVersionRange {
+        api_key: ApiKey::FindCoordinator,
+        min_version: 0,
+        max_version: 6,
+    },
+    ApiVersionRange {
+        api_key: ApiKey::JoinGroup,
+        min_version: 0,
+        max_version: 9,
+    },
+    ApiVersionRange {
+        api_key: ApiKey::Heartbeat,
+        min_version: 0,
+        max_version: 4,
+    },
+    ApiVersionRange {
+        api_key: ApiKey::LeaveGroup,
+        min_version: 0,
+        max_version: 5,
+    },
+    ApiVersionRange {
+        api_key: ApiKey::SyncGroup,
+        min_version: 0,
+        max_version: 5,
+    },
+    ApiVersionRange {
+        api_key: ApiKey::DescribeGroups,
+        min_version: 0,
+        max_version: 5,
+    },
+    ApiVersionRange {
+        api_key: ApiKey::ListGroups,
+        min_version: 0,
+        max_version: 5,
+    },
+    ApiVersionRange {
+        api_key: ApiKey::ApiVersions,
+        min_version: 0,
+        max_version: 3,
+    },
+    ApiVersionRange {
+        api_key: ApiKey::DeleteRecords,
+        min_version: 0,
+        max_version: 2,
+    },
+    ApiVersionRange {
+        api_key: ApiKey::InitProducerId,
+        min_version: 0,
+        max_version: 4,
+    },
+    ApiVersionRange {
+        api_key: ApiKey::DeleteGroups,
+        min_version: 0,
+        max_version: 2,
+    },
+    ApiVersionRange {
+        api_key: ApiKey::OffsetDelete,
+        min_version: 0,
+        max_version: 0,
+    },
+    ApiVersionRange {
+        api_key: ApiKey::ConsumerGroupHeartbeat,
+        min_version: 0,
+        max_version: 0,
+    },
+    ApiVersionRange {
+        api_key: ApiKey::ConsumerGroupDescribe,
+        min_version: 0,
+        max_version: 0,
+    },
+];
+
+impl ApiKey {
+    /// Returns the range of versions the broker implements for this API, if any.
+    pub fn supported_versions(self) -> Option<ApiVersionRange> {
+        SUPPORTED_APIS
+            .iter()
+            .find(|range| range.api_key == self)
+            .copied()
+    }
+
+    /// Returns `true` if the broker can parse and answer `api_version` of this API.
+    pub fn is_version_supported(self, api_version: i16) -> bool {
+        self.supported_versions()
+            .is_some_and(|range| (range.min_version..=range.max_version).contains(&api_version))
+    }
+}
+
+impl ApiKey {
+    /// Returns the first "flexible" version of this API, or `None` if no version is flexible.
+    ///
+    /// Flexible versions (KIP-482) use compact strings/arrays, carry tagged fields, and switch
+    /// the request header to v2. These values mirror the `flexibleVersions` of each API's
+    /// message spec in Apache Kafka.
+    pub fn first_flexible_version(self) -> Option<i16> {
+        let version = match self {
+            ApiKey::Produce => 9,
+            ApiKey::Fetch => 12,
+            ApiKey::ListOffsets => 6,
+            ApiKey::Metadata => 9,
+            ApiKey::LeaderAndIsr => 4,
+            ApiKey::StopReplica => 2,
+            ApiKey::UpdateMetadata => 6,
+            ApiKey::ControlledShutdown => 3,
+            ApiKey::OffsetCommit => 8,
+            ApiKey::OffsetFetch => 6,
+            ApiKey::FindCoordinator => 3,
+            ApiKey::JoinGroup => 6,
+            ApiKey::Heartbeat => 4,
+            ApiKey::LeaveGroup => 4,
+            ApiKey::SyncGroup => 4,
+            ApiKey::DescribeGroups => 5,
+            ApiKey::ListGroups => 3,
+            ApiKey::SaslHandshake => return None,
+            ApiKey::ApiVersions => 3,
+            ApiKey::CreateTopics => 5,
+            ApiKey::DeleteTopics => 4,
+            ApiKey::DeleteRecords => 2,
+            ApiKey::InitProducerId => 2,
+            ApiKey::OffsetForLeaderEpoch => 4,
+            ApiKey::AddPartitionsToTxn => 3,
+            ApiKey::AddOffsetsToTxn => 3,
+            ApiKey::EndTxn => 3,
+            ApiKey::WriteTxnMarkers => 1,
+            ApiKey::TxnOffsetCommit => 3,
+            ApiKey::DescribeAcls => 2,
+            ApiKey::CreateAcls => 2,
+            ApiKey::DeleteAcls => 2,
+            ApiKey::DescribeConfigs => 4,
+            ApiKey::AlterConfigs => 2,
+            ApiKey::AlterReplicaLogDirs => 2,
+            ApiKey::DescribeLogDirs => 2,
+            ApiKey::SaslAuthenticate => 2,
+            ApiKey::CreatePartitions => 2,
+            ApiKey::CreateDelegationToken => 2,
+            ApiKey::RenewDelegationToken => 2,
+            ApiKey::ExpireDelegationToken => 2,
+            ApiKey::DescribeDelegationToken => 2,
+            ApiKey::DeleteGroups => 2,
+            ApiKey::ElectLeaders => 2,
+            ApiKey::IncrementalAlterConfigs => 1,
+            ApiKey::OffsetDelete => return None,
+            ApiKey::DescribeClientQuotas => 1,
+            ApiKey::AlterClientQuotas => 1,
+            ApiKey::BeginQuorumEpoch => 1,
+            ApiKey::EndQuorumEpoch => 1,
+            ApiKey::AlterPartitionReassignments
+            | ApiKey::ListPartitionReassignments
+            | ApiKey::DescribeUserScramCredentials
+            | ApiKey::AlterUserScramCredentials
+            | ApiKey::Vote
+            | ApiKey::DescribeQuorum
+            | ApiKey::AlterPartition
+            | ApiKey::UpdateFeatures
+            | ApiKey::Envelope
+            | ApiKey::FetchSnapshot
+            | ApiKey::DescribeCluster
+            | ApiKey::DescribeProducers
+            | ApiKey::BrokerRegistration
+            | ApiKey::BrokerHeartbeat
+            | ApiKey::UnregisterBroker
+            | ApiKey::DescribeTransactions
+            | ApiKey::ListTransactions
+            | ApiKey::AllocateProducerIds
+            | ApiKey::ConsumerGroupHeartbeat
+            | ApiKey::ConsumerGroupDescribe
+            | ApiKey::ControllerRegistration
+            | ApiKey::GetTelemetrySubscriptions
+            | ApiKey::PushTelemetry
+            | ApiKey::AssignReplicasToDirs
+            | ApiKey::ListClientMetricsResources
+            | ApiKey::DescribeTopicPartitions => 0,
+        };
+        Some(version)
+    }
+
+    /// Returns `true` if `api_version` of this API uses the flexible encoding.
+    pub fn is_flexible(self, api_version: i16) -> bool {
+        self.first_flexible_version()
+            .is_some_and(|first| api_version >= first)
+    }
+
+    /// Returns the request header version used by `api_version` of this API.
+    ///
+    /// Flexible versions use header v2 (with tagged fields); everything else uses header v1
+    /// (with a client id). ControlledShutdown v0 is the one exception, predating `client_id`
+    /// and using header v0.
+    pub fn request_header_version(self, api_version: i16) -> i16 {
+        if self == ApiKey::ControlledShutdown && api_version == 0 {
+            0
+        } else if self.is_flexible(api_version) {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Returns the response header version used for `api_version` of this API.
+    ///
+    /// Flexible versions use header v1 (with tagged fields), everything else header v0. The
+    /// exception is ApiVersions, which always answers with header v0: a client that doesn't
+    /// yet know which versions the broker supports must be able to parse the response.
+    pub fn response_header_version(self, api_version: i16) -> i16 {
+        if self != ApiKey::ApiVersions && self.is_flexible(api_version) {
+            1
+        } else {
+            0
+        }
+    }
+}
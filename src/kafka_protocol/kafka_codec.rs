@@ -0,0 +1,474 @@
+//! # KafkaCodec Module
+//!
+//! Encoders and decoders for the primitive types of the Kafka protocol. Every request and
+//! response body is built out of these, so they are shared by the header parser and all API
+//! messages.
+//!
+//! Decoders take a `cursor: &mut &[u8]` and advance it past the bytes they consume; encoders
+//! append to a `Vec<u8>`. Malformed input is reported as
+//! [`KafkaBrokerError::MalformedRequest`] with `INVALID_REQUEST`.
+//!
+//! Besides the fixed-width integers, this covers the variable-length types introduced for
+//! flexible versions (KIP-482):
+//! - unsigned varints, zig-zag varints and varlongs,
+//! - compact strings, compact nullable strings, compact bytes and compact arrays, which store
+//!   `length + 1` as an unsigned varint so that `0` can mean null,
+//! - tagged field sections: an unsigned-varint count followed by `(tag, size, data)` triples.
+//!
+//! See <https://kafka.apache.org/protocol#protocol_types> for the full definitions.
+
+use crate::kafka_protocol::kafka_error::{KafkaBrokerError, KafkaResult};
+use crate::kafka_protocol::kafka_error_codes::INVALID_REQUEST;
+use tracing::warn;
+use uuid::Uuid;
+
+/// A tagged field as it appears on the wire, kept as raw bytes.
+///
+/// Tags a message doesn't know about must be preserved rather than rejected, so decoders
+/// collect them into a list of these.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawTaggedField {
+    /// The field's tag number.
+    pub tag: u32,
+    /// The encoded field value.
+    pub data: Vec<u8>,
+}
+
+/// Builds the error returned for any decoding failure.
+pub fn malformed(reason: impl Into<String>) -> KafkaBrokerError {
+    let reason = reason.into();
+    warn!("Malformed request: {}", reason);
+    KafkaBrokerError::MalformedRequest {
+        code: INVALID_REQUEST,
+        reason,
+    }
+}
+
+/// Splits `len` bytes off the front of the cursor.
+pub fn read_slice<'a>(cursor: &mut &'a [u8], len: usize, what: &str) -> KafkaResult<&'a [u8]> {
+    if cursor.len() < len {
+        return Err(malformed(format!(
+            "{} needs {} bytes, but only {} remain",
+            what,
+            len,
+            cursor.len()
+        )));
+    }
+    let (head, tail) = cursor.split_at(len);
+    *cursor = tail;
+    Ok(head)
+}
+
+/// Reads exactly `N` bytes into an array.
+fn read_fixed<const N: usize>(cursor: &mut &[u8], what: &str) -> KafkaResult<[u8; N]> {
+    let mut bytes = [0u8; N];
+    bytes.copy_from_slice(read_slice(cursor, N, what)?);
+    Ok(bytes)
+}
+
+/* ---------------------------------------------------------------------------------------------
+Fixed-width types
+--------------------------------------------------------------------------------------------- */
+
+pub fn read_bool(cursor: &mut &[u8]) -> KafkaResult<bool> {
+    Ok(read_i8(cursor)? != 0)
+}
+
+pub fn read_i8(cursor: &mut &[u8]) -> KafkaResult<i8> {
+    Ok(i8::from_be_bytes(read_fixed(cursor, "int8")?))
+}
+
+pub fn read_i16(cursor: &mut &[u8]) -> KafkaResult<i16> {
+    Ok(i16::from_be_bytes(read_fixed(cursor, "int16")?))
+}
+
+pub fn read_i32(cursor: &mut &[u8]) -> KafkaResult<i32> {
+    Ok(i32::from_be_bytes(read_fixed(cursor, "int32")?))
+}
+
+pub fn read_u32(cursor: &mut &[u8]) -> KafkaResult<u32> {
+    Ok(u32::from_be_bytes(read_fixed(cursor, "uint32")?))
+}
+
+pub fn read_i64(cursor: &mut &[u8]) -> KafkaResult<i64> {
+    Ok(i64::from_be_bytes(read_fixed(cursor, "int64")?))
+}
+
+pub fn read_uuid(cursor: &mut &[u8]) -> KafkaResult<Uuid> {
+    Ok(Uuid::from_bytes(read_fixed(cursor, "uuid")?))
+}
+
+pub fn write_bool(buf: &mut Vec<u8>, value: bool) {
+    buf.push(u8::from(value));
+}
+
+pub fn write_i8(buf: &mut Vec<u8>, value: i8) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+pub fn write_i16(buf: &mut Vec<u8>, value: i16) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+pub fn write_i32(buf: &mut Vec<u8>, value: i32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+pub fn write_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+pub fn write_i64(buf: &mut Vec<u8>, value: i64) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+pub fn write_uuid(buf: &mut Vec<u8>, value: &Uuid) {
+    buf.extend_from_slice(value.as_bytes());
+}
+
+/* ---------------------------------------------------------------------------------------------
+Variable-length integers
+--------------------------------------------------------------------------------------------- */
+
+/// Reads an unsigned varint (LEB128, 7 bits per byte, at most 5 bytes). A value that doesn't
+/// fit in 32 bits is malformed.
+pub fn read_unsigned_varint(cursor: &mut &[u8]) -> KafkaResult<u32> {
+    let mut value: u32 = 0;
+    for i in 0..5 {
+        let byte = read_fixed::<1>(cursor, "unsigned varint")?[0];
+        // The 5th byte only has room for the top 4 bits.
+        if i == 4 && byte & 0xf0 != 0 {
+            return Err(malformed("Unsigned varint doesn't fit in 32 bits"));
+        }
+        value |= u32::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    unreachable!("the 5th byte of a varint has no continuation bit")
+}
+
+/// Reads an unsigned varlong (LEB128, 7 bits per byte, at most 10 bytes). A value that doesn't
+/// fit in 64 bits is malformed.
+pub fn read_unsigned_varlong(cursor: &mut &[u8]) -> KafkaResult<u64> {
+    let mut value: u64 = 0;
+    for i in 0..10 {
+        let byte = read_fixed::<1>(cursor, "unsigned varlong")?[0];
+        // The 10th byte only has room for the top bit.
+        if i == 9 && byte & 0xfe != 0 {
+            return Err(malformed("Unsigned varlong doesn't fit in 64 bits"));
+        }
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    unreachable!("the 10th byte of a varlong has no continuation bit")
+}
+
+/// Reads a zig-zag encoded signed varint.
+pub fn read_varint(cursor: &mut &[u8]) -> KafkaResult<i32> {
+    let raw = read_unsigned_varint(cursor)?;
+    Ok(((raw >> 1) as i32) ^ -((raw & 1) as i32))
+}
+
+/// Reads a zig-zag encoded signed varlong.
+pub fn read_varlong(cursor: &mut &[u8]) -> KafkaResult<i64> {
+    let raw = read_unsigned_varlong(cursor)?;
+    Ok(((raw >> 1) as i64) ^ -((raw & 1) as i64))
+}
+
+pub fn write_unsigned_varint(buf: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        buf.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+pub fn write_unsigned_varlong(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+pub fn write_varint(buf: &mut Vec<u8>, value: i32) {
+    write_unsigned_varint(buf, ((value << 1) ^ (value >> 31)) as u32);
+}
+
+pub fn write_varlong(buf: &mut Vec<u8>, value: i64) {
+    write_unsigned_varlong(buf, ((value << 1) ^ (value >> 63)) as u64);
+}
+
+/* ---------------------------------------------------------------------------------------------
+Strings
+--------------------------------------------------------------------------------------------- */
+
+fn utf8(bytes: &[u8]) -> KafkaResult<String> {
+    std::str::from_utf8(bytes)
+        .map(str::to_string)
+        .map_err(|_| malformed("String is not valid UTF-8"))
+}
+
+/// Reads a nullable string with a 2-byte length prefix, where a negative length means null.
+pub fn read_nullable_string(cursor: &mut &[u8]) -> KafkaResult<Option<String>> {
+    let length = read_i16(cursor)?;
+    if length < 0 {
+        return Ok(None);
+    }
+    let bytes = read_slice(cursor, length as usize, "string")?;
+    utf8(bytes).map(Some)
+}
+
+/// Reads a non-null string with a 2-byte length prefix.
+pub fn read_string(cursor: &mut &[u8]) -> KafkaResult<String> {
+    read_nullable_string(cursor)?.ok_or_else(|| malformed("Unexpected null string"))
+}
+
+/// Reads a compact nullable string (unsigned varint `length + 1`, `0` meaning null).
+pub fn read_compact_nullable_string(cursor: &mut &[u8]) -> KafkaResult<Option<String>> {
+    let length_plus_one = read_unsigned_varint(cursor)? as usize;
+    if length_plus_one == 0 {
+        return Ok(None);
+    }
+    let bytes = read_slice(cursor, length_plus_one - 1, "compact string")?;
+    utf8(bytes).map(Some)
+}
+
+/// Reads a non-null compact string.
+pub fn read_compact_string(cursor: &mut &[u8]) -> KafkaResult<String> {
+    read_compact_nullable_string(cursor)?.ok_or_else(|| malformed("Unexpected null compact string"))
+}
+
+pub fn write_nullable_string(buf: &mut Vec<u8>, value: Option<&str>) {
+    match value {
+        Some(s) => {
+            write_i16(buf, s.len() as i16);
+            buf.extend_from_slice(s.as_bytes());
+        }
+        None => write_i16(buf, -1),
+    }
+}
+
+pub fn write_string(buf: &mut Vec<u8>, value: &str) {
+    write_nullable_string(buf, Some(value));
+}
+
+pub fn write_compact_nullable_string(buf: &mut Vec<u8>, value: Option<&str>) {
+    match value {
+        Some(s) => {
+            write_unsigned_varint(buf, s.len() as u32 + 1);
+            buf.extend_from_slice(s.as_bytes());
+        }
+        None => write_unsigned_varint(buf, 0),
+    }
+}
+
+pub fn write_compact_string(buf: &mut Vec<u8>, value: &str) {
+    write_compact_nullable_string(buf, Some(value));
+}
+
+/* ---------------------------------------------------------------------------------------------
+Bytes
+--------------------------------------------------------------------------------------------- */
+
+/// Reads nullable bytes with a 4-byte length prefix, where a negative length means null.
+pub fn read_nullable_bytes(cursor: &mut &[u8]) -> KafkaResult<Option<Vec<u8>>> {
+    let length = read_i32(cursor)?;
+    if length < 0 {
+        return Ok(None);
+    }
+    Ok(Some(read_slice(cursor, length as usize, "bytes")?.to_vec()))
+}
+
+/// Reads non-null bytes with a 4-byte length prefix.
+pub fn read_bytes(cursor: &mut &[u8]) -> KafkaResult<Vec<u8>> {
+    read_nullable_bytes(cursor)?.ok_or_else(|| malformed("Unexpected null bytes"))
+}
+
+/// Reads compact nullable bytes (unsigned varint `length + 1`, `0` meaning null).
+pub fn read_compact_nullable_bytes(cursor: &mut &[u8]) -> KafkaResult<Option<Vec<u8>>> {
+    let length_plus_one = read_unsigned_varint(cursor)? as usize;
+    if length_plus_one == 0 {
+        return Ok(None);
+    }
+    Ok(Some(
+        read_slice(cursor, length_plus_one - 1, "compact bytes")?.to_vec(),
+    ))
+}
+
+/// Reads non-null compact bytes.
+pub fn read_compact_bytes(cursor: &mut &[u8]) -> KafkaResult<Vec<u8>> {
+    read_compact_nullable_bytes(cursor)?.ok_or_else(|| malformed("Unexpected null compact bytes"))
+}
+
+pub fn write_nullable_bytes(buf: &mut Vec<u8>, value: Option<&[u8]>) {
+    match value {
+        Some(bytes) => {
+            write_i32(buf, bytes.len() as i32);
+            buf.extend_from_slice(bytes);
+        }
+        None => write_i32(buf, -1),
+    }
+}
+
+pub fn write_bytes(buf: &mut Vec<u8>, value: &[u8]) {
+    write_nullable_bytes(buf, Some(value));
+}
+
+pub fn write_compact_nullable_bytes(buf: &mut Vec<u8>, value: Option<&[u8]>) {
+    match value {
+        Some(bytes) => {
+            write_unsigned_varint(buf, bytes.len() as u32 + 1);
+            buf.extend_from_slice(bytes);
+        }
+        None => write_unsigned_varint(buf, 0),
+    }
+}
+
+pub fn write_compact_bytes(buf: &mut Vec<u8>, value: &[u8]) {
+    write_compact_nullable_bytes(buf, Some(value));
+}
+
+/* ---------------------------------------------------------------------------------------------
+Arrays
+--------------------------------------------------------------------------------------------- */
+
+/// Reads the 4-byte length of a nullable array, where a negative length means null.
+pub fn read_array_len(cursor: &mut &[u8]) -> KafkaResult<Option<usize>> {
+    let length = read_i32(cursor)?;
+    Ok((length >= 0).then_some(length as usize))
+}
+
+/// Reads the unsigned-varint `length + 1` of a compact nullable array, `0` meaning null.
+pub fn read_compact_array_len(cursor: &mut &[u8]) -> KafkaResult<Option<usize>> {
+    let length_plus_one = read_unsigned_varint(cursor)? as usize;
+    Ok(length_plus_one.checked_sub(1))
+}
+
+/// Reads a non-null array whose length is encoded as in [`read_array_len`] or, if `compact`,
+/// [`read_compact_array_len`], decoding each element with `read_element`.
+pub fn read_array<T>(
+    cursor: &mut &[u8],
+    compact: bool,
+    read_element: impl FnMut(&mut &[u8]) -> KafkaResult<T>,
+) -> KafkaResult<Vec<T>> {
+    read_nullable_array(cursor, compact, read_element)?
+        .ok_or_else(|| malformed("Unexpected null array"))
+}
+
+/// Reads a nullable array; see [`read_array`].
+pub fn read_nullable_array<T>(
+    cursor: &mut &[u8],
+    compact: bool,
+    mut read_element: impl FnMut(&mut &[u8]) -> KafkaResult<T>,
+) -> KafkaResult<Option<Vec<T>>> {
+    let length = if compact {
+        read_compact_array_len(cursor)?
+    } else {
+        read_array_len(cursor)?
+    };
+    let Some(length) = length else {
+        return Ok(None);
+    };
+
+    // Every element takes at least one byte, so never trust a length beyond what's left.
+    let mut elements = Vec::with_capacity(length.min(cursor.len()));
+    for _ in 0..length {
+        elements.push(read_element(cursor)?);
+    }
+    Ok(Some(elements))
+}
+
+/// Writes the length of a nullable array, compact or not.
+pub fn write_array_len(buf: &mut Vec<u8>, length: Option<usize>, compact: bool) {
+    match (length, compact) {
+        (Some(len), true) => write_unsigned_varint(buf, len as u32 + 1),
+        (None, true) => write_unsigned_varint(buf, 0),
+        (Some(len), false) => write_i32(buf, len as i32),
+        (None, false) => write_i32(buf, -1),
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------
+Tagged fields
+--------------------------------------------------------------------------------------------- */
+
+/// Reads a tagged field section: an unsigned-varint count followed by `(tag, size, data)`.
+///
+/// Tags must appear in strictly increasing order.
+pub fn read_tagged_fields(cursor: &mut &[u8]) -> KafkaResult<Vec<RawTaggedField>> {
+    let count = read_unsigned_varint(cursor)? as usize;
+    let mut fields = Vec::with_capacity(count.min(cursor.len()));
+    let mut previous_tag: Option<u32> = None;
+
+    for _ in 0..count {
+        let tag = read_unsigned_varint(cursor)?;
+        if previous_tag.is_some_and(|previous| tag <= previous) {
+            return Err(malformed(format!(
+                "Tagged fields out of order: tag {} follows {:?}",
+                tag, previous_tag
+            )));
+        }
+        previous_tag = Some(tag);
+
+        let size = read_unsigned_varint(cursor)? as usize;
+        let data = read_slice(cursor, size, "tagged field")?.to_vec();
+        fields.push(RawTaggedField { tag, data });
+    }
+    Ok(fields)
+}
+
+/// Writes a tagged field section. `fields` must be sorted by tag.
+pub fn write_tagged_fields(buf: &mut Vec<u8>, fields: &[RawTaggedField]) {
+    write_unsigned_varint(buf, fields.len() as u32);
+    for field in fields {
+        write_unsigned_varint(buf, field.tag);
+        write_unsigned_varint(buf, field.data.len() as u32);
+        buf.extend_from_slice(&field.data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsigned_varint_round_trips_u32_max() {
+        let mut buf = Vec::new();
+        write_unsigned_varint(&mut buf, u32::MAX);
+        assert_eq!(buf, [0xff, 0xff, 0xff, 0xff, 0x0f]);
+        assert_eq!(read_unsigned_varint(&mut buf.as_slice()).unwrap(), u32::MAX);
+    }
+
+    #[test]
+    fn unsigned_varint_beyond_32_bits_is_malformed() {
+        for bytes in [
+            [0xff, 0xff, 0xff, 0xff, 0x1f],
+            [0x80, 0x80, 0x80, 0x80, 0x80],
+        ] {
+            assert!(matches!(
+                read_unsigned_varint(&mut bytes.as_slice()),
+                Err(KafkaBrokerError::MalformedRequest { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn unsigned_varlong_beyond_64_bits_is_malformed() {
+        let mut buf = Vec::new();
+        write_unsigned_varlong(&mut buf, u64::MAX);
+        assert_eq!(
+            read_unsigned_varlong(&mut buf.as_slice()).unwrap(),
+            u64::MAX
+        );
+
+        *buf.last_mut().unwrap() = 0x02;
+        assert!(matches!(
+            read_unsigned_varlong(&mut buf.as_slice()),
+            Err(KafkaBrokerError::MalformedRequest { .. })
+        ));
+    }
+}
@@ -0,0 +1,422 @@
+//! # KafkaCompression Module
+//!
+//! The compression codecs a v2 record batch may use for its records section, identified by the
+//! low three bits of the batch attributes. Only the records are compressed; the batch header
+//! always stays readable.
+//!
+//! Each codec sits behind a cargo feature of the same name (`gzip`, `snappy`, `lz4`, `zstd`),
+//! all enabled by default. A codec whose feature is disabled behaves like an unknown one:
+//! batches using it are rejected with [`KafkaBrokerError::UnsupportedCompressionType`].
+//!
+//! The wire formats follow the Java client rather than each library's defaults:
+//! - gzip is a plain gzip stream,
+//! - snappy uses the framing of `snappy-java`'s `SnappyOutputStream` (a 16-byte header followed
+//!   by length-prefixed raw snappy blocks); unframed raw snappy is accepted on input,
+//! - lz4 is the standard LZ4 frame format with independent blocks and no checksums,
+//! - zstd is a standard zstd frame.
+
+use crate::kafka_protocol::kafka_error::{KafkaBrokerError, KafkaResult};
+use std::io;
+
+/// A record batch compression codec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Records are stored as they are.
+    None = 0,
+    /// gzip (deflate).
+    Gzip = 1,
+    /// Snappy, in `snappy-java` framing.
+    Snappy = 2,
+    /// LZ4 frames.
+    Lz4 = 3,
+    /// Zstandard.
+    Zstd = 4,
+}
+
+impl Compression {
+    /// Looks up the codec with attribute id `id`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::UnsupportedCompressionType`] if `id` names no codec, or a
+    /// codec this build was compiled without.
+    pub fn from_id(id: i16) -> KafkaResult<Self> {
+        let compression = match id {
+            0 => Compression::None,
+            1 => Compression::Gzip,
+            2 => Compression::Snappy,
+            3 => Compression::Lz4,
+            4 => Compression::Zstd,
+            _ => {
+                return Err(KafkaBrokerError::UnsupportedCompressionType(format!(
+                    "Unknown compression codec id {}",
+                    id
+                )))
+            }
+        };
+        if !compression.is_enabled() {
+            return Err(KafkaBrokerError::UnsupportedCompressionType(format!(
+                "Compression codec {} is not enabled in this build",
+                compression.name()
+            )));
+        }
+        Ok(compression)
+    }
+
+    /// Parses a codec name as used by `compression.type` (`none` or `uncompressed`, `gzip`,
+    /// `snappy`, `lz4`, `zstd`). Returns `None` for unknown names and disabled codecs.
+    pub fn parse(name: &str) -> Option<Self> {
+        let compression = match name {
+            "none" | "uncompressed" => Compression::None,
+            "gzip" => Compression::Gzip,
+            "snappy" => Compression::Snappy,
+            "lz4" => Compression::Lz4,
+            "zstd" => Compression::Zstd,
+            _ => return None,
+        };
+        compression.is_enabled().then_some(compression)
+    }
+
+    /// The codec's attribute id.
+    pub fn id(self) -> i16 {
+        self as i16
+    }
+
+    /// The codec's `compression.type` name.
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Snappy => "snappy",
+            Compression::Lz4 => "lz4",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// Whether this build includes the codec.
+    fn is_enabled(self) -> bool {
+        match self {
+            Compression::None => true,
+            Compression::Gzip => cfg!(feature = "gzip"),
+            Compression::Snappy => cfg!(feature = "snappy"),
+            Compression::Lz4 => cfg!(feature = "lz4"),
+            Compression::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+
+    /// Compresses `data` with this codec.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::UnsupportedCompressionType`] if the codec isn't enabled, or
+    /// [`KafkaBrokerError::Io`] if the encoder fails.
+    pub fn compress(self, data: &[u8]) -> KafkaResult<Vec<u8>> {
+        if !self.is_enabled() {
+            return Err(self.disabled());
+        }
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => gzip::compress(data),
+            #[cfg(feature = "snappy")]
+            Compression::Snappy => snappy::compress(data),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => lz4::compress(data),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(zstd::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL)?),
+            #[allow(unreachable_patterns)]
+            _ => unreachable!("disabled codecs are rejected above"),
+        }
+    }
+
+    /// Decompresses `data`, which was compressed with this codec.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::CorruptMessage`] if `data` can't be decompressed, or
+    /// [`KafkaBrokerError::UnsupportedCompressionType`] if the codec isn't enabled.
+    pub fn decompress(self, data: &[u8]) -> KafkaResult<Vec<u8>> {
+        if !self.is_enabled() {
+            return Err(self.disabled());
+        }
+        let decompressed: io::Result<Vec<u8>> = match self {
+            Compression::None => Ok(data.to_vec()),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => gzip::decompress(data),
+            #[cfg(feature = "snappy")]
+            Compression::Snappy => snappy::decompress(data),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => lz4::decompress(data),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::decode_all(data),
+            #[allow(unreachable_patterns)]
+            _ => unreachable!("disabled codecs are rejected above"),
+        };
+        decompressed.map_err(|e| {
+            KafkaBrokerError::CorruptMessage(format!(
+                "Failed to decompress {} records: {}",
+                self.name(),
+                e
+            ))
+        })
+    }
+
+    /// Decompresses the value of a legacy wrapper message with format `magic` (0 or 1).
+    ///
+    /// Same as [`Compression::decompress`], except that LZ4 frames in magic 0 messages are
+    /// accepted despite the wrong header checksum Kafka wrote before KIP-57.
+    #[cfg_attr(not(feature = "lz4"), allow(unused_variables))]
+    pub fn decompress_legacy(self, data: &[u8], magic: i8) -> KafkaResult<Vec<u8>> {
+        match self {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 if magic == 0 => self.decompress(&lz4::fix_header_checksum(data)),
+            _ => self.decompress(data),
+        }
+    }
+
+    /// The error for a codec this build was compiled without.
+    fn disabled(self) -> KafkaBrokerError {
+        KafkaBrokerError::UnsupportedCompressionType(format!(
+            "Compression codec {} is not enabled in this build",
+            self.name()
+        ))
+    }
+}
+
+#[cfg(feature = "gzip")]
+mod gzip {
+    use crate::kafka_protocol::kafka_error::KafkaResult;
+    use flate2::read::MultiGzDecoder;
+    use flate2::write::GzEncoder;
+    use std::io::{self, Read, Write};
+
+    pub fn compress(data: &[u8]) -> KafkaResult<Vec<u8>> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data)?;
+        Ok(encoder.finish()?)
+    }
+
+    pub fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(data).read_to_end(&mut decompressed)?;
+        Ok(decompressed)
+    }
+}
+
+#[cfg(feature = "snappy")]
+mod snappy {
+    use crate::kafka_protocol::kafka_error::KafkaResult;
+    use std::io;
+
+    /// The header `snappy-java` starts its streams with: a magic, then the stream format version
+    /// and the oldest compatible version, both 1.
+    const XERIAL_HEADER: [u8; 16] = [
+        0x82, b'S', b'N', b'A', b'P', b'P', b'Y', 0, 0, 0, 0, 1, 0, 0, 0, 1,
+    ];
+    /// The length of the magic at the start of [`XERIAL_HEADER`].
+    const XERIAL_MAGIC_LEN: usize = 8;
+    /// How much uncompressed data `snappy-java` puts in each block.
+    const XERIAL_BLOCK_SIZE: usize = 32 * 1024;
+
+    pub fn compress(data: &[u8]) -> KafkaResult<Vec<u8>> {
+        let mut encoder = snap::raw::Encoder::new();
+        let mut compressed = XERIAL_HEADER.to_vec();
+        for block in data.chunks(XERIAL_BLOCK_SIZE) {
+            let block = encoder.compress_vec(block).map_err(io::Error::from)?;
+            compressed.extend_from_slice(&(block.len() as i32).to_be_bytes());
+            compressed.extend_from_slice(&block);
+        }
+        Ok(compressed)
+    }
+
+    pub fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decoder = snap::raw::Decoder::new();
+        if !data.starts_with(&XERIAL_HEADER[..XERIAL_MAGIC_LEN]) {
+            return Ok(decoder.decompress_vec(data)?);
+        }
+
+        let mut cursor = data.get(XERIAL_HEADER.len()..).unwrap_or_default();
+        let mut decompressed = Vec::new();
+        while !cursor.is_empty() {
+            let truncated = || io::Error::new(io::ErrorKind::UnexpectedEof, "truncated block");
+            let length = cursor.get(..4).ok_or_else(truncated)?;
+            let length = i32::from_be_bytes(length.try_into().unwrap()).max(0) as usize;
+            let block = cursor.get(4..4 + length).ok_or_else(truncated)?;
+            decompressed.extend_from_slice(&decoder.decompress_vec(block)?);
+            cursor = &cursor[4 + length..];
+        }
+        Ok(decompressed)
+    }
+}
+
+#[cfg(feature = "lz4")]
+mod lz4 {
+    use crate::kafka_protocol::kafka_error::KafkaResult;
+    use lz4_flex::frame::{BlockMode, FrameDecoder, FrameEncoder, FrameInfo};
+    use std::io::{self, Read, Write};
+    use twox_hash::XxHash32;
+
+    pub fn compress(data: &[u8]) -> KafkaResult<Vec<u8>> {
+        // Kafka's own LZ4 reader only handles independent blocks.
+        let info = FrameInfo::new().block_mode(BlockMode::Independent);
+        let mut encoder = FrameEncoder::with_frame_info(info, Vec::new());
+        encoder.write_all(data)?;
+        Ok(encoder.finish().map_err(io::Error::from)?)
+    }
+
+    pub fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        FrameDecoder::new(data).read_to_end(&mut decompressed)?;
+        Ok(decompressed)
+    }
+
+    /// Recomputes the header checksum of a frame written by a pre-KIP-57 client, which hashed
+    /// the frame magic along with the frame descriptor.
+    pub fn fix_header_checksum(data: &[u8]) -> Vec<u8> {
+        let mut data = data.to_vec();
+        let Some(&flags) = data.get(4) else {
+            return data;
+        };
+        // The descriptor is FLG and BD, then the optional content size and dictionary id.
+        let mut checksum_position = 6;
+        if flags & 0x08 != 0 {
+            checksum_position += 8;
+        }
+        if flags & 0x01 != 0 {
+            checksum_position += 4;
+        }
+        if checksum_position < data.len() {
+            let checksum = XxHash32::oneshot(0, &data[4..checksum_position]);
+            data[checksum_position] = (checksum >> 8) as u8;
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kafka_protocol::kafka_error_codes::UNSUPPORTED_COMPRESSION_TYPE;
+
+    /// Compressible records, spanning more than one snappy block.
+    #[cfg_attr(
+        not(any(
+            feature = "gzip",
+            feature = "snappy",
+            feature = "lz4",
+            feature = "zstd"
+        )),
+        allow(dead_code)
+    )]
+    fn records() -> Vec<u8> {
+        (0..40_000u32)
+            .flat_map(|i| (i % 251).to_be_bytes())
+            .collect()
+    }
+
+    #[cfg_attr(
+        not(any(
+            feature = "gzip",
+            feature = "snappy",
+            feature = "lz4",
+            feature = "zstd"
+        )),
+        allow(dead_code)
+    )]
+    fn assert_round_trip(compression: Compression) {
+        let records = records();
+        let compressed = compression.compress(&records).unwrap();
+        assert!(compressed.len() < records.len());
+        assert_eq!(compression.decompress(&compressed).unwrap(), records);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip_round_trip() {
+        assert_round_trip(Compression::Gzip);
+    }
+
+    #[cfg(feature = "snappy")]
+    #[test]
+    fn snappy_round_trip_uses_xerial_framing() {
+        assert_round_trip(Compression::Snappy);
+
+        let records = records();
+        let compressed = Compression::Snappy.compress(&records).unwrap();
+        assert_eq!(compressed[..16], *b"\x82SNAPPY\0\0\0\0\x01\0\0\0\x01");
+        // Walk the length-prefixed blocks: one per 32 KiB of records.
+        let mut cursor = &compressed[16..];
+        let mut blocks = 0;
+        while !cursor.is_empty() {
+            let length = i32::from_be_bytes(cursor[..4].try_into().unwrap()) as usize;
+            cursor = &cursor[4 + length..];
+            blocks += 1;
+        }
+        assert_eq!(blocks, records.len().div_ceil(32 * 1024));
+    }
+
+    #[cfg(feature = "snappy")]
+    #[test]
+    fn unframed_snappy_is_accepted() {
+        let records = records();
+        let compressed = snap::raw::Encoder::new().compress_vec(&records).unwrap();
+        assert_eq!(
+            Compression::Snappy.decompress(&compressed).unwrap(),
+            records
+        );
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_round_trip() {
+        assert_round_trip(Compression::Lz4);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_magic_0_header_checksum_is_fixed() {
+        let records = records();
+        let mut compressed = Compression::Lz4.compress(&records).unwrap();
+        // Without a content size or dictionary id the descriptor is FLG and BD, followed by
+        // the header checksum. Old clients hashed the frame magic along with them.
+        assert_eq!(compressed[4] & 0x09, 0);
+        let checksum = twox_hash::XxHash32::oneshot(0, &compressed[..6]);
+        compressed[6] = (checksum >> 8) as u8;
+
+        assert!(matches!(
+            Compression::Lz4.decompress_legacy(&compressed, 1),
+            Err(KafkaBrokerError::CorruptMessage(_))
+        ));
+        assert_eq!(
+            Compression::Lz4.decompress_legacy(&compressed, 0).unwrap(),
+            records
+        );
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_round_trip() {
+        assert_round_trip(Compression::Zstd);
+    }
+
+    #[test]
+    fn unknown_and_disabled_codecs_are_unsupported() {
+        let unsupported = |result: KafkaResult<_>| matches!(result, Err(e) if e.error_code() == UNSUPPORTED_COMPRESSION_TYPE);
+        assert!(unsupported(Compression::from_id(5).map(|_| ())));
+
+        let codecs = [
+            Compression::Gzip,
+            Compression::Snappy,
+            Compression::Lz4,
+            Compression::Zstd,
+        ];
+        for compression in codecs.into_iter().filter(|c| !c.is_enabled()) {
+            assert!(unsupported(
+                Compression::from_id(compression.id()).map(|_| ())
+            ));
+            assert!(unsupported(compression.compress(b"records").map(|_| ())));
+            assert!(unsupported(compression.decompress(b"records").map(|_| ())));
+            assert_eq!(Compression::parse(compression.name()), None);
+        }
+    }
+}
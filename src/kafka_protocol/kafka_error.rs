@@ -2,7 +2,16 @@ use std::io;
 use thiserror::Error;
 
 use super::kafka_error_codes::{
-    INVALID_REQUEST, UNKNOWN_SERVER_ERROR, /* plus any other codes you need */
+    COORDINATOR_NOT_AVAILABLE, CORRUPT_MESSAGE, FENCED_INSTANCE_ID, FENCED_MEMBER_EPOCH,
+    GROUP_ID_NOT_FOUND, GROUP_MAX_SIZE_REACHED, GROUP_SUBSCRIBED_TO_TOPIC, ILLEGAL_GENERATION,
+    INCONSISTENT_GROUP_PROTOCOL, INVALID_COMMIT_OFFSET_SIZE, INVALID_CONFIG, INVALID_GROUP_ID,
+    INVALID_PRODUCER_EPOCH, INVALID_RECORD, INVALID_REQUEST, INVALID_SESSION_TIMEOUT,
+    MEMBER_ID_REQUIRED, MESSAGE_TOO_LARGE, NON_EMPTY_GROUP, OFFSET_METADATA_TOO_LARGE,
+    OFFSET_OUT_OF_RANGE, OUT_OF_ORDER_SEQUENCE_NUMBER, REBALANCE_IN_PROGRESS, STALE_MEMBER_EPOCH,
+    UNKNOWN_MEMBER_ID, UNKNOWN_SERVER_ERROR, UNKNOWN_TOPIC_OR_PARTITION, UNRELEASED_INSTANCE_ID,
+    UNSUPPORTED_ASSIGNOR, UNSUPPORTED_COMPRESSION_TYPE,
+    UNSUPPORTED_VERSION,
+    /* plus any other codes you need */
 };
 
 /// A specialized `Result` type for Kafka broker operations.
@@ -23,10 +32,226 @@ pub enum KafkaBrokerError {
         reason: String,
     },
 
-    /// A generic internal server error, or an unexpected situation.
-    /// By default, we might map this to `UNKNOWN_SERVER_ERROR`.
-    #[error("Internal server error: {0}")]
-    InternalServerError(String),
+    /// The request header carries an API key that the Kafka protocol does not define.
+    #[error("Unknown API key: {0}")]
+    UnknownApiKey(i16),
+
+    /// The API key is known, but this broker does not implement the requested
+    /// version of it (or does not implement the API at all).
+    #[error("Unsupported API version: api_key={api_key}, api_version={api_version}")]
+    UnsupportedVersion {
+        /// The raw API key from the request header.
+        api_key: i16,
+        /// The API version the client asked for.
+        api_version: i16,
+    },
+
+    /// The client sent a frame with a negative size, or larger than `socket.request.max.bytes`.
+    /// Kafka closes the connection in this case rather than replying.
+    #[error("Request of {size} bytes is not between 0 and the maximum of {max} bytes")]
+    RequestTooLarge {
+        /// The `message_size` announced by the client.
+        size: i32,
+        /// The configured limit.
+        max: usize,
+    },
+
+    /// The topic or partition a request refers to does not exist on this broker.
+    #[error("Unknown topic or partition: {topic}-{partition}")]
+    UnknownTopicOrPartition {
+        /// The topic name.
+        topic: String,
+        /// The partition index.
+        partition: i32,
+    },
+
+    /// A fetch asked for an offset outside of the partition's log.
+    #[error("Offset {offset} is out of range [{log_start_offset}, {log_end_offset}]")]
+    OffsetOutOfRange {
+        /// The requested offset.
+        offset: i64,
+        /// The first offset in the log.
+        log_start_offset: i64,
+        /// The offset the next appended record will get.
+        log_end_offset: i64,
+    },
+
+    /// A record batch failed its integrity checks (CRC mismatch, truncated or
+    /// inconsistent sizes).
+    #[error("Corrupt message: {0}")]
+    CorruptMessage(String),
+
+    /// A record batch is larger than the topic's `max.message.bytes`.
+    #[error("Record batch of {size} bytes exceeds the maximum of {max} bytes")]
+    MessageTooLarge {
+        /// The size of the offending batch.
+        size: usize,
+        /// The configured limit.
+        max: usize,
+    },
+
+    /// A record batch is well-formed but violates the protocol's rules (wrong magic,
+    /// no records, too many batches, ...).
+    #[error("Invalid record: {0}")]
+    InvalidRecord(String),
+
+    /// A record batch uses a compression codec this broker doesn't know or wasn't built with.
+    #[error("Unsupported compression type: {0}")]
+    UnsupportedCompressionType(String),
+
+    /// A topic configuration names an unknown setting or gives one an invalid value.
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+
+    /// A group id is empty where one is required.
+    #[error("Invalid group id: {0:?}")]
+    InvalidGroupId(String),
+
+    /// The group coordinator can't serve the group right now (e.g. it is being removed); the
+    /// client should look the coordinator up again and retry.
+    #[error("Coordinator not available for group {0}")]
+    CoordinatorNotAvailable(String),
+
+    /// A request names a member the group doesn't have.
+    #[error("Unknown member {member_id} in group {group_id}")]
+    UnknownMemberId {
+        /// The group id.
+        group_id: String,
+        /// The member id from the request.
+        member_id: String,
+    },
+
+    /// A JoinGroup from a new member; it must join again with the member id it was given.
+    #[error("Member {member_id} must rejoin with its member id")]
+    MemberIdRequired {
+        /// The member id assigned to the new member.
+        member_id: String,
+    },
+
+    /// A request refers to a generation other than the group's current one.
+    #[error("Generation {generation_id} of group {group_id} is not the current one")]
+    IllegalGeneration {
+        /// The group id.
+        group_id: String,
+        /// The generation from the request.
+        generation_id: i32,
+    },
+
+    /// The group is rebalancing; the member must rejoin.
+    #[error("Group {0} is rebalancing")]
+    RebalanceInProgress(String),
+
+    /// A static member (`group.instance.id`) has been replaced by a newer instance.
+    #[error("Instance {instance_id} of group {group_id} has been fenced")]
+    FencedInstanceId {
+        /// The group id.
+        group_id: String,
+        /// The group instance id from the request.
+        instance_id: String,
+    },
+
+    /// A member's protocol type or assignors don't match the rest of the group.
+    #[error("Inconsistent group protocol for group {0}")]
+    InconsistentGroupProtocol(String),
+
+    /// A session timeout outside `group.min.session.timeout.ms`..`group.max.session.timeout.ms`.
+    #[error("Session timeout {0} ms is out of the allowed range")]
+    InvalidSessionTimeout(i32),
+
+    /// A new member would take the group past `group.max.size`.
+    #[error("Group {0} has reached its maximum size")]
+    GroupMaxSizeReached(String),
+
+    /// The metadata committed along with an offset is longer than `offset.metadata.max.bytes`.
+    #[error("Offset metadata of {size} bytes exceeds the maximum of {max} bytes")]
+    OffsetMetadataTooLarge {
+        /// The size of the metadata.
+        size: usize,
+        /// The configured limit.
+        max: usize,
+    },
+
+    /// An offset commit is too large to be written to `__consumer_offsets`.
+    #[error("Offset commit for group {0} is too large")]
+    InvalidCommitOffsetSize(String),
+
+    /// A request names a group that doesn't exist, or that has another type than the request
+    /// is for (e.g. a JoinGroup for a consumer group).
+    #[error("Group {0} not found")]
+    GroupIdNotFound(String),
+
+    /// A consumer group member's epoch is not its current one: the member has been fenced and
+    /// must rejoin with epoch 0.
+    #[error("Epoch {member_epoch} of member {member_id} of group {group_id} has been fenced")]
+    FencedMemberEpoch {
+        /// The group id.
+        group_id: String,
+        /// The member id from the request.
+        member_id: String,
+        /// The member epoch from the request.
+        member_epoch: i32,
+    },
+
+    /// An offset commit or fetch by a consumer group member with an outdated epoch.
+    #[error("Epoch {member_epoch} of a member of group {group_id} is stale")]
+    StaleMemberEpoch {
+        /// The group id.
+        group_id: String,
+        /// The member epoch from the request.
+        member_epoch: i32,
+    },
+
+    /// A static member joins a consumer group while the instance it replaces hasn't left.
+    #[error("Instance {instance_id} of group {group_id} has not been released")]
+    UnreleasedInstanceId {
+        /// The group id.
+        group_id: String,
+        /// The group instance id from the request.
+        instance_id: String,
+    },
+
+    /// A consumer group member asks for a server assignor the broker doesn't have.
+    #[error("Unsupported assignor {0}")]
+    UnsupportedAssignor(String),
+
+    /// A group can't be deleted, or have its offsets deleted, while it has members.
+    #[error("Group {0} is not empty")]
+    NonEmptyGroup(String),
+
+    /// An offset can't be deleted while the group's members consume its topic.
+    #[error("Group {group_id} is subscribed to topic {topic}")]
+    GroupSubscribedToTopic {
+        /// The group id.
+        group_id: String,
+        /// The topic of the offset.
+        topic: String,
+    },
+
+    /// An idempotent producer's batch doesn't follow the last one it wrote to the partition:
+    /// batches were lost in between.
+    #[error(
+        "Out of order sequence number for producer {producer_id}: {sequence} (last {last_sequence})"
+    )]
+    OutOfOrderSequenceNumber {
+        /// The producer id of the batch.
+        producer_id: i64,
+        /// The sequence number of the batch's first record.
+        sequence: i32,
+        /// The sequence number of the last record the producer wrote to the partition.
+        last_sequence: i32,
+    },
+
+    /// A batch from an older epoch of a producer than the partition has already seen; the
+    /// producer has been replaced by a newer instance.
+    #[error("Epoch {producer_epoch} of producer {producer_id} is older than {current_epoch}")]
+    InvalidProducerEpoch {
+        /// The producer id of the batch.
+        producer_id: i64,
+        /// The producer epoch of the batch.
+        producer_epoch: i16,
+        /// The latest epoch of the producer.
+        current_epoch: i16,
+    },
 
     /// Wrapping an I/O error (such as from the socket),
     /// so we can unify `io::Error` under this custom type.
@@ -45,7 +270,37 @@ impl KafkaBrokerError {
     pub fn error_code(&self) -> i16 {
         match self {
             KafkaBrokerError::MalformedRequest { code, .. } => *code,
-            KafkaBrokerError::InternalServerError(_) => UNKNOWN_SERVER_ERROR,
+            KafkaBrokerError::UnknownApiKey(_) => INVALID_REQUEST,
+            KafkaBrokerError::UnsupportedVersion { .. } => UNSUPPORTED_VERSION,
+            KafkaBrokerError::RequestTooLarge { .. } => INVALID_REQUEST,
+            KafkaBrokerError::UnknownTopicOrPartition { .. } => UNKNOWN_TOPIC_OR_PARTITION,
+            KafkaBrokerError::OffsetOutOfRange { .. } => OFFSET_OUT_OF_RANGE,
+            KafkaBrokerError::CorruptMessage(_) => CORRUPT_MESSAGE,
+            KafkaBrokerError::MessageTooLarge { .. } => MESSAGE_TOO_LARGE,
+            KafkaBrokerError::InvalidRecord(_) => INVALID_RECORD,
+            KafkaBrokerError::UnsupportedCompressionType(_) => UNSUPPORTED_COMPRESSION_TYPE,
+            KafkaBrokerError::InvalidConfig(_) => INVALID_CONFIG,
+            KafkaBrokerError::InvalidGroupId(_) => INVALID_GROUP_ID,
+            KafkaBrokerError::CoordinatorNotAvailable(_) => COORDINATOR_NOT_AVAILABLE,
+            KafkaBrokerError::UnknownMemberId { .. } => UNKNOWN_MEMBER_ID,
+            KafkaBrokerError::MemberIdRequired { .. } => MEMBER_ID_REQUIRED,
+            KafkaBrokerError::IllegalGeneration { .. } => ILLEGAL_GENERATION,
+            KafkaBrokerError::RebalanceInProgress(_) => REBALANCE_IN_PROGRESS,
+            KafkaBrokerError::FencedInstanceId { .. } => FENCED_INSTANCE_ID,
+            KafkaBrokerError::InconsistentGroupProtocol(_) => INCONSISTENT_GROUP_PROTOCOL,
+            KafkaBrokerError::InvalidSessionTimeout(_) => INVALID_SESSION_TIMEOUT,
+            KafkaBrokerError::GroupMaxSizeReached(_) => GROUP_MAX_SIZE_REACHED,
+            KafkaBrokerError::OffsetMetadataTooLarge { .. } => OFFSET_METADATA_TOO_LARGE,
+            KafkaBrokerError::InvalidCommitOffsetSize(_) => INVALID_COMMIT_OFFSET_SIZE,
+            KafkaBrokerError::GroupIdNotFound(_) => GROUP_ID_NOT_FOUND,
+            KafkaBrokerError::FencedMemberEpoch { .. } => FENCED_MEMBER_EPOCH,
+            KafkaBrokerError::StaleMemberEpoch { .. } => STALE_MEMBER_EPOCH,
+            KafkaBrokerError::UnreleasedInstanceId { .. } => UNRELEASED_INSTANCE_ID,
+            KafkaBrokerError::UnsupportedAssignor(_) => UNSUPPORTED_ASSIGNOR,
+            KafkaBrokerError::NonEmptyGroup(_) => NON_EMPTY_GROUP,
+            KafkaBrokerError::GroupSubscribedToTopic { .. } => GROUP_SUBSCRIBED_TO_TOPIC,
+            KafkaBrokerError::OutOfOrderSequenceNumber { .. } => OUT_OF_ORDER_SEQUENCE_NUMBER,
+            KafkaBrokerError::InvalidProducerEpoch { .. } => INVALID_PRODUCER_EPOCH,
             KafkaBrokerError::Io(_) => UNKNOWN_SERVER_ERROR,
             KafkaBrokerError::Other(_) => UNKNOWN_SERVER_ERROR,
         }
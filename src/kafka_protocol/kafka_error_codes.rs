@@ -19,6 +19,10 @@
 //! Below, you'll find each known Kafka error code with a brief description of its meaning and
 //! whether or not the error is considered retriable by a client.
 
+// The table mirrors the protocol's full list of error codes, including the ones this broker
+// never returns.
+#![allow(dead_code)]
+
 /* ---------------------------------------------------------------------------------------------
 -1 to 9
 --------------------------------------------------------------------------------------------- */
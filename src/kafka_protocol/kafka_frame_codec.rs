@@ -0,0 +1,128 @@
+//! # KafkaFrameCodec Module
+//!
+//! Kafka requests are size delimited: each one starts with a 4-byte big-endian `message_size`
+//! followed by exactly that many bytes of header and payload. A single TCP read may contain
+//! part of a request, exactly one request, or several pipelined requests, so the socket has to
+//! be read as a byte stream and cut into frames.
+//!
+//! [`KafkaFrameCodec`] implements [`Decoder`] so it can be driven by
+//! [`FramedRead`](tokio_util::codec::FramedRead). Each decoded frame still includes the size
+//! prefix, which is the layout [`KafkaRequestMessage::from_bytes`] expects.
+//!
+//! [`KafkaRequestMessage::from_bytes`]: crate::kafka_protocol::kafka_request_message::KafkaRequestMessage::from_bytes
+
+use crate::kafka_protocol::kafka_error::KafkaBrokerError;
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::Decoder;
+use tracing::{trace, warn};
+
+/// The number of bytes in the `message_size` prefix.
+const SIZE_PREFIX_LEN: usize = 4;
+
+/// Splits a byte stream into complete, size-prefixed Kafka request frames.
+#[derive(Debug)]
+pub struct KafkaFrameCodec {
+    /// The largest `message_size` accepted (`socket.request.max.bytes`).
+    max_request_bytes: usize,
+}
+
+impl KafkaFrameCodec {
+    /// Creates a codec that rejects any frame whose `message_size` exceeds `max_request_bytes`.
+    pub fn new(max_request_bytes: usize) -> Self {
+        Self { max_request_bytes }
+    }
+}
+
+impl Decoder for KafkaFrameCodec {
+    type Item = BytesMut;
+    type Error = KafkaBrokerError;
+
+    /// Returns the next complete frame (size prefix included), or `None` if more bytes are
+    /// needed.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::RequestTooLarge`] if the size prefix is negative or exceeds
+    /// the configured maximum. The caller is expected to close the connection, mirroring
+    /// Kafka's behaviour.
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < SIZE_PREFIX_LEN {
+            return Ok(None);
+        }
+
+        let mut size_bytes = [0u8; SIZE_PREFIX_LEN];
+        size_bytes.copy_from_slice(&src[..SIZE_PREFIX_LEN]);
+        let message_size = i32::from_be_bytes(size_bytes);
+
+        // Kafka rejects negative sizes like oversized ones, closing the connection.
+        if message_size < 0 || message_size as usize > self.max_request_bytes {
+            warn!(
+                "Frame of {} bytes is not within socket.request.max.bytes={}",
+                message_size, self.max_request_bytes
+            );
+            return Err(KafkaBrokerError::RequestTooLarge {
+                size: message_size,
+                max: self.max_request_bytes,
+            });
+        }
+
+        let message_size = message_size as usize;
+        let frame_len = SIZE_PREFIX_LEN + message_size;
+        if src.len() < frame_len {
+            // Make room for the rest of the frame so the next read can fill it in one go.
+            src.reserve(frame_len - src.len());
+            trace!(
+                "Partial frame: have {} of {} bytes; waiting for more.",
+                src.len(),
+                frame_len
+            );
+            return Ok(None);
+        }
+
+        trace!("Decoded a complete frame of {} bytes.", frame_len);
+        Ok(Some(src.split_to(frame_len)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as i32).to_be_bytes().to_vec();
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn pipelined_and_partial_frames_are_cut_at_their_size() {
+        let mut codec = KafkaFrameCodec::new(16);
+        let mut src = BytesMut::new();
+        src.extend_from_slice(&frame(b"first"));
+        src.extend_from_slice(&frame(b"second")[..6]);
+
+        assert_eq!(codec.decode(&mut src).unwrap().unwrap(), frame(b"first"));
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        src.extend_from_slice(&frame(b"second")[6..]);
+        assert_eq!(codec.decode(&mut src).unwrap().unwrap(), frame(b"second"));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn negative_and_oversized_frames_are_too_large() {
+        let mut codec = KafkaFrameCodec::new(16);
+        for size in [-1, i32::MIN, 17, i32::MAX] {
+            let mut src = BytesMut::from(&size.to_be_bytes()[..]);
+            let error = codec.decode(&mut src).unwrap_err();
+            assert!(
+                matches!(error, KafkaBrokerError::RequestTooLarge { size: s, max: 16 } if s == size),
+                "{:?}",
+                error
+            );
+        }
+
+        // A frame of exactly the maximum is fine.
+        let mut src = BytesMut::from(&frame(&[0; 16])[..]);
+        assert_eq!(codec.decode(&mut src).unwrap().unwrap().len(), 20);
+    }
+}
@@ -0,0 +1,512 @@
+//! # KafkaMessageSet Module
+//!
+//! Decoders and encoders for the legacy message formats (magic 0 and 1) that predate record
+//! batches. Produce v0-2 may carry them and Fetch v0-3 clients can only read them, while the
+//! log itself only holds v2 batches. Legacy input is therefore up-converted into a v2 batch
+//! before it is appended, and batches are down-converted when an old client fetches them.
+//!
+//! A message set is a sequence of messages, each prefixed with its offset and size:
+//!
+//! ```text
+//! offset: int64
+//! messageSize: int32
+//! crc: uint32                        (CRC32 of everything from magic on)
+//! magic: int8                        (0 or 1)
+//! attributes: int8                   (compression, and for magic 1 the timestamp type)
+//! timestamp: int64                   (magic 1 only)
+//! key: bytes
+//! value: bytes
+//! ```
+//!
+//! Compressed messages are "wrapper" messages whose value is a compressed message set of inner
+//! messages. For magic 1 the inner offsets are relative and the wrapper carries the absolute
+//! offset of the last inner message; for magic 0 the inner offsets are absolute.
+//!
+//! See <https://kafka.apache.org/documentation/#messageset> for the full definition.
+
+use crate::kafka_protocol::kafka_codec::{
+    read_i64, read_i8, read_nullable_bytes, read_slice, read_u32, write_i32, write_i64, write_i8,
+    write_nullable_bytes, write_u32,
+};
+use crate::kafka_protocol::kafka_compression::Compression;
+use crate::kafka_protocol::kafka_error::{KafkaBrokerError, KafkaResult};
+use crate::kafka_protocol::kafka_record_batch::{corrupt, Record, RecordBatch};
+
+/// The original message format, without timestamps.
+pub const MAGIC_V0: i8 = 0;
+
+/// The message format that added timestamps (KIP-32).
+pub const MAGIC_V1: i8 = 1;
+
+/// The timestamp of messages that have none (magic 0).
+const NO_TIMESTAMP: i64 = -1;
+
+/// The bytes preceding each message: its offset and size.
+const LOG_OVERHEAD: usize = 12;
+
+/// The bytes of a message covered by the CRC start after the CRC itself.
+const CRC_LENGTH: usize = 4;
+
+/// The attribute bits holding the compression codec.
+const COMPRESSION_CODEC_MASK: i8 = 0x07;
+/// The attribute bit marking the timestamp as `LogAppendTime` (magic 1 only).
+const TIMESTAMP_TYPE_MASK: i8 = 0x08;
+
+/// A magic 0 or 1 message, with the offset it was stored or sent with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LegacyMessage {
+    /// The message offset (see the module documentation for wrapper messages).
+    pub offset: i64,
+    /// The message format, 0 or 1.
+    pub magic: i8,
+    /// Compression codec and, for magic 1, timestamp type.
+    pub attributes: i8,
+    /// The message timestamp, or -1 for magic 0.
+    pub timestamp: i64,
+    /// The message key, if any.
+    pub key: Option<Vec<u8>>,
+    /// The message value: the payload, or the compressed inner messages of a wrapper.
+    pub value: Option<Vec<u8>>,
+}
+
+impl LegacyMessage {
+    /// Reads one offset- and size-prefixed message from the front of the cursor, verifying
+    /// its CRC.
+    ///
+    /// # Errors
+    ///
+    /// - [`KafkaBrokerError::CorruptMessage`] if the message is truncated, its size is
+    ///   inconsistent, or its CRC doesn't match.
+    /// - [`KafkaBrokerError::InvalidRecord`] if its magic isn't 0 or 1.
+    pub fn read(cursor: &mut &[u8]) -> KafkaResult<Self> {
+        if cursor.len() < LOG_OVERHEAD {
+            return Err(KafkaBrokerError::CorruptMessage(format!(
+                "Message is truncated: {} bytes",
+                cursor.len()
+            )));
+        }
+        let offset = read_i64(cursor).map_err(corrupt)?;
+        let size = i32::from_be_bytes(cursor[..4].try_into().unwrap());
+        *cursor = &cursor[4..];
+        if size < 0 || size as usize > cursor.len() {
+            return Err(KafkaBrokerError::CorruptMessage(format!(
+                "Message size {} is inconsistent with the {} bytes available",
+                size,
+                cursor.len()
+            )));
+        }
+        let mut message = read_slice(cursor, size as usize, "message").map_err(corrupt)?;
+
+        let expected_crc = read_u32(&mut message).map_err(corrupt)?;
+        let actual_crc = crc32fast::hash(message);
+        if expected_crc != actual_crc {
+            return Err(KafkaBrokerError::CorruptMessage(format!(
+                "Message CRC mismatch: expected {:#010x}, computed {:#010x}",
+                expected_crc, actual_crc
+            )));
+        }
+
+        let magic = read_i8(&mut message).map_err(corrupt)?;
+        if magic != MAGIC_V0 && magic != MAGIC_V1 {
+            return Err(KafkaBrokerError::InvalidRecord(format!(
+                "Expected a message with magic v0 or v1, got v{}",
+                magic
+            )));
+        }
+        let attributes = read_i8(&mut message).map_err(corrupt)?;
+        let timestamp = if magic == MAGIC_V1 {
+            read_i64(&mut message).map_err(corrupt)?
+        } else {
+            NO_TIMESTAMP
+        };
+        let key = read_nullable_bytes(&mut message).map_err(corrupt)?;
+        let value = read_nullable_bytes(&mut message).map_err(corrupt)?;
+        if !message.is_empty() {
+            return Err(KafkaBrokerError::CorruptMessage(format!(
+                "Message has {} bytes left over after its value",
+                message.len()
+            )));
+        }
+
+        Ok(Self {
+            offset,
+            magic,
+            attributes,
+            timestamp,
+            key,
+            value,
+        })
+    }
+
+    /// Appends the offset- and size-prefixed message to `buf`, computing its CRC.
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        write_i64(buf, self.offset);
+        let size_position = buf.len();
+        write_i32(buf, 0); // Size placeholder, patched below.
+        let crc_position = buf.len();
+        write_u32(buf, 0); // CRC placeholder, patched below.
+        write_i8(buf, self.magic);
+        write_i8(buf, self.attributes);
+        if self.magic == MAGIC_V1 {
+            write_i64(buf, self.timestamp);
+        }
+        write_nullable_bytes(buf, self.key.as_deref());
+        write_nullable_bytes(buf, self.value.as_deref());
+
+        let crc = crc32fast::hash(&buf[crc_position + CRC_LENGTH..]);
+        buf[crc_position..crc_position + 4].copy_from_slice(&crc.to_be_bytes());
+        let size = (buf.len() - crc_position) as i32;
+        buf[size_position..size_position + 4].copy_from_slice(&size.to_be_bytes());
+    }
+
+    /// The codec the message value is compressed with.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::UnsupportedCompressionType`] for unknown codecs, codecs this
+    /// build doesn't include, and zstd, which legacy messages can't use.
+    pub fn compression(&self) -> KafkaResult<Compression> {
+        match Compression::from_id((self.attributes & COMPRESSION_CODEC_MASK) as i16)? {
+            Compression::Zstd => Err(KafkaBrokerError::UnsupportedCompressionType(
+                "zstd compression is not supported for magic v0 and v1 messages".to_string(),
+            )),
+            compression => Ok(compression),
+        }
+    }
+
+    /// Decompresses the inner messages of a wrapper message compressed with `compression`.
+    /// Their offsets are returned as they were written.
+    ///
+    /// # Errors
+    ///
+    /// - [`KafkaBrokerError::CorruptMessage`] if the value can't be decompressed or an inner
+    ///   message is malformed.
+    /// - [`KafkaBrokerError::InvalidRecord`] if the wrapper has no value, or an inner message
+    ///   is itself compressed.
+    fn inner_messages(&self, compression: Compression) -> KafkaResult<Vec<LegacyMessage>> {
+        let value = self.value.as_deref().ok_or_else(|| {
+            KafkaBrokerError::InvalidRecord("Compressed message has a null value".to_string())
+        })?;
+        let decompressed = compression.decompress_legacy(value, self.magic)?;
+        let messages = read_message_set(&decompressed)?;
+        if messages
+            .iter()
+            .any(|message| message.attributes & COMPRESSION_CODEC_MASK != 0)
+        {
+            return Err(KafkaBrokerError::InvalidRecord(
+                "Compressed message contains an inner message that is compressed".to_string(),
+            ));
+        }
+        Ok(messages)
+    }
+}
+
+/// Reads every message of the message set in `data`, without unwrapping compressed ones.
+///
+/// # Errors
+///
+/// Returns the errors of [`LegacyMessage::read`].
+pub fn read_message_set(mut data: &[u8]) -> KafkaResult<Vec<LegacyMessage>> {
+    let mut messages = Vec::new();
+    while !data.is_empty() {
+        messages.push(LegacyMessage::read(&mut data)?);
+    }
+    Ok(messages)
+}
+
+/// Converts the legacy message set in `data` into a single v2 batch with offsets starting at 0,
+/// returning it along with its records.
+///
+/// Compressed wrapper messages are unwrapped, and the batch is compressed with the codec of the
+/// last wrapper (or not at all if there was none). Magic 0 messages get no timestamp.
+///
+/// # Errors
+///
+/// - [`KafkaBrokerError::InvalidRecord`] if the set holds no messages, or any error of
+///   [`LegacyMessage::read`].
+/// - [`KafkaBrokerError::UnsupportedCompressionType`] if a message uses a codec that is
+///   unknown, disabled, or not allowed for legacy messages.
+pub fn up_convert(data: &[u8]) -> KafkaResult<(RecordBatch, Vec<Record>)> {
+    let mut compression = Compression::None;
+    let mut messages = Vec::new();
+    for message in read_message_set(data)? {
+        match message.compression()? {
+            Compression::None => messages.push(message),
+            codec => {
+                messages.extend(message.inner_messages(codec)?);
+                compression = codec;
+            }
+        }
+    }
+    let Some(first) = messages.first() else {
+        return Err(KafkaBrokerError::InvalidRecord(
+            "Message set contains no messages".to_string(),
+        ));
+    };
+
+    let base_timestamp = first.timestamp;
+    let records: Vec<Record> = messages
+        .into_iter()
+        .enumerate()
+        .map(|(index, message)| Record {
+            attributes: 0,
+            timestamp_delta: message.timestamp - base_timestamp,
+            offset_delta: index as i32,
+            key: message.key,
+            value: message.value,
+            headers: Vec::new(),
+        })
+        .collect();
+    let mut batch = RecordBatch::new(0, base_timestamp, &records);
+    batch.set_records(&records, compression)?;
+    Ok((batch, records))
+}
+
+/// Converts the v2 batches in `data` into a message set of format `to_magic` (0 or 1), for
+/// clients too old to read record batches.
+///
+/// Records before `first_offset` and control batches are left out, and record headers are
+/// dropped since legacy messages can't hold them. Each compressed batch becomes one wrapper
+/// message with the same codec, unless the codec can't be used with `to_magic`, in which case
+/// its records are sent uncompressed.
+///
+/// # Errors
+///
+/// Returns the errors of [`RecordBatch::read`] and [`RecordBatch::records`].
+pub fn down_convert(mut data: &[u8], to_magic: i8, first_offset: i64) -> KafkaResult<Vec<u8>> {
+    let mut converted = Vec::with_capacity(data.len());
+    while !data.is_empty() {
+        let batch = RecordBatch::read(&mut data)?;
+        if batch.is_control_batch() {
+            continue;
+        }
+
+        let (attributes, append_time) = match (to_magic, batch.is_log_append_time()) {
+            (MAGIC_V1, true) => (TIMESTAMP_TYPE_MASK, Some(batch.max_timestamp)),
+            _ => (0, None),
+        };
+        let messages: Vec<LegacyMessage> = batch
+            .records()?
+            .into_iter()
+            .map(|record| (batch.base_offset + record.offset_delta as i64, record))
+            .filter(|(offset, _)| *offset >= first_offset)
+            .map(|(offset, record)| LegacyMessage {
+                offset,
+                magic: to_magic,
+                attributes,
+                timestamp: match to_magic {
+                    MAGIC_V0 => NO_TIMESTAMP,
+                    _ => append_time.unwrap_or(batch.base_timestamp + record.timestamp_delta),
+                },
+                key: record.key,
+                value: record.value,
+            })
+            .collect();
+        if messages.is_empty() {
+            continue;
+        }
+
+        match legacy_compression(batch.compression()?, to_magic) {
+            Compression::None => {
+                for message in &messages {
+                    message.write(&mut converted);
+                }
+            }
+            codec => wrap(&messages, codec, attributes)?.write(&mut converted),
+        }
+    }
+    Ok(converted)
+}
+
+/// The codec to use for a batch compressed with `compression` when converting it to
+/// `magic`: zstd didn't exist before v2 batches, and magic 0 LZ4 used a broken frame format.
+fn legacy_compression(compression: Compression, magic: i8) -> Compression {
+    match compression {
+        Compression::Zstd => Compression::None,
+        Compression::Lz4 if magic == MAGIC_V0 => Compression::None,
+        compression => compression,
+    }
+}
+
+/// Compresses `messages` (all of the same magic, in offset order) into a wrapper message.
+fn wrap(
+    messages: &[LegacyMessage],
+    compression: Compression,
+    attributes: i8,
+) -> KafkaResult<LegacyMessage> {
+    let first = &messages[0];
+    let last = &messages[messages.len() - 1];
+
+    let mut inner = Vec::new();
+    for message in messages {
+        if message.magic == MAGIC_V1 {
+            // Magic 1 inner offsets are relative to the first inner message.
+            let relative = LegacyMessage {
+                offset: message.offset - first.offset,
+                ..message.clone()
+            };
+            relative.write(&mut inner);
+        } else {
+            message.write(&mut inner);
+        }
+    }
+
+    Ok(LegacyMessage {
+        offset: last.offset,
+        magic: first.magic,
+        attributes: attributes | compression.id() as i8,
+        timestamp: messages
+            .iter()
+            .map(|message| message.timestamp)
+            .max()
+            .unwrap_or(NO_TIMESTAMP),
+        key: None,
+        value: Some(compression.compress(&inner)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(
+        offset: i64,
+        magic: i8,
+        timestamp: i64,
+        key: Option<&[u8]>,
+        value: &[u8],
+    ) -> LegacyMessage {
+        LegacyMessage {
+            offset,
+            magic,
+            attributes: 0,
+            timestamp: if magic == MAGIC_V0 {
+                NO_TIMESTAMP
+            } else {
+                timestamp
+            },
+            key: key.map(<[u8]>::to_vec),
+            value: Some(value.to_vec()),
+        }
+    }
+
+    /// Three messages of format `magic` at offsets 0 to 2.
+    fn messages(magic: i8) -> Vec<LegacyMessage> {
+        vec![
+            message(0, magic, 1000, Some(b"a"), b"one"),
+            message(1, magic, 1005, None, b"two"),
+            message(2, magic, 1002, Some(b"c"), b"three"),
+        ]
+    }
+
+    fn message_set(messages: &[LegacyMessage]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for message in messages {
+            message.write(&mut buf);
+        }
+        buf
+    }
+
+    /// Up-converts `data` and writes the batch as the log would store it at `base_offset`.
+    fn stored(data: &[u8], base_offset: i64) -> Vec<u8> {
+        let (mut batch, _) = up_convert(data).unwrap();
+        batch.base_offset = base_offset;
+        let mut buf = Vec::new();
+        batch.write(&mut buf);
+        buf
+    }
+
+    /// `messages` moved to start at `base_offset`.
+    fn at(messages: &[LegacyMessage], base_offset: i64) -> Vec<LegacyMessage> {
+        messages
+            .iter()
+            .map(|message| LegacyMessage {
+                offset: base_offset + message.offset,
+                ..message.clone()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn messages_round_trip_through_a_batch() {
+        for magic in [MAGIC_V0, MAGIC_V1] {
+            let messages = messages(magic);
+            let (batch, records) = up_convert(&message_set(&messages)).unwrap();
+            assert_eq!(batch.compression().unwrap(), Compression::None);
+            assert_eq!(batch.last_offset_delta, 2);
+            assert_eq!(batch.base_timestamp, messages[0].timestamp);
+            let timestamps: Vec<i64> = records
+                .iter()
+                .map(|record| batch.base_timestamp + record.timestamp_delta)
+                .collect();
+            let expected: Vec<i64> = messages.iter().map(|message| message.timestamp).collect();
+            assert_eq!(timestamps, expected);
+
+            let converted = down_convert(&stored(&message_set(&messages), 100), magic, 0).unwrap();
+            assert_eq!(read_message_set(&converted).unwrap(), at(&messages, 100));
+        }
+    }
+
+    #[test]
+    fn down_conversion_starts_at_the_fetch_offset() {
+        let messages = messages(MAGIC_V1);
+        let data = stored(&message_set(&messages), 10);
+
+        let converted = down_convert(&data, MAGIC_V1, 11).unwrap();
+        assert_eq!(
+            read_message_set(&converted).unwrap(),
+            at(&messages[1..], 10)
+        );
+        assert!(down_convert(&data, MAGIC_V1, 13).unwrap().is_empty());
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn wrapper_messages_are_unwrapped_and_wrapped_again() {
+        for magic in [MAGIC_V0, MAGIC_V1] {
+            let messages = messages(magic);
+            let data = message_set(&[wrap(&messages, Compression::Gzip, 0).unwrap()]);
+            let (batch, records) = up_convert(&data).unwrap();
+            assert_eq!(batch.compression().unwrap(), Compression::Gzip);
+            assert_eq!(records.len(), 3);
+
+            let converted = down_convert(&stored(&data, 100), magic, 0);
+            let converted = read_message_set(&converted.unwrap()).unwrap();
+            assert_eq!(converted.len(), 1);
+            let wrapper = &converted[0];
+            assert_eq!(wrapper.offset, 102);
+            assert_eq!(wrapper.compression().unwrap(), Compression::Gzip);
+            // Magic 1 inner offsets are relative to the wrapper's first message.
+            let inner_base = if magic == MAGIC_V1 { 0 } else { 100 };
+            assert_eq!(
+                wrapper.inner_messages(Compression::Gzip).unwrap(),
+                at(&messages, inner_base)
+            );
+        }
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn a_wrapper_down_converted_mid_batch_holds_the_rest_of_the_batch() {
+        let messages = messages(MAGIC_V1);
+        let wrapper = wrap(&messages, Compression::Gzip, 0).unwrap();
+        let data = stored(&message_set(&[wrapper]), 10);
+
+        let converted = read_message_set(&down_convert(&data, MAGIC_V1, 11).unwrap()).unwrap();
+        assert_eq!(converted.len(), 1);
+        assert_eq!(converted[0].offset, 12);
+        let inner = converted[0].inner_messages(Compression::Gzip).unwrap();
+        assert_eq!(inner, at(&messages[1..], -1));
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_batches_are_down_converted_to_magic_0_uncompressed() {
+        let messages = messages(MAGIC_V0);
+        let wrapper = wrap(&messages, Compression::Lz4, 0).unwrap();
+        let data = stored(&message_set(&[wrapper]), 0);
+
+        let converted = down_convert(&data, MAGIC_V0, 0).unwrap();
+        assert_eq!(read_message_set(&converted).unwrap(), messages);
+    }
+}
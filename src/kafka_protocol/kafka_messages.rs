@@ -0,0 +1,14 @@
+//! Request and response types generated from Apache Kafka's JSON message specs.
+//!
+//! `build.rs` turns every spec under `resources/message/` into a module named after the message
+//! (e.g. [`api_versions_response`]) holding one struct per (nested) struct in the spec, each with
+//! `read`/`write` methods taking the API version. The top-level message struct is re-exported
+//! here, so callers can write `kafka_messages::ApiVersionsResponse`.
+//!
+//! To support a new API, vendor its `*Request.json`/`*Response.json` from Kafka's
+//! `clients/src/main/resources/common/message/` directory.
+
+// Generated code covers every field of every version, most of which the broker doesn't use.
+#![allow(dead_code, unused_imports, unused_variables, unused_mut, clippy::all)]
+
+include!(concat!(env!("OUT_DIR"), "/kafka_messages.rs"));
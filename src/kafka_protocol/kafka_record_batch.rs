@@ -0,0 +1,647 @@
+//! # KafkaRecordBatch Module
+//!
+//! Decoders and encoders for v2 record batches (magic 2, KIP-98), the format produced records
+//! travel in and are stored as. A batch is a fixed 61-byte header followed by its records:
+//!
+//! ```text
+//! baseOffset: int64
+//! batchLength: int32                 (bytes after this field)
+//! partitionLeaderEpoch: int32
+//! magic: int8                        (2)
+//! crc: uint32                        (CRC32C of everything from attributes on)
+//! attributes: int16                  (compression, timestamp type, transactional, control)
+//! lastOffsetDelta: int32
+//! baseTimestamp: int64
+//! maxTimestamp: int64
+//! producerId: int64
+//! producerEpoch: int16
+//! baseSequence: int32
+//! recordsCount: int32
+//! records: [Record]
+//! ```
+//!
+//! A [`RecordBatch`] keeps its records section as raw bytes, so that decoding and re-encoding
+//! a batch reproduces it byte for byte, and so that compressed batches can be stored without
+//! touching their payload. [`RecordBatch::records`] decompresses and decodes the individual
+//! records on demand.
+//!
+//! Unlike the rest of the protocol, record batches are data, not requests: damaged batches are
+//! reported as [`KafkaBrokerError::CorruptMessage`] and invalid ones as
+//! [`KafkaBrokerError::InvalidRecord`], which the Produce handler returns per partition.
+//!
+//! See <https://kafka.apache.org/documentation/#recordbatch> for the full definition.
+
+use crate::kafka_protocol::kafka_codec::{
+    read_i8, read_slice, read_varint, read_varlong, write_i16, write_i32, write_i64, write_i8,
+    write_u32, write_varint, write_varlong,
+};
+use crate::kafka_protocol::kafka_compression::Compression;
+use crate::kafka_protocol::kafka_error::{KafkaBrokerError, KafkaResult};
+
+/// The magic byte of v2 record batches.
+pub const MAGIC_V2: i8 = 2;
+
+/// The size of a v2 record batch header, i.e. of a batch with no records.
+pub const RECORD_BATCH_OVERHEAD: usize = 61;
+
+/// The bytes not counted by `batchLength`: `baseOffset` and `batchLength` itself.
+pub const LOG_OVERHEAD: usize = 12;
+
+/// Where the CRC-covered part of a batch starts (the `attributes` field).
+const CRC_START: usize = 21;
+
+/* Byte offsets of the header fields inside an encoded batch. */
+const BATCH_LENGTH_OFFSET: usize = 8;
+const MAGIC_OFFSET: usize = 16;
+const CRC_OFFSET: usize = 17;
+
+/// The attribute bits holding the compression codec.
+const COMPRESSION_CODEC_MASK: i16 = 0x07;
+/// The attribute bit marking timestamps as `LogAppendTime`.
+const TIMESTAMP_TYPE_MASK: i16 = 0x08;
+/// The attribute bit marking a batch written by a transactional producer.
+const TRANSACTIONAL_FLAG_MASK: i16 = 0x10;
+/// The attribute bit marking a control batch (transaction markers).
+const CONTROL_FLAG_MASK: i16 = 0x20;
+/// The attribute bit marking `baseTimestamp` as the batch's delete horizon, set by the log
+/// cleaner.
+const DELETE_HORIZON_FLAG_MASK: i16 = 0x40;
+
+/// A v2 record batch.
+///
+/// `batchLength`, `magic` and `crc` are not stored: they are checked by [`RecordBatch::read`]
+/// and recomputed by [`RecordBatch::write`], so a batch whose fields were changed is always
+/// encoded consistently.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordBatch {
+    /// The offset of the first record in the batch.
+    pub base_offset: i64,
+    /// The epoch of the leader that appended the batch.
+    pub partition_leader_epoch: i32,
+    /// Compression codec, timestamp type, transactional and control flags.
+    pub attributes: i16,
+    /// The offset of the last record, relative to `base_offset`.
+    pub last_offset_delta: i32,
+    /// The timestamp of the first record.
+    pub base_timestamp: i64,
+    /// The largest timestamp in the batch, or the append time for `LogAppendTime` batches.
+    pub max_timestamp: i64,
+    /// The producer id, or -1 for non-idempotent producers.
+    pub producer_id: i64,
+    /// The producer epoch, or -1 for non-idempotent producers.
+    pub producer_epoch: i16,
+    /// The sequence number of the first record, or -1 for non-idempotent producers.
+    pub base_sequence: i32,
+    /// The number of records in the batch.
+    pub records_count: i32,
+    /// The encoded (and possibly compressed) records.
+    records: Vec<u8>,
+}
+
+/// The fields of a v2 batch header needed to scan and index a log, decoded without the
+/// records (or the rest of the batch) at hand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordBatchHeader {
+    /// The offset of the first record in the batch.
+    pub base_offset: i64,
+    /// The size of the whole encoded batch in bytes.
+    pub batch_size: usize,
+    /// The offset of the last record, relative to `base_offset`.
+    pub last_offset_delta: i32,
+    /// The largest timestamp in the batch.
+    pub max_timestamp: i64,
+}
+
+impl RecordBatchHeader {
+    /// Decodes the header at the front of `data`, which must hold at least
+    /// [`RECORD_BATCH_OVERHEAD`] bytes. Neither the CRC nor the records are checked.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::CorruptMessage`] if `data` is too short or the header's
+    /// `batchLength` is too small to be valid.
+    pub fn read(data: &[u8]) -> KafkaResult<Self> {
+        if data.len() < RECORD_BATCH_OVERHEAD {
+            return Err(KafkaBrokerError::CorruptMessage(format!(
+                "Record batch header is truncated: {} bytes",
+                data.len()
+            )));
+        }
+        let batch_length = i32::from_be_bytes(field(data, BATCH_LENGTH_OFFSET));
+        let batch_size = LOG_OVERHEAD as i64 + batch_length as i64;
+        if batch_size < RECORD_BATCH_OVERHEAD as i64 {
+            return Err(KafkaBrokerError::CorruptMessage(format!(
+                "Record batch size {} is smaller than a batch header",
+                batch_size
+            )));
+        }
+        Ok(Self {
+            base_offset: i64::from_be_bytes(field(data, 0)),
+            batch_size: batch_size as usize,
+            last_offset_delta: i32::from_be_bytes(field(data, 23)),
+            max_timestamp: i64::from_be_bytes(field(data, 35)),
+        })
+    }
+
+    /// The offset of the last record in the batch.
+    pub fn last_offset(&self) -> i64 {
+        self.base_offset + self.last_offset_delta as i64
+    }
+}
+
+/// A single record inside a batch. Offsets and timestamps are deltas from the batch header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Unused by the protocol; always 0.
+    pub attributes: i8,
+    /// The record timestamp, relative to the batch's `base_timestamp`.
+    pub timestamp_delta: i64,
+    /// The record offset, relative to the batch's `base_offset`.
+    pub offset_delta: i32,
+    /// The record key, if any.
+    pub key: Option<Vec<u8>>,
+    /// The record value, or `None` for a tombstone.
+    pub value: Option<Vec<u8>>,
+    /// The record headers.
+    pub headers: Vec<RecordHeader>,
+}
+
+/// A key/value header attached to a record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordHeader {
+    /// The header key.
+    pub key: String,
+    /// The header value, if any.
+    pub value: Option<Vec<u8>>,
+}
+
+/// The type of a control record, stored in its key as `version: int16, type: int16`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlRecordType {
+    /// Ends a transaction whose records must be discarded.
+    Abort,
+    /// Ends a transaction whose records are visible.
+    Commit,
+    /// A type this broker doesn't know.
+    Unknown(i16),
+}
+
+impl ControlRecordType {
+    /// Parses the type out of a control record key. Returns `None` if the key is too short.
+    pub fn parse(key: &[u8]) -> Option<Self> {
+        let control_type = i16::from_be_bytes(key.get(2..4)?.try_into().ok()?);
+        Some(match control_type {
+            0 => ControlRecordType::Abort,
+            1 => ControlRecordType::Commit,
+            other => ControlRecordType::Unknown(other),
+        })
+    }
+}
+
+/// Returns the magic byte of the batch or legacy message at the front of `data`, if there is
+/// one. Legacy message sets keep it at the same position, so this tells the formats apart.
+pub fn peek_magic(data: &[u8]) -> Option<i8> {
+    data.get(MAGIC_OFFSET).map(|&magic| magic as i8)
+}
+
+impl RecordBatch {
+    /// Creates an uncompressed batch holding `records`, which must carry consecutive offset
+    /// deltas starting at 0.
+    pub fn new(base_offset: i64, base_timestamp: i64, records: &[Record]) -> Self {
+        let max_timestamp = records
+            .iter()
+            .map(|record| base_timestamp + record.timestamp_delta)
+            .max()
+            .unwrap_or(base_timestamp);
+        Self {
+            base_offset,
+            partition_leader_epoch: -1,
+            attributes: 0,
+            last_offset_delta: records.len() as i32 - 1,
+            base_timestamp,
+            max_timestamp,
+            producer_id: -1,
+            producer_epoch: -1,
+            base_sequence: -1,
+            records_count: records.len() as i32,
+            records: encode_records(records),
+        }
+    }
+
+    /// Reads one batch from the front of the cursor, verifying its size, magic and CRC.
+    ///
+    /// # Errors
+    ///
+    /// - [`KafkaBrokerError::CorruptMessage`] if the batch is truncated, its `batchLength` is
+    ///   inconsistent, or its CRC doesn't match.
+    /// - [`KafkaBrokerError::InvalidRecord`] if the batch isn't magic v2.
+    pub fn read(cursor: &mut &[u8]) -> KafkaResult<Self> {
+        let size = Self::peek_size(cursor)?;
+        let data = &cursor[..size];
+
+        let magic = data[MAGIC_OFFSET] as i8;
+        if magic != MAGIC_V2 {
+            return Err(KafkaBrokerError::InvalidRecord(format!(
+                "Expected a record batch with magic v{}, got v{}",
+                MAGIC_V2, magic
+            )));
+        }
+
+        let expected_crc = u32::from_be_bytes(field(data, CRC_OFFSET));
+        let actual_crc = crc32c::crc32c(&data[CRC_START..]);
+        if expected_crc != actual_crc {
+            return Err(KafkaBrokerError::CorruptMessage(format!(
+                "Record batch CRC mismatch: expected {:#010x}, computed {:#010x}",
+                expected_crc, actual_crc
+            )));
+        }
+
+        let batch = Self {
+            base_offset: i64::from_be_bytes(field(data, 0)),
+            partition_leader_epoch: i32::from_be_bytes(field(data, 12)),
+            attributes: i16::from_be_bytes(field(data, 21)),
+            last_offset_delta: i32::from_be_bytes(field(data, 23)),
+            base_timestamp: i64::from_be_bytes(field(data, 27)),
+            max_timestamp: i64::from_be_bytes(field(data, 35)),
+            producer_id: i64::from_be_bytes(field(data, 43)),
+            producer_epoch: i16::from_be_bytes(field(data, 51)),
+            base_sequence: i32::from_be_bytes(field(data, 53)),
+            records_count: i32::from_be_bytes(field(data, 57)),
+            records: data[RECORD_BATCH_OVERHEAD..].to_vec(),
+        };
+        *cursor = &cursor[size..];
+        Ok(batch)
+    }
+
+    /// Returns the size of the batch at the front of `data` according to its `batchLength`,
+    /// without decoding it.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::CorruptMessage`] if `data` is too short to hold the header or
+    /// the whole batch.
+    pub fn peek_size(data: &[u8]) -> KafkaResult<usize> {
+        if data.len() < LOG_OVERHEAD {
+            return Err(KafkaBrokerError::CorruptMessage(format!(
+                "Record batch is truncated: {} bytes",
+                data.len()
+            )));
+        }
+        let batch_length = i32::from_be_bytes(field(data, BATCH_LENGTH_OFFSET));
+        let size = LOG_OVERHEAD as i64 + batch_length as i64;
+        if size < RECORD_BATCH_OVERHEAD as i64 || size > data.len() as i64 {
+            return Err(KafkaBrokerError::CorruptMessage(format!(
+                "Record batch size {} is inconsistent with the {} bytes available",
+                size,
+                data.len()
+            )));
+        }
+        Ok(size as usize)
+    }
+
+    /// Appends the encoded batch to `buf`, computing its `batchLength` and CRC.
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        write_i64(buf, self.base_offset);
+        write_i32(buf, (self.size_in_bytes() - LOG_OVERHEAD) as i32);
+        write_i32(buf, self.partition_leader_epoch);
+        write_i8(buf, MAGIC_V2);
+        write_u32(buf, 0); // CRC placeholder, patched below.
+        write_i16(buf, self.attributes);
+        write_i32(buf, self.last_offset_delta);
+        write_i64(buf, self.base_timestamp);
+        write_i64(buf, self.max_timestamp);
+        write_i64(buf, self.producer_id);
+        write_i16(buf, self.producer_epoch);
+        write_i32(buf, self.base_sequence);
+        write_i32(buf, self.records_count);
+        buf.extend_from_slice(&self.records);
+
+        let crc = crc32c::crc32c(&buf[start + CRC_START..]);
+        buf[start + CRC_OFFSET..start + CRC_OFFSET + 4].copy_from_slice(&crc.to_be_bytes());
+    }
+
+    /// The size of the encoded batch in bytes.
+    pub fn size_in_bytes(&self) -> usize {
+        RECORD_BATCH_OVERHEAD + self.records.len()
+    }
+
+    /// The offset of the last record in the batch.
+    pub fn last_offset(&self) -> i64 {
+        self.base_offset + self.last_offset_delta as i64
+    }
+
+    /// The codec the records are compressed with.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::UnsupportedCompressionType`] if the attributes name an
+    /// unknown codec, or one this build doesn't include.
+    pub fn compression(&self) -> KafkaResult<Compression> {
+        Compression::from_id(self.attributes & COMPRESSION_CODEC_MASK)
+    }
+
+    /// Whether the batch timestamps are `LogAppendTime` rather than `CreateTime`.
+    pub fn is_log_append_time(&self) -> bool {
+        self.attributes & TIMESTAMP_TYPE_MASK != 0
+    }
+
+    /// Marks the batch as `LogAppendTime`, stamped with `timestamp`.
+    pub fn set_log_append_time(&mut self, timestamp: i64) {
+        self.attributes |= TIMESTAMP_TYPE_MASK;
+        self.max_timestamp = timestamp;
+    }
+
+    /// Whether the batch was written by a transactional producer.
+    pub fn is_transactional(&self) -> bool {
+        self.attributes & TRANSACTIONAL_FLAG_MASK != 0
+    }
+
+    /// Whether the batch holds control records (transaction markers).
+    pub fn is_control_batch(&self) -> bool {
+        self.attributes & CONTROL_FLAG_MASK != 0
+    }
+
+    /// When the log cleaner may drop the tombstones and transaction marker of the batch, if it
+    /// has set that yet.
+    pub fn delete_horizon_ms(&self) -> Option<i64> {
+        (self.attributes & DELETE_HORIZON_FLAG_MASK != 0).then_some(self.base_timestamp)
+    }
+
+    /// Stores `delete_horizon_ms` in `baseTimestamp` and flags it, re-basing the timestamp
+    /// deltas of `records` so that their timestamps don't change. The records must then be
+    /// stored with [`RecordBatch::set_records`].
+    pub fn set_delete_horizon(&mut self, delete_horizon_ms: i64, records: &mut [Record]) {
+        for record in records {
+            record.timestamp_delta += self.base_timestamp - delete_horizon_ms;
+        }
+        self.base_timestamp = delete_horizon_ms;
+        self.attributes |= DELETE_HORIZON_FLAG_MASK;
+    }
+
+    /// Decodes the records of the batch.
+    ///
+    /// # Errors
+    ///
+    /// - [`KafkaBrokerError::UnsupportedCompressionType`] if the batch uses an unsupported
+    ///   codec.
+    /// - [`KafkaBrokerError::CorruptMessage`] if the records can't be decompressed, or a record
+    ///   is malformed.
+    /// - [`KafkaBrokerError::InvalidRecord`] if the batch holds a different number of records
+    ///   than `recordsCount` says.
+    pub fn records(&self) -> KafkaResult<Vec<Record>> {
+        let decompressed = self.compression()?.decompress(&self.records)?;
+        let mut cursor = decompressed.as_slice();
+        let mut records = Vec::with_capacity(self.records_count.clamp(0, 1024) as usize);
+        while !cursor.is_empty() {
+            records.push(Record::read(&mut cursor)?);
+        }
+        if records.len() as i64 != i64::from(self.records_count) {
+            return Err(KafkaBrokerError::InvalidRecord(format!(
+                "Record batch claims {} records but contains {}",
+                self.records_count,
+                records.len()
+            )));
+        }
+        Ok(records)
+    }
+
+    /// Replaces the records of the batch with `records`, compressed with `compression`.
+    ///
+    /// The header fields derived from the records (`recordsCount` and `lastOffsetDelta`) are
+    /// updated; everything else, timestamps included, is left as it is.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if the compressor fails.
+    pub fn set_records(&mut self, records: &[Record], compression: Compression) -> KafkaResult<()> {
+        self.records = compression.compress(&encode_records(records))?;
+        self.attributes = (self.attributes & !COMPRESSION_CODEC_MASK) | compression.id();
+        self.records_count = records.len() as i32;
+        self.last_offset_delta = records.last().map_or(-1, |record| record.offset_delta);
+        Ok(())
+    }
+}
+
+impl Record {
+    /// Reads one length-prefixed record from the front of the cursor.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::CorruptMessage`] if the record is truncated, its fields
+    /// don't add up to its length, or a header key isn't valid UTF-8.
+    pub fn read(cursor: &mut &[u8]) -> KafkaResult<Self> {
+        let length = read_varint(cursor).map_err(corrupt)?;
+        if length < 0 {
+            return Err(KafkaBrokerError::CorruptMessage(format!(
+                "Record has a negative length of {}",
+                length
+            )));
+        }
+        let mut body = read_slice(cursor, length as usize, "record").map_err(corrupt)?;
+
+        let attributes = read_i8(&mut body).map_err(corrupt)?;
+        let timestamp_delta = read_varlong(&mut body).map_err(corrupt)?;
+        let offset_delta = read_varint(&mut body).map_err(corrupt)?;
+        let key = read_varint_bytes(&mut body)?;
+        let value = read_varint_bytes(&mut body)?;
+
+        let header_count = read_varint(&mut body).map_err(corrupt)?;
+        if header_count < 0 {
+            return Err(KafkaBrokerError::CorruptMessage(format!(
+                "Record has a negative header count of {}",
+                header_count
+            )));
+        }
+        let mut headers = Vec::with_capacity(header_count.min(64) as usize);
+        for _ in 0..header_count {
+            let key = read_varint_bytes(&mut body)?.ok_or_else(|| {
+                KafkaBrokerError::CorruptMessage("Record header key is null".to_string())
+            })?;
+            let key = String::from_utf8(key).map_err(|_| {
+                KafkaBrokerError::CorruptMessage("Record header key is not valid UTF-8".to_string())
+            })?;
+            let value = read_varint_bytes(&mut body)?;
+            headers.push(RecordHeader { key, value });
+        }
+
+        if !body.is_empty() {
+            return Err(KafkaBrokerError::CorruptMessage(format!(
+                "Record has {} bytes left over after its last header",
+                body.len()
+            )));
+        }
+        Ok(Self {
+            attributes,
+            timestamp_delta,
+            offset_delta,
+            key,
+            value,
+            headers,
+        })
+    }
+
+    /// Appends the length-prefixed record to `buf`.
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        let mut body = Vec::new();
+        write_i8(&mut body, self.attributes);
+        write_varlong(&mut body, self.timestamp_delta);
+        write_varint(&mut body, self.offset_delta);
+        write_varint_bytes(&mut body, self.key.as_deref());
+        write_varint_bytes(&mut body, self.value.as_deref());
+        write_varint(&mut body, self.headers.len() as i32);
+        for header in &self.headers {
+            write_varint_bytes(&mut body, Some(header.key.as_bytes()));
+            write_varint_bytes(&mut body, header.value.as_deref());
+        }
+
+        write_varint(buf, body.len() as i32);
+        buf.extend_from_slice(&body);
+    }
+}
+
+/// Encodes `records` back to back, uncompressed.
+fn encode_records(records: &[Record]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    for record in records {
+        record.write(&mut encoded);
+    }
+    encoded
+}
+
+/// Reads the fixed-width field at `position`; the caller has checked the bounds.
+fn field<const N: usize>(data: &[u8], position: usize) -> [u8; N] {
+    data[position..position + N].try_into().unwrap()
+}
+
+/// Reads bytes prefixed with a zig-zag varint length, where -1 means null.
+fn read_varint_bytes(cursor: &mut &[u8]) -> KafkaResult<Option<Vec<u8>>> {
+    let length = read_varint(cursor).map_err(corrupt)?;
+    if length < 0 {
+        return Ok(None);
+    }
+    let bytes = read_slice(cursor, length as usize, "record field").map_err(corrupt)?;
+    Ok(Some(bytes.to_vec()))
+}
+
+/// Writes bytes prefixed with a zig-zag varint length, or -1 for null.
+fn write_varint_bytes(buf: &mut Vec<u8>, value: Option<&[u8]>) {
+    match value {
+        Some(bytes) => {
+            write_varint(buf, bytes.len() as i32);
+            buf.extend_from_slice(bytes);
+        }
+        None => write_varint(buf, -1),
+    }
+}
+
+/// Turns a codec decoding error into a `CORRUPT_MESSAGE` for record data.
+pub(crate) fn corrupt(error: KafkaBrokerError) -> KafkaBrokerError {
+    KafkaBrokerError::CorruptMessage(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(offset_delta: i32, key: &[u8], value: &[u8]) -> Record {
+        Record {
+            attributes: 0,
+            timestamp_delta: offset_delta as i64,
+            offset_delta,
+            key: Some(key.to_vec()),
+            value: Some(value.to_vec()),
+            headers: vec![RecordHeader {
+                key: "trace".to_string(),
+                value: None,
+            }],
+        }
+    }
+
+    fn encoded_batch() -> Vec<u8> {
+        let mut batch = RecordBatch::new(
+            42,
+            1_700_000_000_000,
+            &[record(0, b"a", b"one"), record(1, b"b", b"two")],
+        );
+        batch.partition_leader_epoch = 3;
+        batch.producer_id = 7;
+        batch.producer_epoch = 1;
+        batch.base_sequence = 10;
+        let mut buf = Vec::new();
+        batch.write(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn decode_then_encode_reproduces_the_bytes() {
+        let encoded = encoded_batch();
+        let mut cursor = encoded.as_slice();
+        let batch = RecordBatch::read(&mut cursor).unwrap();
+        assert!(cursor.is_empty());
+        assert_eq!(batch.base_offset, 42);
+        assert_eq!(batch.last_offset(), 43);
+        assert_eq!(batch.producer_id, 7);
+        assert_eq!(batch.size_in_bytes(), encoded.len());
+
+        let mut reencoded = Vec::new();
+        batch.write(&mut reencoded);
+        assert_eq!(reencoded, encoded);
+    }
+
+    #[test]
+    fn crc_mismatch_is_corrupt() {
+        let mut encoded = encoded_batch();
+        *encoded.last_mut().unwrap() ^= 0xff;
+        assert!(matches!(
+            RecordBatch::read(&mut encoded.as_slice()),
+            Err(KafkaBrokerError::CorruptMessage(_))
+        ));
+    }
+
+    #[test]
+    fn record_varints_and_headers_are_parsed() {
+        let encoded = [
+            0x18, // length: 12
+            0x00, // attributes
+            0xd8, 0x04, // timestamp delta: 300
+            0x02, // offset delta: 1
+            0x02, b'k', // key: "k"
+            0x01, // value: null
+            0x02, // header count: 1
+            0x02, b'h', // header key: "h"
+            0x02, b'v', // header value: "v"
+        ];
+        let mut cursor = encoded.as_slice();
+        let record = Record::read(&mut cursor).unwrap();
+        assert!(cursor.is_empty());
+        assert_eq!(
+            record,
+            Record {
+                attributes: 0,
+                timestamp_delta: 300,
+                offset_delta: 1,
+                key: Some(b"k".to_vec()),
+                value: None,
+                headers: vec![RecordHeader {
+                    key: "h".to_string(),
+                    value: Some(b"v".to_vec()),
+                }],
+            }
+        );
+
+        let mut reencoded = Vec::new();
+        record.write(&mut reencoded);
+        assert_eq!(reencoded, encoded);
+    }
+
+    #[test]
+    fn record_with_leftover_bytes_is_corrupt() {
+        // A length of 7 covers one byte more than the fields use.
+        let encoded = [0x0e, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0xff];
+        assert!(matches!(
+            Record::read(&mut encoded.as_slice()),
+            Err(KafkaBrokerError::CorruptMessage(_))
+        ));
+    }
+}
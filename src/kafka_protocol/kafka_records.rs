@@ -0,0 +1,238 @@
+//! Record data in requests and responses, and the buffer responses are encoded into.
+//!
+//! The `records` fields of Produce requests and Fetch responses are [`Records`]: either bytes
+//! in memory, or a [`FileRegion`] of a log segment. Responses are encoded into an
+//! [`EncodeBuf`], which holds the encoded bytes along with the file regions to send in between
+//! them, so that record data can go from the segment file to the socket without being copied
+//! through the broker (see `client_handler::send_response`).
+
+use crate::kafka_protocol::kafka_codec::{write_i32, write_unsigned_varint};
+use std::fs::File;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+
+/// Record data: bytes in memory, or a region of a segment file.
+#[derive(Debug, Clone)]
+pub enum Records {
+    /// Records held in memory, as parsed from a request or built by the broker.
+    Bytes(Vec<u8>),
+    /// Records to be sent straight from a segment file.
+    File(FileRegion),
+}
+
+impl Records {
+    /// The size of the record data in bytes.
+    pub fn len(&self) -> usize {
+        match self {
+            Records::Bytes(bytes) => bytes.len(),
+            Records::File(region) => region.len(),
+        }
+    }
+
+    /// Returns the record data as bytes, reading it from the file if needed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file region can't be read.
+    pub fn into_bytes(self) -> io::Result<Vec<u8>> {
+        match self {
+            Records::Bytes(bytes) => Ok(bytes),
+            Records::File(region) => region.read(),
+        }
+    }
+}
+
+impl Default for Records {
+    fn default() -> Self {
+        Records::Bytes(Vec::new())
+    }
+}
+
+impl PartialEq for Records {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Records::Bytes(a), Records::Bytes(b)) => a == b,
+            (Records::File(a), Records::File(b)) => {
+                Arc::ptr_eq(&a.file, &b.file) && a.position == b.position && a.len == b.len
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A range of bytes in a file.
+#[derive(Debug, Clone)]
+pub struct FileRegion {
+    file: Arc<File>,
+    position: u64,
+    len: usize,
+}
+
+impl FileRegion {
+    /// The `len` bytes of `file` starting at `position`.
+    pub fn new(file: Arc<File>, position: u64, len: usize) -> Self {
+        Self {
+            file,
+            position,
+            len,
+        }
+    }
+
+    /// The file the region is in.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// The offset of the region in the file.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// The size of the region in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Reads the region into memory.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can't be read, or ends before the region does.
+    pub fn read(&self) -> io::Result<Vec<u8>> {
+        let mut data = vec![0u8; self.len];
+        read_exact_at(&self.file, &mut data, self.position)?;
+        Ok(data)
+    }
+}
+
+/// Fills `buf` with the contents of `file` at `position`, without moving the file's cursor, so
+/// that several threads can read the same file at once.
+#[cfg(unix)]
+pub fn read_exact_at(file: &File, buf: &mut [u8], position: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, position)
+}
+
+/// Fills `buf` with the contents of `file` at `position`. Without positional reads this moves
+/// the file's cursor, so the file must not be read by several threads at once.
+#[cfg(not(unix))]
+pub fn read_exact_at(mut file: &File, buf: &mut [u8], position: u64) -> io::Result<()> {
+    use std::io::{Read, Seek, SeekFrom};
+    file.seek(SeekFrom::Start(position))?;
+    file.read_exact(buf)
+}
+
+/// A buffer messages are encoded into: bytes, with file regions to be sent in between.
+///
+/// It dereferences to the `Vec<u8>` of encoded bytes, so the codec's writers append to it
+/// directly; [`write_records`] adds file regions after the bytes written so far. Note that
+/// `len()` is that of the bytes only; [`EncodeBuf::encoded_len`] includes the file regions.
+#[derive(Debug, Default)]
+pub struct EncodeBuf {
+    bytes: Vec<u8>,
+    /// The file regions, each with the length of `bytes` at the point it was added.
+    regions: Vec<(usize, FileRegion)>,
+}
+
+/// A piece of an [`EncodeBuf`], in the order it must be sent.
+#[derive(Debug)]
+pub enum Chunk<'a> {
+    /// Encoded bytes.
+    Bytes(&'a [u8]),
+    /// A file region.
+    File(&'a FileRegion),
+}
+
+impl EncodeBuf {
+    /// Creates an empty buffer.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Empties the buffer, keeping the capacity of its bytes.
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+        self.regions.clear();
+    }
+
+    /// Whether nothing has been encoded.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty() && self.regions.is_empty()
+    }
+
+    /// The total encoded size: the bytes plus the file regions.
+    pub fn encoded_len(&self) -> usize {
+        self.bytes.len()
+            + self
+                .regions
+                .iter()
+                .map(|(_, region)| region.len())
+                .sum::<usize>()
+    }
+
+    /// Appends `region` after the bytes written so far.
+    pub fn push_file(&mut self, region: FileRegion) {
+        self.regions.push((self.bytes.len(), region));
+    }
+
+    /// Returns the encoded bytes. Tagged fields are encoded on their own before being added
+    /// to a message, which is only possible without file regions; none of the protocol's
+    /// tagged fields hold records.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the buffer holds a file region.
+    pub fn into_bytes(self) -> Vec<u8> {
+        assert!(
+            self.regions.is_empty(),
+            "file regions can't be converted to bytes"
+        );
+        self.bytes
+    }
+
+    /// The pieces of the buffer in the order they must be sent. Empty pieces are skipped.
+    pub fn chunks(&self) -> impl Iterator<Item = Chunk<'_>> {
+        let mut start = 0;
+        let mut chunks = Vec::with_capacity(self.regions.len() * 2 + 1);
+        for (end, region) in &self.regions {
+            if *end > start {
+                chunks.push(Chunk::Bytes(&self.bytes[start..*end]));
+            }
+            chunks.push(Chunk::File(region));
+            start = *end;
+        }
+        if self.bytes.len() > start {
+            chunks.push(Chunk::Bytes(&self.bytes[start..]));
+        }
+        chunks.into_iter()
+    }
+}
+
+impl Deref for EncodeBuf {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.bytes
+    }
+}
+
+impl DerefMut for EncodeBuf {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.bytes
+    }
+}
+
+/// Writes `records` as a (compact, if `flexible`) bytes field. File regions are added to `buf`
+/// rather than read.
+pub fn write_records(buf: &mut EncodeBuf, records: &Records, flexible: bool) {
+    if flexible {
+        write_unsigned_varint(buf, records.len() as u32 + 1);
+    } else {
+        write_i32(buf, records.len() as i32);
+    }
+    match records {
+        Records::Bytes(bytes) => buf.extend_from_slice(bytes),
+        Records::File(region) => buf.push_file(region.clone()),
+    }
+}
@@ -4,11 +4,14 @@
 //! Each version includes more fields than the last:
 //! - V0: `request_api_key`, `request_api_version`, `correlation_id`
 //! - V1: Same as V0 plus `client_id`
-//! - V2: Same as V1 plus a `tag_buffer`
+//! - V2: Same as V1 plus a tagged field section
 //!
-//! In a real Kafka broker, the logic to pick V0 vs. V1 vs. V2 typically depends on
-//! `(api_key, api_version)` and whether that combination is in the “flexible versions” list.
+//! The header version is not sent on the wire. It is derived from the `(api_key, api_version)`
+//! pair: flexible versions of an API use V2, older versions use V1, and ControlledShutdown v0
+//! uses V0 (see [`ApiKey::request_header_version`]).
 
+use crate::kafka_protocol::kafka_api_keys::ApiKey;
+use crate::kafka_protocol::kafka_codec::{read_nullable_string, read_tagged_fields};
 use crate::kafka_protocol::kafka_error::{KafkaBrokerError, KafkaResult};
 use crate::kafka_protocol::kafka_error_codes::{INVALID_REQUEST, UNSUPPORTED_VERSION};
 use std::convert::TryInto;
@@ -23,7 +26,7 @@ pub enum KafkaRequestHeader {
     /// Version 1: Adds a `client_id`.
     V1(KafkaRequestHeaderV1),
 
-    /// Version 2: Adds a `tag_buffer`.
+    /// Version 2: Adds a tagged field section.
     V2(KafkaRequestHeaderV2),
 }
 
@@ -44,18 +47,55 @@ pub struct KafkaRequestHeaderV1 {
     pub client_id: Option<String>,
 }
 
-/// V2 includes everything in V1 plus a `tag_buffer` for additional fields.
+/// V2 includes everything in V1 plus a tagged field section. No header tags are defined, so
+/// the section is read past and dropped.
 #[derive(Debug)]
 pub struct KafkaRequestHeaderV2 {
     pub request_api_key: i16,
     pub request_api_version: i16,
     pub correlation_id: i32,
     pub client_id: Option<String>,
-    pub tag_buffer: Vec<u8>,
 }
 
 impl KafkaRequestHeader {
-    /// Constructs a [`KafkaRequestHeader`] by reading from `raw_data`.
+    /// Returns the `request_api_key` shared by every header version.
+    pub fn request_api_key(&self) -> i16 {
+        match self {
+            KafkaRequestHeader::V0(h) => h.request_api_key,
+            KafkaRequestHeader::V1(h) => h.request_api_key,
+            KafkaRequestHeader::V2(h) => h.request_api_key,
+        }
+    }
+
+    /// Returns the `request_api_version` shared by every header version.
+    pub fn request_api_version(&self) -> i16 {
+        match self {
+            KafkaRequestHeader::V0(h) => h.request_api_version,
+            KafkaRequestHeader::V1(h) => h.request_api_version,
+            KafkaRequestHeader::V2(h) => h.request_api_version,
+        }
+    }
+
+    /// Returns the `correlation_id` the client expects to see echoed in the response.
+    pub fn correlation_id(&self) -> i32 {
+        match self {
+            KafkaRequestHeader::V0(h) => h.correlation_id,
+            KafkaRequestHeader::V1(h) => h.correlation_id,
+            KafkaRequestHeader::V2(h) => h.correlation_id,
+        }
+    }
+
+    /// Returns the `client_id`, if the header version carries one and the client sent it.
+    pub fn client_id(&self) -> Option<&str> {
+        match self {
+            KafkaRequestHeader::V0(_) => None,
+            KafkaRequestHeader::V1(h) => h.client_id.as_deref(),
+            KafkaRequestHeader::V2(h) => h.client_id.as_deref(),
+        }
+    }
+
+    /// Constructs a [`KafkaRequestHeader`] by reading from `raw_data`, returning the header
+    /// together with the remaining bytes (the request body).
     ///
     /// # Layout
     /// - `[0..2]`: `request_api_key` (i16)
@@ -63,14 +103,10 @@ impl KafkaRequestHeader {
     /// - `[4..8]`: `correlation_id` (i32)
     /// - `[8..]`: additional data depending on the version
     ///
-    /// We do a simple match:
-    /// - if `request_api_version == 0` => V0
-    /// - if `request_api_version == 1` => V1
-    /// - if `request_api_version >= 2` => V2
-    ///
-    /// In real Kafka, you might base this on whether `(api_key, api_version)` is known to
-    /// be “flexible,” etc.
-    pub fn from_bytes(raw_data: &[u8]) -> KafkaResult<Self> {
+    /// The header version is looked up from the API's flexible-versions table via
+    /// [`ApiKey::request_header_version`]. For API keys the protocol doesn't define, we fall back
+    /// to V1, whose layout every API shares, so the header can still be parsed and logged.
+    pub fn from_bytes(raw_data: &[u8]) -> KafkaResult<(Self, &[u8])> {
         debug!("Parsing KafkaRequestHeader from {} bytes", raw_data.len());
 
         // Need at least 8 bytes for the fundamental fields.
@@ -119,170 +155,161 @@ impl KafkaRequestHeader {
         let mut cursor = &raw_data[8..];
         trace!("Bytes remaining after fundamental fields: {}", cursor.len());
 
-        // We'll do a naive match on request_api_version:
-        match request_api_version {
+        if request_api_version < 0 {
+            warn!(
+                "Unsupported request_api_version={} for KafkaRequestHeader",
+                request_api_version
+            );
+            return Err(KafkaBrokerError::MalformedRequest {
+                code: UNSUPPORTED_VERSION,
+                reason: format!("Unsupported header version: {}", request_api_version),
+            });
+        }
+
+        let header_version = match ApiKey::try_from(request_api_key) {
+            Ok(api_key) => api_key.request_header_version(request_api_version),
+            Err(_) => {
+                warn!(
+                    "Unknown api_key={}; parsing the header as V1.",
+                    request_api_key
+                );
+                1
+            }
+        };
+        trace!(
+            "api_key={} api_version={} uses request header V{}",
+            request_api_key,
+            request_api_version,
+            header_version
+        );
+
+        let header = match header_version {
             0 => {
                 debug!("Constructing V0 header (no extra fields).");
-                Ok(KafkaRequestHeader::V0(KafkaRequestHeaderV0 {
+                KafkaRequestHeader::V0(KafkaRequestHeaderV0 {
                     request_api_key,
                     request_api_version,
                     correlation_id,
-                }))
+                })
             }
             1 => {
                 debug!("Constructing V1 header (client_id).");
-                let client_id = parse_legacy_string(&mut cursor)?;
+                let client_id = read_nullable_string(&mut cursor)?;
                 debug!("Parsed client_id={:?}", client_id);
 
-                Ok(KafkaRequestHeader::V1(KafkaRequestHeaderV1 {
+                KafkaRequestHeader::V1(KafkaRequestHeaderV1 {
                     request_api_key,
                     request_api_version,
                     correlation_id,
                     client_id,
-                }))
+                })
             }
-            2..=i16::MAX => {
-                debug!("Constructing V2 header (client_id + tag_buffer).");
-                let client_id = parse_legacy_string(&mut cursor)?;
+            _ => {
+                debug!("Constructing V2 header (client_id + tagged fields).");
+                // Note: client_id keeps its 2-byte length prefix even in flexible versions.
+                let client_id = read_nullable_string(&mut cursor)?;
                 debug!("Parsed client_id={:?}", client_id);
 
-                let tag_buffer = parse_tag_buffer(&mut cursor)?;
-                debug!("Parsed tag_buffer length={}", tag_buffer.len());
+                let tagged_fields = read_tagged_fields(&mut cursor)?;
+                debug!("Skipped {} tagged header fields", tagged_fields.len());
 
-                Ok(KafkaRequestHeader::V2(KafkaRequestHeaderV2 {
+                KafkaRequestHeader::V2(KafkaRequestHeaderV2 {
                     request_api_key,
                     request_api_version,
                     correlation_id,
                     client_id,
-                    tag_buffer,
-                }))
-            }
-            _ => {
-                // If somehow request_api_version is negative or we just don't support it
-                warn!(
-                    "Unsupported request_api_version={} for KafkaRequestHeader",
-                    request_api_version
-                );
-                Err(KafkaBrokerError::MalformedRequest {
-                    code: UNSUPPORTED_VERSION,
-                    reason: format!("Unsupported header version: {}", request_api_version),
                 })
             }
-        }
+        };
+
+        trace!("Header parsed; {} body bytes remain.", cursor.len());
+        Ok((header, cursor))
     }
 }
 
-/// Parse a normal 2-byte length “legacy” string (like older Kafka versions).
-/// If the length is -1, we interpret that as `None`.
-fn parse_legacy_string(cursor: &mut &[u8]) -> KafkaResult<Option<String>> {
-    debug!(
-        "Parsing legacy string from {} remaining bytes...",
-        cursor.len()
-    );
-    if cursor.len() < 2 {
-        warn!(
-            "Not enough bytes ({}) to read legacy string length.",
-            cursor.len()
-        );
-        return Err(KafkaBrokerError::MalformedRequest {
-            code: INVALID_REQUEST,
-            reason: "Not enough bytes to read string length".to_string(),
-        });
-    }
-    let length_bytes: [u8; 2] =
-        cursor[..2]
-            .try_into()
-            .map_err(|_| KafkaBrokerError::MalformedRequest {
-                code: INVALID_REQUEST,
-                reason: "Failed to read string length (2 bytes)".to_string(),
-            })?;
-    let length = i16::from_be_bytes(length_bytes);
-    *cursor = &cursor[2..];
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kafka_protocol::kafka_api_keys::SUPPORTED_APIS;
 
-    if length < 0 {
-        // A negative length means “null” string in old Kafka protocols.
-        debug!("Parsed negative string length => treating as None.");
-        return Ok(None);
+    /// A header for `api_key`/`api_version` with client id "c", of header version
+    /// `header_version`, followed by a body.
+    fn header(api_key: i16, api_version: i16, header_version: i16) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&api_key.to_be_bytes());
+        bytes.extend_from_slice(&api_version.to_be_bytes());
+        bytes.extend_from_slice(&7i32.to_be_bytes());
+        if header_version >= 1 {
+            bytes.extend_from_slice(&1i16.to_be_bytes());
+            bytes.push(b'c');
+        }
+        if header_version >= 2 {
+            // One tagged field (tag 0, 1 byte), which is skipped.
+            bytes.extend_from_slice(&[1, 0, 1, 0xff]);
+        }
+        bytes.extend_from_slice(b"body");
+        bytes
     }
 
-    let length = length as usize;
-    if cursor.len() < length {
-        warn!(
-            "Declared string length={}, but only {} bytes remain",
-            length,
-            cursor.len()
-        );
-        return Err(KafkaBrokerError::MalformedRequest {
-            code: INVALID_REQUEST,
-            reason: format!(
-                "String says length={}, but only {} bytes remain",
-                length,
-                cursor.len()
-            ),
-        });
+    /// The header version `bytes` were parsed as, checking the fields and the body left over.
+    fn parsed_version(bytes: &[u8]) -> i16 {
+        let (header, body) = KafkaRequestHeader::from_bytes(bytes).unwrap();
+        assert_eq!(body, b"body");
+        assert_eq!(header.correlation_id(), 7);
+        match header {
+            KafkaRequestHeader::V0(_) => 0,
+            KafkaRequestHeader::V1(header) => {
+                assert_eq!(header.client_id.as_deref(), Some("c"));
+                1
+            }
+            KafkaRequestHeader::V2(header) => {
+                assert_eq!(header.client_id.as_deref(), Some("c"));
+                2
+            }
+        }
     }
 
-    let bytes = &cursor[..length];
-    *cursor = &cursor[length..];
-
-    let s = std::str::from_utf8(bytes).map_err(|_| {
-        warn!("String bytes are not valid UTF-8.");
-        KafkaBrokerError::MalformedRequest {
-            code: INVALID_REQUEST,
-            reason: "String is not valid UTF-8".to_string(),
+    #[test]
+    fn flexible_versions_of_each_api_use_header_v2() {
+        for range in SUPPORTED_APIS {
+            let api_key = range.api_key as i16;
+            let Some(first) = range.api_key.first_flexible_version() else {
+                assert_eq!(parsed_version(&header(api_key, range.max_version, 1)), 1);
+                continue;
+            };
+            if first > 0 {
+                let version = first - 1;
+                assert_eq!(
+                    parsed_version(&header(api_key, version, 1)),
+                    1,
+                    "{:?} v{}",
+                    range.api_key,
+                    version
+                );
+            }
+            assert_eq!(
+                parsed_version(&header(api_key, first, 2)),
+                2,
+                "{:?} v{}",
+                range.api_key,
+                first
+            );
         }
-    })?;
-
-    debug!("Successfully parsed string {:?}", s);
-    Ok(Some(s.to_string()))
-}
-
-/// For V2, parse a “tag_buffer”.
-/// Let’s assume we read 2 bytes => `tag_buffer_len`, then read that many bytes.
-fn parse_tag_buffer(cursor: &mut &[u8]) -> KafkaResult<Vec<u8>> {
-    debug!(
-        "Parsing tag_buffer from {} remaining bytes...",
-        cursor.len()
-    );
-    if cursor.len() < 2 {
-        warn!(
-            "Not enough bytes ({}) to read tag_buffer length.",
-            cursor.len()
-        );
-        return Err(KafkaBrokerError::MalformedRequest {
-            code: INVALID_REQUEST,
-            reason: "Not enough bytes to read tag_buffer length".to_string(),
-        });
     }
-    let len_bytes: [u8; 2] =
-        cursor[..2]
-            .try_into()
-            .map_err(|_| KafkaBrokerError::MalformedRequest {
-                code: INVALID_REQUEST,
-                reason: "Failed to read tag_buffer length (2 bytes)".to_string(),
-            })?;
-    let tag_buffer_len = u16::from_be_bytes(len_bytes);
-    *cursor = &cursor[2..];
 
-    if cursor.len() < tag_buffer_len as usize {
-        warn!(
-            "tag_buffer says length={}, but only {} bytes remain",
-            tag_buffer_len,
-            cursor.len()
-        );
-        return Err(KafkaBrokerError::MalformedRequest {
-            code: INVALID_REQUEST,
-            reason: format!(
-                "tag_buffer says length={}, but only {} bytes remain",
-                tag_buffer_len,
-                cursor.len()
-            ),
-        });
+    #[test]
+    fn produce_v3_uses_header_v1() {
+        let produce = ApiKey::Produce as i16;
+        assert_eq!(parsed_version(&header(produce, 3, 1)), 1);
+        assert_eq!(parsed_version(&header(produce, 8, 1)), 1);
+        assert_eq!(parsed_version(&header(produce, 9, 2)), 2);
     }
 
-    let buffer = cursor[..tag_buffer_len as usize].to_vec();
-    *cursor = &cursor[tag_buffer_len as usize..];
-
-    trace!("Parsed tag_buffer of length={}", buffer.len());
-    Ok(buffer)
+    #[test]
+    fn controlled_shutdown_v0_and_unknown_api_keys_get_their_own_header_versions() {
+        let controlled_shutdown = ApiKey::ControlledShutdown as i16;
+        assert_eq!(parsed_version(&header(controlled_shutdown, 0, 0)), 0);
+        assert_eq!(parsed_version(&header(controlled_shutdown, 1, 1)), 1);
+        assert_eq!(parsed_version(&header(i16::MAX, 0, 1)), 1);
+    }
 }
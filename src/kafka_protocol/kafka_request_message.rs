@@ -10,43 +10,202 @@
 //! standard Kafka error code (see `kafka_error_codes.rs`) so the broker can return appropriate
 //! responses.
 
+use crate::kafka_protocol::kafka_api_keys::ApiKey;
 use crate::kafka_protocol::kafka_error::{KafkaBrokerError, KafkaResult};
 use crate::kafka_protocol::kafka_error_codes::INVALID_REQUEST;
+use crate::kafka_protocol::kafka_messages::{
+    ApiVersionsRequest, ConsumerGroupDescribeRequest, ConsumerGroupHeartbeatRequest,
+    DeleteGroupsRequest, DeleteRecordsRequest, DescribeGroupsRequest, FetchRequest,
+    FindCoordinatorRequest, HeartbeatRequest, InitProducerIdRequest, JoinGroupRequest,
+    LeaveGroupRequest, ListGroupsRequest, MetadataRequest, OffsetCommitRequest,
+    OffsetDeleteRequest, OffsetFetchRequest, ProduceRequest, SyncGroupRequest,
+};
 use crate::kafka_protocol::kafka_request_header::KafkaRequestHeader;
 use std::convert::TryInto;
-use tracing::warn;
+use tracing::{debug, warn};
 
 /// A structured representation of a Kafka request message.
 ///
-/// Currently, this struct only stores:
+/// This struct stores:
 /// - An integer `message_size` (parsed from the first 4 bytes),
-/// - A placeholder `KafkaRequestHeader`,
-/// - A placeholder `KafkaRequest` payload.
-///
-/// # Future Enhancements
-///
-/// In a more complete implementation, you'd parse additional header fields
-/// (e.g., API key, API version, correlation ID, client ID length, etc.) and the
-/// request payload into strongly typed structures.
+/// - The parsed `KafkaRequestHeader`,
+/// - The typed `KafkaRequest` payload for APIs the broker implements.
 #[derive(Debug)]
 pub struct KafkaRequestMessage {
     /// The overall size of the message in bytes, as read from the first 4 bytes of the buffer.
     pub message_size: i32,
 
-    /// Placeholder for the future `KafkaRequestHeader` fields.
-    /// In real Kafka requests, this contains metadata such as API key, version, etc.
+    /// The request header: API key, API version, correlation ID and client ID.
     pub header: KafkaRequestHeader,
 
-    /// Placeholder for the future `KafkaRequest` fields.
-    /// This is where the actual request data (e.g., topic name, partition info) will be stored.
+    /// The API-specific request body.
     pub payload: KafkaRequest,
 }
 
 /// Represents the payload portion of a Kafka request.
-/// In the real Kafka protocol, this section may include details such as
-/// topic-partition data, message sets, and other request-specific fields.
+///
+/// Each API the broker implements gets its own variant, wrapping the body type generated from
+/// Kafka's message specs (see [`kafka_messages`](crate::kafka_protocol::kafka_messages)). Bodies for APIs (or versions) outside
+/// of [`SUPPORTED_APIS`](crate::kafka_protocol::kafka_api_keys::SUPPORTED_APIS) are not parsed,
+/// since their layout may be unknown to us.
 #[derive(Debug)]
-pub struct KafkaRequest;
+pub enum KafkaRequest {
+    /// Produce (key 0).
+    Produce(ProduceRequest),
+
+    /// Fetch (key 1).
+    Fetch(FetchRequest),
+
+    /// Metadata (key 3).
+    Metadata(MetadataRequest),
+
+    /// OffsetCommit (key 8).
+    OffsetCommit(OffsetCommitRequest),
+
+    /// OffsetFetch (key 9).
+    OffsetFetch(OffsetFetchRequest),
+
+    /// FindCoordinator (key 10).
+    FindCoordinator(FindCoordinatorRequest),
+
+    /// JoinGroup (key 11).
+    JoinGroup(JoinGroupRequest),
+
+    /// Heartbeat (key 12).
+    Heartbeat(HeartbeatRequest),
+
+    /// LeaveGroup (key 13).
+    LeaveGroup(LeaveGroupRequest),
+
+    /// SyncGroup (key 14).
+    SyncGroup(SyncGroupRequest),
+
+    /// DescribeGroups (key 15).
+    DescribeGroups(DescribeGroupsRequest),
+
+    /// ListGroups (key 16).
+    ListGroups(ListGroupsRequest),
+
+    /// ApiVersions (key 18).
+    ApiVersions(ApiVersionsRequest),
+
+    /// DeleteRecords (key 21).
+    DeleteRecords(DeleteRecordsRequest),
+
+    /// InitProducerId (key 22).
+    InitProducerId(InitProducerIdRequest),
+
+    /// DeleteGroups (key 42).
+    DeleteGroups(DeleteGroupsRequest),
+
+    /// OffsetDelete (key 47).
+    OffsetDelete(OffsetDeleteRequest),
+
+    /// ConsumerGroupHeartbeat (key 68).
+    ConsumerGroupHeartbeat(ConsumerGroupHeartbeatRequest),
+
+    /// ConsumerGroupDescribe (key 69).
+    ConsumerGroupDescribe(ConsumerGroupDescribeRequest),
+
+    /// A request for an API key or version this broker does not implement.
+    Unsupported,
+}
+
+impl KafkaRequest {
+    /// Parses the body of a request for `api_key`/`api_version`.
+    ///
+    /// Unknown API keys and unsupported versions yield [`KafkaRequest::Unsupported`]; the
+    /// dispatcher decides how to answer those.
+    fn from_bytes(mut body: &[u8], api_key: i16, api_version: i16) -> KafkaResult<Self> {
+        let Ok(api_key) = ApiKey::try_from(api_key) else {
+            return Ok(KafkaRequest::Unsupported);
+        };
+        if !api_key.is_version_supported(api_version) {
+            return Ok(KafkaRequest::Unsupported);
+        }
+
+        debug!(
+            "Parsing {:?} v{} body ({} bytes)",
+            api_key,
+            api_version,
+            body.len()
+        );
+        match api_key {
+            ApiKey::Produce => Ok(KafkaRequest::Produce(ProduceRequest::read(
+                &mut body,
+                api_version,
+            )?)),
+            ApiKey::Fetch => Ok(KafkaRequest::Fetch(FetchRequest::read(
+                &mut body,
+                api_version,
+            )?)),
+            ApiKey::Metadata => Ok(KafkaRequest::Metadata(MetadataRequest::read(
+                &mut body,
+                api_version,
+            )?)),
+            ApiKey::OffsetCommit => Ok(KafkaRequest::OffsetCommit(OffsetCommitRequest::read(
+                &mut body,
+                api_version,
+            )?)),
+            ApiKey::OffsetFetch => Ok(KafkaRequest::OffsetFetch(OffsetFetchRequest::read(
+                &mut body,
+                api_version,
+            )?)),
+            ApiKey::FindCoordinator => Ok(KafkaRequest::FindCoordinator(
+                FindCoordinatorRequest::read(&mut body, api_version)?,
+            )),
+            ApiKey::JoinGroup => Ok(KafkaRequest::JoinGroup(JoinGroupRequest::read(
+                &mut body,
+                api_version,
+            )?)),
+            ApiKey::Heartbeat => Ok(KafkaRequest::Heartbeat(HeartbeatRequest::read(
+                &mut body,
+                api_version,
+            )?)),
+            ApiKey::LeaveGroup => Ok(KafkaRequest::LeaveGroup(LeaveGroupRequest::read(
+                &mut body,
+                api_version,
+            )?)),
+            ApiKey::SyncGroup => Ok(KafkaRequest::SyncGroup(SyncGroupRequest::read(
+                &mut body,
+                api_version,
+            )?)),
+            ApiKey::DescribeGroups => Ok(KafkaRequest::DescribeGroups(
+                DescribeGroupsRequest::read(&mut body, api_version)?,
+            )),
+            ApiKey::ListGroups => Ok(KafkaRequest::ListGroups(ListGroupsRequest::read(
+                &mut body,
+                api_version,
+            )?)),
+            ApiKey::ApiVersions => Ok(KafkaRequest::ApiVersions(ApiVersionsRequest::read(
+                &mut body,
+                api_version,
+            )?)),
+            ApiKey::DeleteRecords => Ok(KafkaRequest::DeleteRecords(DeleteRecordsRequest::read(
+                &mut body,
+                api_version,
+            )?)),
+            ApiKey::InitProducerId => Ok(KafkaRequest::InitProducerId(
+                InitProducerIdRequest::read(&mut body, api_version)?,
+            )),
+            ApiKey::DeleteGroups => Ok(KafkaRequest::DeleteGroups(DeleteGroupsRequest::read(
+                &mut body,
+                api_version,
+            )?)),
+            ApiKey::OffsetDelete => Ok(KafkaRequest::OffsetDelete(OffsetDeleteRequest::read(
+                &mut body,
+                api_version,
+            )?)),
+            ApiKey::ConsumerGroupHeartbeat => Ok(KafkaRequest::ConsumerGroupHeartbeat(
+                ConsumerGroupHeartbeatRequest::read(&mut body, api_version)?,
+            )),
+            ApiKey::ConsumerGroupDescribe => Ok(KafkaRequest::ConsumerGroupDescribe(
+                ConsumerGroupDescribeRequest::read(&mut body, api_version)?,
+            )),
+            _ => Ok(KafkaRequest::Unsupported),
+        }
+    }
+}
 
 impl KafkaRequestMessage {
     /// Constructs a [`KafkaRequestMessage`] from the given raw bytes.
@@ -115,10 +274,9 @@ impl KafkaRequestMessage {
             });
         }
 
-        // For now, we use placeholder structs for the header and payload.
-        let header = KafkaRequestHeader::from_bytes(&raw_data[4..])?;
-
-        let payload = KafkaRequest;
+        let (header, body) = KafkaRequestHeader::from_bytes(&raw_data[4..])?;
+        let payload =
+            KafkaRequest::from_bytes(body, header.request_api_key(), header.request_api_version())?;
 
         // Construct and return the `KafkaRequestMessage`.
         Ok(Self {
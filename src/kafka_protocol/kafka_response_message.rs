@@ -1,18 +1,214 @@
-//! Defines the KafkaResponseMessage struct, which represents a response
-//! to send back to the client. Currently unused, since we're not creating
-//! or sending responses yet.
+//! Defines the [`KafkaResponseMessage`] struct, which represents a response
+//! to send back to the client.
+//!
+//! On the wire, every response is a 4-byte big-endian `message_size`, followed by
+//! a response header and the API-specific payload, encoded for the API version the
+//! response is sent as. Response header v0 holds just the `correlation_id` from the
+//! request; v1, used by flexible versions, appends a tagged field section.
 
+use crate::kafka_protocol::kafka_api_keys::ApiKey;
+use crate::kafka_protocol::kafka_codec::{
+    write_i16, write_i32, write_tagged_fields, RawTaggedField,
+};
+use crate::kafka_protocol::kafka_messages::{
+    ApiVersionsResponse, ConsumerGroupDescribeResponse, ConsumerGroupHeartbeatResponse,
+    DeleteGroupsResponse, DeleteRecordsResponse, DescribeGroupsResponse, FetchResponse,
+    FindCoordinatorResponse, HeartbeatResponse, InitProducerIdResponse, JoinGroupResponse,
+    LeaveGroupResponse, ListGroupsResponse, MetadataResponse, OffsetCommitResponse,
+    OffsetDeleteResponse, OffsetFetchResponse, ProduceResponse, SyncGroupResponse,
+};
+use crate::kafka_protocol::kafka_records::EncodeBuf;
+
+/// A response ready to be encoded and written to the client socket.
 #[derive(Debug)]
 pub struct KafkaResponseMessage {
-    pub message_size: i32,
+    /// The response header, echoing the request's correlation id.
     pub header: KafkaResponseHeader,
+
+    /// The API version the payload is encoded as. Usually the request's version, but
+    /// ApiVersions falls back to v0 when the client asked for a version we don't support.
+    pub api_version: i16,
+
+    /// The API-specific response body.
     pub payload: KafkaResponse,
 }
 
-/// Placeholder for the Kafka header (same as in `kafka_request_message.rs`).
+/// A versioned response header, sent in front of every response payload.
 #[derive(Debug)]
-pub struct KafkaResponseHeader;
+pub enum KafkaResponseHeader {
+    /// Version 0: just the correlation id.
+    V0 {
+        /// The correlation id from the request this response answers.
+        correlation_id: i32,
+    },
+
+    /// Version 1: adds a tagged field section, for flexible API versions.
+    V1 {
+        /// The correlation id from the request this response answers.
+        correlation_id: i32,
+        /// Header tagged fields. None are defined yet, so this is always empty.
+        tagged_fields: Vec<RawTaggedField>,
+    },
+}
 
-/// Placeholder for the Kafka response payload struct.
+/// The payload portion of a Kafka response.
+///
+/// Each supported API gets its own variant as it is implemented, wrapping the body type
+/// generated from Kafka's message specs.
 #[derive(Debug)]
-pub struct KafkaResponse;
+pub enum KafkaResponse {
+    /// Produce (key 0).
+    Produce(ProduceResponse),
+
+    /// Fetch (key 1).
+    Fetch(FetchResponse),
+
+    /// Metadata (key 3).
+    Metadata(MetadataResponse),
+
+    /// OffsetCommit (key 8).
+    OffsetCommit(OffsetCommitResponse),
+
+    /// OffsetFetch (key 9).
+    OffsetFetch(OffsetFetchResponse),
+
+    /// FindCoordinator (key 10).
+    FindCoordinator(FindCoordinatorResponse),
+
+    /// JoinGroup (key 11).
+    JoinGroup(JoinGroupResponse),
+
+    /// Heartbeat (key 12).
+    Heartbeat(HeartbeatResponse),
+
+    /// LeaveGroup (key 13).
+    LeaveGroup(LeaveGroupResponse),
+
+    /// SyncGroup (key 14).
+    SyncGroup(SyncGroupResponse),
+
+    /// DescribeGroups (key 15).
+    DescribeGroups(DescribeGroupsResponse),
+
+    /// ListGroups (key 16).
+    ListGroups(ListGroupsResponse),
+
+    /// ApiVersions (key 18).
+    ApiVersions(ApiVersionsResponse),
+
+    /// DeleteRecords (key 21).
+    DeleteRecords(DeleteRecordsResponse),
+
+    /// InitProducerId (key 22).
+    InitProducerId(InitProducerIdResponse),
+
+    /// DeleteGroups (key 42).
+    DeleteGroups(DeleteGroupsResponse),
+
+    /// OffsetDelete (key 47).
+    OffsetDelete(OffsetDeleteResponse),
+
+    /// ConsumerGroupHeartbeat (key 68).
+    ConsumerGroupHeartbeat(ConsumerGroupHeartbeatResponse),
+
+    /// ConsumerGroupDescribe (key 69).
+    ConsumerGroupDescribe(ConsumerGroupDescribeResponse),
+
+    /// A bare error response, used when the broker has no response type for the request (an
+    /// unknown API key, or an API it doesn't implement). The body consists of just the 2-byte
+    /// Kafka error code.
+    Error {
+        /// The Kafka error code (see `kafka_error_codes.rs`).
+        error_code: i16,
+    },
+}
+
+impl KafkaResponseHeader {
+    /// Creates the header for a response to `api_key`/`api_version`, picking the header version
+    /// with [`ApiKey::response_header_version`]. Unknown API keys get header v0.
+    pub fn new(api_key: i16, api_version: i16, correlation_id: i32) -> Self {
+        let version = ApiKey::try_from(api_key)
+            .map(|key| key.response_header_version(api_version))
+            .unwrap_or(0);
+        match version {
+            0 => KafkaResponseHeader::V0 { correlation_id },
+            _ => KafkaResponseHeader::V1 {
+                correlation_id,
+                tagged_fields: Vec::new(),
+            },
+        }
+    }
+
+    /// Appends the encoded header to `buf`.
+    fn write(&self, buf: &mut Vec<u8>) {
+        match self {
+            KafkaResponseHeader::V0 { correlation_id } => write_i32(buf, *correlation_id),
+            KafkaResponseHeader::V1 {
+                correlation_id,
+                tagged_fields,
+            } => {
+                write_i32(buf, *correlation_id);
+                write_tagged_fields(buf, tagged_fields);
+            }
+        }
+    }
+}
+
+impl KafkaResponseMessage {
+    /// Creates a response to the `api_key` request identified by `correlation_id`, encoded as
+    /// `api_version`.
+    pub fn new(
+        api_key: i16,
+        correlation_id: i32,
+        api_version: i16,
+        payload: KafkaResponse,
+    ) -> Self {
+        Self {
+            header: KafkaResponseHeader::new(api_key, api_version, correlation_id),
+            api_version,
+            payload,
+        }
+    }
+
+    /// Appends the full response frame, including the leading 4-byte `message_size`, to `buf`.
+    /// Record data read from segment files is added as file regions, not copied.
+    ///
+    /// The buffer is meant to be reused across responses on a connection: clearing it keeps
+    /// its capacity, so a steady stream of large responses stops allocating once the buffer has
+    /// grown to fit them.
+    pub fn encode_into(&self, buf: &mut EncodeBuf) {
+        // Reserve room for the size prefix; it's patched in once the body is written.
+        let start = buf.len();
+        let start_len = buf.encoded_len();
+        buf.extend_from_slice(&[0u8; 4]);
+        self.header.write(buf);
+
+        match &self.payload {
+            KafkaResponse::Produce(response) => response.write(buf, self.api_version),
+            KafkaResponse::Fetch(response) => response.write(buf, self.api_version),
+            KafkaResponse::Metadata(response) => response.write(buf, self.api_version),
+            KafkaResponse::OffsetCommit(response) => response.write(buf, self.api_version),
+            KafkaResponse::OffsetFetch(response) => response.write(buf, self.api_version),
+            KafkaResponse::FindCoordinator(response) => response.write(buf, self.api_version),
+            KafkaResponse::JoinGroup(response) => response.write(buf, self.api_version),
+            KafkaResponse::Heartbeat(response) => response.write(buf, self.api_version),
+            KafkaResponse::LeaveGroup(response) => response.write(buf, self.api_version),
+            KafkaResponse::SyncGroup(response) => response.write(buf, self.api_version),
+            KafkaResponse::DescribeGroups(response) => response.write(buf, self.api_version),
+            KafkaResponse::ListGroups(response) => response.write(buf, self.api_version),
+            KafkaResponse::ApiVersions(response) => response.write(buf, self.api_version),
+            KafkaResponse::DeleteRecords(response) => response.write(buf, self.api_version),
+            KafkaResponse::InitProducerId(response) => response.write(buf, self.api_version),
+            KafkaResponse::DeleteGroups(response) => response.write(buf, self.api_version),
+            KafkaResponse::OffsetDelete(response) => response.write(buf, self.api_version),
+            KafkaResponse::ConsumerGroupHeartbeat(response) => {
+                response.write(buf, self.api_version)
+            }
+            KafkaResponse::ConsumerGroupDescribe(response) => response.write(buf, self.api_version),
+            KafkaResponse::Error { error_code } => write_i16(buf, *error_code),
+        }
+
+        let message_size = (buf.encoded_len() - start_len - 4) as i32;
+        buf[start..start + 4].copy_from_slice(&message_size.to_be_bytes());
+    }
+}
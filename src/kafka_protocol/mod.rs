@@ -1,4 +1,13 @@
+pub mod kafka_api_keys;
+pub mod kafka_codec;
+pub mod kafka_compression;
 pub mod kafka_error;
 pub mod kafka_error_codes;
+pub mod kafka_frame_codec;
+pub mod kafka_message_set;
+pub mod kafka_messages;
+pub mod kafka_record_batch;
+pub mod kafka_records;
 pub mod kafka_request_header;
 pub mod kafka_request_message;
+pub mod kafka_response_message;
@@ -8,12 +8,16 @@ use tokio::net::TcpListener;
 use tokio::{select, signal, task::JoinSet, time};
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn, Instrument};
-use tracing_subscriber;
 
+mod api_handlers;
 mod broker_state;
 mod client_handler;
 mod config;
+mod group_coordinator;
 mod kafka_protocol;
+mod producer_id_manager;
+mod purgatory;
+mod storage;
 
 use crate::broker_state::{BrokerState, SharedBrokerState};
 use crate::config::Config;
@@ -35,7 +39,7 @@ fn setup() -> anyhow::Result<()> {
 ///
 /// # Parameters
 ///
-/// - `config`: The broker configuration (host, port, drain time).
+/// - `config`: The broker configuration (host, port, drain time, request size limit).
 /// - `broker_state`: Shared state (e.g., topics, offsets).
 /// - `shutdown_token`: A cancellation token for graceful shutdown.
 /// - `join_set`: A `JoinSet` that tracks spawned client tasks so we can wait on them later.
@@ -64,7 +68,12 @@ async fn accept_loop(
 
                         // Spawn the new function from client_handler
                         join_set.spawn(
-                            client_handler::handle_client(socket, state_clone).instrument(span)
+                            client_handler::handle_client(
+                                socket,
+                                state_clone,
+                                config.socket_request_max_bytes,
+                            )
+                            .instrument(span)
                         );
                     },
                     Err(e) => {
@@ -137,8 +146,9 @@ async fn drain_tasks(join_set: &mut JoinSet<anyhow::Result<()>>, timeout_secs: u
 /// stops `accept_loop` gracefully. Then we attempt to drain existing tasks for
 /// up to `client_drain_timeout_secs`.
 async fn run_server(config: Config) -> anyhow::Result<()> {
-    // Create the shared broker state (topics, metadata, etc.).
-    let broker_state = BrokerState::new();
+    // Create the shared broker state (topics, metadata, etc.), loading the existing logs and
+    // recovering them if the previous run didn't shut down cleanly.
+    let broker_state = BrokerState::load(config.clone()).await?;
     let broker_state_arc = SharedBrokerState::from(broker_state);
 
     // Create a cancellation token for graceful shutdown.
@@ -154,14 +164,93 @@ async fn run_server(config: Config) -> anyhow::Result<()> {
         shutdown_token_clone.cancel();
     });
 
+    // Delete old log segments in the background until shutdown.
+    let retention_task = tokio::spawn({
+        let broker_state = broker_state_arc.clone();
+        let shutdown_token = shutdown_token.clone();
+        let interval = time::Duration::from_millis(config.log_retention_check_interval_ms);
+        async move {
+            broker_state
+                .logs
+                .run_retention(interval, shutdown_token)
+                .await
+        }
+    });
+
+    // Flush logs and checkpoint their recovery points in the background until shutdown.
+    let flusher_task = tokio::spawn({
+        let broker_state = broker_state_arc.clone();
+        let shutdown_token = shutdown_token.clone();
+        let flush_interval = time::Duration::from_millis(config.log_flush_scheduler_interval_ms);
+        let checkpoint_interval =
+            time::Duration::from_millis(config.log_flush_offset_checkpoint_interval_ms);
+        async move {
+            broker_state
+                .logs
+                .run_flusher(flush_interval, checkpoint_interval, shutdown_token)
+                .await
+        }
+    });
+
+    // Compact logs in the background until shutdown, unless the cleaner is disabled.
+    let cleaner_task = config.log_cleaner_enable.then(|| {
+        let broker_state = broker_state_arc.clone();
+        let shutdown_token = shutdown_token.clone();
+        let backoff = time::Duration::from_millis(config.log_cleaner_backoff_ms);
+        tokio::spawn(async move { broker_state.logs.run_cleaner(backoff, shutdown_token).await })
+    });
+
+    // Expire delayed requests (parked fetches, acks=-1 produces) as their deadlines pass. At
+    // shutdown, whatever is still parked is answered at once so that clients can be drained.
+    let reaper_task = tokio::spawn({
+        let broker_state = broker_state_arc.clone();
+        let shutdown_token = shutdown_token.clone();
+        async move { broker_state.run_purgatory_reapers(shutdown_token).await }
+    });
+
+    // Expire group members whose session has lapsed and committed offsets past retention, and
+    // answer the JoinGroup and SyncGroup requests still waiting at shutdown.
+    let group_coordinator_task = tokio::spawn({
+        let broker_state = broker_state_arc.clone();
+        let shutdown_token = shutdown_token.clone();
+        async move { broker_state.group_coordinator.run(shutdown_token).await }
+    });
+
     // This JoinSet will track all spawned client tasks.
     let mut join_set = JoinSet::new();
 
     // Accept connections until the cancellation token fires.
-    accept_loop(&config, broker_state_arc, shutdown_token, &mut join_set).await?;
+    accept_loop(
+        &config,
+        broker_state_arc.clone(),
+        shutdown_token,
+        &mut join_set,
+    )
+    .await?;
 
     // After the accept loop ends, give client tasks a chance to finish.
     drain_tasks(&mut join_set, config.client_drain_timeout_secs).await;
+    if let Err(e) = reaper_task.await {
+        error!("The purgatory reaper task panicked: {:?}", e);
+    }
+    if let Err(e) = group_coordinator_task.await {
+        error!("The group coordinator task panicked: {:?}", e);
+    }
+
+    // Let the background log tasks finish their current pass, then sync the logs and mark the
+    // shutdown as clean, so the next start skips recovery.
+    if let Err(e) = retention_task.await {
+        error!("The log retention task panicked: {:?}", e);
+    }
+    if let Err(e) = flusher_task.await {
+        error!("The log flusher task panicked: {:?}", e);
+    }
+    if let Some(cleaner_task) = cleaner_task {
+        if let Err(e) = cleaner_task.await {
+            error!("The log cleaner task panicked: {:?}", e);
+        }
+    }
+    broker_state_arc.logs.shutdown().await?;
 
     info!("Server has shut down gracefully.");
     Ok(())
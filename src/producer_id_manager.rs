@@ -0,0 +1,110 @@
+//! Allocates the producer ids handed out by InitProducerId.
+//!
+//! A producer id must never be handed out twice, even across restarts, or a new producer
+//! would inherit the sequence numbers an old one left in the partitions' producer state. Ids
+//! are therefore reserved in blocks of [`PRODUCER_ID_BLOCK_SIZE`]: the end of the latest block
+//! is recorded in a `producer-id-block` file in the first log directory before any id of the
+//! block is handed out. After a restart, allocation resumes with the next block, skipping
+//! whatever was left of the previous one.
+//!
+//! The file holds a version line (`0`) and a line with the end of the latest block.
+
+use crate::kafka_protocol::kafka_error::{KafkaBrokerError, KafkaResult};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{debug, info};
+
+/// The file recording the latest reserved block.
+const PRODUCER_ID_BLOCK_FILE: &str = "producer-id-block";
+
+/// The only version of the block file.
+const PRODUCER_ID_BLOCK_VERSION: i32 = 0;
+
+/// How many producer ids are reserved at a time.
+pub const PRODUCER_ID_BLOCK_SIZE: i64 = 1000;
+
+/// The ids of the current block that are still available.
+#[derive(Debug)]
+struct ProducerIdBlock {
+    /// The next id to hand out.
+    next: i64,
+    /// The end of the block, exclusive.
+    end: i64,
+}
+
+/// Hands out unique producer ids.
+#[derive(Debug)]
+pub struct ProducerIdManager {
+    path: PathBuf,
+    block: Mutex<ProducerIdBlock>,
+}
+
+impl ProducerIdManager {
+    /// Resumes allocation after the latest block recorded in `log_dir`, or from 0 if none
+    /// was.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if the block file can't be read, or
+    /// [`KafkaBrokerError::CorruptMessage`] if it is malformed.
+    pub fn open(log_dir: &Path) -> KafkaResult<Self> {
+        let path = log_dir.join(PRODUCER_ID_BLOCK_FILE);
+        let end = match fs::read_to_string(&path) {
+            Ok(contents) => parse_block_file(&contents).ok_or_else(|| {
+                KafkaBrokerError::CorruptMessage(format!(
+                    "Malformed producer id block file {}",
+                    path.display()
+                ))
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        debug!("Producer ids will be allocated from {}", end);
+        Ok(Self {
+            path,
+            block: Mutex::new(ProducerIdBlock { next: end, end }),
+        })
+    }
+
+    /// Returns a producer id that was never handed out before, reserving a new block first if
+    /// the current one is used up.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if a new block is needed but can't be recorded.
+    pub fn generate_producer_id(&self) -> KafkaResult<i64> {
+        let mut block = self.block.lock().unwrap();
+        if block.next >= block.end {
+            let end = block.end + PRODUCER_ID_BLOCK_SIZE;
+            self.write_block_end(end)?;
+            info!("Reserved producer ids {} to {}", block.end, end - 1);
+            block.next = block.end;
+            block.end = end;
+        }
+        let producer_id = block.next;
+        block.next += 1;
+        Ok(producer_id)
+    }
+
+    /// Records `end` as the end of the latest block, syncing it to disk.
+    fn write_block_end(&self, end: i64) -> KafkaResult<()> {
+        let temp_path = self.path.with_extension("tmp");
+        let mut file = File::create(&temp_path)?;
+        file.write_all(format!("{}\n{}\n", PRODUCER_ID_BLOCK_VERSION, end).as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Parses the contents of a block file, returning the end of the latest block.
+fn parse_block_file(contents: &str) -> Option<i64> {
+    let mut lines = contents.lines();
+    let version: i32 = lines.next()?.trim().parse().ok()?;
+    if version != PRODUCER_ID_BLOCK_VERSION {
+        return None;
+    }
+    lines.next()?.trim().parse().ok().filter(|&end| end >= 0)
+}
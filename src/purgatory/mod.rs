@@ -0,0 +1,213 @@
+//! Delayed operations: requests held back until a condition is met or a timeout fires.
+//!
+//! Some requests can't be answered straight away. A Fetch waits for `min_bytes` of data, a
+//! Produce with `acks=-1` waits for the high watermark to pass its records, and a JoinGroup
+//! waits for the rest of its group. As in Kafka, such a request is parked in a [`Purgatory`]
+//! with a deadline, watching the keys (partitions or groups) whose changes may let it complete.
+//!
+//! Each delayed request is an async task looping over the same steps:
+//!
+//! 1. [`Purgatory::watch`] the keys. This comes first, so that a change made while the
+//!    condition is being checked still wakes the request.
+//! 2. Check the condition, and answer if it holds or the [`Watch`] has expired.
+//! 3. Otherwise [`Watch::wait`] until whoever changes a watched key (an append, a high watermark
+//!    move, a group state change) calls [`Purgatory::check_and_complete`] for it, or until the
+//!    deadline passes.
+//!
+//! Deadlines are kept in a hierarchical [`TimingWheel`], which [`Purgatory::run_reaper`]
+//! advances in the background. When the reaper stops, every waiting request expires, so parked
+//! requests are answered promptly at shutdown.
+
+mod timing_wheel;
+
+use crate::storage::TopicPartition;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use timing_wheel::TimingWheel;
+use tokio::select;
+use tokio::sync::Notify;
+use tokio::time::{self, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace};
+
+/// The tick of the lowest timing wheel, in milliseconds (Kafka's `SystemTimer` default).
+const TICK_MS: u64 = 1;
+
+/// The number of buckets in each timing wheel (Kafka's `SystemTimer` default).
+const WHEEL_SIZE: usize = 20;
+
+/// What a delayed operation watches.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DelayedOperationKey {
+    /// A partition, changed by appends and high watermark moves.
+    TopicPartition(TopicPartition),
+    /// A consumer group, changed by its members joining, leaving and syncing.
+    Group(String),
+}
+
+/// Requests of one kind waiting for their keys to change or their deadline to pass.
+pub struct Purgatory {
+    /// The kind of request held, for logging.
+    name: &'static str,
+    /// The instant the timing wheel's clock counts milliseconds from.
+    start: Instant,
+    inner: Mutex<Inner>,
+    /// Wakes the reaper when a timer is added ahead of the one it is sleeping for.
+    timer_added: Notify,
+}
+
+struct Inner {
+    /// The requests watching each key. Entries of requests that have moved on are dropped the
+    /// next time the key is checked, or when the list needs to grow.
+    watchers: HashMap<DelayedOperationKey, Vec<Weak<Waiter>>>,
+    /// The deadline of every waiting request.
+    timers: TimingWheel<Weak<Waiter>>,
+    /// Set once the reaper has stopped: new watches expire at once.
+    closed: bool,
+}
+
+/// The wake-up side of a [`Watch`].
+struct Waiter {
+    notify: Notify,
+    expired: AtomicBool,
+}
+
+impl Waiter {
+    fn wake(&self) {
+        self.notify.notify_one();
+    }
+
+    fn expire(&self) {
+        self.expired.store(true, Ordering::Release);
+        self.notify.notify_one();
+    }
+}
+
+/// A request's registration in a [`Purgatory`]. Dropping it unregisters the request.
+pub struct Watch {
+    waiter: Arc<Waiter>,
+}
+
+impl Watch {
+    /// Whether the request's deadline has passed, in which case it must be answered now.
+    pub fn expired(&self) -> bool {
+        self.waiter.expired.load(Ordering::Acquire)
+    }
+
+    /// Waits until one of the watched keys is checked or the deadline passes. Returns at once
+    /// if either already happened since the watch was created.
+    pub async fn wait(&self) {
+        self.waiter.notify.notified().await;
+    }
+}
+
+impl Purgatory {
+    /// Creates an empty purgatory for requests of kind `name`.
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            start: Instant::now(),
+            inner: Mutex::new(Inner {
+                watchers: HashMap::new(),
+                timers: TimingWheel::new(TICK_MS, WHEEL_SIZE, 0),
+                closed: false,
+            }),
+            timer_added: Notify::new(),
+        }
+    }
+
+    /// Registers a request watching `keys` until `deadline`. The returned [`Watch`] is already
+    /// expired if the deadline has passed.
+    pub fn watch(&self, keys: &[DelayedOperationKey], deadline: Instant) -> Watch {
+        let waiter = Arc::new(Waiter {
+            notify: Notify::new(),
+            expired: AtomicBool::new(false),
+        });
+        let mut inner = self.inner.lock().unwrap();
+        if inner.closed || deadline <= Instant::now() {
+            waiter.expire();
+            return Watch { waiter };
+        }
+
+        for key in keys {
+            let watchers = inner.watchers.entry(key.clone()).or_default();
+            if watchers.len() == watchers.capacity() {
+                watchers.retain(|watcher| watcher.strong_count() > 0);
+            }
+            watchers.push(Arc::downgrade(&waiter));
+        }
+
+        // Round up, so that a request never expires before its deadline.
+        let expiration_ms = self.clock_ms(deadline + Duration::from_micros(999));
+        let next_expiration = inner.timers.next_expiration();
+        if inner
+            .timers
+            .add(expiration_ms, Arc::downgrade(&waiter))
+            .is_some()
+        {
+            waiter.expire();
+        } else if inner.timers.next_expiration() != next_expiration {
+            self.timer_added.notify_one();
+        }
+        Watch { waiter }
+    }
+
+    /// Wakes every request watching `key`, so that it checks its condition again. Returns the
+    /// number of requests woken.
+    pub fn check_and_complete(&self, key: &DelayedOperationKey) -> usize {
+        let watchers = self.inner.lock().unwrap().watchers.remove(key);
+        let woken = watchers
+            .into_iter()
+            .flatten()
+            .filter_map(|watcher| watcher.upgrade())
+            .inspect(|waiter| waiter.wake())
+            .count();
+        if woken > 0 {
+            trace!("Woke {} delayed {} on {:?}", woken, self.name, key);
+        }
+        woken
+    }
+
+    /// Expires requests as their deadlines pass, until `shutdown_token` is cancelled. Every
+    /// request still waiting then expires, as does any watched afterwards.
+    pub async fn run_reaper(&self, shutdown_token: CancellationToken) {
+        loop {
+            let next_expiration = self.inner.lock().unwrap().timers.next_expiration();
+            let next_deadline = async {
+                match next_expiration {
+                    Some(ms) => time::sleep_until(self.start + Duration::from_millis(ms)).await,
+                    None => std::future::pending().await,
+                }
+            };
+            select! {
+                _ = next_deadline => {}
+                _ = self.timer_added.notified() => {}
+                _ = shutdown_token.cancelled() => break,
+            }
+
+            let now_ms = self.clock_ms(Instant::now());
+            let expired = self.inner.lock().unwrap().timers.advance(now_ms);
+            for waiter in expired.iter().filter_map(Weak::upgrade) {
+                waiter.expire();
+            }
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.closed = true;
+        inner.watchers.clear();
+        let waiting = inner.timers.advance(u64::MAX);
+        let count = waiting
+            .iter()
+            .filter_map(Weak::upgrade)
+            .inspect(|waiter| waiter.expire())
+            .count();
+        debug!("Expired {} delayed {} at shutdown", count, self.name);
+    }
+
+    /// The reading of the timing wheel's clock at `instant`.
+    fn clock_ms(&self, instant: Instant) -> u64 {
+        instant.saturating_duration_since(self.start).as_millis() as u64
+    }
+}
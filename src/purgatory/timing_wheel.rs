@@ -0,0 +1,196 @@
+//! A hierarchical timing wheel, as used by Kafka's `SystemTimer`.
+//!
+//! The lowest wheel has `wheel_size` buckets of `tick_ms` each; every higher wheel has as many
+//! buckets, each spanning the whole of the wheel below. A timer goes to the lowest wheel whose
+//! span covers its expiration, so adding one is O(1) however far away it is. When a bucket of a
+//! higher wheel comes due, its timers are added again and fall into finer buckets, until they
+//! expire from the lowest wheel.
+//!
+//! Buckets, not timers, are ordered by expiration: a bucket is queued when it gets its first
+//! timer, and the clock only ever advances to the expiration of the next queued bucket.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::mem;
+
+/// Timers holding an item of type `T`, ordered by expiration in milliseconds.
+#[derive(Debug)]
+pub struct TimingWheel<T> {
+    wheel_size: usize,
+    /// The wheels, finest first. Higher wheels are added as later expirations need them.
+    wheels: Vec<Wheel<T>>,
+    /// The buckets holding timers as `(expiration, wheel, bucket)`. An entry is stale, and
+    /// skipped, once its bucket has been emptied or has moved on to a later expiration.
+    queue: BinaryHeap<Reverse<(u64, usize, usize)>>,
+}
+
+/// One level of the timing wheel.
+#[derive(Debug)]
+struct Wheel<T> {
+    tick_ms: u64,
+    /// The span of the whole wheel: `tick_ms * wheel_size`.
+    interval: u64,
+    /// The start of the current tick; always a multiple of `tick_ms`.
+    current_time: u64,
+    buckets: Vec<Bucket<T>>,
+}
+
+/// The timers expiring within one tick of a wheel.
+#[derive(Debug)]
+struct Bucket<T> {
+    /// The start of the tick the bucket currently stands for, if it holds any timers.
+    expiration: Option<u64>,
+    timers: Vec<(u64, T)>,
+}
+
+impl<T> TimingWheel<T> {
+    /// Creates an empty timing wheel whose clock starts at `start_ms`.
+    pub fn new(tick_ms: u64, wheel_size: usize, start_ms: u64) -> Self {
+        Self {
+            wheel_size,
+            wheels: vec![Wheel::new(tick_ms, wheel_size, start_ms)],
+            queue: BinaryHeap::new(),
+        }
+    }
+
+    /// Adds a timer for `item` expiring at `expiration_ms`. Returns the item back if that time
+    /// has already passed.
+    pub fn add(&mut self, expiration_ms: u64, item: T) -> Option<T> {
+        let mut level = 0;
+        loop {
+            let levels = self.wheels.len();
+            let wheel = &mut self.wheels[level];
+            if expiration_ms < wheel.current_time + wheel.tick_ms {
+                // Only possible in the lowest wheel: higher wheels only get timers beyond the
+                // span of the wheel below.
+                return Some(item);
+            }
+            if expiration_ms < wheel.current_time + wheel.interval {
+                let virtual_id = expiration_ms / wheel.tick_ms;
+                let index = (virtual_id % self.wheel_size as u64) as usize;
+                let bucket = &mut wheel.buckets[index];
+                bucket.timers.push((expiration_ms, item));
+                let bucket_expiration = virtual_id * wheel.tick_ms;
+                if bucket.expiration != Some(bucket_expiration) {
+                    bucket.expiration = Some(bucket_expiration);
+                    self.queue.push(Reverse((bucket_expiration, level, index)));
+                }
+                return None;
+            }
+            if level + 1 == levels {
+                let (tick_ms, current_time) = (wheel.interval, wheel.current_time);
+                self.wheels
+                    .push(Wheel::new(tick_ms, self.wheel_size, current_time));
+            }
+            level += 1;
+        }
+    }
+
+    /// When the next bucket comes due, if any timers are pending.
+    pub fn next_expiration(&self) -> Option<u64> {
+        self.queue
+            .peek()
+            .map(|&Reverse((expiration, _, _))| expiration)
+    }
+
+    /// Advances the clock to `now_ms` and returns the items of the timers that have expired.
+    /// Timers expire at the start of their tick of the lowest wheel.
+    pub fn advance(&mut self, now_ms: u64) -> Vec<T> {
+        let mut expired = Vec::new();
+        while let Some(&Reverse((expiration, level, index))) = self.queue.peek() {
+            if expiration > now_ms {
+                break;
+            }
+            self.queue.pop();
+            let bucket = &mut self.wheels[level].buckets[index];
+            if bucket.expiration != Some(expiration) {
+                continue;
+            }
+            bucket.expiration = None;
+            let timers = mem::take(&mut bucket.timers);
+
+            for wheel in &mut self.wheels {
+                if expiration >= wheel.current_time + wheel.tick_ms {
+                    wheel.current_time = expiration - expiration % wheel.tick_ms;
+                }
+            }
+            // Timers from a higher wheel move down to finer buckets; the rest have expired.
+            for (timer_expiration, item) in timers {
+                if let Some(item) = self.add(timer_expiration, item) {
+                    expired.push(item);
+                }
+            }
+        }
+        expired
+    }
+}
+
+impl<T> Wheel<T> {
+    fn new(tick_ms: u64, wheel_size: usize, start_ms: u64) -> Self {
+        Self {
+            tick_ms,
+            interval: tick_ms * wheel_size as u64,
+            current_time: start_ms - start_ms % tick_ms,
+            buckets: (0..wheel_size)
+                .map(|_| Bucket {
+                    expiration: None,
+                    timers: Vec::new(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::purgatory::{TICK_MS, WHEEL_SIZE};
+
+    #[test]
+    fn overflow_wheels_are_added_for_later_expirations() {
+        let mut timers = TimingWheel::new(TICK_MS, WHEEL_SIZE, 0);
+        assert_eq!(timers.add(19, "lowest"), None);
+        assert_eq!(timers.wheels.len(), 1);
+
+        assert_eq!(timers.add(20, "second"), None);
+        assert_eq!(timers.wheels.len(), 2);
+        assert_eq!(timers.wheels[1].tick_ms, 20);
+        assert_eq!(timers.wheels[1].interval, 400);
+
+        assert_eq!(timers.add(400, "third"), None);
+        assert_eq!(timers.wheels.len(), 3);
+        assert_eq!(timers.wheels[2].tick_ms, 400);
+        assert_eq!(timers.next_expiration(), Some(19));
+    }
+
+    #[test]
+    fn timers_move_down_to_lower_wheels_as_the_clock_advances() {
+        let mut timers = TimingWheel::new(TICK_MS, WHEEL_SIZE, 0);
+        assert_eq!(timers.add(25, "timer"), None);
+        // The second wheel's bucket covers 20 to 39.
+        assert_eq!(timers.next_expiration(), Some(20));
+        assert!(timers.advance(19).is_empty());
+
+        // When that bucket comes due the timer is re-added to the lowest wheel.
+        assert!(timers.advance(20).is_empty());
+        assert!(timers.wheels[1].buckets.iter().all(|b| b.timers.is_empty()));
+        assert_eq!(timers.wheels[0].buckets[25 % WHEEL_SIZE].timers.len(), 1);
+        assert_eq!(timers.next_expiration(), Some(25));
+
+        assert!(timers.advance(24).is_empty());
+        assert_eq!(timers.advance(25), vec!["timer"]);
+        assert_eq!(timers.next_expiration(), None);
+    }
+
+    #[test]
+    fn timers_at_the_current_time_expire_immediately() {
+        let mut timers = TimingWheel::new(TICK_MS, WHEEL_SIZE, 100);
+        assert_eq!(timers.add(100, "now"), Some("now"));
+        assert_eq!(timers.add(99, "past"), Some("past"));
+        assert_eq!(timers.add(101, "next tick"), None);
+
+        assert_eq!(timers.advance(101), vec!["next tick"]);
+        assert_eq!(timers.add(101, "now"), Some("now"));
+        assert_eq!(timers.next_expiration(), None);
+    }
+}
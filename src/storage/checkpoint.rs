@@ -0,0 +1,102 @@
+//! Offset checkpoint files: per-log-directory records of an offset for each partition.
+//!
+//! The format is Kafka's: a version line (`0`), a line with the number of entries, then one
+//! `<topic> <partition> <offset>` line per partition. Files are replaced atomically by writing
+//! a temporary file and renaming it over the old one.
+
+use crate::kafka_protocol::kafka_error::{KafkaBrokerError, KafkaResult};
+use crate::storage::TopicPartition;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// The only checkpoint file version.
+const CHECKPOINT_VERSION: i32 = 0;
+
+/// A checkpoint file mapping partitions to offsets.
+#[derive(Debug, Clone)]
+pub struct OffsetCheckpointFile {
+    path: PathBuf,
+}
+
+impl OffsetCheckpointFile {
+    /// The checkpoint file named `name` in `log_dir`.
+    pub fn new(log_dir: &Path, name: &str) -> Self {
+        Self {
+            path: log_dir.join(name),
+        }
+    }
+
+    /// Reads the checkpoint. A missing file reads as empty.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if the file can't be read, or
+    /// [`KafkaBrokerError::CorruptMessage`] if it is malformed.
+    pub fn read(&self) -> KafkaResult<HashMap<TopicPartition, i64>> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let malformed = || {
+            KafkaBrokerError::CorruptMessage(format!(
+                "Malformed checkpoint file {}",
+                self.path.display()
+            ))
+        };
+
+        let mut lines = contents.lines();
+        let version: i32 = lines
+            .next()
+            .and_then(|line| line.trim().parse().ok())
+            .ok_or_else(malformed)?;
+        if version != CHECKPOINT_VERSION {
+            return Err(malformed());
+        }
+        let count: usize = lines
+            .next()
+            .and_then(|line| line.trim().parse().ok())
+            .ok_or_else(malformed)?;
+
+        let mut offsets = HashMap::with_capacity(count);
+        for line in lines.take(count) {
+            let mut fields = line.split_whitespace();
+            let (Some(topic), Some(partition), Some(offset), None) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err(malformed());
+            };
+            let partition = partition.parse().map_err(|_| malformed())?;
+            let offset = offset.parse().map_err(|_| malformed())?;
+            offsets.insert(TopicPartition::new(topic, partition), offset);
+        }
+        if offsets.len() != count {
+            return Err(malformed());
+        }
+        Ok(offsets)
+    }
+
+    /// Replaces the checkpoint with `offsets`, syncing it to disk.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if the file can't be written.
+    pub fn write(&self, offsets: &HashMap<TopicPartition, i64>) -> KafkaResult<()> {
+        let mut contents = format!("{}\n{}\n", CHECKPOINT_VERSION, offsets.len());
+        for (topic_partition, offset) in offsets {
+            contents.push_str(&format!(
+                "{} {} {}\n",
+                topic_partition.topic, topic_partition.partition, offset
+            ));
+        }
+
+        let temp_path = self.path.with_extension("tmp");
+        let mut file = File::create(&temp_path)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+//! The sparse indexes kept next to each log segment.
+//!
+//! Both indexes are append-only files of fixed-size big-endian entries, with offsets stored
+//! relative to the segment's base offset as in Kafka:
+//!
+//! - the offset index (`.index`) maps an offset to the byte position of the batch holding it,
+//!   as `relativeOffset: int32, position: int32`;
+//! - the time index (`.timeindex`) maps a timestamp to the first offset at or after it, as
+//!   `timestamp: int64, relativeOffset: int32`.
+//!
+//! Entries are added every `index.interval.bytes` of appended data, so a lookup lands near the
+//! wanted batch and the segment is scanned from there. The entries are also kept in memory,
+//! sorted, so that lookups are binary searches.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// The size of an offset index entry.
+const OFFSET_INDEX_ENTRY_SIZE: usize = 8;
+
+/// The size of a time index entry.
+const TIME_INDEX_ENTRY_SIZE: usize = 12;
+
+/// Maps offsets to byte positions in a segment.
+#[derive(Debug)]
+pub struct OffsetIndex {
+    file: File,
+    base_offset: i64,
+    /// `(relative offset, position)` pairs in increasing order of both.
+    entries: Vec<(u32, u32)>,
+}
+
+impl OffsetIndex {
+    /// Opens the index at `path` for the segment starting at `base_offset`, creating an empty
+    /// one if it doesn't exist. A torn entry at the end of the file is ignored.
+    pub fn open(path: &Path, base_offset: i64) -> io::Result<Self> {
+        let (file, data) = open_append(path)?;
+        let entries = data
+            .chunks_exact(OFFSET_INDEX_ENTRY_SIZE)
+            .map(|entry| {
+                (
+                    u32::from_be_bytes(entry[0..4].try_into().unwrap()),
+                    u32::from_be_bytes(entry[4..8].try_into().unwrap()),
+                )
+            })
+            .collect();
+        Ok(Self {
+            file,
+            base_offset,
+            entries,
+        })
+    }
+
+    /// Records that the batch ending at `offset` starts at `position`.
+    pub fn append(&mut self, offset: i64, position: u64) -> io::Result<()> {
+        let relative_offset = (offset - self.base_offset) as u32;
+        let mut entry = [0u8; OFFSET_INDEX_ENTRY_SIZE];
+        entry[0..4].copy_from_slice(&relative_offset.to_be_bytes());
+        entry[4..8].copy_from_slice(&(position as u32).to_be_bytes());
+        self.file.write_all(&entry)?;
+        self.entries.push((relative_offset, position as u32));
+        Ok(())
+    }
+
+    /// Returns the position to start scanning from for `offset`: that of the last entry at or
+    /// before it, or the start of the segment.
+    pub fn lookup(&self, offset: i64) -> u64 {
+        let relative_offset = offset - self.base_offset;
+        let count = self
+            .entries
+            .partition_point(|&(entry_offset, _)| i64::from(entry_offset) <= relative_offset);
+        match count {
+            0 => 0,
+            _ => u64::from(self.entries[count - 1].1),
+        }
+    }
+
+    /// The position of the last entry, or 0 if the index is empty.
+    pub fn last_position(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(0, |&(_, position)| u64::from(position))
+    }
+
+    /// Removes every entry, so the index can be rebuilt.
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.entries.clear();
+        Ok(())
+    }
+
+    /// Syncs the index to disk.
+    pub fn flush(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+}
+
+/// Maps timestamps to offsets in a segment.
+#[derive(Debug)]
+pub struct TimeIndex {
+    file: File,
+    base_offset: i64,
+    /// `(timestamp, relative offset)` pairs in increasing order of both.
+    entries: Vec<(i64, u32)>,
+}
+
+impl TimeIndex {
+    /// Opens the index at `path` for the segment starting at `base_offset`, creating an empty
+    /// one if it doesn't exist. A torn entry at the end of the file is ignored.
+    pub fn open(path: &Path, base_offset: i64) -> io::Result<Self> {
+        let (file, data) = open_append(path)?;
+        let entries = data
+            .chunks_exact(TIME_INDEX_ENTRY_SIZE)
+            .map(|entry| {
+                (
+                    i64::from_be_bytes(entry[0..8].try_into().unwrap()),
+                    u32::from_be_bytes(entry[8..12].try_into().unwrap()),
+                )
+            })
+            .collect();
+        Ok(Self {
+            file,
+            base_offset,
+            entries,
+        })
+    }
+
+    /// Records that `offset` has timestamp `timestamp`, unless an entry with that timestamp or
+    /// a later one already exists.
+    pub fn maybe_append(&mut self, timestamp: i64, offset: i64) -> io::Result<()> {
+        if self.last_timestamp().is_some_and(|last| timestamp <= last) {
+            return Ok(());
+        }
+        let relative_offset = (offset - self.base_offset) as u32;
+        let mut entry = [0u8; TIME_INDEX_ENTRY_SIZE];
+        entry[0..8].copy_from_slice(&timestamp.to_be_bytes());
+        entry[8..12].copy_from_slice(&relative_offset.to_be_bytes());
+        self.file.write_all(&entry)?;
+        self.entries.push((timestamp, relative_offset));
+        Ok(())
+    }
+
+    /// Returns the offset to start scanning from for `timestamp`: that of the last entry
+    /// before it, or the start of the segment.
+    pub fn lookup(&self, timestamp: i64) -> i64 {
+        let count = self
+            .entries
+            .partition_point(|&(entry_timestamp, _)| entry_timestamp < timestamp);
+        match count {
+            0 => self.base_offset,
+            _ => self.base_offset + i64::from(self.entries[count - 1].1),
+        }
+    }
+
+    /// The timestamp of the last entry, if any.
+    pub fn last_timestamp(&self) -> Option<i64> {
+        self.entries.last().map(|&(timestamp, _)| timestamp)
+    }
+
+    /// Removes every entry, so the index can be rebuilt.
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.entries.clear();
+        Ok(())
+    }
+
+    /// Syncs the index to disk.
+    pub fn flush(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+}
+
+/// Opens `path` for appending, creating it if needed, and returns it with its contents.
+fn open_append(path: &Path) -> io::Result<(File, Vec<u8>)> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    Ok((file, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_index_lookup_finds_the_last_entry_before_the_timestamp() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut index = TimeIndex::open(&dir.path().join("0.timeindex"), 100).unwrap();
+        index.maybe_append(1000, 110).unwrap();
+        index.maybe_append(2000, 120).unwrap();
+        index.maybe_append(3000, 130).unwrap();
+        // Not newer than the last entry, so not added.
+        index.maybe_append(2500, 140).unwrap();
+
+        // Before the first entry: scan from the start of the segment.
+        assert_eq!(index.lookup(500), 100);
+        assert_eq!(index.lookup(1000), 100);
+        // Between entries.
+        assert_eq!(index.lookup(1001), 110);
+        assert_eq!(index.lookup(2500), 120);
+        // After the last entry.
+        assert_eq!(index.lookup(9000), 130);
+
+        // The entries survive reopening the index.
+        drop(index);
+        let index = TimeIndex::open(&dir.path().join("0.timeindex"), 100).unwrap();
+        assert_eq!(index.lookup(2500), 120);
+        assert_eq!(index.last_timestamp(), Some(3000));
+    }
+
+    #[test]
+    fn offset_index_lookup_finds_the_last_entry_at_or_before_the_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut index = OffsetIndex::open(&dir.path().join("0.index"), 100).unwrap();
+        index.append(110, 4096).unwrap();
+        index.append(120, 8192).unwrap();
+
+        assert_eq!(index.lookup(105), 0);
+        assert_eq!(index.lookup(110), 4096);
+        assert_eq!(index.lookup(119), 4096);
+        assert_eq!(index.lookup(500), 8192);
+    }
+}
@@ -0,0 +1,595 @@
+//! Log compaction: keeps only the latest record for each key in logs with
+//! `cleanup.policy=compact`.
+//!
+//! A log is split into a clean head, already compacted, and a dirty tail written since. Each
+//! cleaning pass works in two steps, as in Kafka:
+//!
+//! 1. The dirty part is scanned to build an offset map from every key to the offset of its
+//!    latest record. The scan also follows transactions: records of aborted transactions are
+//!    never added, and transactions whose marker hasn't been written yet are left alone.
+//! 2. Every cleanable segment, clean and dirty alike, is copied without the records the map
+//!    shows to be superseded, without aborted transactions, and without the tombstones and
+//!    transaction markers whose delete horizon has passed. A transaction marker is only
+//!    dropped once none of its transaction's records remain.
+//!
+//! Tombstones and markers are timed from when the cleaner first kept them, not from their
+//! timestamps, which the producer chose: the pass that first keeps one sets its batch's delete
+//! horizon to `delete.retention.ms` from then, and a later pass drops it once that has passed.
+//! Consumers that have read up to the dirty part of the log thus always get to see them.
+//!
+//! The active segment and segments with records newer than `min.compaction.lag.ms` are never
+//! cleaned.
+//!
+//! A pass is planned under the log's lock (see
+//! [`PartitionLog::plan_compaction`](crate::storage::PartitionLog::plan_compaction)), but both
+//! steps run without it, reading segments that are no longer appended to and writing the
+//! cleaned segments, indexes included, to a separate directory. The lock is only taken again
+//! to swap the cleaned segments in.
+
+use crate::kafka_protocol::kafka_error::KafkaResult;
+use crate::kafka_protocol::kafka_record_batch::{ControlRecordType, Record, RecordBatch};
+use crate::storage::log_segment::{segment_path, LogSegment, SegmentData, LOG_FILE_SUFFIX};
+use crate::storage::TopicPartition;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+/// What a cleaning pass did.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CleanerStats {
+    /// Bytes read from the cleaned segments.
+    pub bytes_read: u64,
+    /// Bytes written to the segments replacing them.
+    pub bytes_written: u64,
+    /// Records dropped: superseded values, expired tombstones and aborted records.
+    pub records_removed: u64,
+}
+
+/// A cleaning pass over the cleanable part of a log, from
+/// [`PartitionLog::plan_compaction`](crate::storage::PartitionLog::plan_compaction).
+#[derive(Debug)]
+pub struct Compaction {
+    /// The log being cleaned.
+    pub topic_partition: TopicPartition,
+    /// Where the cleaned segments are written.
+    pub cleaned_dir: PathBuf,
+    /// The offset where the dirty part of the log starts.
+    pub first_dirty_offset: i64,
+    /// The offset where the cleanable part of the log ends.
+    pub end_offset: i64,
+    /// When the pass was planned.
+    pub now_ms: i64,
+    /// How long tombstones and markers are kept once cleaned, from `delete.retention.ms`.
+    pub delete_retention_ms: i64,
+    /// How many bytes are written between index entries of the cleaned segments.
+    pub index_interval_bytes: usize,
+    /// The segments to clean, with their base offsets, in groups that are each replaced by a
+    /// single cleaned segment starting at the group's first base offset.
+    pub groups: Vec<Vec<(i64, SegmentData)>>,
+}
+
+impl Compaction {
+    /// Builds the offset map, then writes the cleaned segment of each group, with its indexes,
+    /// to `cleaned_dir`. Anything written is removed again if cleaning fails.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a batch can't be read, decoded or recompressed, or a cleaned
+    /// segment can't be written.
+    pub fn clean(&self) -> KafkaResult<CleanerStats> {
+        match fs::remove_dir_all(&self.cleaned_dir) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        fs::create_dir(&self.cleaned_dir)?;
+        let result = self.write_cleaned_segments();
+        if result.is_err() {
+            let _ = fs::remove_dir_all(&self.cleaned_dir);
+        }
+        result
+    }
+
+    fn write_cleaned_segments(&self) -> KafkaResult<CleanerStats> {
+        let mut cleaner = Cleaner::new(
+            self.groups.iter().flatten().map(|(_, segment)| segment),
+            self.first_dirty_offset,
+            self.now_ms,
+            self.delete_retention_ms,
+        )?;
+        for group in &self.groups {
+            let base_offset = group[0].0;
+            let path = segment_path(&self.cleaned_dir, base_offset, LOG_FILE_SUFFIX);
+            let mut out = BufWriter::new(File::create(path)?);
+            for (_, segment) in group {
+                cleaner.clean_segment(segment, &mut out)?;
+            }
+            out.flush()?;
+            drop(out);
+
+            // Index the cleaned segment here, so that swapping it in is just a few renames.
+            let mut segment = LogSegment::open(&self.cleaned_dir, base_offset)?;
+            segment.recover(self.index_interval_bytes)?;
+            segment.flush()?;
+        }
+        Ok(cleaner.stats())
+    }
+}
+
+/// A producer's transaction whose marker the cleaner hasn't reached yet.
+#[derive(Debug, Default)]
+struct OpenTransaction {
+    /// The base offsets of the transaction's batches.
+    batches: Vec<i64>,
+    /// The keys of the transaction's records, with their offsets.
+    keys: Vec<(Vec<u8>, i64)>,
+}
+
+/// Copies segments of a log without their obsolete records.
+#[derive(Debug)]
+pub struct Cleaner {
+    /// The offset of the latest record for each key in the dirty part of the log.
+    offset_map: HashMap<Vec<u8>, i64>,
+    /// The base offsets of batches belonging to aborted transactions.
+    aborted_batches: HashSet<i64>,
+    /// The base offsets of batches belonging to transactions with no marker yet.
+    ongoing_batches: HashSet<i64>,
+    /// For each producer with a transaction in progress during cleaning, whether any of its
+    /// records were kept.
+    transactions_with_data: HashMap<i64, bool>,
+    /// When the pass was planned.
+    now_ms: i64,
+    /// How long tombstones and markers are kept once cleaned.
+    delete_retention_ms: i64,
+    stats: CleanerStats,
+}
+
+impl Cleaner {
+    /// Prepares to clean `segments`, the cleanable segments of a log in offset order, whose
+    /// dirty part starts at `first_dirty_offset`. Tombstones and markers kept for the first
+    /// time get a delete horizon `delete_retention_ms` after `now_ms`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a batch in the segments can't be read or decoded.
+    pub fn new<'a>(
+        segments: impl Iterator<Item = &'a SegmentData>,
+        first_dirty_offset: i64,
+        now_ms: i64,
+        delete_retention_ms: i64,
+    ) -> KafkaResult<Self> {
+        let mut offset_map: HashMap<Vec<u8>, i64> = HashMap::new();
+        let mut aborted_batches = HashSet::new();
+        let mut open_transactions: HashMap<i64, OpenTransaction> = HashMap::new();
+
+        let mut record_latest = |key: Vec<u8>, offset: i64| {
+            if offset >= first_dirty_offset {
+                let latest = offset_map.entry(key).or_insert(offset);
+                *latest = (*latest).max(offset);
+            }
+        };
+
+        for segment in segments {
+            for batch in segment.batches() {
+                let batch = batch?;
+                if batch.is_control_batch() {
+                    let Some(transaction) = open_transactions.remove(&batch.producer_id) else {
+                        continue;
+                    };
+                    match control_type(&batch)? {
+                        Some(ControlRecordType::Abort) => {
+                            aborted_batches.extend(transaction.batches)
+                        }
+                        _ => {
+                            for (key, offset) in transaction.keys {
+                                record_latest(key, offset);
+                            }
+                        }
+                    }
+                    continue;
+                }
+
+                let records = batch.records()?;
+                if batch.is_transactional() {
+                    let transaction = open_transactions.entry(batch.producer_id).or_default();
+                    transaction.batches.push(batch.base_offset);
+                    transaction
+                        .keys
+                        .extend(records.into_iter().filter_map(|record| {
+                            let offset = batch.base_offset + record.offset_delta as i64;
+                            record.key.map(|key| (key, offset))
+                        }));
+                    continue;
+                }
+                for record in records {
+                    if let Some(key) = record.key {
+                        record_latest(key, batch.base_offset + record.offset_delta as i64);
+                    }
+                }
+            }
+        }
+
+        let ongoing_batches = open_transactions
+            .into_values()
+            .flat_map(|transaction| transaction.batches)
+            .collect();
+        Ok(Self {
+            offset_map,
+            aborted_batches,
+            ongoing_batches,
+            transactions_with_data: HashMap::new(),
+            now_ms,
+            delete_retention_ms,
+            stats: CleanerStats::default(),
+        })
+    }
+
+    /// What the cleaner has done so far.
+    pub fn stats(&self) -> CleanerStats {
+        self.stats
+    }
+
+    /// Writes the batches of `segment` to `out`, without their obsolete records. Segments must
+    /// be cleaned in offset order.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a batch can't be read, decoded or recompressed, or `out` can't be
+    /// written to.
+    pub fn clean_segment(
+        &mut self,
+        segment: &SegmentData,
+        out: &mut impl Write,
+    ) -> KafkaResult<()> {
+        let mut buf = Vec::new();
+        for batch in segment.batches() {
+            let batch = batch?;
+            self.stats.bytes_read += batch.size_in_bytes() as u64;
+            if let Some(cleaned) = self.clean_batch(batch)? {
+                buf.clear();
+                cleaned.write(&mut buf);
+                out.write_all(&buf)?;
+                self.stats.bytes_written += buf.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns what remains of `batch` after cleaning, or `None` if nothing does.
+    fn clean_batch(&mut self, batch: RecordBatch) -> KafkaResult<Option<RecordBatch>> {
+        let expired = batch
+            .delete_horizon_ms()
+            .is_some_and(|delete_horizon_ms| delete_horizon_ms <= self.now_ms);
+
+        if batch.is_control_batch() {
+            // Keep a marker while its transaction has records left, or until it expires.
+            let has_data = self
+                .transactions_with_data
+                .remove(&batch.producer_id)
+                .unwrap_or(false);
+            if has_data || (batch.delete_horizon_ms().is_some() && !expired) {
+                return Ok(Some(batch));
+            }
+            if expired {
+                self.stats.records_removed += batch.records_count.max(0) as u64;
+                return Ok(None);
+            }
+            let mut records = batch.records()?;
+            return self.with_delete_horizon(batch, &mut records).map(Some);
+        }
+        if self.aborted_batches.contains(&batch.base_offset) {
+            self.stats.records_removed += batch.records_count.max(0) as u64;
+            return Ok(None);
+        }
+        if self.ongoing_batches.contains(&batch.base_offset) {
+            self.transactions_with_data.insert(batch.producer_id, true);
+            return Ok(Some(batch));
+        }
+
+        let records = batch.records()?;
+        let count = records.len();
+        let mut retained: Vec<_> = records
+            .into_iter()
+            .filter(|record| {
+                let Some(key) = &record.key else {
+                    // Compacted logs reject records without a key; any left over are dropped.
+                    return false;
+                };
+                let offset = batch.base_offset + record.offset_delta as i64;
+                let latest = self
+                    .offset_map
+                    .get(key)
+                    .is_none_or(|&latest| offset >= latest);
+                latest && !(record.value.is_none() && expired)
+            })
+            .collect();
+        self.stats.records_removed += (count - retained.len()) as u64;
+
+        if batch.is_transactional() {
+            let has_data = self
+                .transactions_with_data
+                .entry(batch.producer_id)
+                .or_insert(false);
+            *has_data |= !retained.is_empty();
+        }
+        if retained.is_empty() {
+            return Ok(None);
+        }
+        let has_tombstones = retained.iter().any(|record| record.value.is_none());
+        if batch.delete_horizon_ms().is_none() && has_tombstones {
+            return self.with_delete_horizon(batch, &mut retained).map(Some);
+        }
+        if retained.len() == count {
+            return Ok(Some(batch));
+        }
+        with_records(batch, &retained).map(Some)
+    }
+
+    /// Returns `batch` holding `records`, with a delete horizon `delete_retention_ms` from now.
+    fn with_delete_horizon(
+        &self,
+        mut batch: RecordBatch,
+        records: &mut [Record],
+    ) -> KafkaResult<RecordBatch> {
+        let delete_horizon_ms = self.now_ms.saturating_add(self.delete_retention_ms);
+        batch.set_delete_horizon(delete_horizon_ms, records);
+        with_records(batch, records)
+    }
+}
+
+/// Returns `batch` holding `records`, a subset of its own records.
+fn with_records(mut batch: RecordBatch, records: &[Record]) -> KafkaResult<RecordBatch> {
+    // The batch keeps its offset range, so that producer sequence numbers still line up.
+    let last_offset_delta = batch.last_offset_delta;
+    let compression = batch.compression()?;
+    batch.set_records(records, compression)?;
+    batch.last_offset_delta = last_offset_delta;
+    Ok(batch)
+}
+
+/// The type of the marker in control batch `batch`.
+fn control_type(batch: &RecordBatch) -> KafkaResult<Option<ControlRecordType>> {
+    Ok(batch
+        .records()?
+        .first()
+        .and_then(|record| record.key.as_deref())
+        .and_then(ControlRecordType::parse))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::batch;
+    use crate::storage::{CleanupPolicy, LogConfig, PartitionLog};
+    use std::path::Path;
+    use uuid::Uuid;
+
+    /// The attribute bit of transactional batches.
+    const TRANSACTIONAL: i16 = 0x10;
+    /// The attribute bits of transaction markers.
+    const CONTROL: i16 = 0x30;
+
+    /// How long tombstones and markers survive compaction in [`compacted_log`].
+    const DELETE_RETENTION_MS: i64 = 1000;
+
+    /// A compacted log in `dir` that is due for cleaning as soon as it has a dirty segment, and
+    /// rolls a segment for every batch.
+    fn compacted_log(dir: &Path) -> PartitionLog {
+        let config = LogConfig {
+            segment_bytes: 1,
+            cleanup_policy: CleanupPolicy {
+                delete: false,
+                compact: true,
+            },
+            delete_retention_ms: DELETE_RETENTION_MS,
+            min_cleanable_dirty_ratio: 0.0,
+            ..LogConfig::for_tests()
+        };
+        PartitionLog::create(dir, TopicPartition::new("t", 0), Uuid::new_v4(), config).unwrap()
+    }
+
+    /// Runs a cleaning pass over `log` at `now_ms`, as the log manager does.
+    fn compact(log: &mut PartitionLog, now_ms: i64) {
+        let compaction = log.plan_compaction(now_ms).unwrap();
+        let stats = compaction.clean().unwrap();
+        log.finish_compaction(compaction, stats).unwrap();
+    }
+
+    /// All batches left in `log`.
+    fn batches(log: &PartitionLog) -> Vec<RecordBatch> {
+        let mut batches = Vec::new();
+        let mut offset = log.log_start_offset();
+        while offset < log.log_end_offset() {
+            let data = log.read(offset, usize::MAX, true).unwrap();
+            let data = data.into_bytes().unwrap();
+            if data.is_empty() {
+                break;
+            }
+            let mut cursor = data.as_slice();
+            while !cursor.is_empty() {
+                let batch = RecordBatch::read(&mut cursor).unwrap();
+                offset = batch.last_offset() + 1;
+                batches.push(batch);
+            }
+        }
+        batches
+    }
+
+    /// The offset, key and value of every record left in `log`, except for markers.
+    fn records(log: &PartitionLog) -> Vec<(i64, String, Option<String>)> {
+        let text = |bytes: Vec<u8>| String::from_utf8_lossy(&bytes).into_owned();
+        batches(log)
+            .into_iter()
+            .filter(|batch| !batch.is_control_batch())
+            .flat_map(|batch| {
+                let base_offset = batch.base_offset;
+                batch.records().unwrap().into_iter().map(move |record| {
+                    (
+                        base_offset + record.offset_delta as i64,
+                        text(record.key.unwrap_or_default()),
+                        record.value.map(text),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// The offsets of the transaction markers left in `log`.
+    fn markers(log: &PartitionLog) -> Vec<i64> {
+        batches(log)
+            .into_iter()
+            .filter(|batch| batch.is_control_batch())
+            .map(|batch| batch.base_offset)
+            .collect()
+    }
+
+    /// `data` as written by `producer_id`, with `attributes` set.
+    fn from_producer(data: Vec<u8>, producer_id: i64, attributes: i16) -> Vec<u8> {
+        let mut batch = RecordBatch::read(&mut data.as_slice()).unwrap();
+        batch.attributes |= attributes;
+        batch.producer_id = producer_id;
+        batch.producer_epoch = 0;
+        batch.base_sequence = 0;
+        let mut data = Vec::new();
+        batch.write(&mut data);
+        data
+    }
+
+    /// A batch of the transaction of `producer_id` holding `records`.
+    fn transaction_batch(producer_id: i64, records: &[(&str, Option<&str>)]) -> Vec<u8> {
+        from_producer(batch(0, records), producer_id, TRANSACTIONAL)
+    }
+
+    /// The marker ending the transaction of `producer_id`, aborting or committing it.
+    fn marker(producer_id: i64, commit: bool) -> Vec<u8> {
+        // The key is the marker's version and type; the value its version and coordinator
+        // epoch.
+        let record = Record {
+            attributes: 0,
+            timestamp_delta: 0,
+            offset_delta: 0,
+            key: Some(vec![0, 0, 0, commit as u8]),
+            value: Some(vec![0; 6]),
+            headers: Vec::new(),
+        };
+        let mut data = Vec::new();
+        RecordBatch::new(0, 0, &[record]).write(&mut data);
+        from_producer(data, producer_id, CONTROL)
+    }
+
+    fn append(log: &mut PartitionLog, data: &[u8]) {
+        log.append(data, 0, 0).unwrap();
+    }
+
+    #[test]
+    fn superseded_records_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = compacted_log(dir.path());
+        append(&mut log, &batch(0, &[("a", Some("1")), ("b", Some("1"))]));
+        append(&mut log, &batch(0, &[("a", Some("2"))]));
+        append(&mut log, &batch(0, &[("c", Some("1")), ("a", Some("3"))]));
+        // The active segment is never cleaned.
+        append(&mut log, &batch(0, &[("a", Some("4"))]));
+
+        compact(&mut log, 10_000);
+
+        assert_eq!(
+            records(&log),
+            [
+                (1, "b".to_string(), Some("1".to_string())),
+                (3, "c".to_string(), Some("1".to_string())),
+                (4, "a".to_string(), Some("3".to_string())),
+                (5, "a".to_string(), Some("4".to_string())),
+            ]
+        );
+        assert_eq!(log.cleaner_offset(), 5);
+    }
+
+    #[test]
+    fn tombstones_are_kept_for_delete_retention_after_they_are_first_cleaned() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = compacted_log(dir.path());
+        append(&mut log, &batch(0, &[("a", Some("1"))]));
+        // Written long before the pass, yet kept by it.
+        append(&mut log, &batch(0, &[("a", None)]));
+        append(&mut log, &batch(0, &[("b", Some("1"))]));
+
+        compact(&mut log, 10_000);
+        assert_eq!(
+            records(&log),
+            [
+                (1, "a".to_string(), None),
+                (2, "b".to_string(), Some("1".to_string())),
+            ]
+        );
+        assert_eq!(
+            batches(&log)[0].delete_horizon_ms(),
+            Some(10_000 + DELETE_RETENTION_MS)
+        );
+
+        // Before the delete horizon, the tombstone survives further passes.
+        append(&mut log, &batch(0, &[("c", Some("1"))]));
+        compact(&mut log, 10_000 + DELETE_RETENTION_MS - 1);
+        assert_eq!(records(&log)[0], (1, "a".to_string(), None));
+
+        append(&mut log, &batch(0, &[("d", Some("1"))]));
+        compact(&mut log, 10_000 + DELETE_RETENTION_MS);
+        assert_eq!(
+            records(&log),
+            [
+                (2, "b".to_string(), Some("1".to_string())),
+                (3, "c".to_string(), Some("1".to_string())),
+                (4, "d".to_string(), Some("1".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn aborted_transactions_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = compacted_log(dir.path());
+        append(&mut log, &transaction_batch(1, &[("a", Some("1"))]));
+        append(&mut log, &marker(1, false));
+        append(&mut log, &transaction_batch(2, &[("b", Some("1"))]));
+        append(&mut log, &marker(2, true));
+        append(&mut log, &batch(0, &[("c", Some("1"))]));
+
+        // The abort marker stays until its delete horizon, the commit marker as long as its
+        // transaction has records.
+        compact(&mut log, 10_000);
+        assert_eq!(
+            records(&log),
+            [
+                (2, "b".to_string(), Some("1".to_string())),
+                (4, "c".to_string(), Some("1".to_string())),
+            ]
+        );
+        assert_eq!(markers(&log), [1, 3]);
+
+        append(&mut log, &batch(0, &[("d", Some("1"))]));
+        compact(&mut log, 10_000 + DELETE_RETENTION_MS);
+        assert_eq!(markers(&log), [3]);
+    }
+
+    #[test]
+    fn ongoing_transactions_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = compacted_log(dir.path());
+        append(&mut log, &batch(0, &[("a", Some("1"))]));
+        // The transaction may still abort, so neither its records nor the ones they would
+        // supersede can go.
+        append(&mut log, &transaction_batch(1, &[("a", Some("2"))]));
+        append(&mut log, &batch(0, &[("b", Some("1"))]));
+
+        compact(&mut log, 10_000);
+
+        assert_eq!(
+            records(&log),
+            [
+                (0, "a".to_string(), Some("1".to_string())),
+                (1, "a".to_string(), Some("2".to_string())),
+                (2, "b".to_string(), Some("1".to_string())),
+            ]
+        );
+    }
+}
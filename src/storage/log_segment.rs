@@ -0,0 +1,548 @@
+//! A single segment of a partition log: a `.log` file of record batches and its two indexes.
+//!
+//! Segments are named after their base offset, the offset of their first record, zero-padded
+//! to 20 digits so that they sort by name (`00000000000000000000.log`). Only the last segment
+//! of a log, the active one, is appended to.
+
+use crate::kafka_protocol::kafka_error::{KafkaBrokerError, KafkaResult};
+use crate::kafka_protocol::kafka_record_batch::{
+    RecordBatch, RecordBatchHeader, RECORD_BATCH_OVERHEAD,
+};
+use crate::kafka_protocol::kafka_records::{read_exact_at, FileRegion};
+use crate::storage::index::{OffsetIndex, TimeIndex};
+use crate::storage::LogConfig;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::warn;
+
+/// The extension of segment data files.
+pub const LOG_FILE_SUFFIX: &str = "log";
+/// The extension of offset index files.
+const INDEX_FILE_SUFFIX: &str = "index";
+/// The extension of time index files.
+const TIME_INDEX_FILE_SUFFIX: &str = "timeindex";
+/// The extension of a cleaned segment about to replace the segments it was cleaned from.
+pub const SWAP_FILE_SUFFIX: &str = "log.swap";
+
+/// A segment of a partition log.
+#[derive(Debug)]
+pub struct LogSegment {
+    dir: PathBuf,
+    base_offset: i64,
+    /// The `.log` file, shared with the Fetch responses sending parts of it.
+    log: Arc<File>,
+    offset_index: OffsetIndex,
+    time_index: TimeIndex,
+    /// The size of the `.log` file.
+    size: u64,
+    /// The offset after the last batch in the segment.
+    next_offset: i64,
+    /// The largest batch timestamp in the segment, or -1 if it is empty.
+    max_timestamp: i64,
+    /// The last offset of the batch holding `max_timestamp`.
+    offset_of_max_timestamp: i64,
+    /// Bytes appended since the last index entry.
+    bytes_since_last_index_entry: usize,
+    /// The time `segment.ms` counts from: the first batch's timestamp, or the time of the first
+    /// append if it had none.
+    rolling_base_timestamp: Option<i64>,
+}
+
+impl LogSegment {
+    /// Opens the segment starting at `base_offset` in `dir`, creating empty files if needed.
+    ///
+    /// The indexes are trusted as they are; only the part of the segment after the last index
+    /// entry is scanned, to find where the segment ends. A damaged batch ends the scan; it is
+    /// left for [`LogSegment::recover`] to truncate.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if a file can't be opened or read.
+    pub fn open(dir: &Path, base_offset: i64) -> KafkaResult<Self> {
+        let log = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(segment_path(dir, base_offset, LOG_FILE_SUFFIX))?;
+        let offset_index = OffsetIndex::open(
+            &segment_path(dir, base_offset, INDEX_FILE_SUFFIX),
+            base_offset,
+        )?;
+        let time_index = TimeIndex::open(
+            &segment_path(dir, base_offset, TIME_INDEX_FILE_SUFFIX),
+            base_offset,
+        )?;
+        let size = log.metadata()?.len();
+
+        let mut segment = Self {
+            dir: dir.to_path_buf(),
+            base_offset,
+            log: Arc::new(log),
+            offset_index,
+            time_index,
+            size,
+            next_offset: base_offset,
+            max_timestamp: -1,
+            offset_of_max_timestamp: base_offset,
+            bytes_since_last_index_entry: 0,
+            rolling_base_timestamp: None,
+        };
+        if let Some(timestamp) = segment.time_index.last_timestamp() {
+            segment.max_timestamp = timestamp;
+        }
+        if let Ok(Some(first)) = segment.read_header(0) {
+            segment.rolling_base_timestamp = Some(first.max_timestamp);
+        }
+
+        let mut position = segment.offset_index.last_position();
+        while let Ok(Some(header)) = segment.read_header(position) {
+            segment.next_offset = header.last_offset() + 1;
+            if header.max_timestamp > segment.max_timestamp {
+                segment.max_timestamp = header.max_timestamp;
+                segment.offset_of_max_timestamp = header.last_offset();
+            }
+            position += header.batch_size as u64;
+        }
+        Ok(segment)
+    }
+
+    /// The offset after the last batch in the segment.
+    pub fn next_offset(&self) -> i64 {
+        self.next_offset
+    }
+
+    /// The size of the segment's data in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The largest batch timestamp in the segment, or -1 if it is empty.
+    pub fn max_timestamp(&self) -> i64 {
+        self.max_timestamp
+    }
+
+    /// The timestamp of the first batch in the segment (or the time it was appended, if it
+    /// had none), or `None` if the segment is empty.
+    pub fn first_timestamp(&self) -> Option<i64> {
+        self.rolling_base_timestamp
+    }
+
+    /// Iterates over the batches of the segment, validating each one.
+    pub fn batches(&self) -> SegmentBatches<'_> {
+        SegmentBatches {
+            log: &self.log,
+            size: self.size,
+            position: 0,
+        }
+    }
+
+    /// The segment's data as it is now, to be read without the log's lock. Batches appended
+    /// afterwards aren't part of it.
+    pub fn data(&self) -> SegmentData {
+        SegmentData {
+            log: self.log.clone(),
+            size: self.size,
+        }
+    }
+
+    /// Whether a batch of `batch_size` bytes, appended at `now_ms`, should go to a new segment
+    /// instead of this one.
+    pub fn should_roll(
+        &self,
+        batch_size: usize,
+        last_offset: i64,
+        now_ms: i64,
+        config: &LogConfig,
+    ) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        let full = self.size + batch_size as u64 > config.segment_bytes;
+        // Compacted logs also roll in time for the active segment to be compacted within
+        // `max.compaction.lag.ms`.
+        let roll_ms = match config.cleanup_policy.compact {
+            true => config.segment_ms.min(config.max_compaction_lag_ms),
+            false => config.segment_ms,
+        };
+        let expired = self
+            .rolling_base_timestamp
+            .is_some_and(|timestamp| now_ms - timestamp > roll_ms);
+        // Offsets in the indexes are relative to the base offset and must fit in an int32.
+        let offset_overflow = last_offset - self.base_offset > i32::MAX as i64;
+        full || expired || offset_overflow
+    }
+
+    /// Appends the encoded batch `data` to the segment, adding index entries every
+    /// `index_interval_bytes`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if writing fails.
+    pub fn append(
+        &mut self,
+        data: &[u8],
+        batch: &RecordBatch,
+        now_ms: i64,
+        index_interval_bytes: usize,
+    ) -> KafkaResult<()> {
+        let position = self.size;
+        (&*self.log).write_all(data)?;
+        self.size += data.len() as u64;
+        self.next_offset = batch.last_offset() + 1;
+        if self.rolling_base_timestamp.is_none() {
+            self.rolling_base_timestamp = Some(match batch.max_timestamp {
+                -1 => now_ms,
+                timestamp => timestamp,
+            });
+        }
+        self.track_batch(batch, position, data.len(), index_interval_bytes)
+    }
+
+    /// Rebuilds the segment's indexes by rescanning it, and truncates it at the first batch
+    /// that is incomplete, fails its CRC check or doesn't follow the previous batch's offsets.
+    /// Returns the number of bytes truncated.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if the segment can't be read, truncated or reindexed.
+    pub fn recover(&mut self, index_interval_bytes: usize) -> KafkaResult<u64> {
+        self.offset_index.clear()?;
+        self.time_index.clear()?;
+        self.next_offset = self.base_offset;
+        self.max_timestamp = -1;
+        self.offset_of_max_timestamp = self.base_offset;
+        self.bytes_since_last_index_entry = 0;
+        self.rolling_base_timestamp = None;
+
+        let mut position = 0;
+        loop {
+            let batch = match self.read_batch(position) {
+                Ok(Some(batch)) => batch,
+                Ok(None) => break,
+                Err(KafkaBrokerError::Io(e)) => return Err(e.into()),
+                Err(e) => {
+                    warn!(
+                        "Found a damaged batch at position {} of segment {} in {}: {}",
+                        position,
+                        self.base_offset,
+                        self.dir.display(),
+                        e
+                    );
+                    break;
+                }
+            };
+            if batch.base_offset < self.next_offset {
+                warn!(
+                    "Found batch with offset {} after offset {} at position {} of segment {} in {}",
+                    batch.base_offset,
+                    self.next_offset - 1,
+                    position,
+                    self.base_offset,
+                    self.dir.display()
+                );
+                break;
+            }
+            let size = batch.size_in_bytes();
+            self.next_offset = batch.last_offset() + 1;
+            self.rolling_base_timestamp
+                .get_or_insert(batch.max_timestamp);
+            self.track_batch(&batch, position, size, index_interval_bytes)?;
+            position += size as u64;
+        }
+        if position > 0 {
+            self.time_index
+                .maybe_append(self.max_timestamp, self.offset_of_max_timestamp)?;
+        }
+
+        let truncated = self.size - position;
+        if truncated > 0 {
+            self.log.set_len(position)?;
+            self.size = position;
+        }
+        Ok(truncated)
+    }
+
+    /// Syncs the segment and its indexes to disk.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if syncing fails.
+    pub fn flush(&self) -> KafkaResult<()> {
+        self.log.sync_all()?;
+        self.offset_index.flush()?;
+        self.time_index.flush()?;
+        Ok(())
+    }
+
+    /// Deletes the segment's files.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if a file can't be removed.
+    pub fn delete(self) -> KafkaResult<()> {
+        for suffix in [LOG_FILE_SUFFIX, INDEX_FILE_SUFFIX, TIME_INDEX_FILE_SUFFIX] {
+            match fs::remove_file(segment_path(&self.dir, self.base_offset, suffix)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Called when a new segment is rolled after this one: records the final max timestamp,
+    /// so that timestamp lookups don't need to scan the segment's tail.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if writing the index fails.
+    pub fn on_roll(&mut self) -> KafkaResult<()> {
+        self.time_index
+            .maybe_append(self.max_timestamp, self.offset_of_max_timestamp)?;
+        Ok(())
+    }
+
+    /// Finds whole batches starting with the one containing `offset`, stopping before the batch
+    /// that would take the result past `max_bytes` (unless `min_one_batch` is set and it is the
+    /// first). Returns the region of the segment file holding them, or `None` if no batch in the
+    /// segment reaches `offset`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if reading fails, or
+    /// [`KafkaBrokerError::CorruptMessage`] if a batch header is damaged.
+    pub fn read(
+        &self,
+        offset: i64,
+        max_bytes: usize,
+        min_one_batch: bool,
+    ) -> KafkaResult<Option<FileRegion>> {
+        let Some(start) = self.find_position(offset)? else {
+            return Ok(None);
+        };
+
+        let mut end = start;
+        while let Some(header) = self.read_header(end)? {
+            let fits = end - start + header.batch_size as u64 <= max_bytes as u64;
+            let oversized_first = min_one_batch && end == start;
+            if !fits && !oversized_first {
+                break;
+            }
+            end += header.batch_size as u64;
+        }
+
+        Ok(Some(FileRegion::new(
+            self.log.clone(),
+            start,
+            (end - start) as usize,
+        )))
+    }
+
+    /// Returns the offset of the first record with a timestamp at or after `timestamp`, if the
+    /// segment holds one.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if reading fails, or a decoding error if the batch
+    /// holding the record is damaged.
+    pub fn find_offset_by_timestamp(&self, timestamp: i64) -> KafkaResult<Option<i64>> {
+        if self.max_timestamp < timestamp {
+            return Ok(None);
+        }
+        let start_offset = self.time_index.lookup(timestamp);
+        let Some(mut position) = self.find_position(start_offset)? else {
+            return Ok(None);
+        };
+
+        while let Some(header) = self.read_header(position)? {
+            if header.max_timestamp >= timestamp {
+                let Some(batch) = self.read_batch(position)? else {
+                    return Ok(None);
+                };
+                if batch.is_log_append_time() {
+                    return Ok(Some(batch.base_offset));
+                }
+                let offset = batch
+                    .records()?
+                    .iter()
+                    .find(|record| batch.base_timestamp + record.timestamp_delta >= timestamp)
+                    .map(|record| batch.base_offset + record.offset_delta as i64);
+                return Ok(offset);
+            }
+            position += header.batch_size as u64;
+        }
+        Ok(None)
+    }
+
+    /// Updates the segment's max timestamp with the batch at `position`, and indexes it if
+    /// `index_interval_bytes` were added since the last index entry.
+    fn track_batch(
+        &mut self,
+        batch: &RecordBatch,
+        position: u64,
+        size: usize,
+        index_interval_bytes: usize,
+    ) -> KafkaResult<()> {
+        if batch.max_timestamp > self.max_timestamp {
+            self.max_timestamp = batch.max_timestamp;
+            self.offset_of_max_timestamp = batch.last_offset();
+        }
+
+        if self.bytes_since_last_index_entry > index_interval_bytes {
+            self.offset_index.append(batch.last_offset(), position)?;
+            self.time_index
+                .maybe_append(self.max_timestamp, self.offset_of_max_timestamp)?;
+            self.bytes_since_last_index_entry = 0;
+        }
+        self.bytes_since_last_index_entry += size;
+        Ok(())
+    }
+
+    /// Reads and validates the whole batch at `position`. Returns `None` at the end of the
+    /// segment, including when the last batch is incomplete.
+    fn read_batch(&self, position: u64) -> KafkaResult<Option<RecordBatch>> {
+        read_batch(&self.log, self.size, position)
+    }
+
+    /// Returns the position of the first batch whose last offset is at or after `offset`.
+    fn find_position(&self, offset: i64) -> KafkaResult<Option<u64>> {
+        let mut position = self.offset_index.lookup(offset);
+        while let Some(header) = self.read_header(position)? {
+            if header.last_offset() >= offset {
+                return Ok(Some(position));
+            }
+            position += header.batch_size as u64;
+        }
+        Ok(None)
+    }
+
+    /// Reads the header of the batch at `position`. Returns `None` at the end of the segment,
+    /// including when the last batch is incomplete.
+    fn read_header(&self, position: u64) -> KafkaResult<Option<RecordBatchHeader>> {
+        read_header(&self.log, self.size, position)
+    }
+}
+
+/// The first `size` bytes of a segment's `.log` file, from [`LogSegment::data`]. Appends only
+/// ever go past them, so they can be read while the segment is in use.
+#[derive(Debug, Clone)]
+pub struct SegmentData {
+    log: Arc<File>,
+    size: u64,
+}
+
+impl SegmentData {
+    /// Iterates over the batches, validating each one.
+    pub fn batches(&self) -> SegmentBatches<'_> {
+        SegmentBatches {
+            log: &self.log,
+            size: self.size,
+            position: 0,
+        }
+    }
+}
+
+/// An iterator over the batches of a segment, from [`LogSegment::batches`] or
+/// [`SegmentData::batches`].
+pub struct SegmentBatches<'a> {
+    log: &'a File,
+    size: u64,
+    position: u64,
+}
+
+impl Iterator for SegmentBatches<'_> {
+    type Item = KafkaResult<RecordBatch>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match read_batch(self.log, self.size, self.position) {
+            Ok(Some(batch)) => {
+                self.position += batch.size_in_bytes() as u64;
+                Some(Ok(batch))
+            }
+            Ok(None) => None,
+            Err(e) => {
+                // Stop after reporting the error; the rest of the segment can't be located.
+                self.position = self.size;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Reads and validates the whole batch at `position` of `log`, a segment file of `size` bytes.
+/// Returns `None` at the end of the segment, including when the last batch is incomplete.
+fn read_batch(log: &File, size: u64, position: u64) -> KafkaResult<Option<RecordBatch>> {
+    let Some(header) = read_header(log, size, position)? else {
+        return Ok(None);
+    };
+    let mut data = vec![0u8; header.batch_size];
+    read_exact_at(log, &mut data, position)?;
+    Ok(Some(RecordBatch::read(&mut data.as_slice())?))
+}
+
+/// Reads the header of the batch at `position` of `log`, a segment file of `size` bytes.
+/// Returns `None` at the end of the segment, including when the last batch is incomplete.
+fn read_header(log: &File, size: u64, position: u64) -> KafkaResult<Option<RecordBatchHeader>> {
+    if position + RECORD_BATCH_OVERHEAD as u64 > size {
+        return Ok(None);
+    }
+    let mut data = [0u8; RECORD_BATCH_OVERHEAD];
+    read_exact_at(log, &mut data, position)?;
+    let header = RecordBatchHeader::read(&data)?;
+    if position + header.batch_size as u64 > size {
+        return Ok(None);
+    }
+    Ok(Some(header))
+}
+
+/// Returns the path of the segment file with base offset `base_offset` and extension `suffix`.
+pub fn segment_path(dir: &Path, base_offset: i64, suffix: &str) -> PathBuf {
+    dir.join(format!("{:020}.{}", base_offset, suffix))
+}
+
+/// Parses the base offset out of the name of a segment file with extension `suffix`, if it is
+/// one.
+pub fn parse_segment_file_name(name: &str, suffix: &str) -> Option<i64> {
+    name.strip_suffix(suffix)?.strip_suffix('.')?.parse().ok()
+}
+
+/// Moves the indexes of the cleaned segment at `base_offset` from `cleaned_dir` to `dir`,
+/// replacing those of the segment there.
+///
+/// # Errors
+///
+/// Returns [`KafkaBrokerError::Io`] if an index can't be renamed.
+pub fn install_cleaned_indexes(
+    cleaned_dir: &Path,
+    dir: &Path,
+    base_offset: i64,
+) -> KafkaResult<()> {
+    for suffix in [INDEX_FILE_SUFFIX, TIME_INDEX_FILE_SUFFIX] {
+        fs::rename(
+            segment_path(cleaned_dir, base_offset, suffix),
+            segment_path(dir, base_offset, suffix),
+        )?;
+    }
+    Ok(())
+}
+
+/// Installs the swap file of the segment at `base_offset` as its data file, dropping the old
+/// segment's indexes so that they are rebuilt from the new data when the segment is opened.
+///
+/// # Errors
+///
+/// Returns [`KafkaBrokerError::Io`] if a file can't be removed or renamed.
+pub fn install_swap_file(dir: &Path, base_offset: i64) -> KafkaResult<()> {
+    for suffix in [INDEX_FILE_SUFFIX, TIME_INDEX_FILE_SUFFIX] {
+        match fs::remove_file(segment_path(dir, base_offset, suffix)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    fs::rename(
+        segment_path(dir, base_offset, SWAP_FILE_SUFFIX),
+        segment_path(dir, base_offset, LOG_FILE_SUFFIX),
+    )?;
+    Ok(())
+}
@@ -0,0 +1,819 @@
+//! Partition logs: where produced record batches are kept and read back from.
+//!
+//! Every topic-partition owns a [`PartitionLog`], an append-only sequence of record batches
+//! addressed by offset and stored on disk as a series of segments. The [`LogManager`] loads the
+//! existing logs from the configured log directories at startup, creates a log for each
+//! partition as topics are created, and hands them out to the Produce and Fetch handlers. Each
+//! log sits behind its own mutex, so appends to different partitions never contend. The file IO
+//! a log does runs on tokio's blocking threads (see [`with_log`] and [`run_blocking`]), so that
+//! a slow disk never stalls the runtime's workers.
+//!
+//! Appends go to the page cache; a log is only synced to disk once `flush.messages` records
+//! have been appended since its last flush, or by a background task
+//! ([`LogManager::run_flusher`]) once its last flush is older than `flush.ms`. The same task
+//! periodically checkpoints each log's recovery point, the offset up to which it is known to be
+//! on disk.
+//!
+//! On a clean shutdown every log is synced to disk, its producer state is snapshotted, and a
+//! `.kafka_cleanshutdown` marker is left
+//! in each log directory. If the marker is missing at startup, the broker crashed: every log in
+//! the directory is recovered from its last checkpointed recovery point (see
+//! [`PartitionLog::recover`]), which truncates torn writes and rebuilds the indexes.
+//!
+//! A background task ([`LogManager::run_retention`]) periodically deletes the segments that
+//! are past each log's retention, forgets the idempotent producers that have been idle for
+//! `producer.id.expiration.ms`, and checkpoints the log start offsets. Another one
+//! ([`LogManager::run_cleaner`]) compacts the logs with `cleanup.policy=compact`, checkpointing
+//! how far each has been cleaned so that the next pass only has to scan what was written since.
+
+mod checkpoint;
+mod index;
+mod log_cleaner;
+mod log_segment;
+mod partition_log;
+mod producer_state;
+
+pub use partition_log::{LogAppendInfo, PartitionLog};
+
+use crate::kafka_protocol::kafka_compression::Compression;
+use crate::kafka_protocol::kafka_error::{KafkaBrokerError, KafkaResult};
+use checkpoint::OffsetCheckpointFile;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::select;
+use tokio::sync::{Mutex, OwnedMutexGuard, RwLock};
+use tokio::task;
+use tokio::time::{self, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+use uuid::Uuid;
+
+/// The marker left in each log directory by a clean shutdown.
+const CLEAN_SHUTDOWN_FILE: &str = ".kafka_cleanshutdown";
+
+/// The checkpoint file recording each log's recovery point.
+const RECOVERY_POINT_CHECKPOINT_FILE: &str = "recovery-point-offset-checkpoint";
+
+/// The checkpoint file recording each log's start offset, which DeleteRecords may have moved
+/// past the first segment's base offset.
+const LOG_START_OFFSET_CHECKPOINT_FILE: &str = "log-start-offset-checkpoint";
+
+/// The checkpoint file recording where the dirty part of each compacted log starts.
+const CLEANER_OFFSET_CHECKPOINT_FILE: &str = "cleaner-offset-checkpoint";
+
+/// A locked partition log which, unlike a borrowed guard, can be moved to a blocking thread.
+pub type LogGuard = OwnedMutexGuard<PartitionLog>;
+
+/// Identifies a single partition of a topic.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TopicPartition {
+    /// The topic name.
+    pub topic: String,
+    /// The partition index.
+    pub partition: i32,
+}
+
+impl TopicPartition {
+    /// Creates a `TopicPartition` for `topic`/`partition`.
+    pub fn new(topic: impl Into<String>, partition: i32) -> Self {
+        Self {
+            topic: topic.into(),
+            partition,
+        }
+    }
+}
+
+impl fmt::Display for TopicPartition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.topic, self.partition)
+    }
+}
+
+/// How the timestamps of appended batches are set (`message.timestamp.type`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampType {
+    /// Keep the timestamps the producer set.
+    CreateTime,
+    /// Overwrite the batch timestamp with the broker's clock at append time.
+    LogAppendTime,
+}
+
+impl TimestampType {
+    /// Parses the `message.timestamp.type` config value (`CreateTime` or `LogAppendTime`).
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "CreateTime" => Some(TimestampType::CreateTime),
+            "LogAppendTime" => Some(TimestampType::LogAppendTime),
+            _ => None,
+        }
+    }
+}
+
+/// How appended batches are compressed (`compression.type`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionType {
+    /// Keep whatever codec the producer used.
+    Producer,
+    /// Store every batch compressed with this codec, recompressing when the producer used
+    /// another one.
+    Codec(Compression),
+}
+
+impl CompressionType {
+    /// Parses the `compression.type` config value: `producer`, `uncompressed`, `gzip`,
+    /// `snappy`, `lz4` or `zstd`. Codecs this build doesn't include are rejected.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "producer" => Some(CompressionType::Producer),
+            _ => Compression::parse(value).map(CompressionType::Codec),
+        }
+    }
+}
+
+/// What happens to old records (`cleanup.policy`): deletion by retention, compaction, or both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CleanupPolicy {
+    /// Whether whole segments are deleted once past `retention.ms` or `retention.bytes`.
+    pub delete: bool,
+    /// Whether the log is compacted, keeping only the latest record for each key.
+    pub compact: bool,
+}
+
+impl CleanupPolicy {
+    /// Parses the `cleanup.policy` config value, a comma-separated list of `delete` and
+    /// `compact`.
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut policy = CleanupPolicy {
+            delete: false,
+            compact: false,
+        };
+        for name in value.split(',').map(str::trim) {
+            match name {
+                "delete" => policy.delete = true,
+                "compact" => policy.compact = true,
+                _ => return None,
+            }
+        }
+        Some(policy)
+    }
+}
+
+/// Per-log settings, taken from the topic configuration.
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    /// How batch timestamps are set on append (`message.timestamp.type`).
+    pub message_timestamp_type: TimestampType,
+    /// The largest record batch the log accepts (`max.message.bytes`).
+    pub max_message_bytes: usize,
+    /// How batches are compressed on append (`compression.type`).
+    pub compression_type: CompressionType,
+    /// The size at which a new segment is rolled (`segment.bytes`).
+    pub segment_bytes: u64,
+    /// The age at which a new segment is rolled, in milliseconds (`segment.ms`).
+    pub segment_ms: i64,
+    /// How many bytes are appended between index entries (`index.interval.bytes`).
+    pub index_interval_bytes: usize,
+    /// How long records are kept, in milliseconds, or -1 to keep them regardless of age
+    /// (`retention.ms`).
+    pub retention_ms: i64,
+    /// How large the log may grow before its oldest segments are deleted, or -1 for no limit
+    /// (`retention.bytes`).
+    pub retention_bytes: i64,
+    /// Whether old records are deleted, compacted or both (`cleanup.policy`).
+    pub cleanup_policy: CleanupPolicy,
+    /// How long tombstones and transaction markers survive compaction, in milliseconds
+    /// (`delete.retention.ms`).
+    pub delete_retention_ms: i64,
+    /// The share of the log that must be dirty (written since the last compaction) before it
+    /// is compacted again (`min.cleanable.dirty.ratio`).
+    pub min_cleanable_dirty_ratio: f64,
+    /// How long a record stays uncompacted, in milliseconds (`min.compaction.lag.ms`).
+    pub min_compaction_lag_ms: i64,
+    /// How long a record may stay uncompacted, in milliseconds, whatever the dirty ratio
+    /// (`max.compaction.lag.ms`).
+    pub max_compaction_lag_ms: i64,
+    /// How many records may be appended before the log is synced to disk (`flush.messages`).
+    pub flush_messages: i64,
+    /// How long appended records may stay unsynced, in milliseconds (`flush.ms`).
+    pub flush_ms: i64,
+    /// How long the state of an idempotent producer that stopped writing is kept, in
+    /// milliseconds (`producer.id.expiration.ms`, a broker-wide setting).
+    pub producer_id_expiration_ms: i64,
+}
+
+impl LogConfig {
+    /// Overrides the setting with topic-level name `name` (e.g. `retention.ms`) with `value`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::InvalidConfig`] if `name` isn't a topic setting this broker
+    /// supports, or `value` isn't valid for it.
+    pub fn set(&mut self, name: &str, value: &str) -> KafkaResult<()> {
+        let invalid = || {
+            KafkaBrokerError::InvalidConfig(format!(
+                "Invalid value {} for configuration {}",
+                value, name
+            ))
+        };
+        match name {
+            "message.timestamp.type" => {
+                self.message_timestamp_type = TimestampType::parse(value).ok_or_else(invalid)?
+            }
+            "max.message.bytes" => self.max_message_bytes = parse_config(name, value, 0)?,
+            "compression.type" => {
+                self.compression_type = CompressionType::parse(value).ok_or_else(invalid)?
+            }
+            "segment.bytes" => self.segment_bytes = parse_config(name, value, 14)?,
+            "segment.ms" => self.segment_ms = parse_config(name, value, 1)?,
+            "index.interval.bytes" => self.index_interval_bytes = parse_config(name, value, 0)?,
+            "retention.ms" => self.retention_ms = parse_config(name, value, -1)?,
+            "retention.bytes" => self.retention_bytes = parse_config(name, value, i64::MIN)?,
+            "cleanup.policy" => {
+                self.cleanup_policy = CleanupPolicy::parse(value).ok_or_else(invalid)?
+            }
+            "delete.retention.ms" => self.delete_retention_ms = parse_config(name, value, 0)?,
+            "min.cleanable.dirty.ratio" => {
+                let ratio = parse_config(name, value, 0.0)?;
+                if ratio > 1.0 {
+                    return Err(invalid());
+                }
+                self.min_cleanable_dirty_ratio = ratio;
+            }
+            "min.compaction.lag.ms" => self.min_compaction_lag_ms = parse_config(name, value, 0)?,
+            "max.compaction.lag.ms" => self.max_compaction_lag_ms = parse_config(name, value, 1)?,
+            "flush.messages" => self.flush_messages = parse_config(name, value, 1)?,
+            "flush.ms" => self.flush_ms = parse_config(name, value, 0)?,
+            _ => {
+                return Err(KafkaBrokerError::InvalidConfig(format!(
+                    "Unknown topic config name: {}",
+                    name
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// Kafka's defaults, which tests then adjust.
+    #[cfg(test)]
+    pub fn for_tests() -> Self {
+        Self {
+            message_timestamp_type: TimestampType::CreateTime,
+            max_message_bytes: 1_048_588,
+            compression_type: CompressionType::Producer,
+            segment_bytes: 1_073_741_824,
+            segment_ms: 604_800_000,
+            index_interval_bytes: 4096,
+            retention_ms: 604_800_000,
+            retention_bytes: -1,
+            cleanup_policy: CleanupPolicy {
+                delete: true,
+                compact: false,
+            },
+            delete_retention_ms: 86_400_000,
+            min_cleanable_dirty_ratio: 0.5,
+            min_compaction_lag_ms: 0,
+            max_compaction_lag_ms: i64::MAX,
+            flush_messages: i64::MAX,
+            flush_ms: i64::MAX,
+            producer_id_expiration_ms: 86_400_000,
+        }
+    }
+}
+
+/// Owns the [`PartitionLog`] of every partition hosted by the broker.
+pub struct LogManager {
+    /// The directories logs are stored in (`log.dirs`).
+    log_dirs: Vec<PathBuf>,
+    logs: RwLock<HashMap<TopicPartition, Arc<Mutex<PartitionLog>>>>,
+    /// Held while the checkpoint files are written, as the tasks that write them share their
+    /// temporary files, and a checkpoint must not be overwritten by an older one.
+    checkpoint_lock: Mutex<()>,
+}
+
+impl LogManager {
+    /// Loads every log stored in `log_dirs`, creating the directories if needed, and opens them
+    /// with the settings `log_config` returns for their topic. Entries that aren't log
+    /// directories are skipped.
+    ///
+    /// Logs in a directory without a clean shutdown marker are recovered from their
+    /// checkpointed recovery points. The marker is then removed, so that a crash before the
+    /// next clean shutdown is detected, and fresh recovery points are checkpointed.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`](crate::kafka_protocol::kafka_error::KafkaBrokerError::Io)
+    /// if a directory can't be read or a log can't be recovered, a decoding error if a
+    /// checkpoint file is damaged, or whatever error `log_config` returns.
+    pub fn open(
+        log_dirs: Vec<PathBuf>,
+        log_config: impl Fn(&str) -> KafkaResult<LogConfig>,
+    ) -> KafkaResult<Self> {
+        let mut logs = HashMap::new();
+        for log_dir in &log_dirs {
+            fs::create_dir_all(log_dir)?;
+            let clean_shutdown_file = log_dir.join(CLEAN_SHUTDOWN_FILE);
+            let clean_shutdown = clean_shutdown_file.exists();
+            let recovery_points =
+                OffsetCheckpointFile::new(log_dir, RECOVERY_POINT_CHECKPOINT_FILE).read()?;
+            let log_start_offsets =
+                OffsetCheckpointFile::new(log_dir, LOG_START_OFFSET_CHECKPOINT_FILE).read()?;
+            let cleaner_offsets =
+                OffsetCheckpointFile::new(log_dir, CLEANER_OFFSET_CHECKPOINT_FILE).read()?;
+
+            let mut dir_logs = Vec::new();
+            for entry in fs::read_dir(log_dir)? {
+                let entry = entry?;
+                if !entry.file_type()?.is_dir() {
+                    continue;
+                }
+                if let Some(mut log) = PartitionLog::open(entry.path(), &log_config)? {
+                    if !clean_shutdown {
+                        let recovery_point = recovery_points
+                            .get(log.topic_partition())
+                            .copied()
+                            .unwrap_or(0);
+                        log.recover(recovery_point)?;
+                    }
+                    if let Some(&log_start_offset) = log_start_offsets.get(log.topic_partition()) {
+                        log.maybe_increment_log_start_offset(log_start_offset);
+                    }
+                    if let Some(&cleaner_offset) = cleaner_offsets.get(log.topic_partition()) {
+                        log.set_cleaner_offset(cleaner_offset);
+                    }
+                    dir_logs.push(log);
+                }
+            }
+            if !clean_shutdown && !dir_logs.is_empty() {
+                info!(
+                    "Recovered {} logs in {} after an unclean shutdown",
+                    dir_logs.len(),
+                    log_dir.display()
+                );
+            }
+
+            let offsets: Vec<_> = dir_logs.iter().map(LogOffsets::new).collect();
+            write_checkpoints(log_dir, &offsets)?;
+            if clean_shutdown {
+                fs::remove_file(&clean_shutdown_file)?;
+            }
+            for log in dir_logs {
+                logs.insert(log.topic_partition().clone(), Arc::new(Mutex::new(log)));
+            }
+        }
+        Ok(Self {
+            log_dirs,
+            logs: RwLock::new(logs),
+            checkpoint_lock: Mutex::new(()),
+        })
+    }
+
+    /// Returns the log of `topic_partition`, creating an empty one with `config` if needed.
+    /// New logs go to the log directory holding the fewest partitions.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`](crate::kafka_protocol::kafka_error::KafkaBrokerError::Io)
+    /// if the log's files can't be created.
+    pub async fn get_or_create(
+        &self,
+        topic_partition: &TopicPartition,
+        topic_id: Uuid,
+        config: &LogConfig,
+    ) -> KafkaResult<Arc<Mutex<PartitionLog>>> {
+        let mut logs = self.logs.write().await;
+        if let Some(log) = logs.get(topic_partition) {
+            return Ok(log.clone());
+        }
+
+        let log_dir = self
+            .log_dirs
+            .iter()
+            .min_by_key(|dir| fs::read_dir(dir).map_or(usize::MAX, |entries| entries.count()))
+            .expect("at least one log directory is configured");
+        let log = spawn_blocking({
+            let log_dir = log_dir.clone();
+            let topic_partition = topic_partition.clone();
+            let config = config.clone();
+            move || PartitionLog::create(&log_dir, topic_partition, topic_id, config)
+        })
+        .await?;
+        let log = Arc::new(Mutex::new(log));
+        logs.insert(topic_partition.clone(), log.clone());
+        Ok(log)
+    }
+
+    /// Returns every hosted partition along with the id of its topic.
+    pub async fn partitions(&self) -> Vec<(TopicPartition, Uuid)> {
+        let logs = self.logs.read().await;
+        let mut partitions = Vec::with_capacity(logs.len());
+        for (topic_partition, log) in logs.iter() {
+            partitions.push((topic_partition.clone(), log.lock().await.topic_id()));
+        }
+        partitions
+    }
+
+    /// Returns the log of `topic_partition`, if the broker hosts it.
+    pub async fn get(&self, topic_partition: &TopicPartition) -> Option<Arc<Mutex<PartitionLog>>> {
+        self.logs.read().await.get(topic_partition).cloned()
+    }
+
+    /// Deletes the segments of every log that are past retention or before the log start
+    /// offset (see [`PartitionLog::delete_old_segments`]) and forgets its expired producers,
+    /// then checkpoints the log start offsets. A log that fails is logged and skipped.
+    pub async fn cleanup_logs(&self) {
+        let now_ms = now_ms();
+        let logs: Vec<_> = self.logs.read().await.values().cloned().collect();
+        let mut deleted = 0;
+        let mut expired_producers = 0;
+        for log in &logs {
+            let (expired, result) = with_log(log, move |log| {
+                let expired = log.remove_expired_producers(now_ms);
+                let result = log.delete_old_segments(now_ms).map_err(|e| {
+                    error!(
+                        "Failed to delete old segments of {}: {}",
+                        log.topic_partition(),
+                        e
+                    )
+                });
+                (expired, result)
+            })
+            .await;
+            expired_producers += expired;
+            deleted += result.unwrap_or(0);
+        }
+        if deleted > 0 {
+            info!("Deleted {} segments past retention", deleted);
+        }
+        if expired_producers > 0 {
+            info!("Removed {} expired producers", expired_producers);
+        }
+        self.checkpoint_logs(&logs).await;
+    }
+
+    /// Runs [`LogManager::cleanup_logs`] every `interval` until `shutdown_token` is cancelled.
+    pub async fn run_retention(&self, interval: Duration, shutdown_token: CancellationToken) {
+        let mut ticker = time::interval(interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            select! {
+                _ = ticker.tick() => self.cleanup_logs().await,
+                _ = shutdown_token.cancelled() => break,
+            }
+        }
+        debug!("Log retention task stopped");
+    }
+
+    /// Compacts every log that is due (see [`PartitionLog::plan_compaction`]), then checkpoints
+    /// how far each has been cleaned. A log that fails is logged and skipped. The cleaning runs
+    /// on a blocking thread without the log's lock; the log is only locked to plan it and to
+    /// swap the cleaned segments in, so appends and fetches carry on meanwhile.
+    pub async fn clean_logs(&self) {
+        let now_ms = now_ms();
+        let logs: Vec<_> = self.logs.read().await.values().cloned().collect();
+        let mut cleaned = 0;
+        for log in &logs {
+            let Some(compaction) = log.lock().await.plan_compaction(now_ms) else {
+                continue;
+            };
+            let topic_partition = compaction.topic_partition.clone();
+            let result =
+                match spawn_blocking(move || compaction.clean().map(|stats| (compaction, stats)))
+                    .await
+                {
+                    Ok((compaction, stats)) => {
+                        with_log(log, move |log| log.finish_compaction(compaction, stats)).await
+                    }
+                    Err(e) => Err(e),
+                };
+            match result {
+                Ok(()) => cleaned += 1,
+                Err(e) => error!("Failed to compact {}: {}", topic_partition, e),
+            }
+        }
+        if cleaned > 0 {
+            self.checkpoint_logs(&logs).await;
+        }
+    }
+
+    /// Runs [`LogManager::clean_logs`] every `backoff` until `shutdown_token` is cancelled.
+    pub async fn run_cleaner(&self, backoff: Duration, shutdown_token: CancellationToken) {
+        let mut ticker = time::interval(backoff);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            select! {
+                _ = ticker.tick() => self.clean_logs().await,
+                _ = shutdown_token.cancelled() => break,
+            }
+        }
+        debug!("Log cleaner task stopped");
+    }
+
+    /// Flushes every log whose last flush is older than its `flush.ms` (see
+    /// [`PartitionLog::maybe_flush`]). A log that fails is logged and skipped.
+    pub async fn flush_logs(&self) {
+        let now_ms = now_ms();
+        let logs: Vec<_> = self.logs.read().await.values().cloned().collect();
+        for log in &logs {
+            with_log(log, move |log| {
+                if let Err(e) = log.maybe_flush(now_ms) {
+                    error!("Failed to flush {}: {}", log.topic_partition(), e);
+                }
+            })
+            .await;
+        }
+    }
+
+    /// Runs [`LogManager::flush_logs`] every `flush_interval` and checkpoints the recovery
+    /// points of every log every `checkpoint_interval`, until `shutdown_token` is cancelled.
+    pub async fn run_flusher(
+        &self,
+        flush_interval: Duration,
+        checkpoint_interval: Duration,
+        shutdown_token: CancellationToken,
+    ) {
+        let mut flush_ticker = time::interval(flush_interval);
+        flush_ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut checkpoint_ticker = time::interval(checkpoint_interval);
+        checkpoint_ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            select! {
+                _ = flush_ticker.tick() => self.flush_logs().await,
+                _ = checkpoint_ticker.tick() => {
+                    let logs: Vec<_> = self.logs.read().await.values().cloned().collect();
+                    self.checkpoint_logs(&logs).await;
+                }
+                _ = shutdown_token.cancelled() => break,
+            }
+        }
+        debug!("Log flusher task stopped");
+    }
+
+    /// Writes the checkpoints of every log directory. Each of `logs` is only locked while its
+    /// offsets are copied out; the files are written once every log lock is released. A directory
+    /// that fails is logged and skipped.
+    async fn checkpoint_logs(&self, logs: &[Arc<Mutex<PartitionLog>>]) {
+        let _checkpoint = self.checkpoint_lock.lock().await;
+        let mut offsets = Vec::with_capacity(logs.len());
+        for log in logs {
+            offsets.push(LogOffsets::new(&*log.lock().await));
+        }
+        let log_dirs = self.log_dirs.clone();
+        spawn_blocking(move || {
+            for log_dir in &log_dirs {
+                if let Err(e) = write_checkpoints(log_dir, &offsets) {
+                    error!("Failed to checkpoint logs in {}: {}", log_dir.display(), e);
+                }
+            }
+        })
+        .await;
+    }
+
+    /// Syncs every log to disk and snapshots its producer state (see [`PartitionLog::close`]),
+    /// checkpoints their recovery points and leaves a clean shutdown marker in each log
+    /// directory, so that the next startup skips recovery. Must only be
+    /// called once nothing appends to the logs anymore.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`](crate::kafka_protocol::kafka_error::KafkaBrokerError::Io)
+    /// if a log can't be synced or a file can't be written.
+    pub async fn shutdown(&self) -> KafkaResult<()> {
+        let logs = self.logs.read().await;
+        let _checkpoint = self.checkpoint_lock.lock().await;
+        let mut offsets = Vec::with_capacity(logs.len());
+        for log in logs.values() {
+            offsets.push(with_log(log, |log| log.close().map(|()| LogOffsets::new(log))).await?);
+        }
+        let count = offsets.len();
+        let log_dirs = self.log_dirs.clone();
+        spawn_blocking(move || -> KafkaResult<()> {
+            for log_dir in &log_dirs {
+                write_checkpoints(log_dir, &offsets)?;
+                File::create(log_dir.join(CLEAN_SHUTDOWN_FILE))?.sync_all()?;
+            }
+            Ok(())
+        })
+        .await?;
+        info!("Flushed {} logs for a clean shutdown", count);
+        Ok(())
+    }
+}
+
+/// Runs `f` on the locked `log` on a blocking thread, so that the file IO it does (segment and
+/// index writes, syncs, rolls) doesn't hold up the runtime's workers. The log is handed back,
+/// still locked, with `f`'s result, for callers that have more to do under the lock.
+pub async fn run_blocking<T, F>(mut log: LogGuard, f: F) -> (LogGuard, T)
+where
+    T: Send + 'static,
+    F: FnOnce(&mut PartitionLog) -> T + Send + 'static,
+{
+    spawn_blocking(move || {
+        let result = f(&mut log);
+        (log, result)
+    })
+    .await
+}
+
+/// Locks `log` and runs `f` on it on a blocking thread (see [`run_blocking`]), releasing the
+/// lock once `f` returns.
+pub async fn with_log<T, F>(log: &Arc<Mutex<PartitionLog>>, f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce(&mut PartitionLog) -> T + Send + 'static,
+{
+    run_blocking(log.clone().lock_owned().await, f).await.1
+}
+
+/// Runs `f` on a blocking thread and waits for it, passing on its panic if it has one.
+async fn spawn_blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|e| panic::resume_unwind(e.into_panic()))
+}
+
+/// The checkpointed offsets of a log, copied out so that the checkpoint files can be written
+/// without holding the log's lock.
+struct LogOffsets {
+    topic_partition: TopicPartition,
+    /// The log directory the log lives in.
+    log_dir: PathBuf,
+    recovery_point: i64,
+    log_start_offset: i64,
+    /// The cleaner offset, for compacted logs only.
+    cleaner_offset: Option<i64>,
+}
+
+impl LogOffsets {
+    fn new(log: &PartitionLog) -> Self {
+        Self {
+            topic_partition: log.topic_partition().clone(),
+            log_dir: log.log_dir().to_path_buf(),
+            recovery_point: log.recovery_point(),
+            log_start_offset: log.log_start_offset(),
+            cleaner_offset: log
+                .config()
+                .cleanup_policy
+                .compact
+                .then(|| log.cleaner_offset()),
+        }
+    }
+}
+
+/// Checkpoints the recovery points, log start offsets and, for compacted logs, cleaner offsets
+/// of the logs in `offsets` that live in `log_dir`.
+fn write_checkpoints(log_dir: &Path, offsets: &[LogOffsets]) -> KafkaResult<()> {
+    let mut recovery_points = HashMap::new();
+    let mut log_start_offsets = HashMap::new();
+    let mut cleaner_offsets = HashMap::new();
+    for log in offsets.iter().filter(|log| log.log_dir == log_dir) {
+        recovery_points.insert(log.topic_partition.clone(), log.recovery_point);
+        log_start_offsets.insert(log.topic_partition.clone(), log.log_start_offset);
+        if let Some(cleaner_offset) = log.cleaner_offset {
+            cleaner_offsets.insert(log.topic_partition.clone(), cleaner_offset);
+        }
+    }
+    OffsetCheckpointFile::new(log_dir, RECOVERY_POINT_CHECKPOINT_FILE).write(&recovery_points)?;
+    OffsetCheckpointFile::new(log_dir, LOG_START_OFFSET_CHECKPOINT_FILE)
+        .write(&log_start_offsets)?;
+    OffsetCheckpointFile::new(log_dir, CLEANER_OFFSET_CHECKPOINT_FILE).write(&cleaner_offsets)
+}
+
+/// Parses `value` as the numeric setting `name`, which must be at least `min`.
+fn parse_config<T: FromStr + PartialOrd>(name: &str, value: &str, min: T) -> KafkaResult<T> {
+    value
+        .parse()
+        .ok()
+        .filter(|parsed| *parsed >= min)
+        .ok_or_else(|| {
+            KafkaBrokerError::InvalidConfig(format!(
+                "Invalid value {} for configuration {}",
+                value, name
+            ))
+        })
+}
+
+/// The current wall-clock time in milliseconds since the Unix epoch.
+pub(crate) fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::kafka_protocol::kafka_record_batch::{Record, RecordBatch};
+
+    /// An encoded batch of `records` as `(key, value)` pairs, with timestamps counting up from
+    /// `timestamp`.
+    pub(crate) fn batch(timestamp: i64, records: &[(&str, Option<&str>)]) -> Vec<u8> {
+        let records: Vec<Record> = records
+            .iter()
+            .enumerate()
+            .map(|(i, (key, value))| Record {
+                attributes: 0,
+                timestamp_delta: i as i64,
+                offset_delta: i as i32,
+                key: Some(key.as_bytes().to_vec()),
+                value: value.map(|value| value.as_bytes().to_vec()),
+                headers: Vec::new(),
+            })
+            .collect();
+        let mut data = Vec::new();
+        RecordBatch::new(0, timestamp, &records).write(&mut data);
+        data
+    }
+
+    fn topic_partition() -> TopicPartition {
+        TopicPartition::new("t", 0)
+    }
+
+    /// Opens the logs in `log_dir`, each rolling a segment every two batches of [`batch`].
+    fn open_logs(log_dir: &Path) -> LogManager {
+        LogManager::open(vec![log_dir.to_path_buf()], |_| Ok(config())).unwrap()
+    }
+
+    fn config() -> LogConfig {
+        LogConfig {
+            segment_bytes: 2 * batch(0, &[("k", Some("v"))]).len() as u64,
+            ..LogConfig::for_tests()
+        }
+    }
+
+    /// Appends `count` single-record batches to the log of [`topic_partition`].
+    async fn append(logs: &LogManager, count: usize) {
+        let log = logs
+            .get_or_create(&topic_partition(), Uuid::new_v4(), &config())
+            .await
+            .unwrap();
+        let mut log = log.lock().await;
+        for _ in 0..count {
+            log.append(&batch(1000, &[("k", Some("v"))]), 0, 1000)
+                .unwrap();
+        }
+    }
+
+    async fn log_end_offset(logs: &LogManager) -> i64 {
+        let log = logs.get(&topic_partition()).await.unwrap();
+        let log_end_offset = log.lock().await.log_end_offset();
+        log_end_offset
+    }
+
+    /// Flips the last byte of the batch at `position` of the segment at `base_offset`.
+    fn damage_batch(log_dir: &Path, base_offset: i64, position: usize) {
+        let path = log_segment::segment_path(
+            &log_dir.join("t-0"),
+            base_offset,
+            log_segment::LOG_FILE_SUFFIX,
+        );
+        let mut data = fs::read(&path).unwrap();
+        data[position + batch(0, &[("k", Some("v"))]).len() - 1] ^= 0xff;
+        fs::write(&path, data).unwrap();
+    }
+
+    #[tokio::test]
+    async fn recovery_starts_at_the_checkpointed_recovery_point() {
+        let dir = tempfile::tempdir().unwrap();
+        let logs = open_logs(dir.path());
+        // Rolling syncs the full segments, moving the recovery point to 4.
+        append(&logs, 5).await;
+        let all: Vec<_> = logs.logs.read().await.values().cloned().collect();
+        logs.checkpoint_logs(&all).await;
+        drop((all, logs));
+
+        // Damage one batch before the recovery point and one after it, then crash.
+        damage_batch(dir.path(), 0, 0);
+        damage_batch(dir.path(), 4, 0);
+        let logs = open_logs(dir.path());
+        // Only the segment past the recovery point was checked.
+        assert_eq!(log_end_offset(&logs).await, 4);
+        let log = logs.get(&topic_partition()).await.unwrap();
+        assert_eq!(log.lock().await.recovery_point(), 4);
+    }
+
+    #[tokio::test]
+    async fn a_clean_shutdown_marker_skips_recovery() {
+        let dir = tempfile::tempdir().unwrap();
+        let logs = open_logs(dir.path());
+        append(&logs, 3).await;
+        logs.shutdown().await.unwrap();
+        drop(logs);
+        assert!(dir.path().join(CLEAN_SHUTDOWN_FILE).exists());
+
+        damage_batch(dir.path(), 2, 0);
+        let logs = open_logs(dir.path());
+        assert_eq!(log_end_offset(&logs).await, 3);
+        // The marker is consumed, so that a crash from here on is detected.
+        assert!(!dir.path().join(CLEAN_SHUTDOWN_FILE).exists());
+        drop(logs);
+
+        let logs = open_logs(dir.path());
+        assert_eq!(log_end_offset(&logs).await, 2);
+    }
+}
@@ -0,0 +1,1222 @@
+//! An append-only log of record batches for a single partition, persisted as segment files.
+//!
+//! Batches are stored exactly as they will be served to consumers: the broker validates the
+//! producer's batch, stamps it with its offsets, leader epoch and (for `LogAppendTime` topics)
+//! timestamp, and writes the resulting bytes to the active segment. Only v2 batches are stored;
+//! legacy message sets are up-converted on append.
+//!
+//! Each log lives in its own directory, `<log dir>/<topic>-<partition>`, holding its segments
+//! (see [`LogSegment`]), the snapshots of its producer state (see [`ProducerStateManager`]) and
+//! a `partition.metadata` file recording the topic id.
+//!
+//! Batches from idempotent producers are checked against the producer's previous batches
+//! before they are appended: a retry of a batch that was already written is acknowledged with
+//! its original offsets instead of being appended again, and a batch that doesn't follow the
+//! producer's last one is rejected.
+//!
+//! Old data is removed a whole segment at a time, once it falls out of `retention.ms` or
+//! `retention.bytes`, or lies entirely before the log start offset (which DeleteRecords can
+//! move forward). Compacted logs are instead rewritten by the [`Cleaner`], which keeps only the
+//! latest record for each key.
+
+use crate::kafka_protocol::kafka_error::{KafkaBrokerError, KafkaResult};
+use crate::kafka_protocol::kafka_message_set;
+use crate::kafka_protocol::kafka_record_batch::{peek_magic, Record, RecordBatch, MAGIC_V2};
+use crate::kafka_protocol::kafka_records::Records;
+use crate::storage::log_cleaner::{CleanerStats, Compaction};
+use crate::storage::log_segment::{
+    install_cleaned_indexes, install_swap_file, parse_segment_file_name, segment_path, LogSegment,
+    SegmentData, LOG_FILE_SUFFIX, SWAP_FILE_SUFFIX,
+};
+use crate::storage::producer_state::ProducerStateManager;
+use crate::storage::{now_ms, CompressionType, LogConfig, TimestampType, TopicPartition};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, trace, warn};
+use uuid::Uuid;
+
+/// The outcome of a successful append.
+#[derive(Debug, Clone, Copy)]
+pub struct LogAppendInfo {
+    /// The offset assigned to the first record of the batch.
+    pub first_offset: i64,
+    /// The offset assigned to the last record of the batch.
+    pub last_offset: i64,
+    /// The broker time stamped on the batch, or -1 for `CreateTime` logs.
+    pub log_append_time: i64,
+    /// The log start offset after the append.
+    pub log_start_offset: i64,
+}
+
+/// The file recording which topic a log directory belongs to.
+const PARTITION_METADATA_FILE: &str = "partition.metadata";
+
+/// The directory, inside a log's directory, the log cleaner writes cleaned segments to.
+const CLEANED_DIR: &str = "cleaned";
+
+/// The log of one topic-partition.
+#[derive(Debug)]
+pub struct PartitionLog {
+    topic_partition: TopicPartition,
+    topic_id: Uuid,
+    dir: PathBuf,
+    config: LogConfig,
+    /// The log's segments, keyed by base offset. Never empty; the last one is the active
+    /// segment.
+    segments: BTreeMap<i64, LogSegment>,
+    log_start_offset: i64,
+    log_end_offset: i64,
+    /// The offset below which the log is known to be synced to disk. Only data after it needs
+    /// to be checked after an unclean shutdown.
+    recovery_point: i64,
+    /// When the log was last synced to disk, in milliseconds since the Unix epoch.
+    last_flush_ms: i64,
+    /// The offset where the part of the log written since the last compaction starts.
+    cleaner_offset: i64,
+    /// The last batches of the idempotent producers writing to the log.
+    producer_state: ProducerStateManager,
+}
+
+impl PartitionLog {
+    /// Creates an empty log for `topic_partition` in a new directory under `log_dir`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if the directory or its files can't be created.
+    pub fn create(
+        log_dir: &Path,
+        topic_partition: TopicPartition,
+        topic_id: Uuid,
+        config: LogConfig,
+    ) -> KafkaResult<Self> {
+        let dir = log_dir.join(topic_partition.to_string());
+        fs::create_dir_all(&dir)?;
+        fs::write(
+            dir.join(PARTITION_METADATA_FILE),
+            format!("version: 0\ntopic_id: {}\n", topic_id),
+        )?;
+        let mut segments = BTreeMap::new();
+        segments.insert(0, LogSegment::open(&dir, 0)?);
+        let producer_state = load_producer_state(&dir, &topic_partition, &segments, 0)?;
+        debug!("Created log for {} in {}", topic_partition, dir.display());
+        Ok(Self {
+            topic_partition,
+            topic_id,
+            dir,
+            config,
+            segments,
+            log_start_offset: 0,
+            log_end_offset: 0,
+            recovery_point: 0,
+            last_flush_ms: now_ms(),
+            cleaner_offset: 0,
+            producer_state,
+        })
+    }
+
+    /// Loads the log stored in `dir`, a directory created by [`PartitionLog::create`], with
+    /// the settings `log_config` returns for its topic. Returns `None` if `dir` isn't a log
+    /// directory.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if the directory can't be read,
+    /// [`KafkaBrokerError::CorruptMessage`] if a segment is damaged, or whatever error
+    /// `log_config` returns.
+    pub fn open(
+        dir: PathBuf,
+        log_config: impl FnOnce(&str) -> KafkaResult<LogConfig>,
+    ) -> KafkaResult<Option<Self>> {
+        let Some(topic_partition) = dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(parse_log_dir_name)
+        else {
+            return Ok(None);
+        };
+        let Some(topic_id) = read_topic_id(&dir)? else {
+            return Ok(None);
+        };
+        let config = log_config(&topic_partition.topic)?;
+
+        // Finish what an interrupted compaction left behind: segments still being cleaned are
+        // discarded, swap files replace the segments they were cleaned from.
+        match fs::remove_dir_all(dir.join(CLEANED_DIR)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            if let Some(base_offset) = parse_segment_file_name(name, SWAP_FILE_SUFFIX) {
+                info!("Completing interrupted compaction of {}", dir.display());
+                install_swap_file(&dir, base_offset)?;
+            }
+        }
+
+        let mut segments = BTreeMap::new();
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            let base_offset = name
+                .to_str()
+                .and_then(|name| parse_segment_file_name(name, LOG_FILE_SUFFIX));
+            if let Some(base_offset) = base_offset {
+                segments.insert(base_offset, LogSegment::open(&dir, base_offset)?);
+            }
+        }
+        // Segments overlapping the one before them were already merged into it by compaction.
+        let mut next_offset = i64::MIN;
+        let mut merged = Vec::new();
+        for (&base_offset, segment) in &segments {
+            if base_offset < next_offset {
+                merged.push(base_offset);
+            } else {
+                next_offset = segment.next_offset();
+            }
+        }
+        for base_offset in merged {
+            if let Some(segment) = segments.remove(&base_offset) {
+                segment.delete()?;
+            }
+        }
+        if segments.is_empty() {
+            segments.insert(0, LogSegment::open(&dir, 0)?);
+        }
+
+        let log_start_offset = *segments.keys().next().unwrap();
+        let log_end_offset = segments.values().next_back().unwrap().next_offset();
+        let producer_state =
+            load_producer_state(&dir, &topic_partition, &segments, log_end_offset)?;
+        debug!(
+            "Loaded log for {} with {} segments, offsets {}..{}",
+            topic_partition,
+            segments.len(),
+            log_start_offset,
+            log_end_offset
+        );
+        Ok(Some(Self {
+            topic_partition,
+            topic_id,
+            dir,
+            config,
+            segments,
+            log_start_offset,
+            log_end_offset,
+            recovery_point: log_end_offset,
+            last_flush_ms: now_ms(),
+            cleaner_offset: log_start_offset,
+            producer_state,
+        }))
+    }
+
+    /// The partition this log belongs to.
+    pub fn topic_partition(&self) -> &TopicPartition {
+        &self.topic_partition
+    }
+
+    /// The id of the topic this log belongs to.
+    pub fn topic_id(&self) -> Uuid {
+        self.topic_id
+    }
+
+    /// The directory holding this log's directory.
+    pub fn log_dir(&self) -> &Path {
+        self.dir.parent().unwrap_or(&self.dir)
+    }
+
+    /// The offset below which the log is known to be synced to disk.
+    pub fn recovery_point(&self) -> i64 {
+        self.recovery_point
+    }
+
+    /// The log's settings.
+    pub fn config(&self) -> &LogConfig {
+        &self.config
+    }
+
+    /// The offset where the part of the log written since the last compaction starts.
+    pub fn cleaner_offset(&self) -> i64 {
+        self.cleaner_offset
+    }
+
+    /// Restores the cleaner offset checkpointed by a previous run.
+    pub fn set_cleaner_offset(&mut self, offset: i64) {
+        self.cleaner_offset = offset;
+    }
+
+    /// Recovers the log after an unclean shutdown: every segment from the one holding
+    /// `recovery_point` onward is rescanned and reindexed, the log is truncated at the first
+    /// damaged batch, and any segments after it are deleted. If the log was truncated, the
+    /// producer state is rebuilt without the lost batches.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if a segment can't be read, truncated or deleted.
+    pub fn recover(&mut self, recovery_point: i64) -> KafkaResult<()> {
+        let first = self
+            .segments
+            .range(..=recovery_point)
+            .next_back()
+            .map_or(recovery_point, |(&base_offset, _)| base_offset);
+        let unflushed: Vec<i64> = self
+            .segments
+            .range(first..)
+            .map(|(&base, _)| base)
+            .collect();
+
+        let mut truncated_at = None;
+        for base_offset in unflushed {
+            if truncated_at.is_some() {
+                // Everything after a truncation point is lost.
+                if let Some(segment) = self.segments.remove(&base_offset) {
+                    segment.delete()?;
+                }
+                continue;
+            }
+            let segment = self.segments.get_mut(&base_offset).unwrap();
+            let truncated = segment.recover(self.config.index_interval_bytes)?;
+            if truncated > 0 {
+                warn!(
+                    "Truncated {} bytes from segment {} of {} during recovery",
+                    truncated, base_offset, self.topic_partition
+                );
+                truncated_at = Some(segment.next_offset());
+            }
+        }
+
+        self.log_end_offset = self.active_segment().next_offset();
+        if truncated_at.is_some() {
+            self.producer_state = load_producer_state(
+                &self.dir,
+                &self.topic_partition,
+                &self.segments,
+                self.log_end_offset,
+            )?;
+        }
+        self.flush()?;
+        info!(
+            "Recovered {} from offset {}, log end offset is {}",
+            self.topic_partition, recovery_point, self.log_end_offset
+        );
+        Ok(())
+    }
+
+    /// Syncs every segment holding data past the recovery point to disk, and moves the
+    /// recovery point to the log end offset.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if syncing fails.
+    pub fn flush(&mut self) -> KafkaResult<()> {
+        let first = self
+            .segments
+            .range(..=self.recovery_point)
+            .next_back()
+            .map_or(self.recovery_point, |(&base_offset, _)| base_offset);
+        for segment in self.segments.range(first..).map(|(_, segment)| segment) {
+            segment.flush()?;
+        }
+        self.recovery_point = self.log_end_offset;
+        self.last_flush_ms = now_ms();
+        Ok(())
+    }
+
+    /// Syncs the log to disk and snapshots its producer state, so that the next startup
+    /// doesn't have to replay any batches to rebuild it. Must only be called once nothing
+    /// appends to the log anymore.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if syncing fails or the snapshot can't be written.
+    pub fn close(&mut self) -> KafkaResult<()> {
+        self.flush()?;
+        self.producer_state.take_snapshot(self.log_end_offset)
+    }
+
+    /// Flushes the log if it holds unsynced records and its last flush is older than
+    /// `flush.ms`. Returns whether it was flushed.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if syncing fails.
+    pub fn maybe_flush(&mut self, now_ms: i64) -> KafkaResult<bool> {
+        if self.log_end_offset <= self.recovery_point
+            || now_ms - self.last_flush_ms < self.config.flush_ms
+        {
+            return Ok(false);
+        }
+        trace!(
+            "Flushing {} up to offset {}, last flushed {} ms ago",
+            self.topic_partition,
+            self.log_end_offset,
+            now_ms - self.last_flush_ms
+        );
+        self.flush()?;
+        Ok(true)
+    }
+
+    /// Moves the log start offset forward to `offset`, making earlier records unreadable.
+    /// Segments that end up entirely before it are deleted by the next
+    /// [`PartitionLog::delete_old_segments`]. Does nothing if `offset` isn't past the current
+    /// log start offset.
+    pub fn maybe_increment_log_start_offset(&mut self, offset: i64) {
+        let offset = offset.min(self.log_end_offset);
+        if offset > self.log_start_offset {
+            debug!(
+                "Moving log start offset of {} from {} to {}",
+                self.topic_partition, self.log_start_offset, offset
+            );
+            self.log_start_offset = offset;
+        }
+    }
+
+    /// Deletes every record before `offset` (DeleteRecords), or before the high watermark if
+    /// `offset` is -1. Returns the new log start offset.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::OffsetOutOfRange`] if `offset` is past the high watermark.
+    pub fn delete_records_before(&mut self, offset: i64) -> KafkaResult<i64> {
+        let offset = match offset {
+            -1 => self.log_end_offset,
+            offset => offset,
+        };
+        if offset < 0 || offset > self.log_end_offset {
+            return Err(KafkaBrokerError::OffsetOutOfRange {
+                offset,
+                log_start_offset: self.log_start_offset,
+                log_end_offset: self.log_end_offset,
+            });
+        }
+        self.maybe_increment_log_start_offset(offset);
+        Ok(self.log_start_offset)
+    }
+
+    /// Deletes the oldest segments that are past the log's retention or lie entirely before
+    /// the log start offset, along with the producer state snapshots older than the remaining
+    /// ones, then moves the log start offset to the first remaining record. Returns the number
+    /// of segments deleted.
+    ///
+    /// A segment is past retention if its newest record is older than `retention.ms`, or if
+    /// the log would still hold at least `retention.bytes` without it; both limits only apply
+    /// to logs with the `delete` cleanup policy. Deletion stops at the first segment that must
+    /// be kept. If the active segment itself is due, a new empty one
+    /// is rolled first, so the log always keeps an active segment.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if a segment can't be deleted.
+    pub fn delete_old_segments(&mut self, now_ms: i64) -> KafkaResult<usize> {
+        let delete_policy = self.config.cleanup_policy.delete;
+        let retention_ms = match delete_policy {
+            true => self.config.retention_ms,
+            false => -1,
+        };
+        let mut excess_bytes = match self.config.retention_bytes {
+            retention_bytes if retention_bytes < 0 || !delete_policy => None,
+            retention_bytes => {
+                let total: u64 = self.segments.values().map(LogSegment::size).sum();
+                Some(total as i64 - retention_bytes)
+            }
+        };
+
+        let mut deletable = Vec::new();
+        let mut segments = self.segments.iter().peekable();
+        while let Some((&base_offset, segment)) = segments.next() {
+            let upper_bound = segments
+                .peek()
+                .map_or(self.log_end_offset, |(&next_base_offset, _)| {
+                    next_base_offset
+                });
+            let before_log_start = upper_bound <= self.log_start_offset;
+            let expired = retention_ms >= 0
+                && segment.size() > 0
+                && now_ms - segment.max_timestamp() > retention_ms;
+            let over_size = excess_bytes.is_some_and(|excess| excess >= segment.size() as i64);
+            if !(before_log_start || expired || over_size) {
+                break;
+            }
+            if let Some(excess) = excess_bytes.as_mut() {
+                *excess -= segment.size() as i64;
+            }
+            deletable.push(base_offset);
+        }
+
+        if deletable.len() == self.segments.len() {
+            if self.active_segment().size() == 0 {
+                deletable.pop();
+            } else {
+                self.roll(self.log_end_offset)?;
+            }
+        }
+
+        for base_offset in &deletable {
+            if let Some(segment) = self.segments.remove(base_offset) {
+                info!(
+                    "Deleting segment {} of {} past retention",
+                    base_offset, self.topic_partition
+                );
+                segment.delete()?;
+            }
+        }
+        let first_base_offset = *self.segments.keys().next().unwrap();
+        self.producer_state
+            .delete_snapshots_before(first_base_offset)?;
+        self.maybe_increment_log_start_offset(first_base_offset);
+        Ok(deletable.len())
+    }
+
+    /// Forgets the idempotent producers that haven't written to the log for
+    /// `producer.id.expiration.ms`. Returns how many were removed.
+    pub fn remove_expired_producers(&mut self, now_ms: i64) -> usize {
+        let expired = self
+            .producer_state
+            .remove_expired_producers(now_ms, self.config.producer_id_expiration_ms);
+        if expired > 0 {
+            debug!(
+                "Removed {} expired producers of {}",
+                expired, self.topic_partition
+            );
+        }
+        expired
+    }
+
+    /// Plans a compaction of the log if it has the `compact` cleanup policy and enough of it is
+    /// dirty, or returns `None` if it isn't due. Nothing is read or written: the cleaning is
+    /// left to [`Compaction::clean`], which runs without the log's lock, and the result is
+    /// swapped in by [`PartitionLog::finish_compaction`].
+    ///
+    /// The log is cleaned once the dirty part makes up at least `min.cleanable.dirty.ratio` of
+    /// the cleanable segments, or as soon as a dirty record is older than
+    /// `max.compaction.lag.ms`. Neighbouring segments are merged into groups that each fit in
+    /// one segment.
+    pub fn plan_compaction(&self, now_ms: i64) -> Option<Compaction> {
+        if !self.config.cleanup_policy.compact {
+            return None;
+        }
+        let (first_dirty_offset, end_offset) = self.cleanable_range(now_ms)?;
+
+        // Merge neighbouring segments as long as the result can't outgrow a single segment or
+        // its offset index.
+        let mut groups: Vec<Vec<(i64, SegmentData)>> = Vec::new();
+        let mut group_size = 0;
+        for (&base_offset, segment) in self.segments.range(..end_offset) {
+            let fits = groups.last().is_some_and(|group| {
+                group_size + segment.size() <= self.config.segment_bytes
+                    && segment.next_offset() - group[0].0 <= i64::from(i32::MAX)
+            });
+            if fits {
+                groups
+                    .last_mut()
+                    .unwrap()
+                    .push((base_offset, segment.data()));
+                group_size += segment.size();
+            } else {
+                groups.push(vec![(base_offset, segment.data())]);
+                group_size = segment.size();
+            }
+        }
+
+        Some(Compaction {
+            topic_partition: self.topic_partition.clone(),
+            cleaned_dir: self.dir.join(CLEANED_DIR),
+            first_dirty_offset,
+            end_offset,
+            now_ms,
+            delete_retention_ms: self.config.delete_retention_ms,
+            index_interval_bytes: self.config.index_interval_bytes,
+            groups,
+        })
+    }
+
+    /// Swaps in the segments cleaned by `compaction`, one group at a time: a `.swap` file is
+    /// complete and replaces the segments it overlaps if the broker crashes, while a cleaned
+    /// directory left by a crash is discarded when the log is next opened.
+    ///
+    /// If segments of the plan were deleted while it was being cleaned, by retention or
+    /// DeleteRecords, the cleaned segments are discarded instead, so that the deleted records
+    /// don't come back; the next pass starts over.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if a segment can't be replaced.
+    pub fn finish_compaction(
+        &mut self,
+        compaction: Compaction,
+        stats: CleanerStats,
+    ) -> KafkaResult<()> {
+        let unchanged = compaction
+            .groups
+            .iter()
+            .flatten()
+            .all(|(base_offset, _)| self.segments.contains_key(base_offset));
+        if unchanged {
+            for group in &compaction.groups {
+                self.swap_in_cleaned_segment(&compaction.cleaned_dir, group)?;
+            }
+        }
+        fs::remove_dir_all(&compaction.cleaned_dir)?;
+        if !unchanged {
+            info!(
+                "Discarded the compaction of {}: segments were deleted while it was cleaned",
+                self.topic_partition
+            );
+            return Ok(());
+        }
+        self.cleaner_offset = compaction.end_offset;
+
+        info!(
+            "Compacted {} up to offset {}: {} bytes read, {} bytes written, {} records removed",
+            self.topic_partition,
+            compaction.end_offset,
+            stats.bytes_read,
+            stats.bytes_written,
+            stats.records_removed
+        );
+        Ok(())
+    }
+
+    /// The offsets where the dirty part of the log starts and where the cleanable part ends,
+    /// if the log is due for cleaning.
+    fn cleanable_range(&self, now_ms: i64) -> Option<(i64, i64)> {
+        let first_dirty_offset = self.cleaner_offset.max(self.log_start_offset);
+        let active_base_offset = *self.segments.keys().next_back().unwrap();
+        let min_lag_horizon_ms = now_ms.saturating_sub(self.config.min_compaction_lag_ms);
+        let end_offset = self
+            .segments
+            .range(..active_base_offset)
+            .find(|(_, segment)| {
+                segment.next_offset() > first_dirty_offset
+                    && segment.max_timestamp() > min_lag_horizon_ms
+            })
+            .map_or(active_base_offset, |(&base_offset, _)| base_offset);
+        if end_offset <= first_dirty_offset {
+            return None;
+        }
+
+        let max_lag_horizon_ms = now_ms.saturating_sub(self.config.max_compaction_lag_ms);
+        let (mut clean_bytes, mut dirty_bytes) = (0, 0);
+        let mut overdue = false;
+        for segment in self
+            .segments
+            .range(..end_offset)
+            .map(|(_, segment)| segment)
+        {
+            if segment.next_offset() <= first_dirty_offset {
+                clean_bytes += segment.size();
+            } else {
+                dirty_bytes += segment.size();
+                overdue |= segment
+                    .first_timestamp()
+                    .is_some_and(|timestamp| timestamp < max_lag_horizon_ms);
+            }
+        }
+        if dirty_bytes == 0 {
+            return None;
+        }
+        let dirty_ratio = dirty_bytes as f64 / (clean_bytes + dirty_bytes) as f64;
+        if dirty_ratio < self.config.min_cleanable_dirty_ratio && !overdue {
+            return None;
+        }
+        debug!(
+            "Cleaning {} from offset {} to {} (dirty ratio {:.2})",
+            self.topic_partition, first_dirty_offset, end_offset, dirty_ratio
+        );
+        Some((first_dirty_offset, end_offset))
+    }
+
+    /// Replaces the segments of `group` with the cleaned segment written for them in
+    /// `cleaned_dir`.
+    fn swap_in_cleaned_segment(
+        &mut self,
+        cleaned_dir: &Path,
+        group: &[(i64, SegmentData)],
+    ) -> KafkaResult<()> {
+        let base_offset = group[0].0;
+        // From here on the cleaned segment survives a crash and replaces the old ones.
+        fs::rename(
+            segment_path(cleaned_dir, base_offset, LOG_FILE_SUFFIX),
+            segment_path(&self.dir, base_offset, SWAP_FILE_SUFFIX),
+        )?;
+        for (segment_base_offset, _) in group {
+            if let Some(segment) = self.segments.remove(segment_base_offset) {
+                segment.delete()?;
+            }
+        }
+        install_cleaned_indexes(cleaned_dir, &self.dir, base_offset)?;
+        fs::rename(
+            segment_path(&self.dir, base_offset, SWAP_FILE_SUFFIX),
+            segment_path(&self.dir, base_offset, LOG_FILE_SUFFIX),
+        )?;
+
+        let segment = LogSegment::open(&self.dir, base_offset)?;
+        self.segments.insert(base_offset, segment);
+        Ok(())
+    }
+
+    /// The offset of the first record still in the log.
+    pub fn log_start_offset(&self) -> i64 {
+        self.log_start_offset
+    }
+
+    /// The offset the next appended record will get. With a single replica this is also the
+    /// high watermark.
+    pub fn log_end_offset(&self) -> i64 {
+        self.log_end_offset
+    }
+
+    /// Reads whole record batches starting with the one containing `offset`, stopping before
+    /// the batch that would take the result past `max_bytes`. A read never spans segments. The
+    /// batches aren't copied: the result is the region of the segment file holding them, which
+    /// stays valid after the segment is rolled, cleaned or deleted.
+    ///
+    /// If `min_one_batch` is set, the first batch is returned even when it alone exceeds
+    /// `max_bytes`, so that consumers can always make progress (KIP-74). Reading at the log end
+    /// offset returns no data.
+    ///
+    /// # Errors
+    ///
+    /// - [`KafkaBrokerError::OffsetOutOfRange`] if `offset` is before the log start offset or
+    ///   past the log end offset.
+    /// - [`KafkaBrokerError::Io`] if a segment can't be read.
+    pub fn read(&self, offset: i64, max_bytes: usize, min_one_batch: bool) -> KafkaResult<Records> {
+        if offset < self.log_start_offset || offset > self.log_end_offset {
+            return Err(KafkaBrokerError::OffsetOutOfRange {
+                offset,
+                log_start_offset: self.log_start_offset,
+                log_end_offset: self.log_end_offset,
+            });
+        }
+
+        // The batch holding `offset` is in the last segment starting at or before it, unless
+        // that segment ends early (e.g. after compaction), in which case it's in a later one.
+        let first = self
+            .segments
+            .range(..=offset)
+            .next_back()
+            .map_or(offset, |(&base_offset, _)| base_offset);
+        for (base_offset, segment) in self.segments.range(first..) {
+            if let Some(region) = segment.read(offset, max_bytes, min_one_batch)? {
+                trace!(
+                    "Read {} bytes from {} at offset {} (segment {})",
+                    region.len(),
+                    self.topic_partition,
+                    offset,
+                    base_offset
+                );
+                return Ok(Records::File(region));
+            }
+        }
+        Ok(Records::default())
+    }
+
+    /// Returns the offset of the first record with a timestamp at or after `timestamp`, or
+    /// `None` if every record is older.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if a segment can't be read, or a decoding error if the
+    /// batch holding the record is damaged.
+    #[allow(dead_code)] // Reserved for ListOffsets.
+    pub fn offset_for_timestamp(&self, timestamp: i64) -> KafkaResult<Option<i64>> {
+        for segment in self.segments.values() {
+            if segment.max_timestamp() < timestamp {
+                continue;
+            }
+            if let Some(offset) = segment.find_offset_by_timestamp(timestamp)? {
+                return Ok(Some(offset.max(self.log_start_offset)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Validates the record batch in `records` and appends it to the log.
+    ///
+    /// `records` is either exactly one v2 batch or, from Produce v0-2, a legacy message set,
+    /// which is up-converted into one v2 batch first. The batch is assigned offsets
+    /// starting at the log end offset and stamped with `leader_epoch`; for `LogAppendTime`
+    /// logs its max timestamp is set to `now_ms`. The records are decompressed for validation,
+    /// and recompressed if the log's `compression.type` names a codec other than the
+    /// producer's.
+    ///
+    /// A batch from an idempotent producer that duplicates one of the producer's last batches
+    /// isn't appended again: the offsets (and append time) of the original are returned
+    /// instead.
+    ///
+    /// A new segment is rolled first if the active one would exceed `segment.bytes`, is older
+    /// than `segment.ms`, or can't index the batch's offsets. The log is synced to disk
+    /// afterwards if it now holds at least `flush.messages` unsynced records.
+    ///
+    /// # Errors
+    ///
+    /// - [`KafkaBrokerError::CorruptMessage`] if the batch is truncated, its sizes are
+    ///   inconsistent, its CRC doesn't match, or one of its records is malformed.
+    /// - [`KafkaBrokerError::InvalidRecord`] if there isn't exactly one batch, its record
+    ///   count is inconsistent, a legacy message set is empty, the log is compacted and a
+    ///   record has no key, or the batch has a producer id but no sequence number.
+    /// - [`KafkaBrokerError::InvalidProducerEpoch`] or
+    ///   [`KafkaBrokerError::OutOfOrderSequenceNumber`] if the batch is from an idempotent
+    ///   producer and doesn't follow its last batch (see [`ProducerStateManager::check`]).
+    /// - [`KafkaBrokerError::MessageTooLarge`] if the batch exceeds `max.message.bytes`.
+    /// - [`KafkaBrokerError::UnsupportedCompressionType`] if the batch uses an unknown codec or
+    ///   one this build doesn't include.
+    /// - [`KafkaBrokerError::Io`] if the batch can't be written.
+    pub fn append(
+        &mut self,
+        records: &[u8],
+        leader_epoch: i32,
+        now_ms: i64,
+    ) -> KafkaResult<LogAppendInfo> {
+        let (mut batch, batch_records) = match peek_magic(records) {
+            Some(magic) if magic < MAGIC_V2 => {
+                up_convert_message_set(records, self.config.max_message_bytes)?
+            }
+            _ => validate_batch(records, self.config.max_message_bytes)?,
+        };
+        if self.config.cleanup_policy.compact
+            && !batch.is_control_batch()
+            && batch_records.iter().any(|record| record.key.is_none())
+        {
+            return Err(KafkaBrokerError::InvalidRecord(format!(
+                "Compacted topic cannot accept message without key in topic partition {}",
+                self.topic_partition
+            )));
+        }
+        if let Some(duplicate) = self.producer_state.check(&batch)? {
+            debug!(
+                "Skipping duplicate batch of producer {} for {}, already at offsets {}..={}",
+                batch.producer_id,
+                self.topic_partition,
+                duplicate.first_offset(),
+                duplicate.last_offset
+            );
+            return Ok(LogAppendInfo {
+                first_offset: duplicate.first_offset(),
+                last_offset: duplicate.last_offset,
+                log_append_time: match self.config.message_timestamp_type {
+                    TimestampType::CreateTime => -1,
+                    TimestampType::LogAppendTime => duplicate.timestamp,
+                },
+                log_start_offset: self.log_start_offset,
+            });
+        }
+        if let CompressionType::Codec(target) = self.config.compression_type {
+            let source = batch.compression()?;
+            if target != source {
+                trace!(
+                    "Recompressing batch for {} from {} to {}",
+                    self.topic_partition,
+                    source.name(),
+                    target.name()
+                );
+                batch.set_records(&batch_records, target)?;
+            }
+        }
+        let first_offset = self.log_end_offset;
+        batch.base_offset = first_offset;
+        let last_offset = batch.last_offset();
+        batch.partition_leader_epoch = leader_epoch;
+        let log_append_time = match self.config.message_timestamp_type {
+            TimestampType::CreateTime => -1,
+            TimestampType::LogAppendTime => {
+                batch.set_log_append_time(now_ms);
+                now_ms
+            }
+        };
+
+        let mut data = Vec::with_capacity(batch.size_in_bytes());
+        batch.write(&mut data);
+        if self
+            .active_segment()
+            .should_roll(data.len(), last_offset, now_ms, &self.config)
+        {
+            self.roll(first_offset)?;
+        }
+        let index_interval_bytes = self.config.index_interval_bytes;
+        self.active_segment_mut()
+            .append(&data, &batch, now_ms, index_interval_bytes)?;
+        self.log_end_offset = last_offset + 1;
+        self.producer_state.update(&batch);
+        if self.log_end_offset - self.recovery_point >= self.config.flush_messages {
+            self.flush()?;
+        }
+
+        trace!(
+            "Appended offsets {}..={} to {}",
+            first_offset,
+            last_offset,
+            self.topic_partition
+        );
+        Ok(LogAppendInfo {
+            first_offset,
+            last_offset,
+            log_append_time,
+            log_start_offset: self.log_start_offset,
+        })
+    }
+
+    /// Starts a new active segment at `base_offset`, the log end offset, syncing the previous
+    /// one to disk and snapshotting the producer state.
+    fn roll(&mut self, base_offset: i64) -> KafkaResult<()> {
+        self.active_segment_mut().on_roll()?;
+        self.flush()?;
+        self.producer_state.take_snapshot(base_offset)?;
+        let segment = LogSegment::open(&self.dir, base_offset)?;
+        self.segments.insert(base_offset, segment);
+        debug!(
+            "Rolled new segment for {} at offset {}",
+            self.topic_partition, base_offset
+        );
+        Ok(())
+    }
+
+    fn active_segment(&self) -> &LogSegment {
+        self.segments.values().next_back().unwrap()
+    }
+
+    fn active_segment_mut(&mut self) -> &mut LogSegment {
+        self.segments.values_mut().next_back().unwrap()
+    }
+}
+
+/// Parses a log directory name, `<topic>-<partition>`.
+fn parse_log_dir_name(name: &str) -> Option<TopicPartition> {
+    let (topic, partition) = name.rsplit_once('-')?;
+    if topic.is_empty() {
+        return None;
+    }
+    Some(TopicPartition::new(topic, partition.parse().ok()?))
+}
+
+/// Reads the topic id from the `partition.metadata` file in `dir`, if there is one.
+fn read_topic_id(dir: &Path) -> KafkaResult<Option<Uuid>> {
+    let contents = match fs::read_to_string(dir.join(PARTITION_METADATA_FILE)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(contents
+        .lines()
+        .find_map(|line| line.strip_prefix("topic_id: "))
+        .and_then(|id| Uuid::parse_str(id.trim()).ok()))
+}
+
+/// Rebuilds the producer state of the log in `dir` from its latest snapshot, replaying the
+/// batches of `segments` written since. Replaying stops at the first damaged batch, which
+/// recovery is left to truncate.
+fn load_producer_state(
+    dir: &Path,
+    topic_partition: &TopicPartition,
+    segments: &BTreeMap<i64, LogSegment>,
+    log_end_offset: i64,
+) -> KafkaResult<ProducerStateManager> {
+    let (mut producer_state, snapshot_offset) =
+        ProducerStateManager::load(dir, topic_partition.clone(), log_end_offset)?;
+    if snapshot_offset == log_end_offset {
+        return Ok(producer_state);
+    }
+    let first = segments
+        .range(..=snapshot_offset)
+        .next_back()
+        .map_or(snapshot_offset, |(&base_offset, _)| base_offset);
+    let mut replayed = 0;
+    for segment in segments.range(first..).map(|(_, segment)| segment) {
+        for batch in segment.batches() {
+            match batch {
+                Ok(batch) if batch.last_offset() < snapshot_offset => {}
+                Ok(batch) => {
+                    producer_state.update(&batch);
+                    replayed += 1;
+                }
+                Err(e) => {
+                    warn!(
+                        "Stopped rebuilding the producer state of {} at a damaged batch: {}",
+                        topic_partition, e
+                    );
+                    return Ok(producer_state);
+                }
+            }
+        }
+    }
+    debug!(
+        "Rebuilt the producer state of {} from offset {}, replaying {} batches",
+        topic_partition, snapshot_offset, replayed
+    );
+    Ok(producer_state)
+}
+
+/// Converts the legacy message set in `records` into a v2 batch. Returns the batch along with
+/// its records.
+fn up_convert_message_set(
+    records: &[u8],
+    max_message_bytes: usize,
+) -> KafkaResult<(RecordBatch, Vec<Record>)> {
+    if records.len() > max_message_bytes {
+        return Err(KafkaBrokerError::MessageTooLarge {
+            size: records.len(),
+            max: max_message_bytes,
+        });
+    }
+    kafka_message_set::up_convert(records)
+}
+
+/// Decodes and checks the single v2 record batch in `records`. Returns the batch along with
+/// its decompressed records.
+fn validate_batch(
+    records: &[u8],
+    max_message_bytes: usize,
+) -> KafkaResult<(RecordBatch, Vec<Record>)> {
+    if records.is_empty() {
+        return Err(KafkaBrokerError::InvalidRecord(
+            "Produce requests with version 3+ must have at least one record batch per partition"
+                .to_string(),
+        ));
+    }
+
+    let batch_size = RecordBatch::peek_size(records)?;
+    if batch_size > max_message_bytes {
+        return Err(KafkaBrokerError::MessageTooLarge {
+            size: batch_size,
+            max: max_message_bytes,
+        });
+    }
+
+    let mut cursor = records;
+    let batch = RecordBatch::read(&mut cursor)?;
+    if !cursor.is_empty() {
+        return Err(KafkaBrokerError::InvalidRecord(
+            "Produce requests with version 3+ may only contain one record batch per partition"
+                .to_string(),
+        ));
+    }
+
+    if batch.records_count <= 0 || batch.last_offset_delta != batch.records_count - 1 {
+        return Err(KafkaBrokerError::InvalidRecord(format!(
+            "Record batch has {} records but a last offset delta of {}",
+            batch.records_count, batch.last_offset_delta
+        )));
+    }
+    let batch_records = batch.records()?;
+
+    Ok((batch, batch_records))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::batch;
+    use std::io::Write;
+
+    fn create_log(dir: &Path, config: LogConfig) -> PartitionLog {
+        PartitionLog::create(dir, TopicPartition::new("t", 0), Uuid::new_v4(), config).unwrap()
+    }
+
+    /// Opens the log [`create_log`] created in `dir` again, as after a restart.
+    fn reopen_log(dir: &Path, config: LogConfig) -> PartitionLog {
+        PartitionLog::open(dir.join("t-0"), |_| Ok(config))
+            .unwrap()
+            .unwrap()
+    }
+
+    /// The path of the `.log` file of the segment at `base_offset` of the log in `dir`.
+    fn segment_file(dir: &Path, base_offset: i64) -> PathBuf {
+        segment_path(&dir.join("t-0"), base_offset, LOG_FILE_SUFFIX)
+    }
+
+    #[test]
+    fn offset_for_timestamp_searches_the_time_indexes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = LogConfig::for_tests();
+        // Index every batch, and give every second batch a new segment.
+        config.index_interval_bytes = 1;
+        config.segment_bytes = 2 * batch(0, &[("k", Some("v")), ("k", Some("v"))]).len() as u64;
+        let mut log = create_log(dir.path(), config);
+        for timestamp in [1000, 2000, 3000, 4000] {
+            let records = batch(timestamp, &[("k", Some("v")), ("k", Some("v"))]);
+            log.append(&records, 0, timestamp).unwrap();
+        }
+        assert_eq!(log.segments.len(), 2);
+
+        // Before the first entry.
+        assert_eq!(log.offset_for_timestamp(500).unwrap(), Some(0));
+        // Within a batch, between index entries, and across segments.
+        assert_eq!(log.offset_for_timestamp(1001).unwrap(), Some(1));
+        assert_eq!(log.offset_for_timestamp(1500).unwrap(), Some(2));
+        assert_eq!(log.offset_for_timestamp(2500).unwrap(), Some(4));
+        assert_eq!(log.offset_for_timestamp(4001).unwrap(), Some(7));
+        // After the last entry.
+        assert_eq!(log.offset_for_timestamp(4002).unwrap(), None);
+    }
+
+    /// Appends three batches of two records, with timestamps 1000, 2000 and 3000, to a new log
+    /// in `dir`. Returns the size of each batch.
+    fn write_three_batches(dir: &Path, config: &LogConfig) -> u64 {
+        let mut log = create_log(dir, config.clone());
+        for timestamp in [1000, 2000, 3000] {
+            let records = batch(timestamp, &[("k", Some("v")), ("k", Some("v"))]);
+            log.append(&records, 0, timestamp).unwrap();
+        }
+        log.close().unwrap();
+        batch(0, &[("k", Some("v")), ("k", Some("v"))]).len() as u64
+    }
+
+    #[test]
+    fn recovery_truncates_a_torn_last_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = LogConfig::for_tests();
+        let batch_size = write_three_batches(dir.path(), &config);
+        // Half of a fourth batch made it to disk.
+        let torn = batch(4000, &[("k", Some("v")), ("k", Some("v"))]);
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(segment_file(dir.path(), 0))
+            .unwrap();
+        file.write_all(&torn[..torn.len() / 2]).unwrap();
+
+        let mut log = reopen_log(dir.path(), config);
+        log.recover(0).unwrap();
+        assert_eq!(log.log_end_offset(), 6);
+        let size = fs::metadata(segment_file(dir.path(), 0)).unwrap().len();
+        assert_eq!(size, 3 * batch_size);
+
+        // Appends carry on where the intact batches end.
+        let records = batch(4000, &[("k", Some("v"))]);
+        assert_eq!(log.append(&records, 0, 4000).unwrap().first_offset, 6);
+    }
+
+    #[test]
+    fn recovery_truncates_at_a_batch_with_a_bad_crc() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = LogConfig::for_tests();
+        let batch_size = write_three_batches(dir.path(), &config);
+        // Damage the last record of the second batch.
+        let path = segment_file(dir.path(), 0);
+        let mut data = fs::read(&path).unwrap();
+        data[2 * batch_size as usize - 1] ^= 0xff;
+        fs::write(&path, data).unwrap();
+
+        let mut log = reopen_log(dir.path(), config);
+        log.recover(0).unwrap();
+        assert_eq!(log.log_end_offset(), 2);
+        assert_eq!(fs::metadata(&path).unwrap().len(), batch_size);
+    }
+
+    #[test]
+    fn recovery_rebuilds_the_indexes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = LogConfig::for_tests();
+        config.index_interval_bytes = 1;
+        let batch_size = write_three_batches(dir.path(), &config);
+        let segment_dir = dir.path().join("t-0");
+        for suffix in ["index", "timeindex"] {
+            fs::write(segment_path(&segment_dir, 0, suffix), b"").unwrap();
+        }
+
+        let mut log = reopen_log(dir.path(), config);
+        log.recover(0).unwrap();
+        for suffix in ["index", "timeindex"] {
+            let size = fs::metadata(segment_path(&segment_dir, 0, suffix))
+                .unwrap()
+                .len();
+            assert!(size > 0, "the .{} file wasn't rebuilt", suffix);
+        }
+        // Lookups by offset and by timestamp use the rebuilt entries.
+        match log.read(5, 1 << 20, true).unwrap() {
+            Records::File(region) => assert_eq!(region.position(), 2 * batch_size),
+            records => panic!("expected records from the segment, got {:?}", records),
+        }
+        assert_eq!(log.offset_for_timestamp(2500).unwrap(), Some(4));
+    }
+
+    /// A log in `dir` with one single-record batch per segment, with timestamps 1000, 2000,
+    /// and so on, `count` of them.
+    fn log_with_segments(dir: &Path, config: LogConfig, count: i64) -> PartitionLog {
+        let mut log = create_log(
+            dir,
+            LogConfig {
+                segment_bytes: 1,
+                ..config
+            },
+        );
+        for timestamp in (1..=count).map(|i| i * 1000) {
+            log.append(&batch(timestamp, &[("k", Some("v"))]), 0, timestamp)
+                .unwrap();
+        }
+        assert_eq!(log.segments.len(), count as usize);
+        log
+    }
+
+    #[test]
+    fn time_retention_deletes_expired_segments_but_keeps_an_active_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = LogConfig {
+            retention_ms: 1000,
+            ..LogConfig::for_tests()
+        };
+        let mut log = log_with_segments(dir.path(), config, 3);
+
+        assert_eq!(log.delete_old_segments(3500).unwrap(), 2);
+        assert_eq!(log.segments.keys().collect::<Vec<_>>(), [&2]);
+        assert_eq!(log.log_start_offset(), 2);
+
+        // Once the active segment expires too, an empty one takes its place.
+        assert_eq!(log.delete_old_segments(10_000).unwrap(), 1);
+        assert_eq!(log.segments.keys().collect::<Vec<_>>(), [&3]);
+        assert_eq!((log.log_start_offset(), log.log_end_offset()), (3, 3));
+        assert_eq!(log.delete_old_segments(20_000).unwrap(), 0);
+        let records = batch(20_000, &[("k", Some("v"))]);
+        assert_eq!(log.append(&records, 0, 20_000).unwrap().first_offset, 3);
+    }
+
+    #[test]
+    fn size_retention_deletes_the_oldest_segments_but_keeps_the_active_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let batch_size = batch(0, &[("k", Some("v"))]).len() as i64;
+        let config = LogConfig {
+            retention_bytes: 2 * batch_size,
+            ..LogConfig::for_tests()
+        };
+        let mut log = log_with_segments(dir.path(), config, 4);
+        assert_eq!(log.delete_old_segments(4000).unwrap(), 2);
+        assert_eq!(log.segments.keys().collect::<Vec<_>>(), [&2, &3]);
+        assert_eq!(log.log_start_offset(), 2);
+
+        // The active segment alone is over the limit, but is kept: without it, the log would
+        // hold less than `retention.bytes`.
+        log.config.retention_bytes = batch_size - 1;
+        assert_eq!(log.delete_old_segments(4000).unwrap(), 1);
+        assert_eq!(log.segments.keys().collect::<Vec<_>>(), [&3]);
+        assert_eq!(log.delete_old_segments(4000).unwrap(), 0);
+        assert_eq!(log.log_end_offset(), 4);
+    }
+
+    #[test]
+    fn delete_records_moves_the_log_start_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = log_with_segments(dir.path(), LogConfig::for_tests(), 4);
+
+        assert_eq!(log.delete_records_before(2).unwrap(), 2);
+        assert!(matches!(
+            log.read(1, 1 << 20, true),
+            Err(KafkaBrokerError::OffsetOutOfRange { .. })
+        ));
+        // An offset before the log start offset leaves it where it is.
+        assert_eq!(log.delete_records_before(1).unwrap(), 2);
+        // The segments before it go with the next retention pass.
+        assert_eq!(log.delete_old_segments(4000).unwrap(), 2);
+        assert_eq!(log.segments.keys().collect::<Vec<_>>(), [&2, &3]);
+
+        // -1 stands for the high watermark, and nothing can be deleted past it.
+        assert_eq!(log.delete_records_before(-1).unwrap(), 4);
+        assert!(matches!(
+            log.delete_records_before(5),
+            Err(KafkaBrokerError::OffsetOutOfRange { offset: 5, .. })
+        ));
+        assert_eq!(log.log_start_offset(), 4);
+    }
+}
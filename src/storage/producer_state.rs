@@ -0,0 +1,532 @@
+//! The state of the idempotent producers writing to a partition, used to deduplicate their
+//! retried batches and to detect lost ones.
+//!
+//! An idempotent producer (`enable.idempotence=true`) numbers the records it sends to each
+//! partition, and stamps every batch with its producer id, its epoch and the sequence number of
+//! its first record. For each producer, the partition remembers the last
+//! [`NUM_BATCHES_TO_RETAIN`] batches it appended, as many as a producer may have in flight. A
+//! batch matching one of them is a retry of a batch that was already written, and is
+//! acknowledged with the original offsets instead of being appended again; any other batch must
+//! follow the producer's last one.
+//!
+//! The state is rebuilt from the log at startup. So that only the end of the log has to be
+//! read again, it is saved in snapshot files next to the segments, `<offset>.snapshot`, holding
+//! the state of every producer as of `<offset>`. A snapshot is taken whenever a segment is
+//! rolled and on clean shutdown, and the snapshots older than the log's first segment are
+//! deleted along with it. Snapshots use Kafka's format, which only keeps the last batch of each
+//! producer.
+
+use crate::kafka_protocol::kafka_codec::{
+    read_i16, read_i32, read_i64, read_u32, write_i16, write_i32, write_i64, write_u32,
+};
+use crate::kafka_protocol::kafka_error::{KafkaBrokerError, KafkaResult};
+use crate::kafka_protocol::kafka_record_batch::RecordBatch;
+use crate::storage::log_segment::{parse_segment_file_name, segment_path};
+use crate::storage::TopicPartition;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+/// How many batches are remembered per producer: the most a producer may have in flight
+/// (`max.in.flight.requests.per.connection`) with idempotence enabled.
+pub const NUM_BATCHES_TO_RETAIN: usize = 5;
+
+/// The extension of producer state snapshot files.
+const SNAPSHOT_FILE_SUFFIX: &str = "snapshot";
+
+/// The version of the snapshot format.
+const SNAPSHOT_VERSION: i16 = 1;
+
+/// Where the checksummed part of a snapshot starts, after its version and CRC.
+const SNAPSHOT_ENTRIES_OFFSET: usize = 6;
+
+/// The producer id of batches from producers that aren't idempotent.
+const NO_PRODUCER_ID: i64 = -1;
+
+/// What is remembered of a batch an idempotent producer appended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchMetadata {
+    /// The sequence number of the batch's last record.
+    pub last_seq: i32,
+    /// The offset of the batch's last record.
+    pub last_offset: i64,
+    /// The offset of the batch's last record, relative to its first.
+    pub offset_delta: i32,
+    /// The batch's max timestamp.
+    pub timestamp: i64,
+}
+
+impl BatchMetadata {
+    /// The sequence number of the batch's first record.
+    fn first_seq(&self) -> i32 {
+        decrement_sequence(self.last_seq, self.offset_delta)
+    }
+
+    /// The offset of the batch's first record.
+    pub fn first_offset(&self) -> i64 {
+        self.last_offset - self.offset_delta as i64
+    }
+}
+
+/// The state of one producer.
+#[derive(Debug, Clone)]
+struct ProducerStateEntry {
+    /// The producer's latest epoch.
+    epoch: i16,
+    /// The producer's last batches in this epoch, oldest first. Never empty.
+    batches: VecDeque<BatchMetadata>,
+}
+
+impl ProducerStateEntry {
+    /// The metadata of the producer's last batch.
+    fn last_batch(&self) -> &BatchMetadata {
+        self.batches
+            .back()
+            .expect("producer state entries hold a batch")
+    }
+}
+
+/// The idempotent producers of a partition.
+#[derive(Debug)]
+pub struct ProducerStateManager {
+    topic_partition: TopicPartition,
+    /// The log directory, where the snapshots are kept.
+    dir: PathBuf,
+    producers: HashMap<i64, ProducerStateEntry>,
+    /// The offsets of the snapshots in `dir`.
+    snapshots: BTreeSet<i64>,
+}
+
+impl ProducerStateManager {
+    /// Loads the state of the partition whose log is in `dir` from its latest snapshot at or
+    /// before `log_end_offset`. Returns it along with the offset the snapshot was taken at (0 if
+    /// there is none): the batches from that offset on must be replayed with
+    /// [`ProducerStateManager::update`].
+    ///
+    /// Snapshots past `log_end_offset`, whose batches were lost in a crash, are deleted, as are
+    /// damaged ones.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if `dir` can't be read or a snapshot can't be deleted.
+    pub fn load(
+        dir: &Path,
+        topic_partition: TopicPartition,
+        log_end_offset: i64,
+    ) -> KafkaResult<(Self, i64)> {
+        let mut snapshots = BTreeSet::new();
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            let offset = name
+                .to_str()
+                .and_then(|name| parse_segment_file_name(name, SNAPSHOT_FILE_SUFFIX));
+            if let Some(offset) = offset {
+                snapshots.insert(offset);
+            }
+        }
+        let mut state = Self {
+            topic_partition,
+            dir: dir.to_path_buf(),
+            producers: HashMap::new(),
+            snapshots,
+        };
+
+        while let Some(&offset) = state.snapshots.last() {
+            if offset > log_end_offset {
+                state.delete_snapshot(offset)?;
+                continue;
+            }
+            match read_snapshot(&segment_path(dir, offset, SNAPSHOT_FILE_SUFFIX)) {
+                Ok(producers) => {
+                    debug!(
+                        "Loaded the state of {} producers of {} from the snapshot at offset {}",
+                        producers.len(),
+                        state.topic_partition,
+                        offset
+                    );
+                    state.producers = producers;
+                    return Ok((state, offset));
+                }
+                Err(e) => {
+                    warn!(
+                        "Deleting damaged producer state snapshot {} of {}: {}",
+                        offset, state.topic_partition, e
+                    );
+                    state.delete_snapshot(offset)?;
+                }
+            }
+        }
+        Ok((state, 0))
+    }
+
+    /// Checks that `batch` can be appended, given what its producer wrote before. Returns the
+    /// metadata of the batch it duplicates if it is a retry of one of the producer's last
+    /// batches, which must then not be appended again.
+    ///
+    /// Batches from producers the partition doesn't know (e.g. whose batches were all deleted)
+    /// are accepted whatever their sequence number.
+    ///
+    /// # Errors
+    ///
+    /// - [`KafkaBrokerError::InvalidRecord`] if the batch has a producer id but no sequence
+    ///   number.
+    /// - [`KafkaBrokerError::InvalidProducerEpoch`] if the batch is from an older epoch of the
+    ///   producer than its last batch.
+    /// - [`KafkaBrokerError::OutOfOrderSequenceNumber`] if the batch doesn't follow the
+    ///   producer's last batch, or starts a new epoch with a sequence number other than 0.
+    pub fn check(&self, batch: &RecordBatch) -> KafkaResult<Option<BatchMetadata>> {
+        if batch.producer_id == NO_PRODUCER_ID || batch.is_control_batch() {
+            return Ok(None);
+        }
+        if batch.base_sequence < 0 {
+            return Err(KafkaBrokerError::InvalidRecord(format!(
+                "Invalid sequence number {} for producer {} in partition {}",
+                batch.base_sequence, batch.producer_id, self.topic_partition
+            )));
+        }
+        let Some(entry) = self.producers.get(&batch.producer_id) else {
+            return Ok(None);
+        };
+        if batch.producer_epoch < entry.epoch {
+            return Err(KafkaBrokerError::InvalidProducerEpoch {
+                producer_id: batch.producer_id,
+                producer_epoch: batch.producer_epoch,
+                current_epoch: entry.epoch,
+            });
+        }
+
+        let last_seq = entry.last_batch().last_seq;
+        let out_of_order = || {
+            Err(KafkaBrokerError::OutOfOrderSequenceNumber {
+                producer_id: batch.producer_id,
+                sequence: batch.base_sequence,
+                last_sequence: last_seq,
+            })
+        };
+        if batch.producer_epoch > entry.epoch {
+            return match batch.base_sequence {
+                0 => Ok(None),
+                _ => out_of_order(),
+            };
+        }
+        let batch_last_seq = increment_sequence(batch.base_sequence, batch.last_offset_delta);
+        if let Some(duplicate) = entry
+            .batches
+            .iter()
+            .find(|meta| meta.first_seq() == batch.base_sequence && meta.last_seq == batch_last_seq)
+        {
+            return Ok(Some(*duplicate));
+        }
+        if !in_sequence(last_seq, batch.base_sequence) {
+            return out_of_order();
+        }
+        Ok(None)
+    }
+
+    /// Records `batch`, appended to the log (or replayed from it) with its offsets assigned.
+    /// Batches from producers that aren't idempotent are ignored.
+    pub fn update(&mut self, batch: &RecordBatch) {
+        if batch.producer_id == NO_PRODUCER_ID || batch.is_control_batch() {
+            return;
+        }
+        let entry = self
+            .producers
+            .entry(batch.producer_id)
+            .or_insert_with(|| ProducerStateEntry {
+                epoch: batch.producer_epoch,
+                batches: VecDeque::with_capacity(NUM_BATCHES_TO_RETAIN),
+            });
+        if batch.producer_epoch != entry.epoch {
+            entry.epoch = batch.producer_epoch;
+            entry.batches.clear();
+        }
+        if entry.batches.len() == NUM_BATCHES_TO_RETAIN {
+            entry.batches.pop_front();
+        }
+        entry.batches.push_back(BatchMetadata {
+            last_seq: increment_sequence(batch.base_sequence, batch.last_offset_delta),
+            last_offset: batch.last_offset(),
+            offset_delta: batch.last_offset_delta,
+            timestamp: batch.max_timestamp,
+        });
+    }
+
+    /// Forgets the producers whose last batch is older than `expiration_ms` at `now_ms`, so
+    /// that their state doesn't pile up. Returns how many were removed.
+    pub fn remove_expired_producers(&mut self, now_ms: i64, expiration_ms: i64) -> usize {
+        let count = self.producers.len();
+        self.producers
+            .retain(|_, entry| now_ms - entry.last_batch().timestamp < expiration_ms);
+        count - self.producers.len()
+    }
+
+    /// Saves the state as of `offset`, the log end offset, in a snapshot file, unless there
+    /// already is one for that offset. The file is synced to disk, and only replaces an older
+    /// one once complete.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if the snapshot can't be written.
+    pub fn take_snapshot(&mut self, offset: i64) -> KafkaResult<()> {
+        if self.snapshots.contains(&offset) {
+            return Ok(());
+        }
+        let path = segment_path(&self.dir, offset, SNAPSHOT_FILE_SUFFIX);
+        let temp_path = path.with_extension("tmp");
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&encode_snapshot(&self.producers))?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &path)?;
+        self.snapshots.insert(offset);
+        debug!(
+            "Took a snapshot of {} producers of {} at offset {}",
+            self.producers.len(),
+            self.topic_partition,
+            offset
+        );
+        Ok(())
+    }
+
+    /// Deletes the snapshots taken before `offset`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KafkaBrokerError::Io`] if a snapshot can't be deleted.
+    pub fn delete_snapshots_before(&mut self, offset: i64) -> KafkaResult<()> {
+        let stale: Vec<i64> = self.snapshots.range(..offset).copied().collect();
+        for snapshot_offset in stale {
+            self.delete_snapshot(snapshot_offset)?;
+        }
+        Ok(())
+    }
+
+    fn delete_snapshot(&mut self, offset: i64) -> KafkaResult<()> {
+        self.snapshots.remove(&offset);
+        match fs::remove_file(segment_path(&self.dir, offset, SNAPSHOT_FILE_SUFFIX)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Encodes the state of `producers` in a snapshot.
+fn encode_snapshot(producers: &HashMap<i64, ProducerStateEntry>) -> Vec<u8> {
+    let mut entries = Vec::new();
+    write_i32(&mut entries, producers.len() as i32);
+    for (&producer_id, entry) in producers {
+        let last_batch = entry.last_batch();
+        write_i64(&mut entries, producer_id);
+        write_i16(&mut entries, entry.epoch);
+        write_i32(&mut entries, last_batch.last_seq);
+        write_i64(&mut entries, last_batch.last_offset);
+        write_i32(&mut entries, last_batch.offset_delta);
+        write_i64(&mut entries, last_batch.timestamp);
+        // Transactions aren't supported: no coordinator epoch, and no ongoing transaction.
+        write_i32(&mut entries, -1);
+        write_i64(&mut entries, -1);
+    }
+
+    let mut buf = Vec::with_capacity(SNAPSHOT_ENTRIES_OFFSET + entries.len());
+    write_i16(&mut buf, SNAPSHOT_VERSION);
+    write_u32(&mut buf, crc32c::crc32c(&entries));
+    buf.extend_from_slice(&entries);
+    buf
+}
+
+/// Reads the snapshot at `path`.
+fn read_snapshot(path: &Path) -> KafkaResult<HashMap<i64, ProducerStateEntry>> {
+    let data = fs::read(path)?;
+    let mut cursor = data.as_slice();
+    let version = read_i16(&mut cursor)?;
+    if version != SNAPSHOT_VERSION {
+        return Err(KafkaBrokerError::CorruptMessage(format!(
+            "Unknown producer state snapshot version {}",
+            version
+        )));
+    }
+    let crc = read_u32(&mut cursor)?;
+    if crc != crc32c::crc32c(cursor) {
+        return Err(KafkaBrokerError::CorruptMessage(
+            "Producer state snapshot CRC doesn't match".to_string(),
+        ));
+    }
+
+    let count = read_i32(&mut cursor)?;
+    let mut producers = HashMap::with_capacity(count.max(0) as usize);
+    for _ in 0..count {
+        let producer_id = read_i64(&mut cursor)?;
+        let epoch = read_i16(&mut cursor)?;
+        let batch = BatchMetadata {
+            last_seq: read_i32(&mut cursor)?,
+            last_offset: read_i64(&mut cursor)?,
+            offset_delta: read_i32(&mut cursor)?,
+            timestamp: read_i64(&mut cursor)?,
+        };
+        let _coordinator_epoch = read_i32(&mut cursor)?;
+        let _current_txn_first_offset = read_i64(&mut cursor)?;
+        producers.insert(
+            producer_id,
+            ProducerStateEntry {
+                epoch,
+                batches: VecDeque::from([batch]),
+            },
+        );
+    }
+    Ok(producers)
+}
+
+/// Whether `next_seq` directly follows `last_seq`. Sequence numbers wrap around to 0 after
+/// `i32::MAX`.
+fn in_sequence(last_seq: i32, next_seq: i32) -> bool {
+    next_seq as i64 == last_seq as i64 + 1 || (next_seq == 0 && last_seq == i32::MAX)
+}
+
+/// The sequence number `increment` records after `sequence`.
+fn increment_sequence(sequence: i32, increment: i32) -> i32 {
+    if sequence > i32::MAX - increment {
+        increment - (i32::MAX - sequence) - 1
+    } else {
+        sequence + increment
+    }
+}
+
+/// The sequence number `decrement` records before `sequence`.
+fn decrement_sequence(sequence: i32, decrement: i32) -> i32 {
+    if sequence < decrement {
+        i32::MAX - (decrement - sequence) + 1
+    } else {
+        sequence - decrement
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kafka_protocol::kafka_error_codes::{
+        INVALID_PRODUCER_EPOCH, OUT_OF_ORDER_SEQUENCE_NUMBER,
+    };
+    use crate::kafka_protocol::kafka_record_batch::Record;
+
+    const PRODUCER_ID: i64 = 1000;
+
+    /// A batch of two records from epoch `epoch` of [`PRODUCER_ID`], appended at
+    /// `base_offset`.
+    fn batch(epoch: i16, base_sequence: i32, base_offset: i64) -> RecordBatch {
+        let records: Vec<Record> = (0..2)
+            .map(|i| Record {
+                attributes: 0,
+                timestamp_delta: 0,
+                offset_delta: i,
+                key: None,
+                value: Some(b"v".to_vec()),
+                headers: Vec::new(),
+            })
+            .collect();
+        let mut batch = RecordBatch::new(base_offset, 1000, &records);
+        batch.producer_id = PRODUCER_ID;
+        batch.producer_epoch = epoch;
+        batch.base_sequence = base_sequence;
+        batch
+    }
+
+    fn load(dir: &Path, log_end_offset: i64) -> (ProducerStateManager, i64) {
+        ProducerStateManager::load(dir, TopicPartition::new("t", 0), log_end_offset).unwrap()
+    }
+
+    /// A manager in `dir` that has seen `count` batches of epoch 0, with sequence numbers and
+    /// offsets counting up from 0.
+    fn with_batches(dir: &Path, count: i32) -> ProducerStateManager {
+        let (mut state, _) = load(dir, 0);
+        for i in 0..count {
+            state.update(&batch(0, 2 * i, 2 * i as i64));
+        }
+        state
+    }
+
+    fn error_code(result: KafkaResult<Option<BatchMetadata>>) -> i16 {
+        result.unwrap_err().error_code()
+    }
+
+    #[test]
+    fn retries_of_the_last_batches_return_their_original_offsets() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = with_batches(dir.path(), 1 + NUM_BATCHES_TO_RETAIN as i32);
+
+        for i in 1..=NUM_BATCHES_TO_RETAIN as i32 {
+            let duplicate = state.check(&batch(0, 2 * i, 100)).unwrap().unwrap();
+            assert_eq!(duplicate.first_offset(), 2 * i as i64);
+            assert_eq!(duplicate.last_offset, 2 * i as i64 + 1);
+        }
+        // The first batch has been forgotten: its retry is just out of order.
+        assert_eq!(
+            error_code(state.check(&batch(0, 0, 100))),
+            OUT_OF_ORDER_SEQUENCE_NUMBER
+        );
+    }
+
+    #[test]
+    fn batches_must_follow_the_last_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = with_batches(dir.path(), 2);
+
+        assert_eq!(state.check(&batch(0, 4, 4)).unwrap(), None);
+        assert_eq!(
+            error_code(state.check(&batch(0, 5, 4))),
+            OUT_OF_ORDER_SEQUENCE_NUMBER
+        );
+        // A new epoch starts over at 0.
+        assert_eq!(state.check(&batch(1, 0, 4)).unwrap(), None);
+        assert_eq!(
+            error_code(state.check(&batch(1, 4, 4))),
+            OUT_OF_ORDER_SEQUENCE_NUMBER
+        );
+    }
+
+    #[test]
+    fn older_epochs_are_fenced() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = with_batches(dir.path(), 1);
+        state.update(&batch(1, 0, 2));
+
+        assert_eq!(
+            error_code(state.check(&batch(0, 2, 4))),
+            INVALID_PRODUCER_EPOCH
+        );
+    }
+
+    #[test]
+    fn snapshots_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = with_batches(dir.path(), 2);
+        state.take_snapshot(4).unwrap();
+
+        let (state, snapshot_offset) = load(dir.path(), 4);
+        assert_eq!(snapshot_offset, 4);
+        // Snapshots keep each producer's last batch.
+        let duplicate = state.check(&batch(0, 2, 100)).unwrap().unwrap();
+        assert_eq!((duplicate.first_offset(), duplicate.last_offset), (2, 3));
+        assert_eq!(state.check(&batch(0, 4, 4)).unwrap(), None);
+        assert_eq!(
+            error_code(state.check(&batch(0, 6, 4))),
+            OUT_OF_ORDER_SEQUENCE_NUMBER
+        );
+    }
+
+    #[test]
+    fn snapshots_past_the_log_end_offset_are_deleted_on_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = with_batches(dir.path(), 1);
+        state.take_snapshot(2).unwrap();
+        state.update(&batch(0, 2, 2));
+        state.take_snapshot(4).unwrap();
+
+        // The batch at offset 2 was lost in a crash.
+        let (state, snapshot_offset) = load(dir.path(), 2);
+        assert_eq!(snapshot_offset, 2);
+        assert!(!segment_path(dir.path(), 4, SNAPSHOT_FILE_SUFFIX).exists());
+        assert_eq!(state.snapshots, BTreeSet::from([2]));
+        assert_eq!(state.check(&batch(0, 2, 2)).unwrap(), None);
+    }
+}